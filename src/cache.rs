@@ -0,0 +1,342 @@
+//! The caches of the Disk.
+//!
+//! The write-back cache keeps blocks in memory until their piece is
+//! complete, so that the piece can be hashed from memory and written
+//! to disk at once, instead of one write per block. The cache has a
+//! memory cap, when it is full the pieces that were not written for
+//! the longest time are flushed to disk, even if they are not complete.
+//!
+//! The read cache keeps the pieces that were read to seed them. The
+//! whole piece is read on the first request of one of its blocks,
+//! because peers usually request all blocks of a piece, and the least
+//! recently used pieces are evicted when the cache is full.
+use std::collections::BTreeMap;
+
+use hashbrown::HashMap;
+
+use crate::tcp_wire::lib::{Block, BlockInfo};
+
+/// The blocks of a piece that are in memory.
+#[derive(Debug, Clone)]
+pub struct CachedPiece {
+    /// k: begin
+    pub blocks: BTreeMap<u32, Vec<u8>>,
+    /// When the last block was added to the piece, see `WriteCache::writes`.
+    last_write: u64,
+}
+
+impl CachedPiece {
+    /// How many bytes of the piece are in memory.
+    pub fn len(&self) -> usize {
+        self.blocks.values().map(|b| b.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WriteCache {
+    /// k: (info_hash, piece index)
+    pieces: HashMap<([u8; 20], usize), CachedPiece>,
+    /// How many bytes are in memory.
+    size: usize,
+    /// How many blocks were inserted, used to order the pieces by their last write.
+    writes: u64,
+    /// How many bytes can be in memory, before pieces are flushed.
+    pub capacity: usize,
+}
+
+impl Default for WriteCache {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
+impl WriteCache {
+    /// 32 MiB
+    pub const DEFAULT_CAPACITY: usize = 32 * 1024 * 1024;
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            pieces: HashMap::new(),
+            size: 0,
+            writes: 0,
+            capacity,
+        }
+    }
+
+    /// Add a block to the cache, a block that is already in the cache is replaced.
+    pub fn insert(&mut self, info_hash: [u8; 20], block: Block) {
+        let len = block.block.len();
+        self.writes += 1;
+
+        let piece = self
+            .pieces
+            .entry((info_hash, block.index))
+            .or_insert_with(|| CachedPiece {
+                blocks: BTreeMap::new(),
+                last_write: 0,
+            });
+
+        piece.last_write = self.writes;
+
+        if let Some(old) = piece.blocks.insert(block.begin, block.block) {
+            self.size -= old.len();
+        }
+
+        self.size += len;
+    }
+
+    /// The bytes of a block, if they are inside one of the blocks in the cache.
+    pub fn get(&self, info_hash: [u8; 20], block_info: &BlockInfo) -> Option<&[u8]> {
+        let piece = self.pieces.get(&(info_hash, block_info.index as usize))?;
+        let (begin, block) = piece.blocks.range(..=block_info.begin).next_back()?;
+
+        let start = (block_info.begin - begin) as usize;
+        block.get(start..start + block_info.len as usize)
+    }
+
+    /// Remove a piece from the cache, to write it to disk or to discard it.
+    pub fn take(&mut self, info_hash: [u8; 20], index: usize) -> Option<CachedPiece> {
+        let piece = self.pieces.remove(&(info_hash, index))?;
+        self.size -= piece.len();
+        Some(piece)
+    }
+
+    /// The piece that was not written for the longest time.
+    pub fn oldest(&self) -> Option<([u8; 20], usize)> {
+        self.pieces
+            .iter()
+            .min_by_key(|(_, piece)| piece.last_write)
+            .map(|(k, _)| *k)
+    }
+
+    /// The pieces of a torrent that are in the cache.
+    pub fn pieces_of(&self, info_hash: [u8; 20]) -> Vec<usize> {
+        self.pieces
+            .keys()
+            .filter(|(i, _)| *i == info_hash)
+            .map(|(_, index)| *index)
+            .collect()
+    }
+
+    /// All pieces in the cache.
+    pub fn keys(&self) -> Vec<([u8; 20], usize)> {
+        self.pieces.keys().copied().collect()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.size > self.capacity
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// A LRU cache of complete pieces.
+#[derive(Debug, Clone)]
+pub struct ReadCache {
+    /// k: (info_hash, piece index), v: (bytes of the piece, last use)
+    pieces: HashMap<([u8; 20], usize), (Vec<u8>, u64)>,
+    /// How many bytes are in memory.
+    size: usize,
+    /// How many times the cache was used, to know which piece was least recently used.
+    uses: u64,
+    /// How many bytes can be in memory, before pieces are evicted.
+    pub capacity: usize,
+}
+
+impl Default for ReadCache {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
+impl ReadCache {
+    /// 16 MiB
+    pub const DEFAULT_CAPACITY: usize = 16 * 1024 * 1024;
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            pieces: HashMap::new(),
+            size: 0,
+            uses: 0,
+            capacity,
+        }
+    }
+
+    /// The bytes of a block, if its piece is in the cache.
+    pub fn get(&mut self, info_hash: [u8; 20], block_info: &BlockInfo) -> Option<&[u8]> {
+        self.uses += 1;
+
+        let (piece, last_use) = self
+            .pieces
+            .get_mut(&(info_hash, block_info.index as usize))?;
+
+        *last_use = self.uses;
+
+        let begin = block_info.begin as usize;
+        piece.get(begin..begin + block_info.len as usize)
+    }
+
+    /// Add a piece to the cache, and evict the least recently used pieces
+    /// until it fits. A piece that is larger than the cache is not added.
+    pub fn insert(&mut self, info_hash: [u8; 20], index: usize, piece: Vec<u8>) {
+        if piece.len() > self.capacity {
+            return;
+        }
+
+        self.remove(info_hash, index);
+
+        while self.size + piece.len() > self.capacity {
+            let Some(lru) = self
+                .pieces
+                .iter()
+                .min_by_key(|(_, (_, last_use))| *last_use)
+                .map(|(k, _)| *k)
+            else {
+                break;
+            };
+            self.remove(lru.0, lru.1);
+        }
+
+        self.uses += 1;
+        self.size += piece.len();
+        self.pieces.insert((info_hash, index), (piece, self.uses));
+    }
+
+    pub fn remove(&mut self, info_hash: [u8; 20], index: usize) {
+        if let Some((piece, _)) = self.pieces.remove(&(info_hash, index)) {
+            self.size -= piece.len();
+        }
+    }
+
+    /// Remove all pieces of a torrent.
+    pub fn remove_torrent(&mut self, info_hash: [u8; 20]) {
+        let pieces: Vec<usize> = self
+            .pieces
+            .keys()
+            .filter(|(i, _)| *i == info_hash)
+            .map(|(_, index)| *index)
+            .collect();
+
+        for index in pieces {
+            self.remove(info_hash, index);
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(index: usize, begin: u32, len: usize) -> Block {
+        Block {
+            index,
+            begin,
+            block: vec![begin as u8; len],
+        }
+    }
+
+    #[test]
+    fn insert_get_and_take() {
+        let mut cache = WriteCache::new(10);
+
+        cache.insert([0; 20], block(0, 0, 4));
+        cache.insert([0; 20], block(0, 4, 4));
+        cache.insert([1; 20], block(0, 0, 4));
+        assert_eq!(cache.size(), 12);
+        assert!(cache.is_full());
+
+        // a block that replaces another is not counted twice
+        cache.insert([0; 20], block(0, 4, 4));
+        assert_eq!(cache.size(), 12);
+
+        let get = |cache: &WriteCache, begin, len| {
+            cache
+                .get(
+                    [0; 20],
+                    &BlockInfo {
+                        index: 0,
+                        begin,
+                        len,
+                    },
+                )
+                .map(|b| b.to_vec())
+        };
+
+        assert_eq!(get(&cache, 4, 4), Some(vec![4; 4]));
+        assert_eq!(get(&cache, 5, 2), Some(vec![4; 2]));
+        // the bytes are in two blocks
+        assert_eq!(get(&cache, 2, 4), None);
+        assert_eq!(get(&cache, 8, 1), None);
+
+        let mut pieces = cache.keys();
+        pieces.sort();
+        assert_eq!(pieces, vec![([0; 20], 0), ([1; 20], 0)]);
+        assert_eq!(cache.pieces_of([1; 20]), vec![0]);
+
+        let piece = cache.take([0; 20], 0).unwrap();
+        assert_eq!(piece.len(), 8);
+        assert_eq!(piece.blocks.keys().collect::<Vec<_>>(), vec![&0, &4]);
+        assert_eq!(cache.size(), 4);
+        assert!(!cache.is_full());
+        assert!(cache.take([0; 20], 0).is_none());
+    }
+
+    #[test]
+    fn oldest_piece() {
+        let mut cache = WriteCache::default();
+        assert!(cache.oldest().is_none());
+
+        cache.insert([0; 20], block(0, 0, 4));
+        cache.insert([0; 20], block(1, 0, 4));
+        assert_eq!(cache.oldest(), Some(([0; 20], 0)));
+
+        // the first piece was written again
+        cache.insert([0; 20], block(0, 4, 4));
+        assert_eq!(cache.oldest(), Some(([0; 20], 1)));
+    }
+
+    #[test]
+    fn read_cache_evicts_the_least_recently_used_piece() {
+        let mut cache = ReadCache::new(8);
+
+        cache.insert([0; 20], 0, vec![0, 1, 2, 3]);
+        cache.insert([0; 20], 1, vec![4, 5, 6, 7]);
+        assert_eq!(cache.size(), 8);
+
+        let b = BlockInfo {
+            index: 0,
+            begin: 1,
+            len: 2,
+        };
+        assert_eq!(cache.get([0; 20], &b), Some(&[1, 2][..]));
+
+        // the second piece was used least recently
+        cache.insert([1; 20], 0, vec![8, 9, 10, 11]);
+        assert_eq!(cache.size(), 8);
+        assert!(cache.get([0; 20], &b).is_some());
+
+        let b = BlockInfo {
+            index: 1,
+            begin: 0,
+            len: 4,
+        };
+        assert!(cache.get([0; 20], &b).is_none());
+
+        // a piece larger than the cache
+        cache.insert([2; 20], 0, vec![0; 9]);
+        assert_eq!(cache.size(), 8);
+
+        cache.remove_torrent([0; 20]);
+        assert_eq!(cache.size(), 4);
+    }
+}
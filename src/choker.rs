@@ -0,0 +1,270 @@
+//! The choking algorithm of a torrent, decides which peers
+//! are allowed to download from us.
+use std::time::{Duration, Instant};
+
+use hashbrown::HashSet;
+use rand::seq::SliceRandom;
+
+use crate::peer::PeerStats;
+
+/// While leeching, the interested peers that upload the fastest to us are
+/// unchoked, and while seeding, the peers that download the fastest from us.
+/// Plus one optimistic unchoke, a random interested peer that is rotated
+/// every [`Choker::OPTIMISTIC_INTERVAL`], to find peers faster than the
+/// current ones, and to give new peers a chance. Snubbed peers can only be
+/// unchoked by the optimistic unchoke.
+#[derive(Debug, Clone)]
+pub struct Choker {
+    /// How many peers are unchoked by their rates,
+    /// not counting the optimistic unchoke.
+    pub slots: usize,
+    /// The peer of the optimistic unchoke.
+    pub optimistic: Option<[u8; 20]>,
+    last_optimistic: Option<Instant>,
+    /// The peers that are unchoked right now.
+    pub unchoked: HashSet<[u8; 20]>,
+}
+
+impl Default for Choker {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_SLOTS)
+    }
+}
+
+impl Choker {
+    /// How often the choker runs.
+    pub const INTERVAL: Duration = Duration::from_secs(10);
+
+    /// How often the optimistic unchoke is rotated.
+    pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+    pub const DEFAULT_SLOTS: usize = 4;
+
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            optimistic: None,
+            last_optimistic: None,
+            unchoked: HashSet::new(),
+        }
+    }
+
+    /// Decide which peers are unchoked, given the stats of the connected
+    /// peers. Returns the peers that must be unchoked, and the
+    /// peers that must be choked, since the last run.
+    pub fn run(
+        &mut self,
+        peers: &[([u8; 20], PeerStats)],
+        seeding: bool,
+        now: Instant,
+    ) -> (Vec<[u8; 20]>, Vec<[u8; 20]>) {
+        let mut interested: Vec<&([u8; 20], PeerStats)> =
+            peers.iter().filter(|(_, s)| s.peer_interested).collect();
+
+        interested.sort_by_key(|(_, s)| {
+            std::cmp::Reverse(if seeding {
+                s.upload_rate
+            } else {
+                s.download_rate
+            })
+        });
+
+        let mut unchoked: HashSet<[u8; 20]> = interested
+            .iter()
+            .filter(|(_, s)| !s.snubbed)
+            .take(self.slots)
+            .map(|(id, _)| *id)
+            .collect();
+
+        // the optimistic unchoke must still be interested, and
+        // must not be one of the peers unchoked by their rates.
+        let optimistic_valid = self
+            .optimistic
+            .map(|id| interested.iter().any(|(i, _)| *i == id) && !unchoked.contains(&id))
+            .unwrap_or(false);
+
+        let rotate = self
+            .last_optimistic
+            .is_none_or(|last| now.saturating_duration_since(last) >= Self::OPTIMISTIC_INTERVAL);
+
+        if !optimistic_valid || rotate {
+            let choked: Vec<[u8; 20]> = interested
+                .iter()
+                .map(|(id, _)| *id)
+                .filter(|id| !unchoked.contains(id) && Some(*id) != self.optimistic)
+                .collect();
+
+            let new = choked.choose(&mut rand::thread_rng()).copied();
+
+            // keep the current optimistic unchoke if there is no one else
+            if new.is_some() || !optimistic_valid {
+                self.optimistic = new;
+                self.last_optimistic = Some(now);
+            }
+        }
+
+        if let Some(optimistic) = self.optimistic {
+            unchoked.insert(optimistic);
+        }
+
+        let unchoke = unchoked.difference(&self.unchoked).copied().collect();
+        let choke = self.unchoked.difference(&unchoked).copied().collect();
+
+        self.unchoked = unchoked;
+
+        (unchoke, choke)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(
+        id: u8,
+        download_rate: u64,
+        upload_rate: u64,
+        interested: bool,
+    ) -> ([u8; 20], PeerStats) {
+        (
+            [id; 20],
+            PeerStats {
+                download_rate,
+                upload_rate,
+                peer_interested: interested,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn sorted(mut ids: Vec<[u8; 20]>) -> Vec<[u8; 20]> {
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn unchoke_fastest_peers_while_leeching() {
+        let mut choker = Choker::new(2);
+        let now = Instant::now();
+
+        let peers = vec![
+            peer(1, 100, 0, true),
+            peer(2, 300, 0, true),
+            peer(3, 200, 0, true),
+            peer(4, 900, 0, false),
+        ];
+
+        let (unchoke, choke) = choker.run(&peers, false, now);
+
+        // peer 1 is the only candidate to the optimistic unchoke
+        assert_eq!(sorted(unchoke), vec![[1; 20], [2; 20], [3; 20]]);
+        assert!(choke.is_empty());
+        assert_eq!(choker.optimistic, Some([1; 20]));
+
+        // peer 1 became the fastest, it takes the place of peer 3,
+        // which becomes the optimistic unchoke, before its rotation.
+        let peers = vec![
+            peer(1, 500, 0, true),
+            peer(2, 300, 0, true),
+            peer(3, 200, 0, true),
+        ];
+
+        let (unchoke, choke) = choker.run(&peers, false, now + Choker::INTERVAL);
+
+        assert!(unchoke.is_empty());
+        assert!(choke.is_empty());
+        assert_eq!(choker.optimistic, Some([3; 20]));
+
+        // peer 4 is interested now, and faster than peer 2
+        let peers = vec![
+            peer(1, 500, 0, true),
+            peer(2, 300, 0, true),
+            peer(3, 200, 0, true),
+            peer(4, 400, 0, true),
+        ];
+
+        let (unchoke, choke) = choker.run(&peers, false, now + Choker::INTERVAL * 2);
+
+        assert_eq!(unchoke, vec![[4; 20]]);
+        assert_eq!(choke, vec![[2; 20]]);
+    }
+
+    #[test]
+    fn upload_rate_while_seeding() {
+        let mut choker = Choker::new(1);
+        let now = Instant::now();
+
+        let peers = vec![peer(1, 900, 10, true), peer(2, 0, 500, true)];
+
+        choker.run(&peers, true, now);
+
+        assert_eq!(choker.optimistic, Some([1; 20]));
+        assert_eq!(choker.unchoked.len(), 2);
+    }
+
+    #[test]
+    fn rotate_optimistic_unchoke() {
+        let mut choker = Choker::new(1);
+        let now = Instant::now();
+
+        let peers = vec![
+            peer(1, 900, 0, true),
+            peer(2, 0, 0, true),
+            peer(3, 0, 0, true),
+        ];
+
+        choker.run(&peers, false, now);
+        let first = choker.optimistic.unwrap();
+        assert_ne!(first, [1; 20]);
+
+        // not yet time to rotate
+        let (unchoke, choke) = choker.run(&peers, false, now + Choker::INTERVAL);
+        assert!(unchoke.is_empty());
+        assert!(choke.is_empty());
+        assert_eq!(choker.optimistic, Some(first));
+
+        // rotate to the other choked peer
+        let (unchoke, choke) = choker.run(&peers, false, now + Choker::OPTIMISTIC_INTERVAL);
+        let second = choker.optimistic.unwrap();
+        assert_ne!(second, first);
+        assert_ne!(second, [1; 20]);
+        assert_eq!(unchoke, vec![second]);
+        assert_eq!(choke, vec![first]);
+    }
+
+    #[test]
+    fn snubbed_peers_only_optimistic() {
+        let mut choker = Choker::new(1);
+        let now = Instant::now();
+
+        let mut snubbed = peer(1, 900, 0, true);
+        snubbed.1.snubbed = true;
+
+        let peers = vec![snubbed, peer(2, 100, 0, true)];
+
+        let (unchoke, _) = choker.run(&peers, false, now);
+
+        // peer 2 takes the regular slot even if it is slower,
+        // and peer 1 can only be the optimistic unchoke
+        assert_eq!(sorted(unchoke), vec![[1; 20], [2; 20]]);
+        assert_eq!(choker.optimistic, Some([1; 20]));
+    }
+
+    #[test]
+    fn choke_peers_that_left() {
+        let mut choker = Choker::new(4);
+        let now = Instant::now();
+
+        choker.run(&[peer(1, 0, 0, true), peer(2, 0, 0, true)], false, now);
+        assert_eq!(choker.unchoked.len(), 2);
+
+        // peer 2 is not interested anymore
+        let (unchoke, choke) = choker.run(
+            &[peer(1, 0, 0, true), peer(2, 0, 0, false)],
+            false,
+            now + Choker::INTERVAL,
+        );
+        assert!(unchoke.is_empty());
+        assert_eq!(choke, vec![[2; 20]]);
+    }
+}
@@ -2,6 +2,8 @@ use std::net::SocketAddr;
 
 use clap::Parser;
 
+use crate::metainfo::Priority;
+
 #[derive(Parser, Debug, Default)]
 #[clap(
     name = "Vincenzo, a BitTorrent client for your terminal",
@@ -17,6 +19,15 @@ pub struct Args {
     #[clap(short, long)]
     pub magnet: Option<String>,
 
+    /// The path to a .torrent file.
+    #[clap(short, long)]
+    pub torrent: Option<String>,
+
+    /// The directory in which the torrent of the magnet or .torrent
+    /// file is saved, instead of the download directory.
+    #[clap(long)]
+    pub save_path: Option<String>,
+
     /// The socket address on which to listen for new connections.
     #[clap(short, long)]
     pub listen: Option<SocketAddr>,
@@ -24,4 +35,23 @@ pub struct Args {
     /// If the program should quit after a torrent is fully downloaded
     #[clap(short, long)]
     pub quit_after_complete: bool,
+
+    /// Download the pieces in order, to play media files while they are downloaded
+    #[clap(short, long)]
+    pub sequential: bool,
+
+    /// Check the pieces that are already on disk before downloading,
+    /// pieces that are valid are not downloaded again.
+    #[clap(short, long)]
+    pub check: bool,
+
+    /// The priority of each file of the torrent, separated by commas, in the
+    /// order of the files of the torrent: skip, low, normal or high.
+    /// Files without a priority are downloaded with the normal priority.
+    #[clap(long, value_delimiter = ',')]
+    pub priorities: Vec<Priority>,
+
+    /// The socket address of the HTTP server that streams the files of the torrents.
+    #[clap(long)]
+    pub stream: Option<SocketAddr>,
 }
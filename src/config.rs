@@ -3,8 +3,27 @@ use std::net::SocketAddr;
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::storage::Allocation;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     pub download_dir: String,
     pub listen: Option<SocketAddr>,
+    /// The socket address of the streaming server, disabled if `None`.
+    pub stream: Option<SocketAddr>,
+    /// How many MiB of blocks the Disk keeps in memory, before
+    /// writing them to disk, 32 MiB if `None`.
+    pub cache_size: Option<usize>,
+    /// How many MiB of pieces the Disk keeps in memory
+    /// to seed them, 16 MiB if `None`.
+    pub read_cache_size: Option<usize>,
+    /// How the files are allocated on disk, `sparse` if `None`.
+    pub allocation: Option<Allocation>,
+    /// The directory in which the torrents are downloaded, they are
+    /// moved to their save path when the download is complete.
+    /// The torrents are downloaded in their save path if `None`.
+    pub incomplete_dir: Option<String>,
+    /// If the files that are still downloading end with `.part`,
+    /// `false` if `None`.
+    pub part_files: Option<bool>,
 }
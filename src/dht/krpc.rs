@@ -0,0 +1,579 @@
+//! KRPC is the protocol of the DHT, each message is a bencoded dictionary
+//! sent over UDP, either a query, a response, or an error.
+//! http://www.bittorrent.org/beps/bep_0005.html
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use bendy::{
+    decoding::{self, FromBencode, Object, ResultExt},
+    encoding::{self, AsString, SingleItemEncoder, ToBencode},
+};
+
+use crate::{error::Error, tracker::Tracker};
+
+use super::NodeId;
+
+/// The ID and address of a node of the DHT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+impl NodeInfo {
+    /// In the compact format, a node is 20 bytes of ID,
+    /// 4 bytes of IPv4 and 2 bytes of port.
+    pub const COMPACT_LEN: usize = 26;
+
+    /// Parse a list of nodes in the compact format,
+    /// a trailing incomplete node is ignored.
+    pub fn parse_compact_list(buf: &[u8]) -> Vec<Self> {
+        buf.chunks_exact(Self::COMPACT_LEN)
+            .map(|chunk| {
+                let (id, addr) = chunk.split_at(20);
+                let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+                let port = u16::from_be_bytes([addr[4], addr[5]]);
+
+                Self {
+                    id: id.try_into().expect("iterator guarantees bounds are OK"),
+                    addr: SocketAddr::new(IpAddr::V4(ip), port),
+                }
+            })
+            .collect()
+    }
+
+    /// Encode a list of nodes in the compact format,
+    /// nodes with an IPv6 address are skipped.
+    pub fn compact_list(nodes: &[Self]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(nodes.len() * Self::COMPACT_LEN);
+
+        for node in nodes {
+            if let Some(addr) = compact_peer(&node.addr) {
+                buf.extend_from_slice(&node.id);
+                buf.extend_from_slice(&addr);
+            }
+        }
+
+        buf
+    }
+}
+
+/// Encode an IPv4 address in the compact format of 6 bytes.
+pub fn compact_peer(addr: &SocketAddr) -> Option<[u8; 6]> {
+    let SocketAddr::V4(addr) = addr else {
+        return None;
+    };
+    let [a, b, c, d] = addr.ip().octets();
+    let [p0, p1] = addr.port().to_be_bytes();
+
+    Some([a, b, c, d, p0, p1])
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: [u8; 20],
+    },
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        /// If the port of the peer is the source port of the UDP packet,
+        /// instead of `port`.
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+}
+
+impl Query {
+    fn method(&self) -> &'static [u8] {
+        match self {
+            Self::Ping => b"ping",
+            Self::FindNode { .. } => b"find_node",
+            Self::GetPeers { .. } => b"get_peers",
+            Self::AnnouncePeer { .. } => b"announce_peer",
+        }
+    }
+}
+
+/// The response to any of the queries, the fields used depend on the query.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Response {
+    /// ID of the node that responded.
+    pub id: NodeId,
+    /// The closest nodes to the target, on `find_node`, and on
+    /// `get_peers` if the node does not know peers of the torrent.
+    pub nodes: Vec<NodeInfo>,
+    /// Peers of the torrent, on `get_peers`.
+    pub values: Vec<SocketAddr>,
+    /// Token to be sent back on `announce_peer`, on `get_peers`.
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Body {
+    Query { id: NodeId, query: Query },
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    /// Chosen by the node that sent the query,
+    /// and echoed back in the response.
+    pub transaction_id: Vec<u8>,
+    pub body: Body,
+}
+
+impl Message {
+    pub fn query(transaction_id: Vec<u8>, id: NodeId, query: Query) -> Self {
+        Self {
+            transaction_id,
+            body: Body::Query { id, query },
+        }
+    }
+    pub fn response(transaction_id: Vec<u8>, response: Response) -> Self {
+        Self {
+            transaction_id,
+            body: Body::Response(response),
+        }
+    }
+    pub fn error(transaction_id: Vec<u8>, code: i64, message: &str) -> Self {
+        Self {
+            transaction_id,
+            body: Body::Error {
+                code,
+                message: message.to_owned(),
+            },
+        }
+    }
+}
+
+/// The arguments of a query, the "a" key of the message.
+struct QueryArgs<'a> {
+    id: &'a NodeId,
+    query: &'a Query,
+}
+
+impl ToBencode for QueryArgs<'_> {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"id", AsString(self.id))?;
+
+            match self.query {
+                Query::Ping => {}
+                Query::FindNode { target } => {
+                    e.emit_pair(b"target", AsString(target))?;
+                }
+                Query::GetPeers { info_hash } => {
+                    e.emit_pair(b"info_hash", AsString(info_hash))?;
+                }
+                Query::AnnouncePeer {
+                    info_hash,
+                    port,
+                    implied_port,
+                    token,
+                } => {
+                    e.emit_pair(b"implied_port", u8::from(*implied_port))?;
+                    e.emit_pair(b"info_hash", AsString(info_hash))?;
+                    e.emit_pair(b"port", *port)?;
+                    e.emit_pair(b"token", AsString(token))?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// All the arguments that a query may have,
+/// they are only known to be valid after reading the method.
+#[derive(Default)]
+struct Args {
+    id: Option<NodeId>,
+    target: Option<NodeId>,
+    info_hash: Option<[u8; 20]>,
+    port: Option<u16>,
+    implied_port: bool,
+    token: Option<Vec<u8>>,
+}
+
+fn decode_id(object: Object) -> Result<[u8; 20], decoding::Error> {
+    object
+        .try_into_bytes()?
+        .try_into()
+        .map_err(|_| decoding::Error::malformed_content(Error::DhtMessageInvalid))
+}
+
+impl FromBencode for Args {
+    fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let mut args = Args::default();
+
+        let mut dict_dec = object.try_into_dictionary()?;
+        while let Some(pair) = dict_dec.next_pair()? {
+            match pair {
+                (b"id", value) => {
+                    args.id = decode_id(value).context("id").map(Some)?;
+                }
+                (b"target", value) => {
+                    args.target = decode_id(value).context("target").map(Some)?;
+                }
+                (b"info_hash", value) => {
+                    args.info_hash = decode_id(value).context("info_hash").map(Some)?;
+                }
+                (b"port", value) => {
+                    args.port = u16::decode_bencode_object(value)
+                        .context("port")
+                        .map(Some)?;
+                }
+                (b"implied_port", value) => {
+                    args.implied_port =
+                        u8::decode_bencode_object(value).context("implied_port")? != 0;
+                }
+                (b"token", value) => {
+                    args.token = Some(value.try_into_bytes().context("token")?.to_vec());
+                }
+                _ => {}
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+impl ToBencode for Response {
+    const MAX_DEPTH: usize = 2;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"id", AsString(&self.id))?;
+
+            if !self.nodes.is_empty() {
+                e.emit_pair(b"nodes", AsString(NodeInfo::compact_list(&self.nodes)))?;
+            }
+            if let Some(token) = &self.token {
+                e.emit_pair(b"token", AsString(token))?;
+            }
+            if !self.values.is_empty() {
+                let values: Vec<AsString<[u8; 6]>> = self
+                    .values
+                    .iter()
+                    .filter_map(compact_peer)
+                    .map(AsString)
+                    .collect();
+                e.emit_pair(b"values", values)?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl FromBencode for Response {
+    fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let mut id = None;
+        let mut res = Response::default();
+
+        let mut dict_dec = object.try_into_dictionary()?;
+        while let Some(pair) = dict_dec.next_pair()? {
+            match pair {
+                (b"id", value) => {
+                    id = decode_id(value).context("id").map(Some)?;
+                }
+                (b"nodes", value) => {
+                    let nodes = value.try_into_bytes().context("nodes")?;
+                    res.nodes = NodeInfo::parse_compact_list(nodes);
+                }
+                (b"values", value) => {
+                    let mut list = value.try_into_list().context("values")?;
+                    while let Some(peer) = list.next_object()? {
+                        let peer = peer.try_into_bytes().context("values")?;
+                        let peers = Tracker::parse_compact_peer_list(peer, peer.len() == 18)
+                            .map_err(decoding::Error::malformed_content)?;
+                        res.values.extend(peers);
+                    }
+                }
+                (b"token", value) => {
+                    res.token = Some(value.try_into_bytes().context("token")?.to_vec());
+                }
+                _ => {}
+            }
+        }
+
+        res.id = id.ok_or_else(|| decoding::Error::missing_field("id"))?;
+
+        Ok(res)
+    }
+}
+
+/// The "e" key of an error message, a list of the code and the message.
+struct KrpcError<'a> {
+    code: i64,
+    message: &'a str,
+}
+
+impl ToBencode for KrpcError<'_> {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), encoding::Error> {
+        encoder.emit_list(|e| {
+            e.emit(self.code)?;
+            e.emit(AsString(self.message.as_bytes()))
+        })
+    }
+}
+
+fn decode_error(object: Object) -> Result<(i64, String), decoding::Error> {
+    let mut list = object.try_into_list()?;
+
+    let code = list
+        .next_object()?
+        .ok_or_else(|| decoding::Error::missing_field("code"))?;
+    let code = i64::decode_bencode_object(code).context("code")?;
+
+    let message = match list.next_object()? {
+        Some(message) => String::decode_bencode_object(message).context("message")?,
+        None => String::new(),
+    };
+
+    Ok((code, message))
+}
+
+impl ToBencode for Message {
+    const MAX_DEPTH: usize = 4;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            let kind: &[u8] = match &self.body {
+                Body::Query { id, query } => {
+                    e.emit_pair(b"a", QueryArgs { id, query })?;
+                    e.emit_pair(b"q", AsString(query.method()))?;
+                    b"q"
+                }
+                Body::Response(response) => {
+                    e.emit_pair(b"r", response)?;
+                    b"r"
+                }
+                Body::Error { code, message } => {
+                    e.emit_pair(
+                        b"e",
+                        KrpcError {
+                            code: *code,
+                            message,
+                        },
+                    )?;
+                    b"e"
+                }
+            };
+
+            e.emit_pair(b"t", AsString(&self.transaction_id))?;
+            e.emit_pair(b"y", AsString(kind))
+        })
+    }
+}
+
+impl FromBencode for Message {
+    fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let mut transaction_id = None;
+        let mut kind = None;
+        let mut method = None;
+        let mut args = None;
+        let mut response = None;
+        let mut error = None;
+
+        let mut dict_dec = object.try_into_dictionary()?;
+        while let Some(pair) = dict_dec.next_pair()? {
+            match pair {
+                (b"t", value) => {
+                    transaction_id = Some(value.try_into_bytes().context("t")?.to_vec());
+                }
+                (b"y", value) => {
+                    kind = Some(value.try_into_bytes().context("y")?.to_vec());
+                }
+                (b"q", value) => {
+                    method = Some(value.try_into_bytes().context("q")?.to_vec());
+                }
+                (b"a", value) => {
+                    args = Args::decode_bencode_object(value).context("a").map(Some)?;
+                }
+                (b"r", value) => {
+                    response = Response::decode_bencode_object(value)
+                        .context("r")
+                        .map(Some)?;
+                }
+                (b"e", value) => {
+                    error = decode_error(value).context("e").map(Some)?;
+                }
+                _ => {}
+            }
+        }
+
+        let transaction_id = transaction_id.ok_or_else(|| decoding::Error::missing_field("t"))?;
+
+        let body = match kind.as_deref() {
+            Some(b"q") => {
+                let args = args.ok_or_else(|| decoding::Error::missing_field("a"))?;
+                let id = args
+                    .id
+                    .ok_or_else(|| decoding::Error::missing_field("id"))?;
+                let info_hash = args
+                    .info_hash
+                    .ok_or_else(|| decoding::Error::missing_field("info_hash"));
+
+                let query = match method.as_deref() {
+                    Some(b"ping") => Query::Ping,
+                    Some(b"find_node") => Query::FindNode {
+                        target: args
+                            .target
+                            .ok_or_else(|| decoding::Error::missing_field("target"))?,
+                    },
+                    Some(b"get_peers") => Query::GetPeers {
+                        info_hash: info_hash?,
+                    },
+                    Some(b"announce_peer") => Query::AnnouncePeer {
+                        info_hash: info_hash?,
+                        port: args.port.unwrap_or_default(),
+                        implied_port: args.implied_port,
+                        token: args
+                            .token
+                            .ok_or_else(|| decoding::Error::missing_field("token"))?,
+                    },
+                    _ => return Err(decoding::Error::malformed_content(Error::DhtMessageInvalid)),
+                };
+
+                Body::Query { id, query }
+            }
+            Some(b"r") => {
+                Body::Response(response.ok_or_else(|| decoding::Error::missing_field("r"))?)
+            }
+            Some(b"e") => {
+                let (code, message) = error.ok_or_else(|| decoding::Error::missing_field("e"))?;
+                Body::Error { code, message }
+            }
+            _ => return Err(decoding::Error::missing_field("y")),
+        };
+
+        Ok(Self {
+            transaction_id,
+            body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ping() {
+        // examples of BEP 5
+        let query = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        let response = b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
+
+        let msg = Message::from_bencode(query).unwrap();
+        assert_eq!(
+            msg,
+            Message::query(b"aa".to_vec(), *b"abcdefghij0123456789", Query::Ping)
+        );
+        assert_eq!(msg.to_bencode().unwrap(), query);
+
+        let msg = Message::from_bencode(response).unwrap();
+        assert_eq!(
+            msg,
+            Message::response(
+                b"aa".to_vec(),
+                Response {
+                    id: *b"mnopqrstuvwxyz123456",
+                    ..Default::default()
+                }
+            )
+        );
+        assert_eq!(msg.to_bencode().unwrap(), response);
+    }
+
+    #[test]
+    fn announce_peer() {
+        let query = b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+
+        let msg = Message::from_bencode(query).unwrap();
+        assert_eq!(
+            msg,
+            Message::query(
+                b"aa".to_vec(),
+                *b"abcdefghij0123456789",
+                Query::AnnouncePeer {
+                    info_hash: *b"mnopqrstuvwxyz123456",
+                    port: 6881,
+                    implied_port: true,
+                    token: b"aoeusnth".to_vec(),
+                }
+            )
+        );
+        assert_eq!(msg.to_bencode().unwrap(), query);
+    }
+
+    #[test]
+    fn get_peers_response() {
+        let node = NodeInfo {
+            id: [7; 20],
+            addr: "10.0.0.1:6881".parse().unwrap(),
+        };
+        let response = Response {
+            id: [1; 20],
+            nodes: vec![node],
+            values: vec![
+                "127.0.0.1:51413".parse().unwrap(),
+                "192.168.0.1:6881".parse().unwrap(),
+            ],
+            token: Some(b"token".to_vec()),
+        };
+        let msg = Message::response(b"t1".to_vec(), response);
+
+        let bytes = msg.to_bencode().unwrap();
+        assert_eq!(Message::from_bencode(&bytes).unwrap(), msg);
+    }
+
+    #[test]
+    fn error() {
+        let error = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+
+        let msg = Message::from_bencode(error).unwrap();
+        assert_eq!(
+            msg,
+            Message::error(b"aa".to_vec(), 201, "A Generic Error Ocurred")
+        );
+        assert_eq!(msg.to_bencode().unwrap(), error);
+    }
+
+    #[test]
+    fn compact_nodes() {
+        let nodes = vec![
+            NodeInfo {
+                id: [1; 20],
+                addr: "1.2.3.4:5".parse().unwrap(),
+            },
+            NodeInfo {
+                id: [2; 20],
+                addr: "[::1]:6881".parse().unwrap(),
+            },
+        ];
+
+        let buf = NodeInfo::compact_list(&nodes);
+
+        assert_eq!(buf.len(), NodeInfo::COMPACT_LEN);
+        assert_eq!(NodeInfo::parse_compact_list(&buf), nodes[..1]);
+    }
+}
@@ -0,0 +1,781 @@
+//! A node of the Mainline DHT, used to find peers of torrents without
+//! trackers, as described in BEP 5.
+//! http://www.bittorrent.org/beps/bep_0005.html
+pub mod krpc;
+pub mod routing;
+
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use bendy::{
+    decoding::{self, FromBencode, Object, ResultExt},
+    encoding::{self, AsString, SingleItemEncoder, ToBencode},
+};
+use hashbrown::{HashMap, HashSet};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    select,
+    sync::{mpsc, oneshot},
+    time::{interval, interval_at},
+};
+use tracing::{info, warn};
+
+use crate::error::Error;
+
+use self::{
+    krpc::{Body, Message, NodeInfo, Query, Response},
+    routing::{distance, RoutingTable, K},
+};
+
+/// IDs of nodes and info hashes share the same 160-bit space.
+pub type NodeId = [u8; 20];
+
+/// Well-known nodes used to join the DHT, when
+/// the routing table does not have enough nodes.
+pub const BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// How many queries of a lookup are in flight at the same time.
+const ALPHA: usize = 3;
+
+/// A query that is not answered in this time has failed.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How often the secret of the tokens is rotated, the routing table
+/// is saved, and the node bootstraps again if the table is too small.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The maximum number of peers that we store for each info hash.
+const MAX_PEERS: usize = 200;
+
+#[derive(Debug)]
+pub enum DhtMsg {
+    /// Find peers of the torrent `info_hash`. If `port` is set, also
+    /// announce that we are a peer of the torrent, listening on `port`.
+    GetPeers {
+        info_hash: [u8; 20],
+        port: Option<u16>,
+        recipient: oneshot::Sender<Vec<SocketAddr>>,
+    },
+    /// Save the routing table and stop the node.
+    Quit(oneshot::Sender<()>),
+}
+
+/// A query that we sent, waiting for a response.
+#[derive(Debug)]
+struct Pending {
+    addr: SocketAddr,
+    /// The ID of the node, unknown for bootstrap nodes.
+    id: Option<NodeId>,
+    sent: Instant,
+    /// The lookup that sent the query, if any.
+    lookup: Option<u64>,
+}
+
+/// A node that may be queried by a lookup.
+#[derive(Debug)]
+struct Candidate {
+    id: Option<NodeId>,
+    addr: SocketAddr,
+    queried: bool,
+    /// The token of the node, to announce to it.
+    token: Option<Vec<u8>>,
+}
+
+/// An iterative lookup of the nodes closest to `target`, each round queries
+/// the closest nodes that were not queried yet, until the [`K`] closest
+/// nodes have all responded or failed.
+#[derive(Debug)]
+struct Lookup {
+    target: [u8; 20],
+    /// `get_peers` instead of `find_node`.
+    get_peers: bool,
+    /// The port to announce to the closest nodes, at the end of the lookup.
+    port: Option<u16>,
+    recipient: Option<oneshot::Sender<Vec<SocketAddr>>>,
+    candidates: Vec<Candidate>,
+    in_flight: usize,
+    peers: HashSet<SocketAddr>,
+}
+
+pub struct Dht {
+    pub tx: mpsc::Sender<DhtMsg>,
+    rx: mpsc::Receiver<DhtMsg>,
+    socket: UdpSocket,
+    pub table: RoutingTable,
+    /// Queries that we sent, by transaction id.
+    pending: HashMap<[u8; 2], Pending>,
+    lookups: HashMap<u64, Lookup>,
+    next_transaction_id: u16,
+    next_lookup_id: u64,
+    /// Peers that announced to us, by info hash.
+    peers: HashMap<[u8; 20], Vec<SocketAddr>>,
+    /// Tokens are the hash of the IP of the node and a secret. The secret is
+    /// rotated, and tokens of the previous secret are still accepted.
+    secret: [u8; 20],
+    prev_secret: [u8; 20],
+    /// Addresses of the nodes used to bootstrap.
+    bootstrap: Vec<String>,
+    /// Where the routing table is saved between runs.
+    state_path: Option<PathBuf>,
+}
+
+impl Dht {
+    /// Bind the UDP socket of the node, and load the routing
+    /// table of the previous run from `state_path`, if any.
+    pub async fn new(
+        addr: SocketAddr,
+        state_path: Option<PathBuf>,
+        bootstrap: Vec<String>,
+    ) -> Result<Self, Error> {
+        let socket = UdpSocket::bind(addr).await?;
+        let (tx, rx) = mpsc::channel::<DhtMsg>(300);
+
+        let state = state_path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|buf| State::from_bencode(&buf).ok());
+
+        let table = match state {
+            Some(state) => {
+                info!("loaded {} DHT nodes", state.nodes.len());
+                let mut table = RoutingTable::new(state.id);
+                let now = Instant::now();
+                for node in state.nodes {
+                    table.insert(node, now);
+                }
+                table
+            }
+            None => RoutingTable::new(rand::random()),
+        };
+
+        Ok(Self {
+            tx,
+            rx,
+            socket,
+            table,
+            pending: HashMap::new(),
+            lookups: HashMap::new(),
+            next_transaction_id: 0,
+            next_lookup_id: 0,
+            peers: HashMap::new(),
+            secret: rand::random(),
+            prev_secret: rand::random(),
+            bootstrap,
+            state_path,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    #[tracing::instrument(name = "dht::run", skip(self))]
+    pub async fn run(&mut self) -> Result<(), Error> {
+        // fill the routing table by looking up our own ID
+        self.start_lookup(self.table.id, false, None, None).await;
+
+        let mut timeout_interval = interval(Duration::from_secs(1));
+        let mut maintenance_interval = interval_at(
+            tokio::time::Instant::now() + MAINTENANCE_INTERVAL,
+            MAINTENANCE_INTERVAL,
+        );
+
+        let mut buf = [0_u8; 2048];
+
+        loop {
+            select! {
+                Ok((len, addr)) = self.socket.recv_from(&mut buf) => {
+                    // ignore anything that is not KRPC
+                    if let Ok(msg) = Message::from_bencode(&buf[..len]) {
+                        self.handle_message(msg, addr).await;
+                    }
+                }
+                Some(msg) = self.rx.recv() => {
+                    match msg {
+                        DhtMsg::GetPeers { info_hash, port, recipient } => {
+                            self.start_lookup(info_hash, true, port, Some(recipient)).await;
+                        }
+                        DhtMsg::Quit(recipient) => {
+                            self.save().await;
+                            let _ = recipient.send(());
+                            return Ok(());
+                        }
+                    }
+                }
+                _ = timeout_interval.tick() => {
+                    self.check_timeouts().await;
+                }
+                _ = maintenance_interval.tick() => {
+                    self.prev_secret = self.secret;
+                    self.secret = rand::random();
+
+                    if self.table.len() < K {
+                        self.start_lookup(self.table.id, false, None, None).await;
+                    }
+
+                    self.save().await;
+                }
+            }
+        }
+    }
+
+    /// Save the routing table to `state_path`.
+    async fn save(&self) {
+        let Some(path) = &self.state_path else {
+            return;
+        };
+
+        let state = State {
+            id: self.table.id,
+            nodes: self.table.nodes().collect(),
+        };
+
+        if let Some(dir) = path.parent() {
+            let _ = tokio::fs::create_dir_all(dir).await;
+        }
+
+        match state.to_bencode() {
+            Ok(buf) => {
+                if let Err(e) = tokio::fs::write(path, buf).await {
+                    warn!("could not save the DHT nodes: {e}");
+                }
+            }
+            Err(_) => warn!("could not encode the DHT nodes"),
+        }
+    }
+
+    /// The token that the node with the given IP must send back to announce.
+    fn token(&self, addr: &SocketAddr, secret: &[u8; 20]) -> Vec<u8> {
+        let mut hash = sha1_smol::Sha1::new();
+        match addr {
+            SocketAddr::V4(addr) => hash.update(&addr.ip().octets()),
+            SocketAddr::V6(addr) => hash.update(&addr.ip().octets()),
+        }
+        hash.update(secret);
+        hash.digest().bytes()[..8].to_vec()
+    }
+
+    fn is_token_valid(&self, addr: &SocketAddr, token: &[u8]) -> bool {
+        token == self.token(addr, &self.secret) || token == self.token(addr, &self.prev_secret)
+    }
+
+    async fn send(&self, msg: &Message, addr: SocketAddr) -> bool {
+        match msg.to_bencode() {
+            Ok(buf) => self.socket.send_to(&buf, addr).await.is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Send a query, returns false if it could not be sent.
+    async fn send_query(
+        &mut self,
+        addr: SocketAddr,
+        id: Option<NodeId>,
+        query: Query,
+        lookup: Option<u64>,
+    ) -> bool {
+        let transaction_id = self.next_transaction_id.to_be_bytes();
+        self.next_transaction_id = self.next_transaction_id.wrapping_add(1);
+
+        let msg = Message::query(transaction_id.to_vec(), self.table.id, query);
+
+        if !self.send(&msg, addr).await {
+            return false;
+        }
+
+        self.pending.insert(
+            transaction_id,
+            Pending {
+                addr,
+                id,
+                sent: Instant::now(),
+                lookup,
+            },
+        );
+
+        true
+    }
+
+    async fn handle_message(&mut self, msg: Message, addr: SocketAddr) {
+        match msg.body {
+            Body::Query { id, query } => {
+                self.table.insert(NodeInfo { id, addr }, Instant::now());
+
+                let body = self.handle_query(query, addr);
+
+                let msg = Message {
+                    transaction_id: msg.transaction_id,
+                    body,
+                };
+                self.send(&msg, addr).await;
+            }
+            Body::Response(response) => {
+                let Some(pending) = self.take_pending(&msg.transaction_id, addr) else {
+                    return;
+                };
+
+                self.table.insert(
+                    NodeInfo {
+                        id: response.id,
+                        addr,
+                    },
+                    Instant::now(),
+                );
+
+                if let Some(lookup) = pending.lookup {
+                    self.lookup_response(lookup, addr, response).await;
+                }
+            }
+            Body::Error { code, message } => {
+                let Some(pending) = self.take_pending(&msg.transaction_id, addr) else {
+                    return;
+                };
+
+                warn!("DHT node {addr} returned error {code}: {message}");
+
+                if let Some(lookup) = pending.lookup {
+                    self.lookup_failure(lookup, addr).await;
+                }
+            }
+        }
+    }
+
+    /// Remove the pending query of the response,
+    /// if it was sent to the node that responded.
+    fn take_pending(&mut self, transaction_id: &[u8], addr: SocketAddr) -> Option<Pending> {
+        let transaction_id: [u8; 2] = transaction_id.try_into().ok()?;
+
+        if self.pending.get(&transaction_id)?.addr != addr {
+            return None;
+        }
+
+        self.pending.remove(&transaction_id)
+    }
+
+    fn handle_query(&mut self, query: Query, addr: SocketAddr) -> Body {
+        let mut response = Response {
+            id: self.table.id,
+            ..Default::default()
+        };
+
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => {
+                response.nodes = self.table.closest(&target, K);
+            }
+            Query::GetPeers { info_hash } => {
+                response.token = Some(self.token(&addr, &self.secret));
+
+                match self.peers.get(&info_hash) {
+                    Some(peers) if !peers.is_empty() => response.values = peers.clone(),
+                    _ => response.nodes = self.table.closest(&info_hash, K),
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if !self.is_token_valid(&addr, &token) {
+                    return Body::Error {
+                        code: 203,
+                        message: "Bad token".to_owned(),
+                    };
+                }
+
+                let port = if implied_port { addr.port() } else { port };
+                let peer = SocketAddr::new(addr.ip(), port);
+                let peers = self.peers.entry(info_hash).or_default();
+
+                if !peers.contains(&peer) {
+                    if peers.len() >= MAX_PEERS {
+                        peers.remove(0);
+                    }
+                    peers.push(peer);
+                }
+            }
+        }
+
+        Body::Response(response)
+    }
+
+    /// Start a lookup from the closest nodes of the routing table, and from the
+    /// bootstrap nodes if the table does not have enough nodes.
+    async fn start_lookup(
+        &mut self,
+        target: [u8; 20],
+        get_peers: bool,
+        port: Option<u16>,
+        recipient: Option<oneshot::Sender<Vec<SocketAddr>>>,
+    ) {
+        let mut candidates: Vec<Candidate> = self
+            .table
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| Candidate {
+                id: Some(node.id),
+                addr: node.addr,
+                queried: false,
+                token: None,
+            })
+            .collect();
+
+        if candidates.len() < K {
+            for node in &self.bootstrap {
+                let Ok(addrs) = lookup_host(node).await else {
+                    continue;
+                };
+                for addr in addrs.filter(|addr| addr.is_ipv4()) {
+                    if !candidates.iter().any(|c| c.addr == addr) {
+                        candidates.push(Candidate {
+                            id: None,
+                            addr,
+                            queried: false,
+                            token: None,
+                        });
+                    }
+                }
+            }
+        }
+
+        let id = self.next_lookup_id;
+        self.next_lookup_id += 1;
+
+        self.lookups.insert(
+            id,
+            Lookup {
+                target,
+                get_peers,
+                port,
+                recipient,
+                candidates,
+                in_flight: 0,
+                peers: HashSet::new(),
+            },
+        );
+
+        self.step_lookup(id).await;
+    }
+
+    /// Query the closest candidates of the lookup, or finish the
+    /// lookup if all of the closest candidates were queried.
+    async fn step_lookup(&mut self, id: u64) {
+        loop {
+            let Some(lookup) = self.lookups.get_mut(&id) else {
+                return;
+            };
+
+            // nodes with an unknown ID go last
+            let target = lookup.target;
+            lookup
+                .candidates
+                .sort_by_key(|c| c.id.map_or([0xFF; 20], |id| distance(&id, &target)));
+
+            let available = ALPHA.saturating_sub(lookup.in_flight);
+            let to_query: Vec<(Option<NodeId>, SocketAddr)> = lookup
+                .candidates
+                .iter_mut()
+                .take(K)
+                .filter(|c| !c.queried)
+                .take(available)
+                .map(|c| {
+                    c.queried = true;
+                    (c.id, c.addr)
+                })
+                .collect();
+
+            if to_query.is_empty() && lookup.in_flight == 0 {
+                self.finish_lookup(id).await;
+                return;
+            }
+
+            lookup.in_flight += to_query.len();
+
+            let query = if lookup.get_peers {
+                Query::GetPeers { info_hash: target }
+            } else {
+                Query::FindNode { target }
+            };
+
+            let mut failed = Vec::new();
+
+            for (node_id, addr) in to_query {
+                if !self
+                    .send_query(addr, node_id, query.clone(), Some(id))
+                    .await
+                {
+                    failed.push(addr);
+                }
+            }
+
+            if failed.is_empty() {
+                return;
+            }
+
+            // try the next candidates instead of the ones that failed
+            if let Some(lookup) = self.lookups.get_mut(&id) {
+                lookup.in_flight -= failed.len();
+                lookup.candidates.retain(|c| !failed.contains(&c.addr));
+            }
+        }
+    }
+
+    async fn lookup_response(&mut self, id: u64, addr: SocketAddr, response: Response) {
+        let Some(lookup) = self.lookups.get_mut(&id) else {
+            return;
+        };
+
+        lookup.in_flight = lookup.in_flight.saturating_sub(1);
+
+        if let Some(candidate) = lookup.candidates.iter_mut().find(|c| c.addr == addr) {
+            candidate.id = Some(response.id);
+            candidate.token = response.token;
+        }
+
+        lookup.peers.extend(response.values);
+
+        for node in response.nodes {
+            let known = node.id == self.table.id
+                || lookup
+                    .candidates
+                    .iter()
+                    .any(|c| c.addr == node.addr || c.id == Some(node.id));
+
+            if !known {
+                lookup.candidates.push(Candidate {
+                    id: Some(node.id),
+                    addr: node.addr,
+                    queried: false,
+                    token: None,
+                });
+            }
+        }
+
+        self.step_lookup(id).await;
+    }
+
+    async fn lookup_failure(&mut self, id: u64, addr: SocketAddr) {
+        let Some(lookup) = self.lookups.get_mut(&id) else {
+            return;
+        };
+
+        lookup.in_flight = lookup.in_flight.saturating_sub(1);
+        lookup.candidates.retain(|c| c.addr != addr);
+
+        self.step_lookup(id).await;
+    }
+
+    /// Send the peers found to the recipient of the lookup, and announce
+    /// to the closest nodes that responded with a token.
+    async fn finish_lookup(&mut self, id: u64) {
+        let Some(lookup) = self.lookups.remove(&id) else {
+            return;
+        };
+
+        if let Some(port) = lookup.port {
+            let closest = lookup
+                .candidates
+                .iter()
+                .filter_map(|c| Some((c.id, c.addr, c.token.clone()?)))
+                .take(K);
+
+            for (node_id, addr, token) in closest {
+                let query = Query::AnnouncePeer {
+                    info_hash: lookup.target,
+                    port,
+                    implied_port: false,
+                    token,
+                };
+                self.send_query(addr, node_id, query, None).await;
+            }
+        }
+
+        if let Some(recipient) = lookup.recipient {
+            info!("DHT lookup found {} peers", lookup.peers.len());
+            let _ = recipient.send(lookup.peers.into_iter().collect());
+        }
+    }
+
+    /// Fail the queries that were not answered in time.
+    async fn check_timeouts(&mut self) {
+        let now = Instant::now();
+
+        let expired: Vec<[u8; 2]> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| now.saturating_duration_since(p.sent) >= QUERY_TIMEOUT)
+            .map(|(t, _)| *t)
+            .collect();
+
+        for transaction_id in expired {
+            let Some(pending) = self.pending.remove(&transaction_id) else {
+                continue;
+            };
+
+            if let Some(id) = pending.id {
+                self.table.fail(&id);
+            }
+
+            if let Some(lookup) = pending.lookup {
+                self.lookup_failure(lookup, pending.addr).await;
+            }
+        }
+    }
+}
+
+/// The state of the node that is saved between runs,
+/// our ID and the nodes of the routing table.
+struct State {
+    id: NodeId,
+    nodes: Vec<NodeInfo>,
+}
+
+impl ToBencode for State {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"id", AsString(&self.id))?;
+            e.emit_pair(b"nodes", AsString(NodeInfo::compact_list(&self.nodes)))
+        })
+    }
+}
+
+impl FromBencode for State {
+    fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let mut id = None;
+        let mut nodes = Vec::new();
+
+        let mut dict_dec = object.try_into_dictionary()?;
+        while let Some(pair) = dict_dec.next_pair()? {
+            match pair {
+                (b"id", value) => {
+                    let bytes = value.try_into_bytes().context("id")?;
+                    id = bytes.try_into().ok();
+                }
+                (b"nodes", value) => {
+                    let bytes = value.try_into_bytes().context("nodes")?;
+                    nodes = NodeInfo::parse_compact_list(bytes);
+                }
+                _ => {}
+            }
+        }
+
+        let id = id.ok_or_else(|| decoding::Error::missing_field("id"))?;
+
+        Ok(Self { id, nodes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{spawn, time::sleep};
+
+    use super::*;
+
+    async fn spawn_node(bootstrap: Vec<String>) -> (SocketAddr, mpsc::Sender<DhtMsg>) {
+        let mut dht = Dht::new("127.0.0.1:0".parse().unwrap(), None, bootstrap)
+            .await
+            .unwrap();
+        let addr = dht.local_addr().unwrap();
+        let tx = dht.tx.clone();
+
+        spawn(async move {
+            dht.run().await.unwrap();
+        });
+
+        (addr, tx)
+    }
+
+    async fn get_peers(
+        tx: &mpsc::Sender<DhtMsg>,
+        info_hash: [u8; 20],
+        port: Option<u16>,
+    ) -> Vec<SocketAddr> {
+        let (otx, orx) = oneshot::channel();
+        tx.send(DhtMsg::GetPeers {
+            info_hash,
+            port,
+            recipient: otx,
+        })
+        .await
+        .unwrap();
+        orx.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn announce_and_get_peers_on_loopback() {
+        let (bootstrap, _bootstrap_tx) = spawn_node(vec![]).await;
+
+        let mut txs = Vec::new();
+        for _ in 0..4 {
+            let (_, tx) = spawn_node(vec![bootstrap.to_string()]).await;
+            txs.push(tx);
+        }
+
+        // wait for the nodes to bootstrap
+        sleep(Duration::from_millis(200)).await;
+
+        let info_hash = [5; 20];
+
+        // nobody is a peer of the torrent yet
+        assert!(get_peers(&txs[0], info_hash, Some(6881)).await.is_empty());
+
+        // wait for the announces to arrive
+        sleep(Duration::from_millis(200)).await;
+
+        let peers = get_peers(&txs[3], info_hash, None).await;
+        assert_eq!(peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn persist_routing_table() {
+        let (bootstrap, _bootstrap_tx) = spawn_node(vec![]).await;
+
+        let path = std::env::temp_dir().join(format!("vcz-dht-{}", rand::random::<u32>()));
+
+        let mut dht = Dht::new(
+            "127.0.0.1:0".parse().unwrap(),
+            Some(path.clone()),
+            vec![bootstrap.to_string()],
+        )
+        .await
+        .unwrap();
+        let id = dht.table.id;
+        let tx = dht.tx.clone();
+
+        let handle = spawn(async move {
+            dht.run().await.unwrap();
+        });
+
+        sleep(Duration::from_millis(200)).await;
+
+        let (otx, orx) = oneshot::channel();
+        tx.send(DhtMsg::Quit(otx)).await.unwrap();
+        orx.await.unwrap();
+        handle.await.unwrap();
+
+        let dht = Dht::new("127.0.0.1:0".parse().unwrap(), Some(path.clone()), vec![])
+            .await
+            .unwrap();
+
+        assert_eq!(dht.table.id, id);
+        assert_eq!(
+            dht.table.nodes().map(|n| n.addr).collect::<Vec<_>>(),
+            vec![bootstrap]
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+//! The Kademlia routing table of a DHT node.
+use std::time::{Duration, Instant};
+
+use super::{krpc::NodeInfo, NodeId};
+
+/// The maximum number of nodes in a bucket.
+pub const K: usize = 8;
+
+/// Get the XOR distance between two IDs.
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut d = [0_u8; 20];
+    for (i, byte) in d.iter_mut().enumerate() {
+        *byte = a[i] ^ b[i];
+    }
+    d
+}
+
+/// A node of the routing table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub info: NodeInfo,
+    /// The last time that the node answered a query or sent us a query.
+    pub last_seen: Instant,
+    /// How many queries in a row that we sent to the node timed out.
+    pub fails: u8,
+}
+
+impl Node {
+    /// A node that has not been seen for this long is questionable,
+    /// and can be replaced by a new node.
+    const QUESTIONABLE: Duration = Duration::from_secs(15 * 60);
+
+    /// A node is removed from the table after this many timeouts in a row.
+    const MAX_FAILS: u8 = 2;
+
+    fn is_good(&self, now: Instant) -> bool {
+        self.fails == 0 && now.saturating_duration_since(self.last_seen) < Self::QUESTIONABLE
+    }
+}
+
+/// The routing table has one bucket for each bit of the ID, nodes go
+/// into the bucket of the first bit that differs from our ID. So that half
+/// of the ID space goes into the first bucket, a quarter into the second,
+/// and so on. Each bucket holds up to [`K`] nodes.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    /// Our node ID.
+    pub id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    /// Get the bucket of the given ID, `None` if it is our ID.
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        distance(&self.id, id)
+            .iter()
+            .enumerate()
+            .find(|(_, byte)| **byte != 0)
+            .map(|(i, byte)| i * 8 + byte.leading_zeros() as usize)
+    }
+
+    /// Insert or refresh a node that we have seen. If its bucket is full,
+    /// the node replaces the worst questionable or failing node of the bucket,
+    /// if there are none, the node is not inserted and false is returned.
+    pub fn insert(&mut self, info: NodeInfo, now: Instant) -> bool {
+        let Some(i) = self.bucket_index(&info.id) else {
+            return false;
+        };
+
+        let bucket = &mut self.buckets[i];
+        let node = Node {
+            info,
+            last_seen: now,
+            fails: 0,
+        };
+
+        // move the refreshed node to the end of the bucket
+        if let Some(pos) = bucket.iter().position(|n| n.info.id == info.id) {
+            bucket.remove(pos);
+            bucket.push(node);
+            return true;
+        }
+
+        if bucket.len() < K {
+            bucket.push(node);
+            return true;
+        }
+
+        let worst = bucket
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| !n.is_good(now))
+            .max_by_key(|(_, n)| (n.fails, now.saturating_duration_since(n.last_seen)))
+            .map(|(pos, _)| pos);
+
+        if let Some(pos) = worst {
+            bucket.remove(pos);
+            bucket.push(node);
+            return true;
+        }
+
+        false
+    }
+
+    /// A query to the node timed out, the node is removed
+    /// if it has failed too many times.
+    pub fn fail(&mut self, id: &NodeId) {
+        let Some(i) = self.bucket_index(id) else {
+            return;
+        };
+
+        let bucket = &mut self.buckets[i];
+
+        if let Some(pos) = bucket.iter().position(|n| n.info.id == *id) {
+            bucket[pos].fails += 1;
+
+            if bucket[pos].fails >= Node::MAX_FAILS {
+                bucket.remove(pos);
+            }
+        }
+    }
+
+    /// Get the `n` nodes that are closest to `target`.
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self.nodes().collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    /// Iterate over all nodes of the table.
+    pub fn nodes(&self) -> impl Iterator<Item = NodeInfo> + '_ {
+        self.buckets.iter().flatten().map(|n| n.info)
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(first_byte: u8, last_byte: u8) -> NodeInfo {
+        let mut id = [0; 20];
+        id[0] = first_byte;
+        id[19] = last_byte;
+        NodeInfo {
+            id,
+            addr: format!("127.0.0.1:{}", 1000 + last_byte as u16)
+                .parse()
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn bucket_index() {
+        let table = RoutingTable::new([0; 20]);
+
+        assert_eq!(table.bucket_index(&[0; 20]), None);
+        assert_eq!(table.bucket_index(&node(0b1000_0000, 0).id), Some(0));
+        assert_eq!(table.bucket_index(&node(0b0000_0001, 0).id), Some(7));
+        assert_eq!(table.bucket_index(&node(0, 1).id), Some(159));
+    }
+
+    #[test]
+    fn insert_and_replace() {
+        let mut table = RoutingTable::new([0; 20]);
+        let now = Instant::now();
+
+        // our own ID is never inserted
+        assert!(!table.insert(node(0, 0), now));
+
+        for i in 0..K as u8 {
+            assert!(table.insert(node(0xFF, i), now));
+        }
+
+        // the bucket is full of good nodes
+        assert!(!table.insert(node(0xFF, 100), now));
+        assert_eq!(table.len(), K);
+
+        // refreshing a node does not add a new one
+        assert!(table.insert(node(0xFF, 3), now));
+        assert_eq!(table.len(), K);
+
+        // a failing node is replaced
+        table.fail(&node(0xFF, 5).id);
+        assert!(table.insert(node(0xFF, 100), now));
+        assert_eq!(table.len(), K);
+        assert!(table.nodes().all(|n| n != node(0xFF, 5)));
+
+        // nodes that fail too many times are removed
+        table.fail(&node(0xFF, 6).id);
+        table.fail(&node(0xFF, 6).id);
+        assert_eq!(table.len(), K - 1);
+
+        // questionable nodes are replaced
+        let later = now + Duration::from_secs(20 * 60);
+        assert!(table.insert(node(0xFF, 101), later));
+        assert!(table.insert(node(0xFF, 102), later));
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn closest() {
+        let mut table = RoutingTable::new([0; 20]);
+        let now = Instant::now();
+
+        table.insert(node(0b1000_0000, 1), now);
+        table.insert(node(0b0100_0000, 2), now);
+        table.insert(node(0b0010_0000, 3), now);
+        table.insert(node(0b0000_0001, 4), now);
+
+        let closest = table.closest(&node(0b0100_0000, 0).id, 2);
+
+        assert_eq!(closest, vec![node(0b0100_0000, 2), node(0b0000_0001, 4)]);
+    }
+}
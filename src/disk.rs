@@ -1,31 +1,43 @@
 use std::{
-    collections::VecDeque,
-    io::SeekFrom,
+    collections::{BTreeMap, VecDeque},
     path::{Path, PathBuf},
     sync::Arc,
 };
 
 use hashbrown::{HashMap, HashSet};
 use tokio::{
-    fs::{create_dir_all, File, OpenOptions},
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
-    sync::{mpsc::Receiver, oneshot::Sender},
+    fs::{File, OpenOptions},
+    sync::{
+        mpsc::{error::TrySendError, Receiver},
+        oneshot::{self, Sender},
+    },
 };
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{
+    bitfield::Bitfield,
+    cache::{ReadCache, WriteCache},
     error::Error,
-    metainfo,
+    io_pool::{IoDone, IoJob, IoPool, Validate},
+    metainfo::{self, Priority},
     peer::{PeerCtx, PeerMsg},
+    picker::Picker,
+    resume::ResumeData,
+    storage::{self, FsStorage, Segment, Storage},
     tcp_wire::lib::{Block, BlockInfo},
     torrent::{TorrentCtx, TorrentMsg},
 };
 
+/// Where the data of a block that was read is sent.
+type BlockSender = Sender<Result<Vec<u8>, Error>>;
+
 #[derive(Debug)]
 pub enum DiskMsg {
     /// After the client downloaded the Info from peers, this message will be sent,
     /// to create the skeleton of the torrent on disk (empty files and folders),
-    /// and to add the torrent ctx.
+    /// and to add the torrent ctx. If the files can't be created, e.g.
+    /// there is not enough free space, the torrent receives a
+    /// [`TorrentMsg::DiskError`].
     NewTorrent(Arc<TorrentCtx>),
     /// The Peer does not have an ID until the handshake (that is why it's an option), when that happens,
     /// this message will be sent immediately to add the peer context.
@@ -42,11 +54,14 @@ pub enum DiskMsg {
     ValidatePiece(usize, [u8; 20], Sender<Result<(), Error>>),
     OpenFile(String, Sender<File>),
     /// Write the given block to disk, the Disk struct will get the seeked file
-    /// automatically.
+    /// automatically. `peer_id` is the id of the peer that sent the block,
+    /// if the piece of the block turns out to be corrupted, the failure
+    /// will be counted against this peer.
     WriteBlock {
         b: Block,
         recipient: Sender<Result<(), Error>>,
         info_hash: [u8; 20],
+        peer_id: [u8; 20],
     },
     /// Request block infos that the peer has, that we do not have ir nor requested it.
     RequestBlocks {
@@ -59,14 +74,93 @@ pub enum DiskMsg {
     /// the outgoing/pending blocks of this peer must be appended back
     /// to the list of available block_infos.
     ReturnBlockInfos([u8; 20], VecDeque<BlockInfo>),
+    /// The peer sent its bitfield, used to count
+    /// the availability of the pieces.
+    PeerBitfield {
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        bitfield: Bitfield,
+    },
+    /// The peer sent a Have of a piece.
+    PeerHave {
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        index: usize,
+    },
+    /// The session with the peer has ended,
+    /// its pieces are no longer available.
+    DeletePeer {
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+    },
+    /// Enable or disable the sequential mode of the picker of the torrent.
+    SetSequential([u8; 20], bool),
+    /// The priorities of the files of the torrent, on `TorrentCtx`, have
+    /// changed. Files that are not skipped anymore are created.
+    SetFilePriorities([u8; 20]),
+    /// Read a block for the streaming server. If the piece of the block
+    /// was not downloaded yet, it is picked before the other pieces, and
+    /// the block is only sent after the piece is written and validated.
+    ReadStreamBlock {
+        b: BlockInfo,
+        recipient: Sender<Result<Vec<u8>, Error>>,
+        info_hash: [u8; 20],
+    },
+    /// Get the ctx of a torrent, if we have its info.
+    GetTorrentCtx([u8; 20], Sender<Option<Arc<TorrentCtx>>>),
+    /// Write the resume file of a torrent. The Disk fills the fields that
+    /// it knows about: the pieces, the unfinished blocks, the save path,
+    /// and the sizes of the files.
+    SaveResumeData(ResumeData),
+    /// Restore the pieces and blocks of a torrent from its resume file,
+    /// sent after `NewTorrent`. Answers with how many pieces were restored.
+    RestoreResumeData(ResumeData, Sender<Result<usize, Error>>),
+    /// Hash the pieces of a torrent that are on disk, see [`TorrentMsg::Check`].
+    Check([u8; 20]),
+    /// The torrent has quit, write its blocks that are in memory,
+    /// close its files, and forget about it.
+    RemoveTorrent([u8; 20]),
+    /// Move the files of a torrent to another directory, its save path.
+    /// The messages of the torrent that read or write its files wait until
+    /// the files are moved, the other torrents are not affected.
+    MoveStorage(Arc<TorrentCtx>, String),
+    /// The download of the torrent is complete, move its files out of the
+    /// incomplete dir to its save path, and remove their `.part` suffix.
+    CompleteStorage(Arc<TorrentCtx>),
+    /// Write the blocks that are in memory and sync the open files.
     Quit,
 }
 
+impl DiskMsg {
+    /// The torrent of the message, if the message reads or writes its files.
+    fn storage_of(&self) -> Option<[u8; 20]> {
+        match self {
+            DiskMsg::NewTorrent(torrent_ctx)
+            | DiskMsg::MoveStorage(torrent_ctx, _)
+            | DiskMsg::CompleteStorage(torrent_ctx) => Some(torrent_ctx.info_hash),
+            DiskMsg::ReadBlock { info_hash, .. }
+            | DiskMsg::WriteBlock { info_hash, .. }
+            | DiskMsg::ReadStreamBlock { info_hash, .. }
+            | DiskMsg::ValidatePiece(_, info_hash, _)
+            | DiskMsg::SetFilePriorities(info_hash)
+            | DiskMsg::Check(info_hash)
+            | DiskMsg::RemoveTorrent(info_hash) => Some(*info_hash),
+            DiskMsg::SaveResumeData(data) | DiskMsg::RestoreResumeData(data, _) => {
+                Some(data.info_hash)
+            }
+            _ => None,
+        }
+    }
+}
+
 /// The Disk struct responsabilities:
 /// - Open and create files, create directories
-/// - Read/Write blocks to files
-/// - Store block infos of all torrents
+/// - Read/Write blocks to files, with a write-back cache
+/// - Pick the blocks that are requested from peers
 /// - Validate hash of pieces
+///
+/// The Disk only does the bookkeeping, the reads, the writes and the
+/// hashing of pieces are done by the workers of [`IoPool`].
 #[derive(Debug)]
 pub struct Disk {
     rx: Receiver<DiskMsg>,
@@ -74,75 +168,336 @@ pub struct Disk {
     pub torrent_ctxs: HashMap<[u8; 20], Arc<TorrentCtx>>,
     /// k: peer_id
     pub peer_ctxs: HashMap<[u8; 20], Arc<PeerCtx>>,
-    /// The block infos of a torrent to be downloaded,
+    /// The piece picker of each torrent, with
+    /// the block infos to be downloaded.
     /// K: info_hash
-    block_infos: HashMap<[u8; 20], VecDeque<BlockInfo>>,
+    pickers: HashMap<[u8; 20], Picker>,
     /// The downloaded block infos of all torrents.
-    /// A hashmap of torrents, in which the values are
-    /// hashsets of block_infos
     /// K: info_hash (torrent)
-    downloaded_infos: HashMap<[u8; 20], HashSet<BlockInfo>>,
+    downloaded_infos: HashMap<[u8; 20], DownloadedInfos>,
+    /// Blocks read by the streaming server, of pieces that we don't have yet.
+    /// K: info_hash
+    stream_waiters: HashMap<[u8; 20], Vec<(BlockInfo, BlockSender)>>,
     download_dir: String,
+    /// The directory of the resume files of the torrents,
+    /// fast resume is disabled if `None`.
+    pub resume_dir: Option<PathBuf>,
+    /// The blocks that were not written to disk yet,
+    /// they are written when their piece is complete.
+    pub write_cache: WriteCache,
+    /// The pieces that were read to seed them.
+    pub read_cache: ReadCache,
+    /// Where the files of the torrents are, on disk by default.
+    pub storage: Arc<dyn Storage>,
+    /// The workers that read and write the storage.
+    pub io_pool: IoPool,
+    /// The torrents that are being checked.
+    /// K: info_hash
+    checking: HashMap<[u8; 20], Check>,
+    /// The torrents that wait until their jobs are done, e.g. while their
+    /// files are moved, with the messages that read or write their files,
+    /// that will be handled after, see [`Disk::park`].
+    /// K: info_hash
+    parked: HashMap<[u8; 20], Vec<DiskMsg>>,
+    /// The reads of blocks of many pieces, that wait until
+    /// the jobs of the pieces after the first one are done.
+    spanning_reads: Vec<(BlockInfo, [u8; 20], bool, BlockSender)>,
+    /// Messages that are handled before the ones of `rx`.
+    ready: VecDeque<DiskMsg>,
+    /// `Quit` was received, the Disk quits when the
+    /// jobs and the messages that wait for them are done.
+    quit: bool,
+}
+
+/// The progress of the check of a torrent, see [`Disk::check`].
+#[derive(Debug)]
+struct Check {
+    /// The blocks of each piece, in order.
+    blocks: Vec<Vec<BlockInfo>>,
+    /// How many pieces were checked.
+    checked: usize,
+    /// How many pieces are valid.
+    valid: usize,
+}
+
+/// The downloaded blocks of a torrent, by piece, with
+/// the id of the peer that sent them.
+#[derive(Debug, Default)]
+struct DownloadedInfos {
+    /// K: index of the piece
+    pieces: HashMap<usize, DownloadedPiece>,
+}
+
+#[derive(Debug, Default)]
+struct DownloadedPiece {
+    /// How many bytes of the piece were downloaded.
+    len: u32,
+    /// V: peer_id
+    blocks: HashMap<BlockInfo, [u8; 20]>,
+}
+
+impl DownloadedInfos {
+    /// Add a block sent by `peer_id`, false if it was already downloaded.
+    fn insert(&mut self, block_info: BlockInfo, peer_id: [u8; 20]) -> bool {
+        let piece = self.pieces.entry(block_info.index as usize).or_default();
+
+        if piece.blocks.contains_key(&block_info) {
+            return false;
+        }
+
+        piece.len += block_info.len;
+        piece.blocks.insert(block_info, peer_id);
+        true
+    }
+
+    /// How many bytes of the piece `index` were downloaded.
+    fn piece_len(&self, index: usize) -> u32 {
+        self.pieces.get(&index).map(|p| p.len).unwrap_or(0)
+    }
+
+    /// Remove the blocks of the piece `index`, with the peers that sent them.
+    fn remove_piece(&mut self, index: usize) -> HashMap<BlockInfo, [u8; 20]> {
+        self.pieces
+            .remove(&index)
+            .map(|p| p.blocks)
+            .unwrap_or_default()
+    }
+
+    fn blocks(&self) -> impl Iterator<Item = &BlockInfo> {
+        self.pieces.values().flat_map(|p| p.blocks.keys())
+    }
+
+    fn clear(&mut self) {
+        self.pieces.clear();
+    }
 }
 
 impl Disk {
     pub fn new(rx: Receiver<DiskMsg>, download_dir: String) -> Self {
         Self {
             rx,
+            storage: Arc::new(FsStorage::new(download_dir.clone())),
+            io_pool: IoPool::default(),
+            checking: HashMap::new(),
+            parked: HashMap::new(),
+            spanning_reads: Vec::new(),
+            ready: VecDeque::new(),
+            quit: false,
             download_dir,
             peer_ctxs: HashMap::new(),
             torrent_ctxs: HashMap::new(),
-            block_infos: HashMap::new(),
+            pickers: HashMap::new(),
             downloaded_infos: HashMap::new(),
+            stream_waiters: HashMap::new(),
+            resume_dir: None,
+            write_cache: WriteCache::default(),
+            read_cache: ReadCache::default(),
         }
     }
 
     pub async fn new_torrent(&mut self, torrent_ctx: Arc<TorrentCtx>) -> Result<(), Error> {
         let info_hash = torrent_ctx.info_hash;
+
+        // the torrent was resumed after an error, the
+        // files are created again, e.g. if they were deleted.
+        if self.torrent_ctxs.contains_key(&info_hash) {
+            return self.create_files(info_hash, false).await;
+        }
+
+        let part_files = *torrent_ctx.part_files.read().await;
         self.torrent_ctxs.insert(info_hash, torrent_ctx);
 
-        let torrent_ctx = self.torrent_ctxs.get(&info_hash).unwrap();
+        let location = self.location(info_hash).await;
+        self.storage.set_save_path(info_hash, location.into());
+        self.storage.set_part_files(info_hash, part_files);
+
+        // the picker is created after the files, and the torrent
+        // is removed if they can't be, see `Disk::io_done`.
+        self.create_files(info_hash, true).await
+    }
+
+    /// Create the picker of a new torrent whose files were created,
+    /// and tell the peers that we have the info.
+    async fn new_picker(&mut self, info_hash: [u8; 20]) -> Result<(), Error> {
+        let torrent_ctx = self
+            .torrent_ctxs
+            .get(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?;
+
         let info = torrent_ctx.info.read().await;
+        let priorities = torrent_ctx.file_priorities.read().await;
 
-        // base is download_dir/name_of_torrent
-        let mut base = PathBuf::new();
-        base.push(&self.download_dir);
-        base.push(&info.name);
-
-        // create "skeleton" of the torrent, empty files and directories
-        if let Some(files) = info.files.clone() {
-            for mut file in files {
-                // extract the file, the last item of the vec
-                // file.txt
-                let last = file.path.pop();
-
-                // the directory of the current file
-                // download_dir/name_of_torrent/name_of_dir
-                let mut file_dir = base.clone();
-                for dir_path in file.path {
-                    file_dir.push(&dir_path);
-                }
+        // generate block_infos of the new torrent
+        let mut picker = Picker::new(info.get_block_infos()?);
+        picker.set_priorities(&priorities, &info);
 
-                create_dir_all(&file_dir).await?;
+        drop(info);
+        drop(priorities);
 
-                // now with the dirs created, we create the file
-                if let Some(file_ext) = last {
-                    file_dir.push(file_ext);
+        self.pickers.insert(info_hash, picker);
+        self.downloaded_infos
+            .insert(info_hash, DownloadedInfos::default());
 
-                    self.open_file(file_dir).await?;
-                }
-            }
+        for peer in self.peer_ctxs.values() {
+            let _ = peer.tx.send(PeerMsg::HaveInfo).await;
         }
 
-        // generate block_infos of the new torrent
-        self.block_infos.insert(info_hash, info.get_block_infos()?);
-        self.downloaded_infos.insert(info_hash, HashSet::new());
-        drop(info);
+        Ok(())
+    }
+
+    /// Send the job that creates the "skeleton" of the torrent on the
+    /// storage, the other messages of the torrent wait until it is done.
+    /// `new` if the torrent was just added.
+    pub async fn create_files(&mut self, info_hash: [u8; 20], new: bool) -> Result<(), Error> {
+        let torrent_ctx = self
+            .torrent_ctxs
+            .get(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?
+            .clone();
+
+        let priorities = torrent_ctx.file_priorities.read().await.clone();
+
+        self.parked.entry(info_hash).or_default();
+        self.io_pool.send(
+            torrent_ctx,
+            self.storage.clone(),
+            IoJob::Create { priorities, new },
+        );
+
+        Ok(())
+    }
+
+    /// See [`DiskMsg::RemoveTorrent`]. The files are synced and closed by a
+    /// worker, after the blocks in memory are written.
+    pub async fn remove_torrent(&mut self, info_hash: [u8; 20]) {
+        if self.torrent_busy(info_hash) {
+            self.park(info_hash, DiskMsg::RemoveTorrent(info_hash));
+            return;
+        }
+
+        let Some(torrent_ctx) = self.torrent_ctxs.remove(&info_hash) else {
+            return;
+        };
+
+        self.read_cache.remove_torrent(info_hash);
+        self.checking.remove(&info_hash);
+        self.pickers.remove(&info_hash);
+        self.downloaded_infos.remove(&info_hash);
+
+        for (_, recipient) in self.stream_waiters.remove(&info_hash).unwrap_or_default() {
+            let _ = recipient.send(Err(Error::TorrentDoesNotExist));
+        }
+
+        // the torrent can be added again after its files are closed
+        self.io_pool
+            .send(torrent_ctx, self.storage.clone(), IoJob::Remove);
+        self.parked.entry(info_hash).or_default();
+    }
+
+    /// The directory in which the files of a torrent are saved.
+    pub async fn save_path(&self, info_hash: [u8; 20]) -> String {
+        let save_path = match self.torrent_ctxs.get(&info_hash) {
+            Some(torrent_ctx) => torrent_ctx.save_path.read().await.clone(),
+            None => None,
+        };
+        save_path.unwrap_or_else(|| self.download_dir.clone())
+    }
+
+    /// The directory in which the files of a torrent are now,
+    /// its incomplete dir while it is downloading.
+    pub async fn location(&self, info_hash: [u8; 20]) -> String {
+        let incomplete_dir = match self.torrent_ctxs.get(&info_hash) {
+            Some(torrent_ctx) => torrent_ctx.incomplete_dir.read().await.clone(),
+            None => None,
+        };
+
+        match incomplete_dir {
+            Some(incomplete_dir) => incomplete_dir,
+            None => self.save_path(info_hash).await,
+        }
+    }
+
+    /// Move the files of a torrent to `path`, see [`DiskMsg::MoveStorage`].
+    /// The files are moved by a worker, the torrent is told about the
+    /// error if they could not be moved. The files of a torrent that is not
+    /// on the Disk yet, e.g. without the info, are created on `path`, and
+    /// the files that are in the incomplete dir are moved to `path` when
+    /// the download is complete.
+    pub async fn move_storage(&mut self, torrent_ctx: Arc<TorrentCtx>, path: String) {
+        let info_hash = torrent_ctx.info_hash;
+
+        if !self.torrent_ctxs.contains_key(&info_hash)
+            || torrent_ctx.incomplete_dir.read().await.is_some()
+        {
+            *torrent_ctx.save_path.write().await = Some(path);
+            return;
+        }
 
-        for peer in &self.peer_ctxs {
-            peer.1.tx.send(PeerMsg::HaveInfo).await?;
+        if self.torrent_busy(info_hash) {
+            self.park(info_hash, DiskMsg::MoveStorage(torrent_ctx, path));
+            return;
         }
 
+        let part_files = *torrent_ctx.part_files.read().await;
+        self.relocate(torrent_ctx, path, part_files);
+    }
+
+    /// Move the files of a torrent that was downloaded to its save path,
+    /// without the `.part` suffix, see [`DiskMsg::CompleteStorage`].
+    pub async fn complete_storage(&mut self, torrent_ctx: Arc<TorrentCtx>) {
+        let info_hash = torrent_ctx.info_hash;
+
+        let incomplete = torrent_ctx.incomplete_dir.read().await.is_some();
+        let part_files = *torrent_ctx.part_files.read().await;
+
+        // the files are already where they should be
+        if !self.torrent_ctxs.contains_key(&info_hash) || !incomplete && !part_files {
+            return;
+        }
+
+        if self.torrent_busy(info_hash) {
+            self.park(info_hash, DiskMsg::CompleteStorage(torrent_ctx));
+            return;
+        }
+
+        let path = self.save_path(info_hash).await;
+        self.relocate(torrent_ctx, path, false);
+    }
+
+    /// Send the job that moves the files of a torrent to `path`, the blocks
+    /// in memory were written, and no job of the torrent is running.
+    fn relocate(&mut self, torrent_ctx: Arc<TorrentCtx>, path: String, part: bool) {
+        // the other messages of the torrent wait until the files are moved
+        self.parked.entry(torrent_ctx.info_hash).or_default();
+
+        self.io_pool.send(
+            torrent_ctx,
+            self.storage.clone(),
+            IoJob::Move {
+                path: path.into(),
+                part,
+            },
+        );
+    }
+
+    /// Update the priorities of the pieces from the priorities of the files,
+    /// see [`DiskMsg::SetFilePriorities`].
+    pub async fn set_file_priorities(&mut self, info_hash: [u8; 20]) -> Result<(), Error> {
+        self.create_files(info_hash, false).await?;
+
+        let torrent_ctx = self
+            .torrent_ctxs
+            .get(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?;
+
+        let info = torrent_ctx.info.read().await;
+        let priorities = torrent_ctx.file_priorities.read().await;
+
+        self.pickers
+            .get_mut(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?
+            .set_priorities(&priorities, &info);
+
         Ok(())
     }
 
@@ -152,461 +507,2385 @@ impl Disk {
         Ok(())
     }
 
+    /// Pick up to `qnt` blocks, of the pieces that the peer has,
+    /// and that were not requested yet.
     pub async fn request_blocks(
         &mut self,
         info_hash: [u8; 20],
-        mut qnt: usize,
+        qnt: usize,
         peer_id: [u8; 20],
     ) -> Result<VecDeque<BlockInfo>, Error> {
-        let mut infos: VecDeque<BlockInfo> = VecDeque::new();
-        let mut idxs = VecDeque::new();
-
-        let block_infos = self
-            .block_infos
+        let picker = self
+            .pickers
             .get_mut(&info_hash)
             .ok_or(Error::TorrentDoesNotExist)?;
 
-        let available = block_infos.len();
+        info!("disk: available blocks {}", picker.len());
 
-        info!("disk: available blocks {available}");
+        // only request blocks that the peer has
+        let peer = self.peer_ctxs.get(&peer_id).ok_or(Error::PeerIdInvalid)?;
+        let pieces = peer.pieces.read().await;
 
-        // prevent from requesting more blocks than what is available
-        if available < qnt {
-            qnt = available;
-        }
+        Ok(picker.pick(&pieces, qnt))
+    }
 
-        for (i, info) in block_infos.iter_mut().enumerate() {
-            if infos.len() >= qnt {
-                break;
-            }
+    /// Read a block for the streaming server, or wait until its piece is
+    /// validated, see [`DiskMsg::ReadStreamBlock`].
+    pub async fn read_stream_block(
+        &mut self,
+        block_info: BlockInfo,
+        info_hash: [u8; 20],
+        recipient: Sender<Result<Vec<u8>, Error>>,
+    ) {
+        let Some(torrent_ctx) = self.torrent_ctxs.get(&info_hash) else {
+            let _ = recipient.send(Err(Error::TorrentDoesNotExist));
+            return;
+        };
 
-            // only request blocks that the peer has
-            let peer = self.peer_ctxs.get(&peer_id).ok_or(Error::PeerIdInvalid)?;
-            let pieces = peer.pieces.read().await;
+        let index = block_info.index as usize;
 
-            if let Some(r) = pieces.get(info.index as usize) {
-                if r.bit == 1 {
-                    idxs.push_front(i);
-                    infos.push_back(info.clone());
-                }
-            }
+        if torrent_ctx.pieces.read().await.has(index) {
+            self.queue_read(block_info, info_hash, false, recipient)
+                .await;
+            return;
         }
-        // remove the requested infos from block_infos
-        for i in idxs {
-            block_infos.remove(i);
+
+        if let Some(picker) = self.pickers.get_mut(&info_hash) {
+            picker.set_urgent(index);
+            picker.set_cursor(index);
         }
 
-        Ok(infos)
+        self.stream_waiters
+            .entry(info_hash)
+            .or_default()
+            .push((block_info, recipient));
     }
 
-    #[tracing::instrument(skip(self))]
-    pub async fn run(&mut self) -> Result<(), Error> {
-        while let Some(msg) = self.rx.recv().await {
-            match msg {
-                DiskMsg::NewTorrent(torrent) => {
-                    let _ = self.new_torrent(torrent).await;
-                }
-                DiskMsg::ReadBlock {
-                    b,
-                    recipient,
-                    info_hash,
-                } => {
-                    let result = self.read_block(b, info_hash).await;
-                    let _ = recipient.send(result);
-                }
-                DiskMsg::WriteBlock {
-                    b,
-                    recipient,
-                    info_hash,
-                } => {
-                    let _ = recipient.send(self.write_block(b, info_hash).await);
-                    // let _ = recipient.send(Ok(()));
-                }
-                DiskMsg::OpenFile(path, tx) => {
-                    let file = self.open_file(path).await?;
-                    let _ = tx.send(file);
-                }
-                DiskMsg::RequestBlocks {
-                    qnt,
-                    recipient,
-                    info_hash,
-                    peer_id,
-                } => {
-                    let infos = self.request_blocks(info_hash, qnt, peer_id).await?;
-                    let _ = recipient.send(infos);
-                }
-                DiskMsg::ValidatePiece(index, info_hash, tx) => {
-                    let r = self.validate_piece(info_hash, index).await;
-                    let _ = tx.send(r);
-                }
-                DiskMsg::NewPeer(peer) => {
-                    self.new_peer(peer).await?;
-                }
-                DiskMsg::ReturnBlockInfos(info_hash, mut block_infos) => {
-                    self.block_infos
-                        .get_mut(&info_hash)
-                        .ok_or(Error::TorrentDoesNotExist)?
-                        .append(&mut block_infos);
-                }
-                DiskMsg::Quit => {
-                    return Ok(());
-                }
-            }
+    /// The piece `index` was validated, send its blocks to
+    /// the streaming server, if it is waiting for them.
+    async fn answer_stream_waiters(&mut self, info_hash: [u8; 20], index: usize) {
+        if let Some(picker) = self.pickers.get_mut(&info_hash) {
+            picker.remove_urgent(index);
         }
 
-        Ok(())
-    }
+        let Some(waiters) = self.stream_waiters.get_mut(&info_hash) else {
+            return;
+        };
 
-    pub async fn open_file(&self, path: impl AsRef<Path>) -> Result<File, Error> {
-        let path = path.as_ref().to_owned();
+        let (ready, waiting): (Vec<_>, Vec<_>) = std::mem::take(waiters)
+            .into_iter()
+            .partition(|(b, _)| b.index as usize == index);
 
-        OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&path)
-            .await
-            .map_err(|_| Error::FileOpenError(path.to_str().unwrap().to_owned()))
+        *waiters = waiting;
+
+        for (block_info, recipient) in ready {
+            self.queue_read(block_info, info_hash, false, recipient)
+                .await;
+        }
     }
 
-    #[tracing::instrument(skip(self))]
-    pub async fn validate_piece(&mut self, info_hash: [u8; 20], index: usize) -> Result<(), Error> {
-        // let torrent_ctx = self
-        //     .torrent_ctxs
-        //     .get(&info_hash)
-        //     .ok_or(Error::TorrentDoesNotExist)?;
-        //
-        // let b = index * 20;
-        // let e = b + 20;
-        //
-        // let pieces = torrent_ctx.pieces.read().await;
-        // let hash_from_info = pieces[b..e].to_owned();
-        //
-        // let mut buf = Vec::new();
-        // let previous_blocks_len = index * (*info.blocks_per_piece() as usize);
-        //
-        // let downloaded_blocks = torrent_ctx
-        //     .requested_blocks
-        //     .read()
-        //     .await
-        //     .iter()
-        //     .skip(previous_blocks_len)
-        //     .take(5);
-        //
-        // for b in downloaded_blocks {
-        //     buf.push(b.block)
-        // }
-        //
-        // let mut hash = sha1_smol::Sha1::new();
-        // hash.update(&buf);
-        //
-        // let hash = hash.digest().bytes();
-        //
-        // if hash_from_info != hash {
-        //     return Err(Error::PieceInvalid);
-        // }
+    pub async fn set_sequential(
+        &mut self,
+        info_hash: [u8; 20],
+        sequential: bool,
+    ) -> Result<(), Error> {
+        let torrent_ctx = self
+            .torrent_ctxs
+            .get(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?;
+
+        let info = torrent_ctx.info.read().await;
+
+        self.pickers
+            .get_mut(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?
+            .set_sequential(sequential, &info);
 
         Ok(())
     }
 
-    pub async fn read_block(
-        &self,
-        block_info: BlockInfo,
-        info_hash: [u8; 20],
-    ) -> Result<Vec<u8>, Error> {
-        let mut file = self
-            .get_file_from_block_info(&block_info, info_hash)
-            .await?;
+    /// Fill the resume data with the state of the torrent on disk, and write it.
+    pub async fn save_resume_data(&mut self, mut data: ResumeData) -> Result<(), Error> {
+        let Some(resume_dir) = self.resume_dir.clone() else {
+            return Ok(());
+        };
 
-        // how many bytes to read, after offset (begin)
-        let mut buf = vec![0; block_info.len as usize];
+        let info_hash = data.info_hash;
 
-        file.0.read_exact(&mut buf).await?;
+        // the unfinished blocks must be on disk
+        if self.torrent_busy(info_hash) {
+            self.park(info_hash, DiskMsg::SaveResumeData(data));
+            return Ok(());
+        }
 
-        let torrent_tx = &self
+        let torrent_ctx = self
             .torrent_ctxs
             .get(&info_hash)
             .ok_or(Error::TorrentDoesNotExist)?
-            .tx;
-
-        // increment uploaded count
-        torrent_tx
-            .send(TorrentMsg::IncrementUploaded(block_info.len as u64))
-            .await?;
-
-        Ok(buf)
-    }
+            .clone();
 
-    #[tracing::instrument(skip(self, block))]
-    pub async fn write_block(&mut self, block: Block, info_hash: [u8; 20]) -> Result<(), Error> {
-        let len = block.block.len() as u32;
-        let begin = block.begin;
-        let index = block.index;
+        let pieces = torrent_ctx.pieces.read().await;
 
-        let block_info = BlockInfo {
-            index: index as u32,
-            len,
-            begin,
-        };
+        data.pieces = pieces.inner.clone();
 
-        let torrent_downloaded_infos = self
+        // the blocks of a complete piece are in the bitfield
+        data.unfinished = self
             .downloaded_infos
-            .get_mut(&info_hash)
-            .ok_or(Error::TorrentDoesNotExist)?;
-
-        let already_downloaded = torrent_downloaded_infos.get(&block_info).is_some();
-
-        if already_downloaded {
-            info!("already downloaded, ignoring");
-            return Ok(());
-        }
+            .get(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?
+            .blocks()
+            .filter(|b| !pieces.has(b.index as usize))
+            .cloned()
+            .collect();
+
+        data.unfinished.sort_by_key(|b| (b.index, b.begin));
+        data.save_path = self.save_path(info_hash).await;
+        data.incomplete_dir = torrent_ctx.incomplete_dir.read().await.clone();
+        data.part_files = *torrent_ctx.part_files.read().await;
+        drop(pieces);
+
+        // the sizes of the files are read, and the file is written, by a worker
+        self.io_pool.send(
+            torrent_ctx,
+            self.storage.clone(),
+            IoJob::SaveResume {
+                data: Box::new(data),
+                dir: resume_dir,
+            },
+        );
 
-        torrent_downloaded_infos.insert(block_info.clone());
+        Ok(())
+    }
 
-        let (mut fs_file, _) = self
-            .get_file_from_block_info(&block_info, info_hash)
-            .await?;
+    /// Restore the pieces and the unfinished blocks of a torrent from its
+    /// resume data, without hashing them. A piece is only restored if the
+    /// size and modification time of all of its files did not change.
+    pub async fn restore_resume_data(&mut self, data: ResumeData) -> Result<usize, Error> {
+        let info_hash = data.info_hash;
 
         let torrent_ctx = self
             .torrent_ctxs
             .get(&info_hash)
             .ok_or(Error::TorrentDoesNotExist)?;
 
-        let torrent_tx = torrent_ctx.tx.clone();
-
-        // the write operation is heavy, so we want to spawn a thread here,
-        // and only increment the downloaded count after writing,
-        // because otherwise the UI could say the torrent is complete
-        // while not every byte is written to disk.
-        // note: spawning a task to do this write was causing bugs on Windows.
-        // todo: fix this
-        fs_file.write_all(&block.block).await.unwrap();
-        let _ = torrent_tx
-            .send(TorrentMsg::IncrementDownloaded(len as u64))
-            .await;
-
-        let mut pieces = torrent_ctx.pieces.write().await;
-        let torrent_tx = &torrent_ctx.tx;
+        if data.save_path != self.save_path(info_hash).await
+            || data.incomplete_dir != *torrent_ctx.incomplete_dir.read().await
+            || data.part_files != *torrent_ctx.part_files.read().await
+        {
+            return Ok(0);
+        }
 
         let info = torrent_ctx.info.read().await;
+        let mut trusted = vec![true; info.pieces() as usize];
+
+        for (i, (size, (_, range))) in self
+            .storage
+            .file_sizes(info_hash, &info)
+            .into_iter()
+            .zip(info.get_files_pieces())
+            .enumerate()
+        {
+            if data.file_sizes.get(i) != Some(&size) {
+                info!("file {i} changed since the resume data was saved");
+                for piece in trusted.iter_mut().take(range.end).skip(range.start) {
+                    *piece = false;
+                }
+            }
+        }
 
-        // if this is the last block of a piece,
-        // validate the hash
-        if begin + len >= info.piece_length {
-            // todo: add validation of pieces
-            // self.validate_piece(info_hash, index).await?;
+        let saved = Bitfield::from(data.pieces);
+        let unfinished: HashSet<BlockInfo> = data.unfinished.into_iter().collect();
 
-            torrent_tx.send(TorrentMsg::DownloadedPiece(index)).await?;
+        let blocks: Vec<BlockInfo> = info
+            .get_block_infos()?
+            .into_iter()
+            .filter(|b| {
+                let index = b.index as usize;
+                trusted.get(index) == Some(&true) && (saved.has(index) || unfinished.contains(b))
+            })
+            .collect();
 
-            // info!("hash of piece {index:?} is valid");
+        let mut pieces = torrent_ctx.pieces.write().await;
+        let mut restored = 0;
 
-            // update the bitfield of the torrent
+        for index in (0..trusted.len()).filter(|i| trusted[*i] && saved.has(*i)) {
             pieces.set(index);
+            restored += 1;
         }
 
+        drop(pieces);
         drop(info);
 
-        Ok(())
-    }
+        let downloaded_infos = self
+            .downloaded_infos
+            .get_mut(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?;
 
-    /// Return a seeked fs::File, given an `index` and `begin`.
-    /// use cases:
-    /// - After we receive a Piece msg with the Block, we need to
-    /// map a block to a fs::File to be able to write to disk efficiently
-    /// - When a leecher sends a Request msg with a BlockInfo msg, we need
-    /// to first get the corresponding file and advance the corresponding bytes
-    /// of the `piece` and `begin` variables. After that, we can get the correct Block
-    /// on the returned File.
-    pub async fn get_file_from_block_info(
-        &self,
-        block_info: &BlockInfo,
-        info_hash: [u8; 20],
-    ) -> Result<(File, metainfo::File), Error> {
-        let torrent = self
-            .torrent_ctxs
-            .get(&info_hash)
-            .ok_or(Error::InfoHashInvalid)?;
+        for block in &blocks {
+            downloaded_infos.insert(block.clone(), [0; 20]);
+        }
+
+        self.pickers
+            .get_mut(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?
+            .remove_blocks(blocks);
+
+        Ok(restored)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn run(&mut self) -> Result<(), Error> {
+        loop {
+            if self.quit && self.ready.is_empty() && self.parked.is_empty() {
+                for (info_hash, index) in self.write_cache.keys() {
+                    self.flush_piece(info_hash, index);
+                }
+
+                if self.io_pool.in_flight() == 0 {
+                    for torrent_ctx in self.torrent_ctxs.values() {
+                        self.io_pool
+                            .send(torrent_ctx.clone(), self.storage.clone(), IoJob::Flush);
+                    }
+                    self.wait_io().await;
+                    return Ok(());
+                }
+            }
+
+            let msg = match self.ready.pop_front() {
+                Some(msg) => Some(msg),
+                None => tokio::select! {
+                    Some(done) = self.io_pool.recv() => {
+                        self.io_done(done).await;
+                        continue;
+                    }
+                    msg = self.rx.recv() => msg,
+                },
+            };
+
+            let Some(msg) = msg else {
+                break;
+            };
+
+            self.handle(msg).await;
+        }
+
+        Ok(())
+    }
+
+    /// Handle a message, or park it if its torrent waits for its jobs.
+    async fn handle(&mut self, msg: DiskMsg) {
+        // the torrent waits until its jobs are done
+        if let Some(parked) = msg.storage_of().and_then(|i| self.parked.get_mut(&i)) {
+            parked.push(msg);
+            return;
+        }
+
+        match msg {
+            DiskMsg::NewTorrent(torrent) => {
+                let tx = torrent.tx.clone();
+
+                if let Err(e) = self.new_torrent(torrent).await {
+                    warn!("could not add the torrent: {e}");
+                    let _ = tx.send(TorrentMsg::DiskError(e.to_string())).await;
+                }
+            }
+            DiskMsg::ReadBlock {
+                b,
+                recipient,
+                info_hash,
+            } => {
+                self.queue_read(b, info_hash, true, recipient).await;
+            }
+            DiskMsg::WriteBlock {
+                b,
+                recipient,
+                info_hash,
+                peer_id,
+            } => {
+                let result = self.write_block(b, info_hash, peer_id).await;
+
+                if let Err(e) = &result {
+                    self.disk_error(info_hash, e).await;
+                }
+
+                let _ = recipient.send(result);
+            }
+            DiskMsg::OpenFile(path, tx) => match self.open_file(path).await {
+                Ok(file) => {
+                    let _ = tx.send(file);
+                }
+                Err(e) => warn!("{e}"),
+            },
+            DiskMsg::RequestBlocks {
+                qnt,
+                recipient,
+                info_hash,
+                peer_id,
+            } => match self.request_blocks(info_hash, qnt, peer_id).await {
+                Ok(infos) => {
+                    let _ = recipient.send(infos);
+                }
+                Err(e) => warn!("could not request blocks: {e}"),
+            },
+            DiskMsg::ValidatePiece(index, info_hash, tx) => {
+                self.queue_validate(info_hash, index, tx).await;
+            }
+            DiskMsg::NewPeer(peer) => {
+                if let Err(e) = self.new_peer(peer).await {
+                    warn!("could not add the peer: {e}");
+                }
+            }
+            DiskMsg::ReturnBlockInfos(info_hash, block_infos) => {
+                if let Some(picker) = self.pickers.get_mut(&info_hash) {
+                    picker.return_blocks(block_infos);
+                }
+            }
+            // peers that connected before we had the info
+            // send their bitfield when they receive `HaveInfo`.
+            DiskMsg::PeerBitfield {
+                info_hash,
+                peer_id,
+                bitfield,
+            } => {
+                if let Some(picker) = self.pickers.get_mut(&info_hash) {
+                    picker.peer_bitfield(peer_id, bitfield);
+                }
+            }
+            DiskMsg::PeerHave {
+                info_hash,
+                peer_id,
+                index,
+            } => {
+                if let Some(picker) = self.pickers.get_mut(&info_hash) {
+                    picker.peer_have(peer_id, index);
+                }
+            }
+            DiskMsg::ReadStreamBlock {
+                b,
+                recipient,
+                info_hash,
+            } => {
+                self.read_stream_block(b, info_hash, recipient).await;
+            }
+            DiskMsg::GetTorrentCtx(info_hash, recipient) => {
+                let _ = recipient.send(self.torrent_ctxs.get(&info_hash).cloned());
+            }
+            DiskMsg::SetSequential(info_hash, sequential) => {
+                if let Err(e) = self.set_sequential(info_hash, sequential).await {
+                    warn!("could not set the sequential mode: {e}");
+                }
+            }
+            DiskMsg::SetFilePriorities(info_hash) => {
+                if let Err(e) = self.set_file_priorities(info_hash).await {
+                    warn!("could not set the priorities of the files: {e}");
+                    self.disk_error(info_hash, &e).await;
+                }
+            }
+            DiskMsg::DeletePeer { info_hash, peer_id } => {
+                self.peer_ctxs.remove(&peer_id);
+
+                if let Some(picker) = self.pickers.get_mut(&info_hash) {
+                    picker.remove_peer(&peer_id);
+                }
+            }
+            DiskMsg::SaveResumeData(data) => {
+                let info_hash = data.info_hash;
+
+                if let Err(e) = self.save_resume_data(data).await {
+                    warn!("could not save the resume data: {e}");
+                    self.disk_error(info_hash, &e).await;
+                }
+            }
+            DiskMsg::RestoreResumeData(data, recipient) => {
+                let _ = recipient.send(self.restore_resume_data(data).await);
+            }
+            DiskMsg::Check(info_hash) => {
+                if let Err(e) = self.check(info_hash).await {
+                    warn!("could not check the torrent: {e}");
+                    self.disk_error(info_hash, &e).await;
+
+                    if let Some(torrent_ctx) = self.torrent_ctxs.get(&info_hash) {
+                        let _ = torrent_ctx.tx.send(TorrentMsg::CheckComplete).await;
+                    }
+                }
+            }
+            DiskMsg::RemoveTorrent(info_hash) => {
+                self.remove_torrent(info_hash).await;
+            }
+            DiskMsg::MoveStorage(torrent_ctx, path) => {
+                self.move_storage(torrent_ctx, path).await;
+            }
+            DiskMsg::CompleteStorage(torrent_ctx) => {
+                self.complete_storage(torrent_ctx).await;
+            }
+            // the blocks in memory are written, the jobs and the messages
+            // that wait for them are done, and the files are synced
+            // before quitting, see `Disk::run`.
+            DiskMsg::Quit => {
+                self.quit = true;
+            }
+        }
+    }
+
+    /// Tell the torrent that its files could not be read or written, the
+    /// torrent stops until the user fixes the problem and resumes it.
+    /// Errors that did not happen on the storage are ignored.
+    async fn disk_error(&self, info_hash: [u8; 20], e: &Error) {
+        if let Some(reason) = storage::error_reason(e) {
+            self.send_disk_error(info_hash, reason).await;
+        }
+    }
+
+    async fn send_disk_error(&self, info_hash: [u8; 20], reason: String) {
+        warn!("disk error on torrent {}: {reason}", hex::encode(info_hash));
+
+        if let Some(torrent_ctx) = self.torrent_ctxs.get(&info_hash) {
+            let _ = torrent_ctx.tx.send(TorrentMsg::DiskError(reason)).await;
+        }
+    }
+
+    /// If the torrent has the piece `index`.
+    async fn has_piece(&self, info_hash: [u8; 20], index: usize) -> bool {
+        match self.torrent_ctxs.get(&info_hash) {
+            Some(torrent_ctx) => torrent_ctx.pieces.read().await.has(index),
+            None => false,
+        }
+    }
+
+    pub async fn open_file(&self, path: impl AsRef<Path>) -> Result<File, Error> {
+        let path = path.as_ref().to_owned();
+
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .await
+            .map_err(|_| Error::FileOpenError(path.to_str().unwrap().to_owned()))
+    }
+
+    /// Hash all the downloaded blocks of the piece `index`, compare it with
+    /// the hash of the piece on `Info.pieces`, and send the result to
+    /// `recipient`. The piece is hashed by the worker of the piece,
+    /// after its blocks are written.
+    #[tracing::instrument(skip(self, recipient))]
+    pub async fn queue_validate(
+        &mut self,
+        info_hash: [u8; 20],
+        index: usize,
+        recipient: Sender<Result<(), Error>>,
+    ) {
+        // the blocks of the piece must be on disk
+        self.flush_piece(info_hash, index);
+
+        let (Some(torrent_ctx), Some(downloaded_infos)) = (
+            self.torrent_ctxs.get(&info_hash),
+            self.downloaded_infos.get(&info_hash),
+        ) else {
+            let _ = recipient.send(Err(Error::TorrentDoesNotExist));
+            return;
+        };
+
+        if downloaded_infos.piece_len(index) != torrent_ctx.info.read().await.piece_size(index) {
+            let _ = recipient.send(Err(Error::PieceInvalid));
+            return;
+        }
+
+        self.io_pool.send(
+            torrent_ctx.clone(),
+            self.storage.clone(),
+            IoJob::Validate { index, recipient },
+        );
+    }
+
+    /// Validate the piece `index` and wait for the result, see [`Disk::queue_validate`].
+    pub async fn validate_piece(&mut self, info_hash: [u8; 20], index: usize) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        self.queue_validate(info_hash, index, tx).await;
+        self.wait_for(rx).await
+    }
+
+    /// Send the blocks of the piece `index` that are in the cache to the
+    /// workers, to be written to disk, see [`storage::write_blocks`].
+    fn flush_piece(&mut self, info_hash: [u8; 20], index: usize) {
+        let Some(piece) = self.write_cache.take(info_hash, index) else {
+            return;
+        };
+
+        self.write_piece(info_hash, index, piece.blocks, Validate::No);
+    }
+
+    /// Send the blocks of the piece `index` to the workers, to be written
+    /// to disk. If they can't be written, the blocks of the piece are
+    /// downloaded again, after the torrent is resumed, see [`Disk::io_done`].
+    fn write_piece(
+        &mut self,
+        info_hash: [u8; 20],
+        index: usize,
+        blocks: BTreeMap<u32, Vec<u8>>,
+        validate: Validate,
+    ) {
+        let Some(torrent_ctx) = self.torrent_ctxs.get(&info_hash) else {
+            return;
+        };
+
+        self.io_pool.send(
+            torrent_ctx.clone(),
+            self.storage.clone(),
+            IoJob::Write {
+                index,
+                blocks,
+                validate,
+            },
+        );
+    }
+
+    /// Send all the pieces of a torrent that are in the cache to the workers.
+    fn flush_torrent(&mut self, info_hash: [u8; 20]) {
+        for index in self.write_cache.pieces_of(info_hash) {
+            self.flush_piece(info_hash, index);
+        }
+    }
+
+    /// Send the blocks of a torrent that are in memory to the workers,
+    /// and return if jobs of the torrent are not done yet. The messages
+    /// that use all the files of a torrent wait until they are done.
+    fn torrent_busy(&mut self, info_hash: [u8; 20]) -> bool {
+        self.flush_torrent(info_hash);
+        self.io_pool.torrent_in_flight(info_hash) > 0
+    }
+
+    /// Handle `msg` after the jobs of its torrent are done, the next
+    /// messages that read or write the files of the torrent wait after it,
+    /// the other torrents are not affected.
+    fn park(&mut self, info_hash: [u8; 20], msg: DiskMsg) {
+        self.parked.entry(info_hash).or_default().push(msg);
+    }
+
+    /// Wait until the workers are done with all the jobs that were sent,
+    /// and handle their results, and the messages that were waiting for them.
+    pub async fn wait_io(&mut self) {
+        loop {
+            while self.io_pool.in_flight() > 0 {
+                let Some(done) = self.io_pool.recv().await else {
+                    break;
+                };
+                self.io_done(done).await;
+            }
+
+            let Some(msg) = self.ready.pop_front() else {
+                break;
+            };
+            self.handle(msg).await;
+        }
+    }
+
+    /// Handle the results of the workers until `rx` receives its answer.
+    async fn wait_for<T>(
+        &mut self,
+        mut rx: oneshot::Receiver<Result<T, Error>>,
+    ) -> Result<T, Error> {
+        loop {
+            tokio::select! {
+                r = &mut rx => return r?,
+                Some(done) = self.io_pool.recv() => self.io_done(done).await,
+            }
+        }
+    }
+
+    /// The jobs of the torrents that were waiting are done, handle their
+    /// messages, and send the reads of many pieces that can be read.
+    async fn release(&mut self) {
+        let idle: Vec<[u8; 20]> = self
+            .parked
+            .keys()
+            .filter(|info_hash| self.io_pool.torrent_in_flight(**info_hash) == 0)
+            .copied()
+            .collect();
+
+        for info_hash in idle {
+            let parked = self.parked.remove(&info_hash).unwrap_or_default();
+            self.ready.extend(parked);
+        }
+
+        for (block_info, info_hash, upload, recipient) in std::mem::take(&mut self.spanning_reads) {
+            self.queue_read(block_info, info_hash, upload, recipient)
+                .await;
+        }
+    }
+
+    /// Handle the result of a job of the workers.
+    async fn io_done(&mut self, done: IoDone) {
+        match done {
+            IoDone::Read {
+                info_hash,
+                block_info,
+                upload,
+                result,
+            } => match result {
+                Ok(piece) => {
+                    if let Some(piece) = piece {
+                        self.read_cache
+                            .insert(info_hash, block_info.index as usize, piece);
+                    }
+                    if upload {
+                        self.uploaded(info_hash, &block_info, false).await;
+                    }
+                }
+                // a peer can request a block of a piece that we don't have
+                Err(Some(reason)) => {
+                    if self.has_piece(info_hash, block_info.index as usize).await {
+                        self.send_disk_error(info_hash, reason).await;
+                    }
+                }
+                Err(None) => {}
+            },
+            IoDone::Write {
+                info_hash,
+                index,
+                result,
+            } => match result {
+                Ok(None) => {}
+                Ok(Some(true)) => {
+                    info!("hash of piece {index:?} is valid");
+
+                    if let Some(torrent_ctx) = self.torrent_ctxs.get(&info_hash) {
+                        // update the bitfield of the torrent, before the torrent
+                        // checks if the download is complete.
+                        torrent_ctx.pieces.write().await.set(index);
+
+                        let _ = torrent_ctx
+                            .tx
+                            .send(TorrentMsg::DownloadedPiece(index))
+                            .await;
+
+                        self.answer_stream_waiters(info_hash, index).await;
+                    }
+                }
+                Ok(Some(false)) => {
+                    warn!("hash of piece {index:?} is invalid, downloading it again");
+
+                    if let Err(e) = self.reset_piece(info_hash, index).await {
+                        warn!("could not reset the piece {index}: {e}");
+                    }
+                }
+                Err(e) => {
+                    self.write_cache.take(info_hash, index);
+                    let _ = self.forget_piece(info_hash, index).await;
+                    self.disk_error(info_hash, &e).await;
+                }
+            },
+            IoDone::Hash {
+                info_hash,
+                index,
+                valid,
+            } => {
+                if let Err(e) = self.piece_checked(info_hash, index, valid).await {
+                    warn!("could not check the piece {index}: {e}");
+                }
+            }
+            IoDone::Validate { index, valid, .. } => {
+                info!("hash of piece {index} is valid: {valid}");
+            }
+            IoDone::Move {
+                info_hash,
+                path,
+                part,
+                result,
+            } => match result {
+                Ok(_) => {
+                    info!("moved the files of {} to {path:?}", hex::encode(info_hash));
+
+                    if let Some(torrent_ctx) = self.torrent_ctxs.get(&info_hash) {
+                        let path = path.to_string_lossy().into_owned();
+                        *torrent_ctx.save_path.write().await = Some(path);
+                        *torrent_ctx.incomplete_dir.write().await = None;
+                        *torrent_ctx.part_files.write().await = part;
+                    }
+                }
+                Err(e) => self.disk_error(info_hash, &e).await,
+            },
+            IoDone::Create {
+                info_hash,
+                new,
+                result,
+            } => {
+                let result = match result {
+                    Ok(_) if new => self.new_picker(info_hash).await,
+                    r => r,
+                };
+
+                if let Err(e) = result {
+                    self.send_disk_error(info_hash, e.to_string()).await;
+
+                    // the torrent is not added if its files can't be created
+                    if new {
+                        self.torrent_ctxs.remove(&info_hash);
+                    }
+                }
+            }
+            IoDone::Flush { result, .. } => {
+                if let Err(e) = result {
+                    warn!("could not sync the files to disk: {e}");
+                }
+            }
+            IoDone::Remove { result, .. } => {
+                if let Err(e) = result {
+                    warn!("could not remove the torrent: {e}");
+                }
+            }
+            IoDone::SaveResume { info_hash, result } => {
+                if let Err(e) = result {
+                    warn!("could not save the resume data: {e}");
+                    self.disk_error(info_hash, &e).await;
+                }
+            }
+        }
+
+        self.release().await;
+    }
+
+    /// Split a block into the parts that are inside each file of the torrent,
+    /// see [`storage::segments`].
+    pub async fn block_segments(
+        &self,
+        block_info: &BlockInfo,
+        info_hash: [u8; 20],
+    ) -> Result<Vec<Segment>, Error> {
+        let torrent_ctx = self
+            .torrent_ctxs
+            .get(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?;
+
+        let info = torrent_ctx.info.read().await;
+
+        storage::segments(&info, block_info)
+    }
+
+    /// Hash every piece of a torrent that is on disk. Only the valid pieces
+    /// are set on the bitfield of the torrent, the blocks of the other pieces
+    /// go back into the picker. The pieces are hashed by the workers, the
+    /// torrent is told about the progress as they are done.
+    #[tracing::instrument(skip(self))]
+    pub async fn check(&mut self, info_hash: [u8; 20]) -> Result<(), Error> {
+        let torrent_ctx = self
+            .torrent_ctxs
+            .get(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?
+            .clone();
+
+        // the blocks in memory are checked too
+        if self.torrent_busy(info_hash) {
+            self.park(info_hash, DiskMsg::Check(info_hash));
+            return Ok(());
+        }
+
+        self.read_cache.remove_torrent(info_hash);
+
+        let info = torrent_ctx.info.read().await;
+        let block_infos = info.get_block_infos()?;
+        let priorities = info.piece_priorities(&torrent_ctx.file_priorities.read().await);
+        drop(info);
+
+        // forget what we know about the pieces, all blocks are free again
+        let mut pieces = torrent_ctx.pieces.write().await;
+        *pieces = Bitfield::from(vec![0_u8; pieces.inner.len()]);
+        drop(pieces);
+
+        self.downloaded_infos
+            .get_mut(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?
+            .clear();
+
+        self.pickers
+            .get_mut(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?
+            .return_blocks(block_infos.clone());
+
+        // the blocks of each piece, in order
+        let mut by_piece: Vec<Vec<BlockInfo>> = Vec::new();
+
+        for block_info in block_infos {
+            let index = block_info.index as usize;
+            if by_piece.len() <= index {
+                by_piece.resize(index + 1, Vec::new());
+            }
+            by_piece[index].push(block_info);
+        }
+
+        let mut check = Check {
+            blocks: by_piece,
+            checked: 0,
+            valid: 0,
+        };
+
+        for index in 0..check.blocks.len() {
+            // the files of skipped pieces may not exist, and are not created
+            if priorities.get(index) == Some(&Priority::Skip) {
+                check.checked += 1;
+                continue;
+            }
+
+            self.io_pool.send(
+                torrent_ctx.clone(),
+                self.storage.clone(),
+                IoJob::Hash { index },
+            );
+        }
+
+        let done = check.checked == check.blocks.len();
+        self.checking.insert(info_hash, check);
+
+        if done {
+            self.check_complete(info_hash).await?;
+        }
+
+        Ok(())
+    }
+
+    /// A worker hashed the piece `index` of a torrent that is being checked.
+    async fn piece_checked(
+        &mut self,
+        info_hash: [u8; 20],
+        index: usize,
+        valid: bool,
+    ) -> Result<(), Error> {
+        let torrent_ctx = self
+            .torrent_ctxs
+            .get(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?
+            .clone();
+
+        let Some(check) = self.checking.get_mut(&info_hash) else {
+            return Ok(());
+        };
+
+        check.checked += 1;
+        let checked = check.checked;
+        let done = check.checked == check.blocks.len();
+
+        if valid {
+            check.valid += 1;
+            let blocks = std::mem::take(&mut check.blocks[index]);
+
+            torrent_ctx.pieces.write().await.set(index);
+
+            let downloaded_infos = self
+                .downloaded_infos
+                .get_mut(&info_hash)
+                .ok_or(Error::TorrentDoesNotExist)?;
+
+            for block_info in &blocks {
+                downloaded_infos.insert(block_info.clone(), [0; 20]);
+            }
+
+            self.pickers
+                .get_mut(&info_hash)
+                .ok_or(Error::TorrentDoesNotExist)?
+                .remove_blocks(blocks);
+
+            torrent_ctx
+                .tx
+                .send(TorrentMsg::DownloadedPiece(index))
+                .await?;
+
+            self.answer_stream_waiters(info_hash, index).await;
+        }
+
+        torrent_ctx
+            .tx
+            .send(TorrentMsg::CheckProgress(checked))
+            .await?;
+
+        if done {
+            self.check_complete(info_hash).await?;
+        }
+
+        Ok(())
+    }
+
+    /// All the pieces of a torrent were checked.
+    async fn check_complete(&mut self, info_hash: [u8; 20]) -> Result<(), Error> {
+        let Some(check) = self.checking.remove(&info_hash) else {
+            return Ok(());
+        };
+
+        info!("{} pieces are valid", check.valid);
+
+        if let Some(torrent_ctx) = self.torrent_ctxs.get(&info_hash) {
+            torrent_ctx.tx.send(TorrentMsg::CheckComplete).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The piece `index` failed the hash check. Put the block infos of the piece
+    /// back into the picker, so that they can be downloaded again,
+    /// and count the failure against the peers that sent the blocks.
+    #[tracing::instrument(skip(self))]
+    pub async fn reset_piece(&mut self, info_hash: [u8; 20], index: usize) -> Result<(), Error> {
+        self.write_cache.take(info_hash, index);
+
+        for peer_id in self.forget_piece(info_hash, index).await? {
+            let Some(peer) = self.peer_ctxs.get(&peer_id) else {
+                continue;
+            };
+
+            // the peer can be waiting for an answer of the Disk,
+            // waiting for room on its channel would block both.
+            if let Err(TrySendError::Full(_)) = peer.tx.try_send(PeerMsg::PieceInvalid(index)) {
+                warn!("the channel of the peer is full, piece {index} was not reported to it");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forget the downloaded blocks of the piece `index`, and put them back
+    /// into the picker, so that they can be downloaded again. The bytes of
+    /// the blocks are not counted as downloaded anymore.
+    /// Returns the peers that sent the blocks.
+    async fn forget_piece(
+        &mut self,
+        info_hash: [u8; 20],
+        index: usize,
+    ) -> Result<Vec<[u8; 20]>, Error> {
+        let downloaded_infos = self
+            .downloaded_infos
+            .get_mut(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?;
+
+        let blocks = downloaded_infos.remove_piece(index);
+        let mut peers: Vec<[u8; 20]> = Vec::new();
+
+        for peer_id in blocks.values() {
+            if !peers.contains(peer_id) {
+                peers.push(*peer_id);
+            }
+        }
+
+        let block_infos: Vec<BlockInfo> = blocks.into_keys().collect();
+
+        let len: u64 = block_infos.iter().map(|b| b.len as u64).sum();
+
+        self.pickers
+            .get_mut(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?
+            .return_blocks(block_infos);
+
+        if let Some(torrent_ctx) = self.torrent_ctxs.get(&info_hash) {
+            let _ = torrent_ctx
+                .tx
+                .send(TorrentMsg::DecrementDownloaded(len))
+                .await;
+        }
+
+        Ok(peers)
+    }
+
+    /// Read a block from the caches, or send it to the workers to be read
+    /// from disk. On the first read of a block of a complete piece, the
+    /// whole piece is read and added to the read cache, for the next blocks
+    /// of the piece. The block is sent to `recipient`, and counted as
+    /// uploaded if `upload`, when a peer requested it.
+    pub async fn queue_read(
+        &mut self,
+        block_info: BlockInfo,
+        info_hash: [u8; 20],
+        upload: bool,
+        recipient: Sender<Result<Vec<u8>, Error>>,
+    ) {
+        let Some(torrent_ctx) = self.torrent_ctxs.get(&info_hash).cloned() else {
+            let _ = recipient.send(Err(Error::TorrentDoesNotExist));
+            return;
+        };
+
+        let cached = if let Some(buf) = self.write_cache.get(info_hash, &block_info) {
+            Some(buf.to_vec())
+        } else {
+            self.read_cache
+                .get(info_hash, &block_info)
+                .map(|buf| buf.to_vec())
+        };
+
+        if let Some(buf) = cached {
+            let _ = recipient.send(Ok(buf));
+            if upload {
+                self.uploaded(info_hash, &block_info, true).await;
+            }
+            return;
+        }
+
+        let index = block_info.index as usize;
+        let info = torrent_ctx.info.read().await;
+        let piece_size = info.piece_size(index);
+        let end = block_info.begin as u64 + block_info.len as u64;
+        let in_piece = end <= piece_size as u64;
+        // the block also ends in the pieces `index + 1..until`, if it is not in its piece
+        let spanned = end.div_ceil(info.piece_length.max(1) as u64) as usize;
+        let until = (index + spanned).min(info.pieces() as usize);
+        drop(info);
+        let whole_piece = in_piece && torrent_ctx.pieces.read().await.has(index);
+
+        // the jobs are only ordered inside a piece, a block of many
+        // pieces is read after the jobs of the next pieces are done.
+        if (index + 1..until).any(|i| self.io_pool.piece_in_flight(info_hash, i) > 0) {
+            self.spanning_reads
+                .push((block_info, info_hash, upload, recipient));
+            return;
+        }
+
+        self.io_pool.send(
+            torrent_ctx,
+            self.storage.clone(),
+            IoJob::Read {
+                block_info,
+                whole_piece,
+                upload,
+                recipient,
+            },
+        );
+    }
+
+    /// Read a block that a peer requested and wait for it, see [`Disk::queue_read`].
+    pub async fn read_block(
+        &mut self,
+        block_info: BlockInfo,
+        info_hash: [u8; 20],
+    ) -> Result<Vec<u8>, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.queue_read(block_info, info_hash, true, tx).await;
+        self.wait_for(rx).await
+    }
+
+    /// A block was sent to a peer, `hit` if it was in the caches.
+    async fn uploaded(&self, info_hash: [u8; 20], block_info: &BlockInfo, hit: bool) {
+        let Some(torrent_ctx) = self.torrent_ctxs.get(&info_hash) else {
+            return;
+        };
+
+        // increment uploaded count
+        let _ = torrent_ctx
+            .tx
+            .send(TorrentMsg::IncrementUploaded(block_info.len as u64))
+            .await;
+
+        let _ = torrent_ctx.tx.send(TorrentMsg::CacheRead(hit)).await;
+    }
+
+    #[tracing::instrument(skip(self, block))]
+    pub async fn write_block(
+        &mut self,
+        block: Block,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+    ) -> Result<(), Error> {
+        let len = block.block.len() as u32;
+        let begin = block.begin;
+        let index = block.index;
+
+        let block_info = BlockInfo {
+            index: index as u32,
+            len,
+            begin,
+        };
+
+        let torrent_downloaded_infos = self
+            .downloaded_infos
+            .get_mut(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?;
+
+        if !torrent_downloaded_infos.insert(block_info, peer_id) {
+            info!("already downloaded, ignoring");
+            return Ok(());
+        }
+
+        // how many bytes of this piece we have, including this block
+        let piece_downloaded = torrent_downloaded_infos.piece_len(index);
+
+        let torrent_ctx = self
+            .torrent_ctxs
+            .get(&info_hash)
+            .ok_or(Error::TorrentDoesNotExist)?;
+
+        let torrent_tx = torrent_ctx.tx.clone();
+
+        let info = torrent_ctx.info.read().await;
+        let piece_size = info.piece_size(index);
+        drop(info);
+
+        // the block is only written to disk when its piece is complete,
+        // the torrent is only complete after its pieces are on the bitfield,
+        // which happens after they are written.
+        self.write_cache.insert(info_hash, block);
+
+        let _ = torrent_tx
+            .send(TorrentMsg::IncrementDownloaded(len as u64))
+            .await;
+
+        if piece_downloaded < piece_size {
+            // under memory pressure, write the
+            // pieces that are not complete yet
+            while self.write_cache.is_full() {
+                let Some((info_hash, index)) = self.write_cache.oldest() else {
+                    break;
+                };
+                self.flush_piece(info_hash, index);
+            }
+
+            return Ok(());
+        }
+
+        // this is the last block of a piece, validate the hash
+        let piece = self.write_cache.take(info_hash, index);
+        let blocks = piece.map(|p| p.blocks).unwrap_or_default();
+        let cached: usize = blocks.values().map(|b| b.len()).sum();
+
+        // if the entire piece is in memory, it is hashed before it is written,
+        // otherwise some blocks were flushed or restored, and it is hashed from disk.
+        let validate = if cached == piece_size as usize {
+            Validate::Memory
+        } else {
+            Validate::Storage
+        };
+
+        // the bitfield is updated when the worker is done, see `Disk::io_done`
+        self.write_piece(info_hash, index, blocks, validate);
+
+        Ok(())
+    }
+
+    /// Return the segment of the file where the block starts, given an
+    /// `index` and `begin`, with the offset of the block in the file. The
+    /// block may not end in the same file, see [`Disk::block_segments`].
+    pub async fn get_file_from_block_info(
+        &self,
+        block_info: &BlockInfo,
+        info_hash: [u8; 20],
+    ) -> Result<(Segment, metainfo::File), Error> {
+        let torrent = self
+            .torrent_ctxs
+            .get(&info_hash)
+            .ok_or(Error::InfoHashInvalid)?;
 
         let info = torrent.info.read().await;
 
-        // find a file on a list of files,
-        // given a piece_index and a piece_len
-        let piece_begin = block_info.index * info.piece_length;
-        let cursor = piece_begin + block_info.begin;
+        // find a file on a list of files,
+        // given a piece_index and a piece_len
+        let piece_begin = block_info.index as u64 * info.piece_length as u64;
+        let cursor = piece_begin + block_info.begin as u64;
+
+        let files = match &info.files {
+            Some(files) => files.clone(),
+            // single file torrent
+            None => vec![metainfo::File {
+                path: vec![info.name.to_owned()],
+                length: info.file_length.unwrap_or(0),
+            }],
+        };
+
+        // the offset of the current file in the torrent
+        let mut file_begin: u64 = 0;
+
+        for (i, file_info) in files.into_iter().enumerate() {
+            let file_end = file_begin + file_info.length;
+
+            if cursor < file_end {
+                let segment = Segment {
+                    file: i,
+                    offset: cursor - file_begin,
+                    len: (block_info.len as u64).min(file_end - cursor) as usize,
+                };
+                return Ok((segment, file_info));
+            }
+
+            file_begin = file_end;
+        }
+
+        Err(Error::BlockInvalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ops::Range, path::Path};
+
+    use bendy::decoding::FromBencode;
+    use rand::{distributions::Alphanumeric, Rng};
+
+    use crate::{
+        bitfield::Bitfield,
+        frontend::FrMsg,
+        metainfo::{self, Info, MetaInfo},
+        peer::PeerStats,
+        storage::MemoryStorage,
+        tcp_wire::lib::{Block, BLOCK_LEN},
+        torrent::Torrent,
+    };
+
+    use super::*;
+    use tokio::{
+        fs,
+        io::AsyncWriteExt,
+        sync::{mpsc, RwLock},
+    };
+
+    // when we send the msg `NewTorrent` the `Disk` must create
+    // the "skeleton" of the torrent tree. Empty folders and empty files.
+    #[tokio::test]
+    async fn create_file_tree() {
+        let name = "arch".to_owned();
+        let magnet = format!("magnet:?xt=urn:btih:9999999999999999999999999999999999999999&amp;dn={name}&amp;tr=udp%3A%2F%2Ftracker.coppersurfer.tk%3A6969%2Fannounce&amp;tr=udp%3A%2F%2Ftracker.openbittorrent.com%3A6969%2Fannounce&amp;tr=udp%3A%2F%2Ftracker.bittor.pw%3A1337%2Fannounce&amp;tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337&amp;tr=udp%3A%2F%2Fbt.xxx-tracker.com%3A2710%2Fannounce&amp;tr=udp%3A%2F%2Fpublic.popcorn-tracker.org%3A6969%2Fannounce&amp;tr=udp%3A%2F%2Feddie4.nl%3A6969%2Fannounce&amp;tr=udp%3A%2F%2Ftracker.torrent.eu.org%3A451%2Fannounce&amp;tr=udp%3A%2F%2Fp4p.arenabg.com%3A1337%2Fannounce&amp;tr=udp%3A%2F%2Ftracker.tiny-vps.com%3A6969%2Fannounce&amp;tr=udp%3A%2F%2Fopen.stealth.si%3A80%2Fannounce");
+        let (disk_tx, disk_rx) = mpsc::channel::<DiskMsg>(1000);
+
+        let (fr_tx, _) = mpsc::channel::<FrMsg>(300);
+        let torrent = Torrent::new(disk_tx.clone(), fr_tx, &magnet);
+        let torrent_ctx = torrent.ctx.clone();
+
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
+
+        let mut disk = Disk::new(disk_rx, download_dir.clone());
+
+        let info = Info {
+            file_length: None,
+            name,
+            piece_length: BLOCK_LEN,
+            pieces: vec![],
+            files: Some(vec![
+                metainfo::File {
+                    length: BLOCK_LEN as u64 * 2,
+                    path: vec!["foo.txt".to_owned()],
+                },
+                metainfo::File {
+                    length: BLOCK_LEN as u64 * 2,
+                    path: vec!["bar".to_owned(), "baz.txt".to_owned()],
+                },
+                metainfo::File {
+                    length: BLOCK_LEN as u64 * 2,
+                    path: vec!["bar".to_owned(), "buzz".to_owned(), "bee.txt".to_owned()],
+                },
+            ]),
+        };
+
+        disk.torrent_ctxs
+            .insert(torrent_ctx.info_hash, torrent_ctx.clone());
+
+        let mut info_ctx = torrent.ctx.info.write().await;
+        *info_ctx = info.clone();
+        drop(info_ctx);
+
+        disk.new_torrent(torrent_ctx.clone()).await.unwrap();
+        disk.wait_io().await;
+
+        let mut path = PathBuf::new();
+        path.push(&download_dir);
+        path.push(&info.name);
+        path.push("foo.txt");
+
+        let result = disk.open_file(path).await;
+        assert!(result.is_ok());
+
+        let mut path = PathBuf::new();
+        path.push(&download_dir);
+        path.push(&info.name);
+        path.push("bar/baz.txt");
+        let result = disk.open_file(path).await;
+        assert!(result.is_ok());
+
+        let mut path = PathBuf::new();
+        path.push(&download_dir);
+        path.push(&info.name);
+        path.push("bar/buzz/bee.txt");
+        let result = disk.open_file(path).await;
+        assert!(result.is_ok());
+
+        assert!(Path::new(&format!("{download_dir}/arch/foo.txt")).is_file());
+        assert!(Path::new(&format!("{download_dir}/arch/bar/baz.txt")).is_file());
+        assert!(Path::new(&format!("{download_dir}/arch/bar/buzz/bee.txt")).is_file());
+
+        tokio::fs::remove_dir_all(download_dir).await.unwrap();
+    }
+
+    // skipped files are not created, until they are not skipped anymore.
+    #[tokio::test]
+    async fn skipped_files_are_not_created() {
+        let name = "skip".to_owned();
+        let magnet =
+            format!("magnet:?xt=urn:btih:9999999999999999999999999999999999999999&amp;dn={name}");
+        let (disk_tx, disk_rx) = mpsc::channel::<DiskMsg>(1000);
+
+        let (fr_tx, _) = mpsc::channel::<FrMsg>(300);
+        let torrent = Torrent::new(disk_tx.clone(), fr_tx, &magnet);
+        let torrent_ctx = torrent.ctx.clone();
+        let info_hash = torrent_ctx.info_hash;
+
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
+
+        let mut disk = Disk::new(disk_rx, download_dir.clone());
+
+        let info = Info {
+            file_length: None,
+            name,
+            piece_length: BLOCK_LEN,
+            pieces: vec![0; 60],
+            files: Some(vec![
+                metainfo::File {
+                    length: BLOCK_LEN as u64,
+                    path: vec!["foo.txt".to_owned()],
+                },
+                metainfo::File {
+                    length: BLOCK_LEN as u64,
+                    path: vec!["bar".to_owned(), "baz.txt".to_owned()],
+                },
+                metainfo::File {
+                    length: BLOCK_LEN as u64,
+                    path: vec!["bee.txt".to_owned()],
+                },
+            ]),
+        };
+
+        *torrent_ctx.info.write().await = info;
+        *torrent_ctx.file_priorities.write().await = vec![Priority::Normal, Priority::Skip];
+
+        disk.new_torrent(torrent_ctx.clone()).await.unwrap();
+        disk.wait_io().await;
+
+        assert!(Path::new(&format!("{download_dir}/skip/foo.txt")).is_file());
+        assert!(Path::new(&format!("{download_dir}/skip/bar")).is_dir());
+        assert!(!Path::new(&format!("{download_dir}/skip/bar/baz.txt")).exists());
+        assert!(Path::new(&format!("{download_dir}/skip/bee.txt")).is_file());
+
+        // the piece of the skipped file is never picked
+        let bitfield = Bitfield::from(vec![0b1110_0000]);
+        let picker = disk.pickers.get_mut(&info_hash).unwrap();
+        let picked = picker.pick(&bitfield, 10);
+        assert!(picked.iter().all(|b| b.index != 1));
+        assert_eq!(picked.len(), 2);
+
+        torrent_ctx.file_priorities.write().await[1] = Priority::High;
+        disk.set_file_priorities(info_hash).await.unwrap();
+        disk.wait_io().await;
+
+        assert!(Path::new(&format!("{download_dir}/skip/bar/baz.txt")).is_file());
+
+        let picked = disk
+            .pickers
+            .get_mut(&info_hash)
+            .unwrap()
+            .pick(&bitfield, 10);
+        assert_eq!(picked.len(), 1);
+        assert_eq!(picked[0].index, 1);
+
+        tokio::fs::remove_dir_all(download_dir).await.unwrap();
+    }
+
+    // the pieces and the unfinished blocks are restored from the resume file,
+    // unless the files of the piece changed since it was written.
+    #[tokio::test]
+    async fn save_and_restore_resume_data() {
+        // a new Disk, as if the program was restarted
+        async fn new_disk(
+            info: &Info,
+            download_dir: &str,
+            resume_dir: &Path,
+        ) -> (Disk, Arc<TorrentCtx>) {
+            let magnet = format!(
+                "magnet:?xt=urn:btih:9999999999999999999999999999999999999999&amp;dn={}",
+                info.name
+            );
+            let (disk_tx, disk_rx) = mpsc::channel::<DiskMsg>(10);
+            let (fr_tx, _) = mpsc::channel::<FrMsg>(10);
+            let torrent = Torrent::new(disk_tx, fr_tx, &magnet);
+            *torrent.ctx.info.write().await = info.clone();
+
+            let mut disk = Disk::new(disk_rx, download_dir.to_owned());
+            disk.resume_dir = Some(resume_dir.to_owned());
+            disk.new_torrent(torrent.ctx.clone()).await.unwrap();
+            disk.wait_io().await;
+
+            (disk, torrent.ctx.clone())
+        }
+
+        let name = "arch".to_owned();
+
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
+        let resume_dir = PathBuf::from(&download_dir).join("state");
+
+        // 3 pieces of 2 blocks, one piece per file
+        let info = Info {
+            file_length: None,
+            name,
+            piece_length: BLOCK_LEN * 2,
+            pieces: vec![0; 60],
+            files: Some(vec![
+                metainfo::File {
+                    length: BLOCK_LEN as u64 * 2,
+                    path: vec!["foo.txt".to_owned()],
+                },
+                metainfo::File {
+                    length: BLOCK_LEN as u64 * 2,
+                    path: vec!["bar.txt".to_owned()],
+                },
+                metainfo::File {
+                    length: BLOCK_LEN as u64 * 2,
+                    path: vec!["baz.txt".to_owned()],
+                },
+            ]),
+        };
+
+        let block = |index, begin| BlockInfo {
+            index,
+            begin,
+            len: BLOCK_LEN,
+        };
+
+        let (mut disk, torrent_ctx) = new_disk(&info, &download_dir, &resume_dir).await;
+        let info_hash = torrent_ctx.info_hash;
+
+        // we have the first piece, and the first block of the second piece
+        torrent_ctx.pieces.write().await.set(0);
+        let downloaded_infos = disk.downloaded_infos.get_mut(&info_hash).unwrap();
+        for b in [block(0, 0), block(0, BLOCK_LEN), block(1, 0)] {
+            downloaded_infos.insert(b, [0; 20]);
+        }
+
+        disk.save_resume_data(ResumeData {
+            info_hash,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        disk.wait_io().await;
+
+        let path = ResumeData::path(&resume_dir, info_hash);
+        let data = ResumeData::from_bencode(&fs::read(&path).await.unwrap()).unwrap();
+
+        assert_eq!(data.unfinished, vec![block(1, 0)]);
+        assert_eq!(data.save_path, download_dir);
+        assert_eq!(data.file_sizes.len(), 3);
+
+        let (mut disk, torrent_ctx) = new_disk(&info, &download_dir, &resume_dir).await;
+        assert_eq!(disk.restore_resume_data(data.clone()).await.unwrap(), 1);
+
+        assert!(torrent_ctx.pieces.read().await.has(0_usize));
+        let picker = disk.pickers.get(&info_hash).unwrap();
+        assert_eq!(picker.free_blocks(0), 0);
+        assert_eq!(picker.free_blocks(1), 1);
+        assert_eq!(picker.free_blocks(2), 2);
+
+        // the second file changed, its block is downloaded again
+        fs::write(format!("{download_dir}/arch/bar.txt"), b"changed")
+            .await
+            .unwrap();
+
+        let (mut disk, torrent_ctx) = new_disk(&info, &download_dir, &resume_dir).await;
+        assert_eq!(disk.restore_resume_data(data).await.unwrap(), 1);
+
+        assert!(torrent_ctx.pieces.read().await.has(0_usize));
+        let picker = disk.pickers.get(&info_hash).unwrap();
+        assert_eq!(picker.free_blocks(0), 0);
+        assert_eq!(picker.free_blocks(1), 2);
+
+        tokio::fs::remove_dir_all(download_dir).await.unwrap();
+    }
+
+    // only the pieces on disk that have a valid hash are set
+    #[tokio::test]
+    async fn check_pieces_on_disk() {
+        // the content of all files, each piece has 6 bytes
+        let content: Vec<u8> = (1..=36).collect();
+        let pieces: Vec<u8> = content
+            .chunks(6)
+            .flat_map(|piece| {
+                let mut hash = sha1_smol::Sha1::new();
+                hash.update(piece);
+                hash.digest().bytes()
+            })
+            .collect();
+
+        let info = Info {
+            file_length: None,
+            name: "arch".to_owned(),
+            piece_length: 6,
+            pieces,
+            files: Some(vec![
+                metainfo::File {
+                    length: 12,
+                    path: vec!["foo.txt".to_owned()],
+                },
+                metainfo::File {
+                    length: 12,
+                    path: vec!["bar.txt".to_owned()],
+                },
+                metainfo::File {
+                    length: 12,
+                    path: vec!["baz.txt".to_owned()],
+                },
+            ]),
+        };
+
+        let magnet = "magnet:?xt=urn:btih:9999999999999999999999999999999999999999&amp;dn=arch";
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
+
+        let (disk_tx, disk_rx) = mpsc::channel::<DiskMsg>(10);
+        let (fr_tx, _) = mpsc::channel::<FrMsg>(10);
+        let mut torrent = Torrent::new(disk_tx, fr_tx, magnet);
+        *torrent.ctx.info.write().await = info;
+
+        let mut disk = Disk::new(disk_rx, download_dir.clone());
+        disk.new_torrent(torrent.ctx.clone()).await.unwrap();
+        disk.wait_io().await;
+
+        // the first file is complete, the second has
+        // an invalid piece, and the last one is empty.
+        fs::write(format!("{download_dir}/arch/foo.txt"), &content[..12])
+            .await
+            .unwrap();
+        let mut bar = content[12..24].to_vec();
+        bar[0] = 0;
+        fs::write(format!("{download_dir}/arch/bar.txt"), bar)
+            .await
+            .unwrap();
+
+        let info_hash = torrent.ctx.info_hash;
+        disk.check(info_hash).await.unwrap();
+        disk.wait_io().await;
+
+        let pieces = torrent.ctx.pieces.read().await;
+        assert_eq!(
+            (0..6).filter(|i| pieces.has(*i)).collect::<Vec<usize>>(),
+            vec![0, 1, 3]
+        );
+        drop(pieces);
+
+        let picker = disk.pickers.get(&info_hash).unwrap();
+        assert_eq!(picker.len(), 3);
+        assert_eq!(picker.free_blocks(2), 1);
+
+        // the torrent is told about the progress
+        let mut progress = 0;
+        while let Ok(msg) = torrent.rx.try_recv() {
+            match msg {
+                TorrentMsg::CheckProgress(checked) => progress = checked,
+                TorrentMsg::CheckComplete => break,
+                _ => {}
+            }
+        }
+        assert_eq!(progress, 6);
+
+        tokio::fs::remove_dir_all(download_dir).await.unwrap();
+    }
+
+    // blocks are only written to disk when their piece is complete and valid,
+    // or when the cache is full.
+    #[tokio::test]
+    async fn write_cache_flushes_whole_pieces() {
+        // the first piece has 8 bytes, in both files
+        let content: Vec<u8> = (1..=12).collect();
+        let pieces: Vec<u8> = content
+            .chunks(8)
+            .flat_map(|piece| {
+                let mut hash = sha1_smol::Sha1::new();
+                hash.update(piece);
+                hash.digest().bytes()
+            })
+            .collect();
+
+        let info = Info {
+            file_length: None,
+            name: "arch".to_owned(),
+            piece_length: 8,
+            pieces,
+            files: Some(vec![
+                metainfo::File {
+                    length: 6,
+                    path: vec!["foo.txt".to_owned()],
+                },
+                metainfo::File {
+                    length: 6,
+                    path: vec!["bar.txt".to_owned()],
+                },
+            ]),
+        };
+
+        let magnet = "magnet:?xt=urn:btih:9999999999999999999999999999999999999999&amp;dn=arch";
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
+
+        let (disk_tx, disk_rx) = mpsc::channel::<DiskMsg>(10);
+        let (fr_tx, _) = mpsc::channel::<FrMsg>(10);
+        let torrent = Torrent::new(disk_tx, fr_tx, magnet);
+        *torrent.ctx.info.write().await = info;
+        let info_hash = torrent.ctx.info_hash;
+
+        let mut disk = Disk::new(disk_rx, download_dir.clone());
+        disk.new_torrent(torrent.ctx.clone()).await.unwrap();
+        disk.wait_io().await;
+
+        let foo = format!("{download_dir}/arch/foo.txt");
+        let bar = format!("{download_dir}/arch/bar.txt");
+
+        let block = |index: usize, begin: u32, range: Range<usize>| Block {
+            index,
+            begin,
+            block: content[range].to_vec(),
+        };
+
+        disk.write_block(block(0, 0, 0..4), info_hash, [0; 20])
+            .await
+            .unwrap();
+
+        // the block is only in memory
+        assert!(fs::read(&foo).await.unwrap().is_empty());
+        let b = BlockInfo {
+            index: 0,
+            begin: 1,
+            len: 2,
+        };
+        assert_eq!(disk.read_block(b, info_hash).await.unwrap(), vec![2, 3]);
+
+        disk.write_block(block(0, 4, 4..8), info_hash, [0; 20])
+            .await
+            .unwrap();
+        disk.wait_io().await;
+
+        assert_eq!(fs::read(&foo).await.unwrap(), content[..6]);
+        assert_eq!(fs::read(&bar).await.unwrap(), content[6..8]);
+        assert!(torrent.ctx.pieces.read().await.has(0_usize));
+        assert_eq!(disk.write_cache.size(), 0);
+
+        // the cache is full, the piece is written before it is complete
+        disk.write_cache.capacity = 1;
+
+        disk.write_block(block(1, 0, 8..10), info_hash, [0; 20])
+            .await
+            .unwrap();
+        disk.wait_io().await;
+
+        assert_eq!(fs::read(&bar).await.unwrap(), content[6..10]);
+        assert!(!torrent.ctx.pieces.read().await.has(1_usize));
+
+        // and it is hashed from disk
+        disk.write_block(block(1, 2, 10..12), info_hash, [0; 20])
+            .await
+            .unwrap();
+        disk.wait_io().await;
+
+        assert_eq!(fs::read(&bar).await.unwrap(), content[6..]);
+        assert!(torrent.ctx.pieces.read().await.has(1_usize));
+
+        tokio::fs::remove_dir_all(download_dir).await.unwrap();
+    }
+
+    // the whole piece is read on the first read of one of
+    // its blocks, and the next blocks are read from memory.
+    #[tokio::test]
+    async fn read_cache_reads_whole_pieces() {
+        let info = Info {
+            file_length: None,
+            name: "arch".to_owned(),
+            piece_length: 8,
+            pieces: vec![0; 40],
+            files: Some(vec![
+                metainfo::File {
+                    length: 6,
+                    path: vec!["foo.txt".to_owned()],
+                },
+                metainfo::File {
+                    length: 6,
+                    path: vec!["bar.txt".to_owned()],
+                },
+            ]),
+        };
+
+        let magnet = "magnet:?xt=urn:btih:9999999999999999999999999999999999999999&amp;dn=arch";
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
+
+        let (disk_tx, disk_rx) = mpsc::channel::<DiskMsg>(10);
+        let (fr_tx, _) = mpsc::channel::<FrMsg>(10);
+        let mut torrent = Torrent::new(disk_tx, fr_tx, magnet);
+        *torrent.ctx.info.write().await = info;
+        let info_hash = torrent.ctx.info_hash;
+
+        let mut disk = Disk::new(disk_rx, download_dir.clone());
+        disk.new_torrent(torrent.ctx.clone()).await.unwrap();
+        disk.wait_io().await;
+
+        fs::write(format!("{download_dir}/arch/foo.txt"), [1, 2, 3, 4, 5, 6])
+            .await
+            .unwrap();
+        fs::write(
+            format!("{download_dir}/arch/bar.txt"),
+            [7, 8, 9, 10, 11, 12],
+        )
+        .await
+        .unwrap();
+        torrent.ctx.pieces.write().await.set(0);
+
+        let block = |begin| BlockInfo {
+            index: 0,
+            begin,
+            len: 4,
+        };
+
+        assert_eq!(
+            disk.read_block(block(0), info_hash).await.unwrap(),
+            vec![1, 2, 3, 4]
+        );
+        // the piece is cached when the worker is done with the read
+        disk.wait_io().await;
+        assert_eq!(disk.read_cache.size(), 8);
+
+        // the piece is in memory, even if the files change
+        fs::write(format!("{download_dir}/arch/bar.txt"), [0; 6])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            disk.read_block(block(4), info_hash).await.unwrap(),
+            vec![5, 6, 7, 8]
+        );
+
+        let mut reads = Vec::new();
+        while let Ok(msg) = torrent.rx.try_recv() {
+            if let TorrentMsg::CacheRead(hit) = msg {
+                reads.push(hit);
+            }
+        }
+        assert_eq!(reads, vec![false, true]);
+
+        tokio::fs::remove_dir_all(download_dir).await.unwrap();
+    }
+
+    // the blocks in memory are written before the torrent is removed
+    #[tokio::test]
+    async fn remove_torrent() {
+        let info = Info {
+            file_length: Some(8),
+            name: "arch.iso".to_owned(),
+            piece_length: 8,
+            pieces: vec![0; 20],
+            files: None,
+        };
+
+        let magnet = "magnet:?xt=urn:btih:9999999999999999999999999999999999999999&amp;dn=arch";
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
+        fs::create_dir_all(&download_dir).await.unwrap();
+
+        let (disk_tx, disk_rx) = mpsc::channel::<DiskMsg>(10);
+        let (fr_tx, _) = mpsc::channel::<FrMsg>(10);
+        let torrent = Torrent::new(disk_tx, fr_tx, magnet);
+        *torrent.ctx.info.write().await = info;
+        let info_hash = torrent.ctx.info_hash;
+
+        let mut disk = Disk::new(disk_rx, download_dir.clone());
+        disk.new_torrent(torrent.ctx.clone()).await.unwrap();
+        disk.wait_io().await;
 
-        // multi file torrent
-        if let Some(files) = &info.files {
-            let mut file_begin: u64 = 0;
-            let mut file_end: u64 = 0;
+        let block = Block {
+            index: 0,
+            begin: 0,
+            block: vec![1, 2, 3, 4],
+        };
+        disk.write_block(block, info_hash, [0; 20]).await.unwrap();
+        disk.flush_torrent(info_hash);
+
+        let block = Block {
+            index: 0,
+            begin: 4,
+            block: vec![5, 6, 7, 8],
+        };
+        disk.write_cache.insert(info_hash, block);
+
+        // the torrent waits until its blocks are written
+        disk.remove_torrent(info_hash).await;
+        assert!(disk.parked.contains_key(&info_hash));
+        disk.wait_io().await;
+
+        assert!(disk.torrent_ctxs.get(&info_hash).is_none());
+        assert_eq!(
+            fs::read(format!("{download_dir}/arch.iso")).await.unwrap(),
+            vec![1, 2, 3, 4, 5, 6, 7, 8]
+        );
+
+        tokio::fs::remove_dir_all(download_dir).await.unwrap();
+    }
+
+    // a torrent that does not fit on disk is not added
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn not_enough_space() {
+        let info = Info {
+            file_length: Some(u64::MAX / 2),
+            name: "arch.iso".to_owned(),
+            piece_length: BLOCK_LEN,
+            pieces: vec![],
+            files: None,
+        };
+
+        let magnet = "magnet:?xt=urn:btih:9999999999999999999999999999999999999999&amp;dn=arch";
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
+
+        let (disk_tx, disk_rx) = mpsc::channel::<DiskMsg>(10);
+        let (fr_tx, _) = mpsc::channel::<FrMsg>(10);
+        let mut torrent = Torrent::new(disk_tx, fr_tx, magnet);
+        *torrent.ctx.info.write().await = info;
+        let info_hash = torrent.ctx.info_hash;
+
+        let mut disk = Disk::new(disk_rx, download_dir.clone());
+
+        disk.new_torrent(torrent.ctx.clone()).await.unwrap();
+        disk.wait_io().await;
+
+        assert!(disk.torrent_ctxs.get(&info_hash).is_none());
+        assert!(disk.pickers.get(&info_hash).is_none());
+        assert!(!Path::new(&download_dir).exists());
+
+        let mut reason = None;
+        while let Ok(msg) = torrent.rx.try_recv() {
+            if let TorrentMsg::DiskError(r) = msg {
+                reason = Some(r);
+            }
+        }
+        assert!(reason.unwrap().contains("not enough free space"));
+    }
+
+    // a block that can't be written is downloaded again, and
+    // the torrent is told about the error.
+    #[tokio::test]
+    async fn disk_errors_are_sent_to_the_torrent() {
+        let content: Vec<u8> = (1..=12).collect();
+        let pieces: Vec<u8> = content
+            .chunks(8)
+            .flat_map(|piece| {
+                let mut hash = sha1_smol::Sha1::new();
+                hash.update(piece);
+                hash.digest().bytes()
+            })
+            .collect();
+
+        let info = Info {
+            file_length: None,
+            name: "arch".to_owned(),
+            piece_length: 8,
+            pieces,
+            files: Some(vec![
+                metainfo::File {
+                    length: 6,
+                    path: vec!["foo.txt".to_owned()],
+                },
+                metainfo::File {
+                    length: 6,
+                    path: vec!["bar.txt".to_owned()],
+                },
+            ]),
+        };
+
+        let magnet = "magnet:?xt=urn:btih:9999999999999999999999999999999999999999&amp;dn=arch";
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
+
+        let (disk_tx, disk_rx) = mpsc::channel::<DiskMsg>(10);
+        let (fr_tx, _) = mpsc::channel::<FrMsg>(10);
+        let mut torrent = Torrent::new(disk_tx, fr_tx, magnet);
+        *torrent.ctx.info.write().await = info;
+        let info_hash = torrent.ctx.info_hash;
+
+        let mut disk = Disk::new(disk_rx, download_dir.clone());
+        disk.new_torrent(torrent.ctx.clone()).await.unwrap();
+        disk.wait_io().await;
+
+        // the directory of the torrent is not usable anymore
+        let dir = format!("{download_dir}/arch");
+        fs::remove_dir_all(&dir).await.unwrap();
+        fs::write(&dir, b"").await.unwrap();
+
+        let block = Block {
+            index: 0,
+            begin: 0,
+            block: content[..8].to_vec(),
+        };
+
+        disk.write_block(block.clone(), info_hash, [0; 20])
+            .await
+            .unwrap();
+        disk.wait_io().await;
+
+        assert!(disk.downloaded_infos[&info_hash].blocks().next().is_none());
+        assert!(!torrent.ctx.pieces.read().await.has(0_usize));
 
-            let file_info = files.iter().enumerate().find(|(_, f)| {
-                file_end += f.length as u64;
+        let mut reason = None;
+        while let Ok(msg) = torrent.rx.try_recv() {
+            if let TorrentMsg::DiskError(r) = msg {
+                reason = Some(r);
+            }
+        }
+        assert!(reason.is_some());
+
+        // the problem is fixed, and the torrent is resumed
+        fs::remove_file(&dir).await.unwrap();
+        disk.new_torrent(torrent.ctx.clone()).await.unwrap();
+        disk.wait_io().await;
+
+        disk.write_block(block, info_hash, [0; 20]).await.unwrap();
+        disk.wait_io().await;
+        assert!(torrent.ctx.pieces.read().await.has(0_usize));
+
+        tokio::fs::remove_dir_all(download_dir).await.unwrap();
+    }
+
+    // a torrent is saved in its own save path, and its files
+    // can be moved while the torrent is seeding.
+    #[tokio::test]
+    async fn save_path_and_move_storage() {
+        let content: Vec<u8> = (1..=12).collect();
+        let pieces: Vec<u8> = content
+            .chunks(8)
+            .flat_map(|piece| {
+                let mut hash = sha1_smol::Sha1::new();
+                hash.update(piece);
+                hash.digest().bytes()
+            })
+            .collect();
+
+        let info = Info {
+            file_length: None,
+            name: "arch".to_owned(),
+            piece_length: 8,
+            pieces,
+            files: Some(vec![
+                metainfo::File {
+                    length: 6,
+                    path: vec!["foo.txt".to_owned()],
+                },
+                metainfo::File {
+                    length: 6,
+                    path: vec!["bar.txt".to_owned()],
+                },
+            ]),
+        };
+
+        let magnet = "magnet:?xt=urn:btih:9999999999999999999999999999999999999999&amp;dn=arch";
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
+        let save_path = format!("{download_dir}/saved");
+        let moved = format!("{download_dir}/moved");
+
+        let (disk_tx, disk_rx) = mpsc::channel::<DiskMsg>(10);
+        let (fr_tx, _) = mpsc::channel::<FrMsg>(10);
+        let torrent = Torrent::new(disk_tx, fr_tx, magnet);
+        *torrent.ctx.info.write().await = info;
+        *torrent.ctx.save_path.write().await = Some(save_path.clone());
+        let info_hash = torrent.ctx.info_hash;
+
+        let mut disk = Disk::new(disk_rx, download_dir.clone());
+        disk.new_torrent(torrent.ctx.clone()).await.unwrap();
+        disk.wait_io().await;
+        assert!(Path::new(&format!("{save_path}/arch/foo.txt")).exists());
+
+        disk.write_block(
+            Block {
+                index: 0,
+                begin: 0,
+                block: content[..8].to_vec(),
+            },
+            info_hash,
+            [0; 20],
+        )
+        .await
+        .unwrap();
+
+        disk.move_storage(torrent.ctx.clone(), moved.clone()).await;
+
+        // the files are being moved, the reads of the torrent wait
+        assert!(disk.parked.contains_key(&info_hash));
+        disk.wait_io().await;
+        assert!(disk.parked.is_empty());
+
+        assert_eq!(disk.save_path(info_hash).await, moved);
+        assert!(!Path::new(&format!("{save_path}/arch")).exists());
+        assert_eq!(
+            fs::read(format!("{moved}/arch/foo.txt")).await.unwrap(),
+            content[..6]
+        );
+
+        // the torrent keeps seeding from the new path
+        let b = BlockInfo {
+            index: 0,
+            begin: 2,
+            len: 6,
+        };
+        assert_eq!(disk.read_block(b, info_hash).await.unwrap(), content[2..8]);
+
+        tokio::fs::remove_dir_all(download_dir).await.unwrap();
+    }
+
+    // a torrent is downloaded in the incomplete dir, with the `.part` suffix,
+    // and its files are moved to its save path when the download is complete.
+    #[tokio::test]
+    async fn incomplete_dir_and_part_files() {
+        let content: Vec<u8> = (1..=12).collect();
+        let pieces: Vec<u8> = content
+            .chunks(8)
+            .flat_map(|piece| {
+                let mut hash = sha1_smol::Sha1::new();
+                hash.update(piece);
+                hash.digest().bytes()
+            })
+            .collect();
+
+        let info = Info {
+            file_length: None,
+            name: "arch".to_owned(),
+            piece_length: 8,
+            pieces,
+            files: Some(vec![
+                metainfo::File {
+                    length: 6,
+                    path: vec!["foo.txt".to_owned()],
+                },
+                metainfo::File {
+                    length: 6,
+                    path: vec!["bar.txt".to_owned()],
+                },
+            ]),
+        };
+
+        let magnet = "magnet:?xt=urn:btih:9999999999999999999999999999999999999999&amp;dn=arch";
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
+        let incomplete_dir = format!("{download_dir}/incomplete");
+        let save_path = format!("{download_dir}/complete");
+
+        let (disk_tx, disk_rx) = mpsc::channel::<DiskMsg>(10);
+        let (fr_tx, _) = mpsc::channel::<FrMsg>(10);
+        let torrent = Torrent::new(disk_tx, fr_tx, magnet);
+        *torrent.ctx.info.write().await = info;
+        *torrent.ctx.incomplete_dir.write().await = Some(incomplete_dir.clone());
+        *torrent.ctx.part_files.write().await = true;
+        let info_hash = torrent.ctx.info_hash;
+
+        let mut disk = Disk::new(disk_rx, download_dir.clone());
+        disk.new_torrent(torrent.ctx.clone()).await.unwrap();
+        disk.wait_io().await;
+
+        assert_eq!(disk.location(info_hash).await, incomplete_dir);
+        assert!(Path::new(&format!("{incomplete_dir}/arch/foo.txt.part")).exists());
+        assert!(Path::new(&format!("{incomplete_dir}/arch/bar.txt.part")).exists());
+
+        for (index, piece) in content.chunks(8).enumerate() {
+            disk.write_block(
+                Block {
+                    index,
+                    begin: 0,
+                    block: piece.to_vec(),
+                },
+                info_hash,
+                [0; 20],
+            )
+            .await
+            .unwrap();
+        }
+        disk.wait_io().await;
 
-                let r = (cursor as u64 + 1) <= file_end;
+        // the save path changes, but the files stay in the incomplete dir
+        disk.move_storage(torrent.ctx.clone(), save_path.clone())
+            .await;
+        assert!(disk.parked.is_empty());
+        assert_eq!(disk.save_path(info_hash).await, save_path);
+        assert_eq!(disk.location(info_hash).await, incomplete_dir);
+
+        disk.complete_storage(torrent.ctx.clone()).await;
+        disk.wait_io().await;
+
+        assert_eq!(disk.location(info_hash).await, save_path);
+        assert!(torrent.ctx.incomplete_dir.read().await.is_none());
+        assert!(!*torrent.ctx.part_files.read().await);
+        assert!(!Path::new(&format!("{incomplete_dir}/arch")).exists());
+        assert_eq!(
+            fs::read(format!("{save_path}/arch/foo.txt")).await.unwrap(),
+            content[..6]
+        );
+        assert_eq!(
+            fs::read(format!("{save_path}/arch/bar.txt")).await.unwrap(),
+            content[6..]
+        );
+
+        // the torrent keeps seeding from the save path
+        let b = BlockInfo {
+            index: 1,
+            begin: 0,
+            len: 4,
+        };
+        assert_eq!(disk.read_block(b, info_hash).await.unwrap(), content[8..]);
 
-                if !r {
-                    file_begin += file_end;
-                }
-                r
-            });
-            if file_info.is_none() {
-                return Err(Error::FileOpenError("".to_owned()));
-            }
-            let (_, file_info) = file_info.unwrap();
+        tokio::fs::remove_dir_all(download_dir).await.unwrap();
+    }
 
-            let mut path = PathBuf::new();
-            path.push(&self.download_dir);
-            path.push(&info.name);
-            for p in &file_info.path {
-                path.push(p);
-            }
+    // the Disk works the same on a storage that is not on disk
+    #[tokio::test]
+    async fn memory_storage() {
+        let content: Vec<u8> = (1..=12).collect();
+        let pieces: Vec<u8> = content
+            .chunks(8)
+            .flat_map(|piece| {
+                let mut hash = sha1_smol::Sha1::new();
+                hash.update(piece);
+                hash.digest().bytes()
+            })
+            .collect();
 
-            let mut file = self.open_file(&path).await?;
+        let info = Info {
+            file_length: None,
+            name: "arch".to_owned(),
+            piece_length: 8,
+            pieces,
+            files: Some(vec![
+                metainfo::File {
+                    length: 6,
+                    path: vec!["foo.txt".to_owned()],
+                },
+                metainfo::File {
+                    length: 6,
+                    path: vec!["bar.txt".to_owned()],
+                },
+            ]),
+        };
 
-            let is_first_file = file_begin == 0;
+        let magnet = "magnet:?xt=urn:btih:9999999999999999999999999999999999999999&amp;dn=arch";
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
 
-            let offset = if is_first_file {
-                piece_begin as u64 + block_info.begin as u64
-            } else {
-                let a = file_end - file_info.length as u64;
-                cursor as u64 - a
-            };
+        let (disk_tx, disk_rx) = mpsc::channel::<DiskMsg>(10);
+        let (fr_tx, _) = mpsc::channel::<FrMsg>(10);
+        let torrent = Torrent::new(disk_tx, fr_tx, magnet);
+        *torrent.ctx.info.write().await = info;
+        let info_hash = torrent.ctx.info_hash;
 
-            file.seek(SeekFrom::Start(offset)).await?;
+        let mut disk = Disk::new(disk_rx, download_dir.clone());
+        disk.storage = Arc::new(MemoryStorage::default());
+        disk.new_torrent(torrent.ctx.clone()).await.unwrap();
+        disk.wait_io().await;
 
-            return Ok((file, file_info.clone()));
-        }
+        let block = |index: usize, begin: u32, range: Range<usize>| Block {
+            index,
+            begin,
+            block: content[range].to_vec(),
+        };
 
-        let mut path = PathBuf::new();
-        path.push(&self.download_dir);
-        path.push(&info.name);
+        // hashed from memory
+        disk.write_block(block(0, 0, 0..8), info_hash, [0; 20])
+            .await
+            .unwrap();
+        disk.wait_io().await;
+        assert!(torrent.ctx.pieces.read().await.has(0_usize));
 
-        // single file torrent
-        let mut file = self.open_file(path).await?;
-        file.seek(SeekFrom::Start(
-            piece_begin as u64 + block_info.begin as u64,
-        ))
-        .await
-        .unwrap();
+        // hashed from the storage
+        disk.write_cache.capacity = 0;
+        disk.write_block(block(1, 0, 8..10), info_hash, [0; 20])
+            .await
+            .unwrap();
+        disk.write_block(block(1, 2, 10..12), info_hash, [0; 20])
+            .await
+            .unwrap();
+        disk.wait_io().await;
+        assert!(torrent.ctx.pieces.read().await.has(1_usize));
 
-        let file_info = metainfo::File {
-            path: vec![info.name.to_owned()],
-            length: info.file_length.unwrap(),
+        let b = BlockInfo {
+            index: 0,
+            begin: 4,
+            len: 8,
         };
+        assert_eq!(disk.read_block(b, info_hash).await.unwrap(), content[4..]);
 
-        Ok((file, file_info))
+        // nothing was written to disk
+        assert!(!Path::new(&download_dir).exists());
     }
 
-    pub async fn get_block_from_block_info(
-        &self,
-        block_info: &BlockInfo,
-        info_hash: [u8; 20],
-    ) -> Result<Block, Error> {
-        let mut file = self.get_file_from_block_info(block_info, info_hash).await?;
+    // the Disk does not wait for a peer whose channel is full,
+    // the peer could be waiting for an answer of the Disk.
+    #[tokio::test]
+    async fn invalid_piece_of_a_busy_peer() {
+        let content: Vec<u8> = (1..=8).collect();
+        let mut hash = sha1_smol::Sha1::new();
+        hash.update(&content);
+
+        let info = Info {
+            file_length: Some(8),
+            name: "arch".to_owned(),
+            piece_length: 8,
+            pieces: hash.digest().bytes().to_vec(),
+            files: None,
+        };
 
-        let mut buf = vec![0; block_info.len as usize];
+        let magnet = "magnet:?xt=urn:btih:9999999999999999999999999999999999999999&amp;dn=arch";
+        let (disk_tx, disk_rx) = mpsc::channel::<DiskMsg>(10);
+        let (fr_tx, _) = mpsc::channel::<FrMsg>(10);
+        let torrent = Torrent::new(disk_tx, fr_tx, magnet);
+        *torrent.ctx.info.write().await = info;
+        let info_hash = torrent.ctx.info_hash;
 
-        file.0.read_exact(&mut buf).await.unwrap();
+        let mut disk = Disk::new(disk_rx, "unused".to_owned());
+        disk.storage = Arc::new(MemoryStorage::default());
+        disk.new_torrent(torrent.ctx.clone()).await.unwrap();
+        disk.wait_io().await;
+
+        let (peer_tx, mut peer_rx) = mpsc::channel(1);
+        peer_tx.try_send(PeerMsg::Choke).unwrap();
+        disk.peer_ctxs.insert(
+            [1; 20],
+            Arc::new(PeerCtx {
+                tx: peer_tx,
+                pieces: RwLock::new(Bitfield::default()),
+                id: RwLock::new(Some([1; 20])),
+                addr: "127.0.0.1:6881".parse().unwrap(),
+                stats: RwLock::new(PeerStats::default()),
+            }),
+        );
 
         let block = Block {
-            index: block_info.index as usize,
-            begin: block_info.begin,
-            block: buf,
+            index: 0,
+            begin: 0,
+            block: vec![0; 8],
         };
+        disk.write_block(block, info_hash, [1; 20]).await.unwrap();
+        disk.wait_io().await;
 
-        Ok(block)
+        // the piece is downloaded again, the peer only has the old message
+        assert_eq!(disk.pickers[&info_hash].free_blocks(0), 1);
+        assert!(matches!(peer_rx.try_recv(), Ok(PeerMsg::Choke)));
+        assert!(peer_rx.try_recv().is_err());
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::path::Path;
-
-    use rand::{distributions::Alphanumeric, Rng};
-
-    use crate::{
-        bitfield::Bitfield,
-        frontend::FrMsg,
-        metainfo::{self, Info},
-        tcp_wire::lib::{Block, BLOCK_LEN},
-        torrent::Torrent,
-    };
-
-    use super::*;
-    use tokio::{fs, sync::mpsc};
 
-    // when we send the msg `NewTorrent` the `Disk` must create
-    // the "skeleton" of the torrent tree. Empty folders and empty files.
+    // a block that ends in the next file is split, and
+    // each part is written to and read from its file.
     #[tokio::test]
-    async fn create_file_tree() {
-        let name = "arch".to_owned();
-        let magnet = format!("magnet:?xt=urn:btih:9999999999999999999999999999999999999999&amp;dn={name}&amp;tr=udp%3A%2F%2Ftracker.coppersurfer.tk%3A6969%2Fannounce&amp;tr=udp%3A%2F%2Ftracker.openbittorrent.com%3A6969%2Fannounce&amp;tr=udp%3A%2F%2Ftracker.bittor.pw%3A1337%2Fannounce&amp;tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337&amp;tr=udp%3A%2F%2Fbt.xxx-tracker.com%3A2710%2Fannounce&amp;tr=udp%3A%2F%2Fpublic.popcorn-tracker.org%3A6969%2Fannounce&amp;tr=udp%3A%2F%2Feddie4.nl%3A6969%2Fannounce&amp;tr=udp%3A%2F%2Ftracker.torrent.eu.org%3A451%2Fannounce&amp;tr=udp%3A%2F%2Fp4p.arenabg.com%3A1337%2Fannounce&amp;tr=udp%3A%2F%2Ftracker.tiny-vps.com%3A6969%2Fannounce&amp;tr=udp%3A%2F%2Fopen.stealth.si%3A80%2Fannounce");
-        let (disk_tx, disk_rx) = mpsc::channel::<DiskMsg>(1000);
-
-        let (fr_tx, _) = mpsc::channel::<FrMsg>(300);
-        let torrent = Torrent::new(disk_tx.clone(), fr_tx, &magnet);
-        let torrent_ctx = torrent.ctx.clone();
+    async fn read_write_blocks_across_files() {
+        let metainfo = include_bytes!("../test-files/music.torrent");
+        let info = MetaInfo::from_bencode(metainfo).unwrap().info;
+        let files = info.files.clone().unwrap();
 
+        let magnet = "magnet:?xt=urn:btih:9999999999999999999999999999999999999999&amp;dn=music";
         let mut rng = rand::thread_rng();
         let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
 
+        let (disk_tx, disk_rx) = mpsc::channel::<DiskMsg>(10);
+        let (fr_tx, _) = mpsc::channel::<FrMsg>(10);
+        let torrent = Torrent::new(disk_tx, fr_tx, magnet);
+        *torrent.ctx.info.write().await = info.clone();
+        let info_hash = torrent.ctx.info_hash;
+
         let mut disk = Disk::new(disk_rx, download_dir.clone());
+        disk.new_torrent(torrent.ctx.clone()).await.unwrap();
+        disk.wait_io().await;
 
-        let info = Info {
-            file_length: None,
-            name,
-            piece_length: BLOCK_LEN,
-            pieces: vec![],
-            files: Some(vec![
-                metainfo::File {
-                    length: BLOCK_LEN * 2,
-                    path: vec!["foo.txt".to_owned()],
-                },
-                metainfo::File {
-                    length: BLOCK_LEN * 2,
-                    path: vec!["bar".to_owned(), "baz.txt".to_owned()],
-                },
-                metainfo::File {
-                    length: BLOCK_LEN * 2,
-                    path: vec!["bar".to_owned(), "buzz".to_owned(), "bee.txt".to_owned()],
-                },
-            ]),
-        };
+        // write the blocks to disk immediately
+        disk.write_cache.capacity = 0;
 
-        disk.torrent_ctxs
-            .insert(torrent_ctx.info_hash, torrent_ctx.clone());
+        let paths = FsStorage::new(download_dir.clone()).file_paths(info_hash, &info);
+        let last = files.len() - 1;
 
-        let mut info_ctx = torrent.ctx.info.write().await;
-        *info_ctx = info.clone();
-        drop(info_ctx);
+        // the first file ends 5920 bytes into the block
+        let block_info = BlockInfo {
+            index: 25,
+            begin: 163840,
+            len: BLOCK_LEN,
+        };
 
-        disk.new_torrent(torrent_ctx.clone()).await.unwrap();
+        assert_eq!(
+            disk.block_segments(&block_info, info_hash).await.unwrap(),
+            vec![
+                Segment {
+                    file: 0,
+                    offset: files[0].length - 5920,
+                    len: 5920,
+                },
+                Segment {
+                    file: 1,
+                    offset: 0,
+                    len: 10464,
+                },
+            ]
+        );
+
+        let block: Vec<u8> = (0..BLOCK_LEN).map(|i| i as u8).collect();
+        disk.write_block(
+            Block {
+                index: 25,
+                begin: 163840,
+                block: block.clone(),
+            },
+            info_hash,
+            [0; 20],
+        )
+        .await
+        .unwrap();
+        disk.wait_io().await;
 
-        let mut path = PathBuf::new();
-        path.push(&download_dir);
-        path.push(&info.name);
-        path.push("foo.txt");
+        let first = fs::read(&paths[0]).await.unwrap();
+        assert_eq!(first.len() as u64, files[0].length);
+        assert_eq!(first[first.len() - 5920..], block[..5920]);
+        assert_eq!(fs::read(&paths[1]).await.unwrap(), block[5920..]);
 
-        let result = disk.open_file(path).await;
-        assert!(result.is_ok());
+        assert_eq!(disk.read_block(block_info, info_hash).await.unwrap(), block);
 
-        let mut path = PathBuf::new();
-        path.push(&download_dir);
-        path.push(&info.name);
-        path.push("bar/baz.txt");
-        let result = disk.open_file(path).await;
-        assert!(result.is_ok());
+        // the last block contains the entire last file
+        let block_info = BlockInfo {
+            index: 823,
+            begin: 126633,
+            len: 100,
+        };
 
-        let mut path = PathBuf::new();
-        path.push(&download_dir);
-        path.push(&info.name);
-        path.push("bar/buzz/bee.txt");
-        let result = disk.open_file(path).await;
-        assert!(result.is_ok());
+        assert_eq!(
+            disk.block_segments(&block_info, info_hash).await.unwrap(),
+            vec![
+                Segment {
+                    file: last - 1,
+                    offset: files[last - 1].length - 54,
+                    len: 54,
+                },
+                Segment {
+                    file: last,
+                    offset: 0,
+                    len: 46,
+                },
+            ]
+        );
+
+        disk.write_block(
+            Block {
+                index: 823,
+                begin: 126633,
+                block: vec![9; 100],
+            },
+            info_hash,
+            [0; 20],
+        )
+        .await
+        .unwrap();
+        disk.wait_io().await;
 
-        assert!(Path::new(&format!("{download_dir}/arch/foo.txt")).is_file());
-        assert!(Path::new(&format!("{download_dir}/arch/bar/baz.txt")).is_file());
-        assert!(Path::new(&format!("{download_dir}/arch/bar/buzz/bee.txt")).is_file());
+        assert_eq!(fs::read(&paths[last]).await.unwrap(), vec![9; 46]);
+        assert_eq!(
+            disk.read_block(block_info, info_hash).await.unwrap(),
+            vec![9; 100]
+        );
+
+        // a block after the end of the torrent
+        let block_info = BlockInfo {
+            index: 823,
+            begin: 126633,
+            len: 101,
+        };
+        assert!(disk.block_segments(&block_info, info_hash).await.is_err());
 
         tokio::fs::remove_dir_all(download_dir).await.unwrap();
     }
@@ -659,6 +2938,7 @@ mod tests {
         drop(info_ctx);
 
         disk.new_torrent(torrent_ctx).await.unwrap();
+        disk.wait_io().await;
 
         // write 0s to all files with their sizes
         for file in &info.files.clone().unwrap() {
@@ -777,16 +3057,120 @@ mod tests {
         tokio::fs::remove_dir_all(download_dir).await.unwrap();
     }
 
+    // blocks after the 4 GiB boundary must be mapped to the right file and offset.
+    // the files are sparse, so this does not use GiBs of disk space.
+    #[tokio::test]
+    async fn read_write_blocks_larger_than_4_gib() {
+        const GIB: u64 = 1024 * 1024 * 1024;
+
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
+        let name = "disk_image".to_owned();
+
+        let info = Info {
+            name: name.clone(),
+            piece_length: 1048576,
+            file_length: None,
+            pieces: vec![],
+            files: Some(vec![
+                metainfo::File {
+                    length: 3 * GIB,
+                    path: vec!["a.iso".to_owned()],
+                },
+                metainfo::File {
+                    length: 2 * GIB + 100,
+                    path: vec!["b.iso".to_owned()],
+                },
+                metainfo::File {
+                    length: 46,
+                    path: vec!["c.txt".to_owned()],
+                },
+            ]),
+        };
+
+        let magnet = format!("magnet:?xt=urn:btih:9999999999999999999999999999999999999999&amp;dn={name}&amp;tr=udp%3A%2F%2Ftracker.coppersurfer.tk%3A6969%2Fannounce");
+
+        let (disk_tx, disk_rx) = mpsc::channel::<DiskMsg>(3);
+
+        let (fr_tx, _) = mpsc::channel::<FrMsg>(300);
+        let torrent = Torrent::new(disk_tx.clone(), fr_tx, &magnet);
+        let info_hash = torrent.ctx.info_hash;
+        let mut disk = Disk::new(disk_rx, download_dir.clone());
+
+        let mut info_ctx = torrent.ctx.info.write().await;
+        *info_ctx = info.clone();
+        drop(info_ctx);
+
+        disk.new_torrent(torrent.ctx.clone()).await.unwrap();
+        disk.wait_io().await;
+
+        // first block after the 4 GiB boundary, 1 GiB inside the second file
+        let block_info = BlockInfo {
+            index: 4096,
+            begin: 0,
+            len: BLOCK_LEN,
+        };
+
+        let (segment, meta_file) = disk
+            .get_file_from_block_info(&block_info, info_hash)
+            .await
+            .unwrap();
+
+        assert_eq!(meta_file, info.files.as_ref().unwrap()[1]);
+        assert_eq!(segment.offset, GIB);
+
+        // the last file starts 100 bytes into the last piece
+        let (segment, meta_file) = disk
+            .get_file_from_block_info(
+                &BlockInfo {
+                    index: 5120,
+                    begin: 110,
+                    len: 36,
+                },
+                info_hash,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(meta_file, info.files.as_ref().unwrap()[2]);
+        assert_eq!(segment.offset, 10);
+
+        let block = Block {
+            index: 4096,
+            begin: 0,
+            block: vec![7; BLOCK_LEN as usize],
+        };
+
+        let result = disk.write_block(block.clone(), info_hash, [0; 20]).await;
+        assert!(result.is_ok());
+
+        let result = disk.read_block(block_info, info_hash).await;
+        assert_eq!(result.unwrap(), block.block);
+
+        tokio::fs::remove_dir_all(download_dir).await.unwrap();
+    }
+
     // if we can write, read blocks, and then validate the hash of the pieces
     #[tokio::test]
     async fn read_write_blocks_and_validate_pieces() {
         let name = "arch";
 
+        // the content of all files, each piece has 6 bytes
+        let content: Vec<u8> = (1..=36).collect();
+        let pieces: Vec<u8> = content
+            .chunks(6)
+            .flat_map(|piece| {
+                let mut hash = sha1_smol::Sha1::new();
+                hash.update(piece);
+                hash.digest().bytes()
+            })
+            .collect();
+
         let info = Info {
             file_length: None,
             name: name.to_owned(),
             piece_length: 6,
-            pieces: vec![60; 0],
+            pieces,
             files: Some(vec![
                 metainfo::File {
                     length: 12,
@@ -819,14 +3203,17 @@ mod tests {
         drop(info_t);
 
         disk.new_torrent(torrent.ctx.clone()).await.unwrap();
+        disk.wait_io().await;
 
         let info_hash = torrent.ctx.info_hash;
 
         let mut p = torrent.ctx.pieces.write().await;
-        *p = Bitfield::from(vec![255, 255, 255, 255]);
+        *p = Bitfield::from(vec![0]);
         drop(info);
         drop(p);
 
+        let peer_id = [0; 20];
+
         //
         //  WRITE BLOCKS
         //
@@ -839,8 +3226,20 @@ mod tests {
             block: vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
         };
 
-        let result = disk.write_block(block.clone(), info_hash).await;
-        assert!(result.is_ok());
+        for piece in 0..2 {
+            let result = disk
+                .write_block(
+                    Block {
+                        index: piece,
+                        begin: 0,
+                        block: block.block[piece * 6..piece * 6 + 6].to_vec(),
+                    },
+                    info_hash,
+                    peer_id,
+                )
+                .await;
+            assert!(result.is_ok());
+        }
 
         // validate that the first file contains the bytes that we wrote
         let block_info = BlockInfo {
@@ -859,8 +3258,20 @@ mod tests {
             block: vec![13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24],
         };
 
-        let result = disk.write_block(block.clone(), info_hash).await;
-        assert!(result.is_ok());
+        for piece in 2..4 {
+            let result = disk
+                .write_block(
+                    Block {
+                        index: piece,
+                        begin: 0,
+                        block: block.block[(piece - 2) * 6..(piece - 2) * 6 + 6].to_vec(),
+                    },
+                    info_hash,
+                    peer_id,
+                )
+                .await;
+            assert!(result.is_ok());
+        }
 
         // validate that the second file contains the bytes that we wrote
         let block_info = BlockInfo {
@@ -879,8 +3290,58 @@ mod tests {
             block: vec![25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36],
         };
 
-        let result = disk.write_block(block.clone(), info_hash).await;
+        let result = disk
+            .write_block(
+                Block {
+                    index: 4,
+                    begin: 0,
+                    block: block.block[..6].to_vec(),
+                },
+                info_hash,
+                peer_id,
+            )
+            .await;
+        assert!(result.is_ok());
+
+        // the last piece is corrupted, it must be downloaded again
+        let result = disk
+            .write_block(
+                Block {
+                    index: 5,
+                    begin: 0,
+                    block: vec![0; 6],
+                },
+                info_hash,
+                peer_id,
+            )
+            .await;
+        assert!(result.is_ok());
+        disk.wait_io().await;
+
+        let picker = disk.pickers.get(&info_hash).unwrap();
+        assert_eq!(picker.free_blocks(5), 1);
+        assert_eq!(disk.downloaded_infos[&info_hash].piece_len(5), 0);
+        assert_eq!(torrent.ctx.pieces.read().await.get(5_usize).unwrap().bit, 0);
+        assert!(disk.validate_piece(info_hash, 5).await.is_err());
+
+        let result = disk
+            .write_block(
+                Block {
+                    index: 5,
+                    begin: 0,
+                    block: block.block[6..].to_vec(),
+                },
+                info_hash,
+                peer_id,
+            )
+            .await;
         assert!(result.is_ok());
+        disk.wait_io().await;
+
+        // all pieces were validated
+        for piece in 0..6_usize {
+            assert_eq!(torrent.ctx.pieces.read().await.get(piece).unwrap().bit, 1);
+        }
 
         // validate that the third file contains the bytes that we wrote
         let block_info = BlockInfo {
@@ -950,38 +3411,47 @@ mod tests {
         //  VALIDATE PIECES
         //
 
-        println!("---- piece 0 ----");
-        let r = disk.validate_piece(info_hash, 0).await;
-        assert!(r.is_ok());
-
-        println!("---- piece 1 ----");
-        let r = disk.validate_piece(info_hash, 1).await;
-        assert!(r.is_ok());
+        for piece in 0..6 {
+            let r = disk.validate_piece(info_hash, piece).await;
+            assert!(r.is_ok());
+        }
 
-        println!("---- piece 2 ----");
-        let r = disk.validate_piece(info_hash, 2).await;
-        assert!(r.is_ok());
+        // pieces that do not exist
+        let r = disk.validate_piece(info_hash, 6).await;
+        assert!(r.is_err());
 
-        println!("---- piece 20 ----");
         let r = disk.validate_piece(info_hash, 20).await;
-        assert!(r.is_ok());
-
-        println!("---- piece 21 ----");
-        let r = disk.validate_piece(info_hash, 21).await;
-        assert!(r.is_ok());
-
-        println!("---- piece 22 ----");
-        let r = disk.validate_piece(info_hash, 22).await;
-        assert!(r.is_ok());
-
-        println!("---- piece 24 ----");
-        let r = disk.validate_piece(info_hash, 24).await;
-        assert!(r.is_ok());
-
-        println!("---- piece 25 ----");
-        let r = disk.validate_piece(info_hash, 25).await;
-        assert!(r.is_ok());
+        assert!(r.is_err());
 
         tokio::fs::remove_dir_all(&download_dir).await.unwrap();
     }
+
+    #[test]
+    fn downloaded_infos() {
+        let block = |index: u32, begin: u32| BlockInfo {
+            index,
+            begin,
+            len: BLOCK_LEN,
+        };
+        let mut infos = DownloadedInfos::default();
+
+        assert!(infos.insert(block(0, 0), [1; 20]));
+        assert!(infos.insert(block(0, BLOCK_LEN), [2; 20]));
+        assert!(infos.insert(block(1, 0), [1; 20]));
+        // the same block is only counted once
+        assert!(!infos.insert(block(0, 0), [3; 20]));
+
+        assert_eq!(infos.piece_len(0), BLOCK_LEN * 2);
+        assert_eq!(infos.piece_len(1), BLOCK_LEN);
+        assert_eq!(infos.piece_len(2), 0);
+
+        let removed = infos.remove_piece(0);
+        assert_eq!(removed.len(), 2);
+        assert_eq!(removed[&block(0, 0)], [1; 20]);
+        assert_eq!(infos.piece_len(0), 0);
+        assert_eq!(infos.blocks().collect::<Vec<_>>(), vec![&block(1, 0)]);
+
+        infos.clear();
+        assert!(infos.blocks().next().is_none());
+    }
 }
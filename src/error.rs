@@ -4,7 +4,8 @@ use thiserror::Error;
 use tokio::sync::{mpsc, oneshot};
 
 use crate::{
-    disk::DiskMsg, frontend::FrMsg, peer::PeerMsg, torrent::TorrentMsg, tracker::TrackerMsg,
+    dht::DhtMsg, disk::DiskMsg, frontend::FrMsg, peer::PeerMsg, torrent::TorrentMsg,
+    tracker::TrackerMsg,
 };
 
 #[derive(Error, Debug)]
@@ -14,7 +15,11 @@ pub enum Error {
     #[error("Failed to decode or encode the bencode buffer")]
     BencodeError,
     #[error("Failed to resolve socket address")]
-    PeerSocketAddrs(#[from] io::Error),
+    PeerSocketAddrs(io::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("An I/O worker panicked while running a job")]
+    IoJobPanicked,
     #[error("Peer resolved to no unusable addresses")]
     PeerSocketAddr,
     #[error("Tracker resolved to no unusable addresses")]
@@ -33,6 +38,12 @@ pub enum Error {
     TrackerCompactPeerList,
     #[error("Could not connect to the UDP socket of the tracker")]
     TrackerSocketConnect,
+    #[error("The KRPC message received from a DHT node is not valid")]
+    DhtMessageInvalid,
+    #[error("The tracker refused the announce: {0}")]
+    TrackerFailure(String),
+    #[error("Could not send the HTTP request to the tracker")]
+    TrackerHttp(#[from] reqwest::Error),
     #[error("Error when serializing/deserializing")]
     SpeedyError(#[from] speedy::Error),
     #[error("Error when reading magnet link")]
@@ -59,30 +70,38 @@ pub enum Error {
     TorrentDoesNotExist,
     #[error("The piece downloaded does not have a valid hash")]
     PieceInvalid,
+    #[error("The block is not inside the files of the torrent")]
+    BlockInvalid,
+    #[error("There is not enough free space on disk, {0} bytes are needed but only {1} are free")]
+    NotEnoughSpace(u64, u64),
     #[error("The peer ID does not exist on this torrent")]
     PeerIdInvalid,
     #[error("Disk does not have the provided info_hash")]
     InfoHashInvalid,
     #[error("The peer took to long to respond")]
     Timeout,
-    #[error("Your magnet does not have a tracker. Currently, this client does not support DHT, you need to use a magnet that has a tracker.")]
-    MagnetNoTracker,
     #[error(
         "Your magnet does not have an info_hash, are you sure you copied the entire magnet link?"
     )]
     MagnetNoInfoHash,
+    // boxed because some messages of the Disk are large,
+    // and the size of this variant is the size of the entire enum.
     #[error("Could not send message to Disk")]
-    SendErrorDisk(#[from] mpsc::error::SendError<DiskMsg>),
+    SendErrorDisk(Box<mpsc::error::SendError<DiskMsg>>),
     #[error("Could not receive message from oneshot")]
     ReceiveErrorOneshot(#[from] oneshot::error::RecvError),
     #[error("Could not send message to Peer")]
     SendErrorPeer(#[from] mpsc::error::SendError<PeerMsg>),
     #[error("Could not send message to Tracker")]
     SendErrorTracker(#[from] mpsc::error::SendError<TrackerMsg>),
+    #[error("Could not send message to DHT")]
+    SendErrorDht(#[from] mpsc::error::SendError<DhtMsg>),
+    // boxed because the torrents send their whole state to the Frontend.
     #[error("Could not send message to Frontend")]
-    SendErrorFr(#[from] mpsc::error::SendError<FrMsg>),
+    SendErrorFr(Box<mpsc::error::SendError<FrMsg>>),
+    // boxed because some messages of the Torrent hold an `Error`.
     #[error("Could not send message to Torrent")]
-    SendErrorTorrent(#[from] mpsc::error::SendError<TorrentMsg>),
+    SendErrorTorrent(Box<mpsc::error::SendError<TorrentMsg>>),
     #[error("The `{0}` folder was not found, please edit the config file manually at `{1}")]
     FolderNotFound(String, String),
     #[error("Tried to load $HOME but could not find it. Please make sure you have a $HOME env and that this program has the permission to create dirs.")]
@@ -90,3 +109,21 @@ pub enum Error {
     #[error("The given PATH is invalid")]
     PathInvalid,
 }
+
+impl From<mpsc::error::SendError<DiskMsg>> for Error {
+    fn from(value: mpsc::error::SendError<DiskMsg>) -> Self {
+        Self::SendErrorDisk(Box::new(value))
+    }
+}
+
+impl From<mpsc::error::SendError<FrMsg>> for Error {
+    fn from(value: mpsc::error::SendError<FrMsg>) -> Self {
+        Self::SendErrorFr(Box::new(value))
+    }
+}
+
+impl From<mpsc::error::SendError<TorrentMsg>> for Error {
+    fn from(value: mpsc::error::SendError<TorrentMsg>) -> Self {
+        Self::SendErrorTorrent(Box::new(value))
+    }
+}
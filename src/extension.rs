@@ -1,9 +1,20 @@
+use std::net::{IpAddr, SocketAddr};
+
 use bendy::{
     decoding::{self, FromBencode, Object, ResultExt},
-    encoding::ToBencode,
+    encoding::{AsString, ToBencode},
 };
+use hashbrown::HashSet;
+
+use crate::{error, tracker::Tracker};
+
+/// The ID of the ut_metadata extension on our side,
+/// peers use this ID when sending us metadata messages.
+pub const UT_METADATA: u8 = 3;
 
-use crate::error;
+/// The ID of the ut_pex extension on our side,
+/// peers use this ID when sending us PEX messages.
+pub const UT_PEX: u8 = 1;
 
 /// This is the payload of the extension protocol described on:
 /// BEP 10 - Extension Protocol
@@ -29,8 +40,8 @@ impl Extension {
     /// Extensions that the client supports
     pub fn supported(metadata_size: Option<u32>) -> Self {
         let m = M {
-            ut_metadata: Some(3),
-            ut_pex: None,
+            ut_metadata: Some(UT_METADATA),
+            ut_pex: Some(UT_PEX),
         };
         Self {
             m,
@@ -44,8 +55,7 @@ impl Extension {
 
 /// Messages of the Extension protocol
 /// lists all extensions that a peer supports
-/// in our case, we only support ut_metadata at the moment
-/// and naturally, we are only interested in reading this part of the metadata
+/// in our case, we support ut_metadata and ut_pex
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct M {
     pub ut_metadata: Option<u8>,
@@ -292,6 +302,175 @@ impl FromBencode for Extension {
     }
 }
 
+/// Peer Exchange message (BEP 11), the peers that the sender connected
+/// to, and disconnected from, since its last PEX message.
+/// http://www.bittorrent.org/beps/bep_0011.html
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Pex {
+    pub added: Vec<SocketAddr>,
+    /// Flags of each peer of `added`, in the same order.
+    pub added_f: Vec<u8>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl Pex {
+    /// The peer accepts incoming connections,
+    /// set for the peers that we connected to.
+    pub const FLAG_REACHABLE: u8 = 0x10;
+
+    /// A message should not have more than this many added,
+    /// or dropped, peers.
+    pub const MAX_PEERS: usize = 50;
+
+    /// Create the delta between the peers in `sent`, that the remote peer
+    /// already knows about, and the peers that we are `connected` to, and
+    /// update `sent`. The remote peer itself, `to`, is never sent.
+    /// `None` if nothing changed.
+    pub fn delta(
+        sent: &mut HashSet<SocketAddr>,
+        connected: &[SocketAddr],
+        to: SocketAddr,
+    ) -> Option<Self> {
+        let added: Vec<SocketAddr> = connected
+            .iter()
+            .filter(|addr| **addr != to && !sent.contains(*addr))
+            .take(Self::MAX_PEERS)
+            .copied()
+            .collect();
+
+        let dropped: Vec<SocketAddr> = sent
+            .iter()
+            .filter(|addr| !connected.contains(*addr))
+            .take(Self::MAX_PEERS)
+            .copied()
+            .collect();
+
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        sent.extend(added.iter().copied());
+        for addr in &dropped {
+            sent.remove(addr);
+        }
+
+        Some(Self {
+            added_f: vec![Self::FLAG_REACHABLE; added.len()],
+            added,
+            dropped,
+        })
+    }
+}
+
+/// Encode the addresses of one IP version in the compact format,
+/// with the flags of each address if given.
+fn compact_peers(addrs: &[SocketAddr], flags: Option<&[u8]>, ipv6: bool) -> (Vec<u8>, Vec<u8>) {
+    let mut buf = Vec::new();
+    let mut f = Vec::new();
+
+    for (i, addr) in addrs.iter().enumerate() {
+        match addr.ip() {
+            IpAddr::V4(ip) if !ipv6 => buf.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) if ipv6 => buf.extend_from_slice(&ip.octets()),
+            _ => continue,
+        }
+        buf.extend_from_slice(&addr.port().to_be_bytes());
+
+        if let Some(flags) = flags {
+            f.push(flags.get(i).copied().unwrap_or_default());
+        }
+    }
+
+    (buf, f)
+}
+
+impl ToBencode for Pex {
+    const MAX_DEPTH: usize = 1;
+    fn encode(
+        &self,
+        encoder: bendy::encoding::SingleItemEncoder,
+    ) -> Result<(), bendy::encoding::Error> {
+        let (added, added_f) = compact_peers(&self.added, Some(&self.added_f), false);
+        let (added6, added6_f) = compact_peers(&self.added, Some(&self.added_f), true);
+        let (dropped, _) = compact_peers(&self.dropped, None, false);
+        let (dropped6, _) = compact_peers(&self.dropped, None, true);
+
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"added", AsString(&added))?;
+            e.emit_pair(b"added.f", AsString(&added_f))?;
+            if !added6.is_empty() {
+                e.emit_pair(b"added6", AsString(&added6))?;
+                e.emit_pair(b"added6.f", AsString(&added6_f))?;
+            }
+            e.emit_pair(b"dropped", AsString(&dropped))?;
+            if !dropped6.is_empty() {
+                e.emit_pair(b"dropped6", AsString(&dropped6))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl FromBencode for Pex {
+    fn decode_bencode_object(object: Object) -> Result<Self, bendy::decoding::Error>
+    where
+        Self: Sized,
+    {
+        let mut added = Vec::new();
+        let mut added_f = Vec::new();
+        let mut added6 = Vec::new();
+        let mut added6_f = Vec::new();
+        let mut dropped = Vec::new();
+
+        let mut dict = object.try_into_dictionary()?;
+
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"added", Object::Bytes(bytes)) => {
+                    added = Tracker::parse_compact_peer_list(bytes, false)
+                        .map_err(decoding::Error::malformed_content)?;
+                }
+                (b"added.f", Object::Bytes(bytes)) => {
+                    added_f = bytes.to_vec();
+                }
+                (b"added6", Object::Bytes(bytes)) => {
+                    added6 = Tracker::parse_compact_peer_list(bytes, true)
+                        .map_err(decoding::Error::malformed_content)?;
+                }
+                (b"added6.f", Object::Bytes(bytes)) => {
+                    added6_f = bytes.to_vec();
+                }
+                (b"dropped", Object::Bytes(bytes)) => {
+                    dropped.extend(
+                        Tracker::parse_compact_peer_list(bytes, false)
+                            .map_err(decoding::Error::malformed_content)?,
+                    );
+                }
+                (b"dropped6", Object::Bytes(bytes)) => {
+                    dropped.extend(
+                        Tracker::parse_compact_peer_list(bytes, true)
+                            .map_err(decoding::Error::malformed_content)?,
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        // the flags are optional, peers without flags have none set
+        added_f.resize(added.len(), 0);
+        added6_f.resize(added6.len(), 0);
+
+        added.extend(added6);
+        added_f.extend(added6_f);
+
+        Ok(Self {
+            added,
+            added_f,
+            dropped,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::metainfo::MetaInfo;
@@ -323,6 +502,95 @@ mod tests {
         assert_eq!(r, metadata_data);
     }
 
+    #[test]
+    fn pex_roundtrip_serialization() {
+        let pex = Pex {
+            added: vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "[::1]:51413".parse().unwrap(),
+                "10.0.0.2:6882".parse().unwrap(),
+            ],
+            added_f: vec![0x10, 0x02, 0x00],
+            dropped: vec!["10.0.0.3:6883".parse().unwrap()],
+        };
+
+        let bytes = pex.to_bencode().unwrap();
+
+        let mut expected = b"d5:added12:".to_vec();
+        expected.extend_from_slice(&[10, 0, 0, 1, 0x1A, 0xE1, 10, 0, 0, 2, 0x1A, 0xE2]);
+        expected.extend_from_slice(b"7:added.f2:");
+        expected.extend_from_slice(&[0x10, 0x00]);
+        expected.extend_from_slice(b"6:added618:");
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xC8, 0xD5]);
+        expected.extend_from_slice(b"8:added6.f1:");
+        expected.extend_from_slice(&[0x02]);
+        expected.extend_from_slice(b"7:dropped6:");
+        expected.extend_from_slice(&[10, 0, 0, 3, 0x1A, 0xE3]);
+        expected.extend_from_slice(b"e");
+
+        assert_eq!(bytes, expected);
+
+        // IPv4 peers are decoded before IPv6 peers
+        let decoded = Pex::from_bencode(&bytes).unwrap();
+        assert_eq!(
+            decoded,
+            Pex {
+                added: vec![
+                    "10.0.0.1:6881".parse().unwrap(),
+                    "10.0.0.2:6882".parse().unwrap(),
+                    "[::1]:51413".parse().unwrap(),
+                ],
+                added_f: vec![0x10, 0x00, 0x02],
+                dropped: vec!["10.0.0.3:6883".parse().unwrap()],
+            }
+        );
+    }
+
+    #[test]
+    fn pex_without_flags() {
+        let mut b = b"d5:added6:".to_vec();
+        b.extend_from_slice(&[10, 0, 0, 1, 0x1A, 0xE1]);
+        b.extend_from_slice(b"7:dropped0:e");
+
+        let pex = Pex::from_bencode(&b).unwrap();
+
+        assert_eq!(pex.added, vec!["10.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(pex.added_f, vec![0]);
+        assert!(pex.dropped.is_empty());
+    }
+
+    #[test]
+    fn pex_delta() {
+        let to: SocketAddr = "10.0.0.9:6881".parse().unwrap();
+        let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let c: SocketAddr = "10.0.0.3:6881".parse().unwrap();
+
+        let mut sent = HashSet::new();
+
+        // the first message has all peers, except the receiver
+        let pex = Pex::delta(&mut sent, &[a, b, to], to).unwrap();
+        assert_eq!(pex.added, vec![a, b]);
+        assert_eq!(pex.added_f, vec![Pex::FLAG_REACHABLE; 2]);
+        assert!(pex.dropped.is_empty());
+
+        // nothing changed
+        assert_eq!(Pex::delta(&mut sent, &[a, b, to], to), None);
+
+        let pex = Pex::delta(&mut sent, &[b, c], to).unwrap();
+        assert_eq!(pex.added, vec![c]);
+        assert_eq!(pex.dropped, vec![a]);
+        assert_eq!(sent, HashSet::from([b, c]));
+
+        // no more than 50 peers in a message
+        let many: Vec<SocketAddr> = (0..60)
+            .map(|i| SocketAddr::from(([10, 0, 1, i], 6881)))
+            .collect();
+        let pex = Pex::delta(&mut sent, &many, to).unwrap();
+        assert_eq!(pex.added.len(), Pex::MAX_PEERS);
+        assert_eq!(pex.dropped.len(), 2);
+    }
+
     #[test]
     fn metadata_reject_roundtrip_serialization() {
         let metadata_request = Metadata::reject(0);
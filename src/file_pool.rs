@@ -0,0 +1,244 @@
+//! A pool of open files, used by the Disk to read and write blocks
+//! without opening the file on every read and write.
+//!
+//! The pool has a maximum number of open files, when it is full the
+//! least recently used file is closed. Files are opened read-only,
+//! and reopened read-write when they are written to.
+//!
+//! The files are shared by the I/O workers of the Disk, a worker locks
+//! a file while it reads or writes it, so that other workers can use the
+//! pool and the other files in the meantime. A file that is closed while a
+//! worker is using it, stays open until the worker is done with it.
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use hashbrown::HashMap;
+
+use crate::error::Error;
+
+#[derive(Debug)]
+struct PooledFile {
+    file: Arc<Mutex<File>>,
+    writable: bool,
+    /// When the file was last used, see `Files::uses`.
+    last_use: u64,
+}
+
+#[derive(Debug, Default)]
+struct Files {
+    /// k: path
+    open: HashMap<PathBuf, PooledFile>,
+    /// How many times the pool was used, to know which file was least recently used.
+    uses: u64,
+}
+
+/// The pool is locked only to find, open or remove a file, the files are
+/// flushed and synced after it is unlocked, so that a slow sync doesn't
+/// block the workers that use the other files.
+#[derive(Debug)]
+pub struct FilePool {
+    files: Mutex<Files>,
+    /// How many files can be open at the same time.
+    pub capacity: usize,
+}
+
+impl Default for FilePool {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
+impl FilePool {
+    pub const DEFAULT_CAPACITY: usize = 128;
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            files: Mutex::new(Files::default()),
+            capacity,
+        }
+    }
+
+    /// Get the open file of `path`, or open it. If `write` is true, the file
+    /// is opened read-write, and created if it does not exist.
+    pub fn get(&self, path: &Path, write: bool) -> Result<Arc<Mutex<File>>, Error> {
+        let mut closed: Vec<PooledFile> = Vec::new();
+        let file = self.open(path, write, &mut closed);
+        Self::flush(closed);
+        file
+    }
+
+    /// Get or open the file of `path` with the pool locked, the files
+    /// that are closed to open it are added to `closed`.
+    fn open(
+        &self,
+        path: &Path,
+        write: bool,
+        closed: &mut Vec<PooledFile>,
+    ) -> Result<Arc<Mutex<File>>, Error> {
+        let mut files = self.files.lock().unwrap();
+        files.uses += 1;
+
+        // a read-only file is reopened
+        if write && files.open.get(path).is_some_and(|f| !f.writable) {
+            closed.extend(files.open.remove(path));
+        }
+
+        if !files.open.contains_key(path) {
+            while files.open.len() >= self.capacity.max(1) {
+                let Some(lru) = files
+                    .open
+                    .iter()
+                    .min_by_key(|(_, f)| f.last_use)
+                    .map(|(path, _)| path.clone())
+                else {
+                    break;
+                };
+                closed.extend(files.open.remove(&lru));
+            }
+
+            let file = OpenOptions::new()
+                .read(true)
+                .write(write)
+                .create(write)
+                .truncate(false)
+                .open(path)
+                .map_err(|_| Error::FileOpenError(path.to_string_lossy().into_owned()))?;
+
+            files.open.insert(
+                path.to_owned(),
+                PooledFile {
+                    file: Arc::new(Mutex::new(file)),
+                    writable: write,
+                    last_use: 0,
+                },
+            );
+        }
+
+        let uses = files.uses;
+        let pooled = files.open.get_mut(path).unwrap();
+        pooled.last_use = uses;
+
+        Ok(pooled.file.clone())
+    }
+
+    /// Flush the files that were removed from the pool, they are
+    /// closed when the workers that use them are done.
+    fn flush(closed: Vec<PooledFile>) {
+        for pooled in closed {
+            let _ = pooled.file.lock().unwrap().flush();
+        }
+    }
+
+    /// Close the file of `path`, if it is open.
+    pub fn close(&self, path: &Path) {
+        let closed = self.files.lock().unwrap().open.remove(path);
+        Self::flush(closed.into_iter().collect());
+    }
+
+    /// Close all files inside the directory `dir`.
+    pub fn close_dir(&self, dir: &Path) {
+        let mut files = self.files.lock().unwrap();
+
+        let paths: Vec<PathBuf> = files
+            .open
+            .keys()
+            .filter(|path| path.starts_with(dir))
+            .cloned()
+            .collect();
+
+        let closed = paths
+            .iter()
+            .filter_map(|path| files.open.remove(path))
+            .collect();
+
+        drop(files);
+        Self::flush(closed);
+    }
+
+    /// Flush and sync the files that were opened to be written.
+    pub fn sync_all(&self) -> Result<(), Error> {
+        self.sync_dir(Path::new(""))
+    }
+
+    /// Flush and sync the files inside the directory `dir`
+    /// that were opened to be written.
+    pub fn sync_dir(&self, dir: &Path) -> Result<(), Error> {
+        let writable: Vec<Arc<Mutex<File>>> = self
+            .files
+            .lock()
+            .unwrap()
+            .open
+            .iter()
+            .filter(|(path, f)| f.writable && path.starts_with(dir))
+            .map(|(_, f)| f.file.clone())
+            .collect();
+
+        for file in writable {
+            let mut file = file.lock().unwrap();
+            file.flush()?;
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.lock().unwrap().open.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.lock().unwrap().open.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom};
+
+    use super::*;
+
+    #[test]
+    fn open_reopen_and_evict() {
+        let dir = std::env::temp_dir().join(format!("vcz-pool-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let a = dir.join("a");
+        let b = dir.join("b");
+        let pool = FilePool::new(1);
+
+        // a file that does not exist is not created to be read
+        assert!(pool.get(&a, false).is_err());
+        assert!(!a.exists());
+
+        pool.get(&a, true)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .write_all(b"abc")
+            .unwrap();
+        assert!(a.is_file());
+
+        // the read-write file is reused to read
+        let file = pool.get(&a, false).unwrap();
+        let mut file = file.lock().unwrap();
+        file.seek(SeekFrom::Start(1)).unwrap();
+        let mut buf = [0; 2];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"bc");
+        drop(file);
+
+        // a is closed to open b
+        pool.get(&b, true).unwrap();
+        assert_eq!(pool.len(), 1);
+        assert!(pool.files.lock().unwrap().open.contains_key(&b));
+
+        pool.sync_all().unwrap();
+        pool.close_dir(&dir);
+        assert!(pool.is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
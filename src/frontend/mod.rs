@@ -5,9 +5,15 @@ use hashbrown::HashMap;
 
 use std::{
     io::{self, Stdout},
+    path::PathBuf,
     sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    select, spawn,
+    sync::{mpsc, oneshot},
+    time::timeout,
 };
-use tokio::{select, spawn, sync::mpsc};
 
 use crossterm::{
     self,
@@ -22,12 +28,16 @@ use ratatui::{
 };
 
 use torrent_list::TorrentList;
+use tracing::warn;
 
 use crate::{
     cli::Args,
     config::Config,
+    dht::DhtMsg,
     disk::DiskMsg,
     error::Error,
+    metainfo::{File, Priority},
+    resume::ResumeData,
     torrent::{Stats, Torrent, TorrentMsg, TorrentStatus},
 };
 
@@ -62,9 +72,18 @@ impl AppStyle {
 
 #[derive(Debug, Clone)]
 pub enum FrMsg {
-    NewTorrent(String),
+    /// A magnet link or the path to a .torrent file, and the directory in
+    /// which the torrent is saved, the download dir if `None`.
+    NewTorrent(String, Option<String>),
     Draw([u8; 20], TorrentInfo),
     TogglePause([u8; 20]),
+    ToggleSequential([u8; 20]),
+    /// Check the pieces of the torrent that are on disk.
+    Check([u8; 20]),
+    /// Set the priority of the file with the given index.
+    SetFilePriority([u8; 20], usize, Priority),
+    /// Move the files of the torrent to another directory.
+    MoveStorage([u8; 20], String),
     Quit,
 }
 
@@ -78,6 +97,13 @@ pub struct TorrentInfo {
     pub uploaded: u64,
     pub size: u64,
     pub info_hash: [u8; 20],
+    pub sequential: bool,
+    /// The files of the torrent and their priorities,
+    /// empty while the info is not downloaded.
+    pub files: Vec<(File, Priority)>,
+    /// How many pieces were checked, while the status is `Checking`.
+    pub checked: usize,
+    pub pieces: usize,
 }
 
 pub struct Frontend<'a> {
@@ -86,8 +112,12 @@ pub struct Frontend<'a> {
     pub torrent_list: TorrentList<'a>,
     torrent_txs: HashMap<[u8; 20], mpsc::Sender<TorrentMsg>>,
     disk_tx: mpsc::Sender<DiskMsg>,
+    dht_tx: Option<mpsc::Sender<DhtMsg>>,
     terminal: Terminal<CrosstermBackend<Stdout>>,
     config: Config,
+    /// The directory of the resume files, the torrents of the
+    /// previous run are added when the UI starts.
+    pub resume_dir: Option<PathBuf>,
 }
 
 pub struct FrontendCtx {
@@ -95,7 +125,12 @@ pub struct FrontendCtx {
 }
 
 impl<'a> Frontend<'a> {
-    pub fn new(fr_tx: mpsc::Sender<FrMsg>, disk_tx: mpsc::Sender<DiskMsg>, config: Config) -> Self {
+    pub fn new(
+        fr_tx: mpsc::Sender<FrMsg>,
+        disk_tx: mpsc::Sender<DiskMsg>,
+        dht_tx: Option<mpsc::Sender<DhtMsg>>,
+        config: Config,
+    ) -> Self {
         let stdout = io::stdout();
         let style = AppStyle::new();
         let backend = CrosstermBackend::new(stdout);
@@ -111,7 +146,9 @@ impl<'a> Frontend<'a> {
             torrent_txs: HashMap::new(),
             ctx,
             disk_tx,
+            dht_tx,
             style,
+            resume_dir: None,
         }
     }
 
@@ -131,6 +168,7 @@ impl<'a> Frontend<'a> {
             original_hook(panic);
         }));
 
+        self.resume_torrents().await;
         self.torrent_list.draw(&mut self.terminal).await;
 
         loop {
@@ -160,13 +198,29 @@ impl<'a> Frontend<'a> {
 
                             self.torrent_list.draw(&mut self.terminal).await;
                         },
-                        FrMsg::NewTorrent(magnet) => {
-                            self.new_torrent(&magnet).await;
+                        FrMsg::NewTorrent(input, save_path) => {
+                            self.new_torrent(&input, save_path).await;
                         }
                         FrMsg::TogglePause(id) => {
                             let tx = self.torrent_txs.get(&id).ok_or(Error::TorrentDoesNotExist)?;
                             tx.send(TorrentMsg::TogglePause).await?;
                         }
+                        FrMsg::ToggleSequential(id) => {
+                            let tx = self.torrent_txs.get(&id).ok_or(Error::TorrentDoesNotExist)?;
+                            tx.send(TorrentMsg::ToggleSequential).await?;
+                        }
+                        FrMsg::Check(id) => {
+                            let tx = self.torrent_txs.get(&id).ok_or(Error::TorrentDoesNotExist)?;
+                            tx.send(TorrentMsg::Check).await?;
+                        }
+                        FrMsg::SetFilePriority(id, file, priority) => {
+                            let tx = self.torrent_txs.get(&id).ok_or(Error::TorrentDoesNotExist)?;
+                            tx.send(TorrentMsg::SetFilePriority(file, priority)).await?;
+                        }
+                        FrMsg::MoveStorage(id, path) => {
+                            let tx = self.torrent_txs.get(&id).ok_or(Error::TorrentDoesNotExist)?;
+                            tx.send(TorrentMsg::MoveStorage(path)).await?;
+                        }
                     }
                 }
             }
@@ -191,9 +245,85 @@ impl<'a> Frontend<'a> {
     }
 
     // Create a Torrent, and then Add it. This will be called when the user
-    // adds a torrent using the UI.
-    async fn new_torrent(&mut self, magnet: &str) {
-        let mut torrent = Torrent::new(self.disk_tx.clone(), self.ctx.fr_tx.clone(), magnet);
+    // adds a torrent using the UI. The input is either a magnet link,
+    // or the path to a .torrent file.
+    async fn new_torrent(&mut self, input: &str, save_path: Option<String>) {
+        let input = input.trim();
+
+        let mut torrent = if input.starts_with("magnet:") {
+            Torrent::new(self.disk_tx.clone(), self.ctx.fr_tx.clone(), input)
+        } else {
+            let torrent = std::fs::read(input)
+                .map_err(|_| Error::FileOpenError(input.to_owned()))
+                .and_then(|buf| {
+                    Torrent::new_from_metainfo(self.disk_tx.clone(), self.ctx.fr_tx.clone(), &buf)
+                });
+
+            match torrent {
+                Ok(torrent) => torrent,
+                Err(e) => {
+                    warn!("could not add the torrent {input}: {e}");
+                    return;
+                }
+            }
+        };
+
+        torrent.dht_tx = self.dht_tx.clone();
+        let args = Args::parse();
+        *torrent.ctx.save_path.write().await = save_path;
+        *torrent.ctx.incomplete_dir.write().await = self.config.incomplete_dir.clone();
+        *torrent.ctx.part_files.write().await = self.config.part_files.unwrap_or(false);
+
+        // the options of the CLI are for the torrent of the CLI
+        if Some(input) == args.torrent.as_deref() || Some(input) == args.magnet.as_deref() {
+            *torrent.ctx.file_priorities.write().await = args.priorities.clone();
+            torrent.sequential = args.sequential;
+            torrent.check = args.check;
+        }
+
+        self.add_torrent(torrent).await;
+    }
+
+    /// Add the torrents of the previous run, from their resume files.
+    async fn resume_torrents(&mut self) {
+        let Some(resume_dir) = self.resume_dir.clone() else {
+            return;
+        };
+
+        for data in ResumeData::load_all(&resume_dir).await {
+            let torrent = Torrent::new_from_metainfo(
+                self.disk_tx.clone(),
+                self.ctx.fr_tx.clone(),
+                &data.metainfo,
+            );
+
+            let mut torrent = match torrent {
+                Ok(torrent) if torrent.ctx.info_hash == data.info_hash => torrent,
+                _ => {
+                    warn!(
+                        "the resume file of {} is invalid",
+                        hex::encode(data.info_hash)
+                    );
+                    continue;
+                }
+            };
+
+            torrent.dht_tx = self.dht_tx.clone();
+            torrent.sequential = data.sequential;
+            torrent.uploaded = data.uploaded;
+            torrent.downloaded = data.downloaded;
+            *torrent.ctx.file_priorities.write().await = data.file_priorities.clone();
+            *torrent.ctx.save_path.write().await = Some(data.save_path.clone());
+            *torrent.ctx.incomplete_dir.write().await = data.incomplete_dir.clone();
+            *torrent.ctx.part_files.write().await = data.part_files;
+            torrent.resume = Some(data);
+
+            self.add_torrent(torrent).await;
+        }
+    }
+
+    /// Start a torrent and show it on the UI.
+    async fn add_torrent(&mut self, mut torrent: Torrent) {
         let info_hash = torrent.ctx.info_hash;
 
         // prevent the user from adding a duplicate torrent,
@@ -219,7 +349,10 @@ impl<'a> Frontend<'a> {
 
             spawn(async move {
                 torrent.start_and_run(listen).await.unwrap();
-                torrent.disk_tx.send(DiskMsg::Quit).await.unwrap();
+
+                // a torrent only quits by itself after its download
+                // is complete, with the `quit_after_complete` flag.
+                let _ = torrent.fr_tx.send(FrMsg::Quit).await;
             });
 
             self.torrent_list.draw(&mut self.terminal).await;
@@ -231,11 +364,27 @@ impl<'a> Frontend<'a> {
 
         // tell all torrents that we are gracefully shutting down,
         // each torrent will kill their peers tasks, and their tracker task
-        for (_, tx) in std::mem::take(&mut self.torrent_txs) {
-            spawn(async move {
-                let _ = tx.send(TorrentMsg::Quit).await;
-            });
+        let txs: Vec<_> = std::mem::take(&mut self.torrent_txs)
+            .into_values()
+            .collect();
+
+        for tx in &txs {
+            let _ = tx.send(TorrentMsg::Quit).await;
         }
+
+        // wait for the torrents to send their resume data to the Disk,
+        // the receiver of a torrent is dropped when it has quit.
+        let quit = futures::future::join_all(txs.iter().map(|tx| tx.closed()));
+        let _ = timeout(Duration::from_secs(10), quit).await;
+
+        // wait for the DHT to save its routing table
+        if let Some(dht_tx) = &self.dht_tx {
+            let (otx, orx) = oneshot::channel();
+            if dht_tx.send(DhtMsg::Quit(otx)).await.is_ok() {
+                let _ = orx.await;
+            }
+        }
+
         let _ = self.disk_tx.send(DiskMsg::Quit).await;
     }
 }
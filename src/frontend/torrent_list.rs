@@ -12,7 +12,7 @@ use ratatui::{
 };
 use tracing::info;
 
-use crate::{to_human_readable, torrent::TorrentStatus};
+use crate::{metainfo::Priority, to_human_readable, torrent::TorrentStatus};
 
 use super::{AppStyle, FrMsg, FrontendCtx, TorrentInfo};
 
@@ -24,7 +24,16 @@ pub struct TorrentList<'a> {
     active_torrent: Option<[u8; 20]>,
     ctx: Arc<FrontendCtx>,
     show_popup: bool,
+    /// If the popup with the files of the active torrent is open.
+    show_files: bool,
+    files_state: ListState,
+    /// If the popup to move the files of the active torrent is open.
+    show_move: bool,
     input: String,
+    /// The second input of the popup to add a torrent, the save path.
+    save_path: String,
+    /// If the save path is being edited, instead of `input`.
+    editing_save_path: bool,
     cursor_position: usize,
     footer: List<'a>,
 }
@@ -43,6 +52,14 @@ impl<'a> TorrentList<'a> {
             " add torrent ".into(),
             Span::styled("p".to_string(), style.highlight_fg),
             " toggle pause/resume ".into(),
+            Span::styled("s".to_string(), style.highlight_fg),
+            " toggle sequential ".into(),
+            Span::styled("f".to_string(), style.highlight_fg),
+            " files ".into(),
+            Span::styled("c".to_string(), style.highlight_fg),
+            " check ".into(),
+            Span::styled("m".to_string(), style.highlight_fg),
+            " move ".into(),
             Span::styled("q".to_string(), style.highlight_fg),
             " quit".into(),
         ]
@@ -60,8 +77,13 @@ impl<'a> TorrentList<'a> {
             footer,
             cursor_position: 0,
             input: String::new(),
+            save_path: String::new(),
+            editing_save_path: false,
+            show_move: false,
             torrent_infos: HashMap::new(),
             show_popup: false,
+            show_files: false,
+            files_state: ListState::default(),
             ctx,
             state,
         }
@@ -70,27 +92,54 @@ impl<'a> TorrentList<'a> {
     pub async fn keybindings<T: Backend>(&mut self, k_event: KeyEvent, terminal: &mut Terminal<T>) {
         let k = k_event.code;
         match k {
-            k if self.show_popup && k_event.kind == KeyEventKind::Press => match k {
-                KeyCode::Enter => self.submit_magnet_link(terminal).await,
-                KeyCode::Char(to_insert) => {
-                    self.enter_char(to_insert);
-                    self.draw(terminal).await;
-                }
-                KeyCode::Backspace => {
-                    self.delete_char();
-                    self.draw(terminal).await;
+            k if (self.show_popup || self.show_move) && k_event.kind == KeyEventKind::Press => {
+                match k {
+                    KeyCode::Enter => self.submit_input(terminal).await,
+                    // switch between the torrent and its save path
+                    KeyCode::Tab if self.show_popup => {
+                        self.editing_save_path = !self.editing_save_path;
+                        self.cursor_position = self.focused_input().len();
+                        self.draw(terminal).await;
+                    }
+                    KeyCode::Char(to_insert) => {
+                        self.enter_char(to_insert);
+                        self.draw(terminal).await;
+                    }
+                    KeyCode::Backspace => {
+                        self.delete_char();
+                        self.draw(terminal).await;
+                    }
+                    KeyCode::Left => {
+                        self.move_cursor_left();
+                        self.draw(terminal).await;
+                    }
+                    KeyCode::Right => {
+                        self.move_cursor_right();
+                        self.draw(terminal).await;
+                    }
+                    KeyCode::Esc => {
+                        // self.input_mode = InputMode::Normal;
+                        self.quit(terminal).await;
+                        self.draw(terminal).await;
+                    }
+                    _ => {}
                 }
-                KeyCode::Left => {
-                    self.move_cursor_left();
+            }
+            k if self.show_files && k_event.kind == KeyEventKind::Press => match k {
+                KeyCode::Down | KeyCode::Char('j') => {
+                    self.next_file();
                     self.draw(terminal).await;
                 }
-                KeyCode::Right => {
-                    self.move_cursor_right();
+                KeyCode::Up | KeyCode::Char('k') => {
+                    self.previous_file();
                     self.draw(terminal).await;
                 }
-                KeyCode::Esc => {
-                    // self.input_mode = InputMode::Normal;
-                    self.quit(terminal).await;
+                KeyCode::Char('s') => self.set_file_priority(Priority::Skip).await,
+                KeyCode::Char('l') => self.set_file_priority(Priority::Low).await,
+                KeyCode::Char('n') => self.set_file_priority(Priority::Normal).await,
+                KeyCode::Char('h') => self.set_file_priority(Priority::High).await,
+                KeyCode::Char('q') | KeyCode::Char('f') | KeyCode::Esc => {
+                    self.show_files = false;
                     self.draw(terminal).await;
                 }
                 _ => {}
@@ -124,6 +173,29 @@ impl<'a> TorrentList<'a> {
                         self.draw(terminal).await;
                     }
                 }
+                KeyCode::Char('s') => {
+                    if let Some(active_torrent) = self.active_torrent {
+                        let _ = self
+                            .ctx
+                            .fr_tx
+                            .send(FrMsg::ToggleSequential(active_torrent))
+                            .await;
+                    }
+                }
+                KeyCode::Char('c') => {
+                    if let Some(active_torrent) = self.active_torrent {
+                        let _ = self.ctx.fr_tx.send(FrMsg::Check(active_torrent)).await;
+                    }
+                }
+                KeyCode::Char('m') if self.active_torrent.is_some() => {
+                    self.show_move = true;
+                    self.draw(terminal).await;
+                }
+                KeyCode::Char('f') if self.active_torrent.is_some() => {
+                    self.show_files = true;
+                    self.files_state.select(Some(0));
+                    self.draw(terminal).await;
+                }
                 _ => {}
             },
         }
@@ -141,7 +213,7 @@ impl<'a> TorrentList<'a> {
 
             let status_style = match ctx.status {
                 TorrentStatus::Seeding => self.style.success,
-                TorrentStatus::Error => self.style.error,
+                TorrentStatus::Error(_) => self.style.error,
                 TorrentStatus::Paused => self.style.warning,
                 _ => self.style.highlight_fg,
             };
@@ -158,9 +230,29 @@ impl<'a> TorrentList<'a> {
                 status_txt.push(download_and_rate);
             }
 
+            if let TorrentStatus::Error(reason) = &ctx.status {
+                status_txt.push(format!(" {reason}").into());
+            }
+
+            if ctx.status == TorrentStatus::Checking && ctx.pieces > 0 {
+                let progress = ctx.checked * 100 / ctx.pieces;
+                status_txt.push(format!(" {progress}%").into());
+            }
+
+            if ctx.sequential {
+                status_txt.push(Span::styled(" sequential", self.style.highlight_fg));
+            }
+
             let s = ctx.stats.seeders.to_string();
             let l = ctx.stats.leechers.to_string();
-            let sl = format!("Seeders {s} Leechers {l}").into();
+            let mut sl = format!("Seeders {s} Leechers {l}");
+
+            let reads = ctx.stats.cache_hits + ctx.stats.cache_misses;
+            if reads > 0 {
+                let hits = ctx.stats.cache_hits;
+                let misses = ctx.stats.cache_misses;
+                sl.push_str(&format!(" - Cache hits {hits} misses {misses}"));
+            }
 
             let mut line_top = Line::from("-".repeat(terminal.size().unwrap().width as usize));
             let mut line_bottom = line_top.clone();
@@ -174,7 +266,7 @@ impl<'a> TorrentList<'a> {
                 line_top,
                 name.into(),
                 to_human_readable(ctx.size as f64).into(),
-                sl,
+                sl.into(),
                 status_txt.into(),
                 line_bottom,
             ];
@@ -193,6 +285,33 @@ impl<'a> TorrentList<'a> {
         let torrent_list =
             List::new(rows).block(Block::default().borders(Borders::ALL).title("Torrents"));
 
+        let files: Vec<ListItem> = self
+            .active_files()
+            .iter()
+            .map(|(file, priority)| {
+                let style = if *priority == Priority::Skip {
+                    self.style.base_style
+                } else {
+                    self.style.highlight_fg
+                };
+                let priority: &str = (*priority).into();
+
+                ListItem::new(Line::from(vec![
+                    Span::styled(format!("{priority:<7}"), style),
+                    format!("{} ", file.path.join("/")).into(),
+                    to_human_readable(file.length as f64).into(),
+                ]))
+            })
+            .collect();
+
+        let files = List::new(files)
+            .highlight_style(self.style.highlight_bg)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Files - s skip, l low, n normal, h high"),
+            );
+
         terminal
             .draw(|f| {
                 // Create two chunks, the body, and the footer
@@ -203,14 +322,52 @@ impl<'a> TorrentList<'a> {
 
                 if self.show_popup {
                     let area = self.centered_rect(60, 20, f.size());
+                    let rows = Layout::default()
+                        .direction(Direction::Vertical)
+                        .constraints([Constraint::Length(3), Constraint::Length(3)].as_ref())
+                        .split(area);
 
                     let input = Paragraph::new(self.input.as_str())
                         .style(self.style.highlight_fg)
                         .block(Block::default().borders(Borders::ALL).title("Add Torrent"));
 
+                    let save_path = Paragraph::new(self.save_path.as_str())
+                        .style(self.style.highlight_fg)
+                        .block(
+                            Block::default()
+                                .borders(Borders::ALL)
+                                .title("Save path - Tab to edit, empty for the download dir"),
+                        );
+
+                    let row = if self.editing_save_path {
+                        rows[1]
+                    } else {
+                        rows[0]
+                    };
+
+                    f.render_widget(Clear, area);
+                    f.render_widget(input, rows[0]);
+                    f.render_widget(save_path, rows[1]);
+                    f.set_cursor(row.x + self.cursor_position as u16 + 1, row.y + 1);
+                } else if self.show_move {
+                    let area = self.centered_rect(60, 20, f.size());
+
+                    let input = Paragraph::new(self.input.as_str())
+                        .style(self.style.highlight_fg)
+                        .block(
+                            Block::default()
+                                .borders(Borders::ALL)
+                                .title("Move files to"),
+                        );
+
                     f.render_widget(Clear, area);
                     f.render_widget(input, area);
                     f.set_cursor(area.x + self.cursor_position as u16 + 1, area.y + 1);
+                } else if self.show_files {
+                    let area = self.centered_rect(80, 60, f.size());
+
+                    f.render_widget(Clear, area);
+                    f.render_stateful_widget(files, area, &mut self.files_state);
                 } else {
                     f.render_stateful_widget(torrent_list, chunks[0], &mut self.state);
                     f.render_widget(self.footer.clone(), chunks[1]);
@@ -245,9 +402,54 @@ impl<'a> TorrentList<'a> {
         }
     }
 
+    /// The files of the selected torrent, and their priorities.
+    fn active_files(&self) -> &[(crate::metainfo::File, Priority)] {
+        self.active_torrent
+            .and_then(|id| self.torrent_infos.get(&id))
+            .map(|ctx| ctx.files.as_slice())
+            .unwrap_or_default()
+    }
+
+    fn next_file(&mut self) {
+        let len = self.active_files().len();
+        if len > 0 {
+            let i = self.files_state.selected().map_or(0, |v| (v + 1) % len);
+            self.files_state.select(Some(i));
+        }
+    }
+
+    fn previous_file(&mut self) {
+        let len = self.active_files().len();
+        if len > 0 {
+            let i = self
+                .files_state
+                .selected()
+                .map_or(0, |v| if v == 0 { len - 1 } else { v - 1 });
+            self.files_state.select(Some(i));
+        }
+    }
+
+    /// Set the priority of the selected file, the new priority is
+    /// shown on the next draw, after the torrent has updated it.
+    async fn set_file_priority(&mut self, priority: Priority) {
+        if let (Some(active_torrent), Some(file)) =
+            (self.active_torrent, self.files_state.selected())
+        {
+            let _ = self
+                .ctx
+                .fr_tx
+                .send(FrMsg::SetFilePriority(active_torrent, file, priority))
+                .await;
+        }
+    }
+
     async fn quit<T: Backend>(&mut self, terminal: &mut Terminal<T>) {
-        if self.show_popup {
+        if self.show_popup || self.show_move {
             self.show_popup = false;
+            self.show_move = false;
+            self.editing_save_path = false;
+            self.input.clear();
+            self.save_path.clear();
             self.draw(terminal).await;
             self.reset_cursor();
         } else {
@@ -290,8 +492,26 @@ impl<'a> TorrentList<'a> {
         self.cursor_position = self.clamp_cursor(cursor_moved_right);
     }
 
+    /// The input that is being edited.
+    fn focused_input(&self) -> &String {
+        if self.editing_save_path {
+            &self.save_path
+        } else {
+            &self.input
+        }
+    }
+
+    fn focused_input_mut(&mut self) -> &mut String {
+        if self.editing_save_path {
+            &mut self.save_path
+        } else {
+            &mut self.input
+        }
+    }
+
     fn enter_char(&mut self, new_char: char) {
-        self.input.insert(self.cursor_position, new_char);
+        let cursor_position = self.cursor_position;
+        self.focused_input_mut().insert(cursor_position, new_char);
         self.move_cursor_right();
     }
 
@@ -305,32 +525,45 @@ impl<'a> TorrentList<'a> {
             let current_index = self.cursor_position;
             let from_left_to_current_index = current_index - 1;
 
+            let input = self.focused_input_mut();
+
             // Getting all characters before the selected character.
-            let before_char_to_delete = self.input.chars().take(from_left_to_current_index);
+            let before_char_to_delete = input.chars().take(from_left_to_current_index);
             // Getting all characters after selected character.
-            let after_char_to_delete = self.input.chars().skip(current_index);
+            let after_char_to_delete = input.chars().skip(current_index);
 
             // Put all characters together except the selected one.
             // By leaving the selected one out, it is forgotten and therefore deleted.
-            self.input = before_char_to_delete.chain(after_char_to_delete).collect();
+            *input = before_char_to_delete.chain(after_char_to_delete).collect();
             self.move_cursor_left();
         }
     }
 
     fn clamp_cursor(&self, new_cursor_pos: usize) -> usize {
-        new_cursor_pos.clamp(0, self.input.len())
+        new_cursor_pos.clamp(0, self.focused_input().len())
     }
 
     fn reset_cursor(&mut self) {
         self.cursor_position = 0;
     }
 
-    async fn submit_magnet_link<T: Backend>(&mut self, terminal: &mut Terminal<T>) {
-        let _ = self
-            .ctx
-            .fr_tx
-            .send(FrMsg::NewTorrent(std::mem::take(&mut self.input)))
-            .await;
+    async fn submit_input<T: Backend>(&mut self, terminal: &mut Terminal<T>) {
+        let input = std::mem::take(&mut self.input);
+
+        let msg = if self.show_move {
+            let path = input.trim().to_owned();
+            self.active_torrent
+                .filter(|_| !path.is_empty())
+                .map(|active_torrent| FrMsg::MoveStorage(active_torrent, path))
+        } else {
+            let save_path = self.save_path.trim().to_owned();
+            let save_path = Some(save_path).filter(|p| !p.is_empty());
+            Some(FrMsg::NewTorrent(input, save_path))
+        };
+
+        if let Some(msg) = msg {
+            let _ = self.ctx.fr_tx.send(msg).await;
+        }
         self.quit(terminal).await;
     }
 }
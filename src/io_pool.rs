@@ -0,0 +1,1027 @@
+//! The I/O workers of the Disk.
+//!
+//! The Disk is the coordinator, it owns the bookkeeping of the torrents
+//! (the pickers, the downloaded blocks and the caches), and sends the reads,
+//! the writes and the hashing of pieces to a pool of workers, each one on its
+//! own OS thread. A slow write does not stall the reads of the other pieces.
+//!
+//! The jobs of a piece always go to the same worker, and a worker runs its
+//! jobs in order, so the requests for the same region of the files stay
+//! ordered, e.g. a read of a piece that is being written happens after the
+//! write. Every job sends one [`IoDone`] back to the Disk when it is done,
+//! even if it panicked. The pool counts the jobs of each torrent and of each
+//! piece that are not done, so the Disk only waits for the jobs it depends on.
+use std::{
+    collections::BTreeMap,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    sync::{mpsc as std_mpsc, Arc},
+    thread,
+};
+
+use hashbrown::HashMap;
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver},
+    oneshot::Sender,
+};
+use tracing::warn;
+
+use crate::{
+    error::Error,
+    metainfo::{Info, Priority},
+    resume::ResumeData,
+    storage::{self, Storage},
+    tcp_wire::lib::BlockInfo,
+    torrent::TorrentCtx,
+};
+
+#[derive(Debug)]
+pub enum IoJob {
+    /// Read a block, and send it to `recipient`. If `whole_piece` is true,
+    /// the whole piece of the block is read, to be cached. `upload` if
+    /// the block is sent to a peer.
+    Read {
+        block_info: BlockInfo,
+        whole_piece: bool,
+        upload: bool,
+        recipient: Sender<Result<Vec<u8>, Error>>,
+    },
+    /// Write the blocks of the piece `index`, by their offset in the piece.
+    Write {
+        index: usize,
+        blocks: BTreeMap<u32, Vec<u8>>,
+        validate: Validate,
+    },
+    /// Hash the piece `index` that is on the storage, for the check of the torrent.
+    Hash { index: usize },
+    /// Hash the piece `index` that is on the storage, and send
+    /// to `recipient` if it is valid, see `DiskMsg::ValidatePiece`.
+    Validate {
+        index: usize,
+        recipient: Sender<Result<(), Error>>,
+    },
+    /// Move the files of the torrent to the directory `path`, with the
+    /// `.part` suffix if `part` is true. The Disk does not send other jobs
+    /// of the torrent until it is done.
+    Move { path: PathBuf, part: bool },
+    /// Create the files of the torrent that are not skipped, see
+    /// [`Storage::create`]. `new` if the torrent was just added.
+    Create {
+        priorities: Vec<Priority>,
+        new: bool,
+    },
+    /// Make sure that the writes to the files of the torrent are persisted.
+    Flush,
+    /// Sync and close the files of a torrent that was removed from the Disk.
+    Remove,
+    /// Write the resume file of the torrent inside `dir`, with the sizes of its files.
+    SaveResume { data: Box<ResumeData>, dir: PathBuf },
+}
+
+/// How a piece is validated when it is written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Validate {
+    /// The piece is not complete, it is only written.
+    No,
+    /// All the blocks of the piece are in memory, they are
+    /// hashed before they are written, and only written if valid.
+    Memory,
+    /// Some blocks of the piece are already on the storage,
+    /// the piece is hashed from the storage after it is written.
+    Storage,
+}
+
+/// The result of a job, sent back to the Disk.
+#[derive(Debug)]
+pub enum IoDone {
+    /// The block was sent to the recipient. `Ok` with the bytes of the
+    /// piece, if the whole piece was read. `Err` with the reason of the
+    /// error, if it happened on the storage, see [`storage::error_reason`].
+    Read {
+        info_hash: [u8; 20],
+        block_info: BlockInfo,
+        upload: bool,
+        result: Result<Option<Vec<u8>>, Option<String>>,
+    },
+    /// `Ok` with the result of the validation, if the piece was validated.
+    Write {
+        info_hash: [u8; 20],
+        index: usize,
+        result: Result<Option<bool>, Error>,
+    },
+    Hash {
+        info_hash: [u8; 20],
+        index: usize,
+        valid: bool,
+    },
+    /// The result was sent to the recipient.
+    Validate {
+        info_hash: [u8; 20],
+        index: usize,
+        valid: bool,
+    },
+    Move {
+        info_hash: [u8; 20],
+        path: PathBuf,
+        part: bool,
+        result: Result<(), Error>,
+    },
+    Create {
+        info_hash: [u8; 20],
+        new: bool,
+        result: Result<(), Error>,
+    },
+    Flush {
+        info_hash: [u8; 20],
+        result: Result<(), Error>,
+    },
+    Remove {
+        info_hash: [u8; 20],
+        result: Result<(), Error>,
+    },
+    SaveResume {
+        info_hash: [u8; 20],
+        result: Result<(), Error>,
+    },
+}
+
+#[derive(Debug)]
+struct Job {
+    torrent_ctx: Arc<TorrentCtx>,
+    storage: Arc<dyn Storage>,
+    /// The piece of the job, 0 for the jobs of the whole torrent.
+    index: usize,
+    job: IoJob,
+}
+
+#[derive(Debug)]
+pub struct IoPool {
+    workers: Vec<std_mpsc::Sender<Job>>,
+    /// The results, with the torrent and the piece of their job.
+    done_rx: UnboundedReceiver<(([u8; 20], usize), IoDone)>,
+    /// How many jobs are not done yet.
+    in_flight: usize,
+    /// How many jobs of each torrent are not done yet.
+    /// K: info_hash
+    torrents: HashMap<[u8; 20], usize>,
+    /// How many jobs of each piece are not done yet.
+    /// K: (info_hash, index)
+    pieces: HashMap<([u8; 20], usize), usize>,
+}
+
+impl Default for IoPool {
+    fn default() -> Self {
+        let workers = thread::available_parallelism().map_or(4, |n| n.get());
+        Self::new(workers.clamp(2, 8))
+    }
+}
+
+impl IoPool {
+    pub fn new(workers: usize) -> Self {
+        let (done_tx, done_rx) = mpsc::unbounded_channel();
+
+        let workers = (0..workers.max(1))
+            .map(|i| {
+                let (tx, rx) = std_mpsc::channel::<Job>();
+                let done_tx = done_tx.clone();
+
+                thread::Builder::new()
+                    .name(format!("io-worker-{i}"))
+                    .spawn(move || {
+                        // the worker stops when the pool is dropped
+                        while let Ok(job) = rx.recv() {
+                            let key = (job.torrent_ctx.info_hash, job.index);
+
+                            // a job that panics is done with an error,
+                            // and the worker keeps running the next ones
+                            let failed = job.failed();
+                            let done = panic::catch_unwind(AssertUnwindSafe(|| job.run()))
+                                .unwrap_or(failed);
+                            let _ = done_tx.send((key, done));
+                        }
+                    })
+                    .expect("could not spawn an I/O worker");
+
+                tx
+            })
+            .collect();
+
+        Self {
+            workers,
+            done_rx,
+            in_flight: 0,
+            torrents: HashMap::new(),
+            pieces: HashMap::new(),
+        }
+    }
+
+    /// Send a job of a torrent to the worker of its piece.
+    pub fn send(&mut self, torrent_ctx: Arc<TorrentCtx>, storage: Arc<dyn Storage>, job: IoJob) {
+        let index = match &job {
+            IoJob::Read { block_info, .. } => block_info.index as usize,
+            IoJob::Write { index, .. } | IoJob::Hash { index } | IoJob::Validate { index, .. } => {
+                *index
+            }
+            IoJob::Move { .. }
+            | IoJob::Create { .. }
+            | IoJob::Flush
+            | IoJob::Remove
+            | IoJob::SaveResume { .. } => 0,
+        };
+
+        // the pieces of a torrent are spread over the workers
+        let info_hash = torrent_ctx.info_hash;
+        let mut seed = [0; 8];
+        seed.copy_from_slice(&info_hash[..8]);
+        let worker = (u64::from_le_bytes(seed) as usize).wrapping_add(index) % self.workers.len();
+
+        let job = Job {
+            torrent_ctx,
+            storage,
+            index,
+            job,
+        };
+
+        match self.workers[worker].send(job) {
+            Ok(_) => {
+                self.in_flight += 1;
+                *self.torrents.entry(info_hash).or_default() += 1;
+                *self.pieces.entry((info_hash, index)).or_default() += 1;
+            }
+            Err(e) => warn!("the I/O worker {worker} stopped, {:?}", e.0.job),
+        }
+    }
+
+    /// Receive the result of the next job that is done.
+    pub async fn recv(&mut self) -> Option<IoDone> {
+        let ((info_hash, index), done) = self.done_rx.recv().await?;
+        self.in_flight -= 1;
+
+        if let Some(n) = self.torrents.get_mut(&info_hash) {
+            *n -= 1;
+            if *n == 0 {
+                self.torrents.remove(&info_hash);
+            }
+        }
+
+        if let Some(n) = self.pieces.get_mut(&(info_hash, index)) {
+            *n -= 1;
+            if *n == 0 {
+                self.pieces.remove(&(info_hash, index));
+            }
+        }
+
+        Some(done)
+    }
+
+    /// How many jobs are not done yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    /// How many jobs of a torrent are not done yet.
+    pub fn torrent_in_flight(&self, info_hash: [u8; 20]) -> usize {
+        self.torrents.get(&info_hash).copied().unwrap_or(0)
+    }
+
+    /// How many jobs of the piece `index` of a torrent are not done yet.
+    pub fn piece_in_flight(&self, info_hash: [u8; 20], index: usize) -> usize {
+        self.pieces.get(&(info_hash, index)).copied().unwrap_or(0)
+    }
+
+    /// How many workers are in the pool.
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+}
+
+/// If the hash of the piece `index` is the hash on `Info.pieces`.
+fn is_valid(info: &Info, index: usize, hash: [u8; 20]) -> bool {
+    info.pieces.get(index * 20..index * 20 + 20) == Some(&hash[..])
+}
+
+impl Job {
+    /// The result of the job if it panics. The recipient of a read is
+    /// dropped, and it receives an error.
+    fn failed(&self) -> IoDone {
+        let info_hash = self.torrent_ctx.info_hash;
+
+        match &self.job {
+            IoJob::Read {
+                block_info, upload, ..
+            } => IoDone::Read {
+                info_hash,
+                block_info: block_info.clone(),
+                upload: *upload,
+                result: Err(None),
+            },
+            IoJob::Write { index, .. } => IoDone::Write {
+                info_hash,
+                index: *index,
+                result: Err(Error::IoJobPanicked),
+            },
+            IoJob::Hash { index } => IoDone::Hash {
+                info_hash,
+                index: *index,
+                valid: false,
+            },
+            IoJob::Validate { index, .. } => IoDone::Validate {
+                info_hash,
+                index: *index,
+                valid: false,
+            },
+            IoJob::Move { path, part } => IoDone::Move {
+                info_hash,
+                path: path.clone(),
+                part: *part,
+                result: Err(Error::IoJobPanicked),
+            },
+            IoJob::Create { new, .. } => IoDone::Create {
+                info_hash,
+                new: *new,
+                result: Err(Error::IoJobPanicked),
+            },
+            IoJob::Flush => IoDone::Flush {
+                info_hash,
+                result: Err(Error::IoJobPanicked),
+            },
+            IoJob::Remove => IoDone::Remove {
+                info_hash,
+                result: Err(Error::IoJobPanicked),
+            },
+            IoJob::SaveResume { .. } => IoDone::SaveResume {
+                info_hash,
+                result: Err(Error::IoJobPanicked),
+            },
+        }
+    }
+
+    /// Run the job, on the thread of a worker.
+    fn run(self) -> IoDone {
+        let storage = &*self.storage;
+        let info_hash = self.torrent_ctx.info_hash;
+        let info = self.torrent_ctx.info.blocking_read();
+
+        match self.job {
+            IoJob::Read {
+                block_info,
+                whole_piece,
+                upload,
+                recipient,
+            } => {
+                let result = if whole_piece {
+                    let index = block_info.index as usize;
+                    let piece = BlockInfo {
+                        index: block_info.index,
+                        begin: 0,
+                        len: info.piece_size(index),
+                    };
+
+                    storage::read_block(storage, info_hash, &info, &piece).and_then(|piece| {
+                        let begin = block_info.begin as usize;
+                        let block = piece
+                            .get(begin..begin + block_info.len as usize)
+                            .ok_or(Error::BlockInvalid)?
+                            .to_vec();
+                        Ok((block, Some(piece)))
+                    })
+                } else {
+                    storage::read_block(storage, info_hash, &info, &block_info)
+                        .map(|block| (block, None))
+                };
+
+                let result = match result {
+                    Ok((block, piece)) => {
+                        let _ = recipient.send(Ok(block));
+                        Ok(piece)
+                    }
+                    Err(e) => {
+                        let reason = storage::error_reason(&e);
+                        let _ = recipient.send(Err(e));
+                        Err(reason)
+                    }
+                };
+
+                IoDone::Read {
+                    info_hash,
+                    block_info,
+                    upload,
+                    result,
+                }
+            }
+            IoJob::Write {
+                index,
+                blocks,
+                validate,
+            } => {
+                let result = match validate {
+                    Validate::No => {
+                        storage::write_blocks(storage, info_hash, &info, index, &blocks)
+                            .map(|_| None)
+                    }
+                    Validate::Memory => {
+                        let mut hash = sha1_smol::Sha1::new();
+                        for block in blocks.values() {
+                            hash.update(block);
+                        }
+
+                        let valid = is_valid(&info, index, hash.digest().bytes());
+
+                        if valid {
+                            storage::write_blocks(storage, info_hash, &info, index, &blocks)
+                                .map(|_| Some(true))
+                        } else {
+                            Ok(Some(false))
+                        }
+                    }
+                    Validate::Storage => {
+                        // a piece that can't be read back is an error
+                        // of the storage, not of the peers that sent it
+                        storage::write_blocks(storage, info_hash, &info, index, &blocks).and_then(
+                            |_| {
+                                let hash = storage.hash_piece(info_hash, &info, index)?;
+                                Ok(Some(is_valid(&info, index, hash)))
+                            },
+                        )
+                    }
+                };
+
+                IoDone::Write {
+                    info_hash,
+                    index,
+                    result,
+                }
+            }
+            IoJob::Hash { index } => {
+                let hash = storage.hash_piece(info_hash, &info, index);
+
+                IoDone::Hash {
+                    info_hash,
+                    index,
+                    valid: hash.is_ok_and(|hash| is_valid(&info, index, hash)),
+                }
+            }
+            IoJob::Validate { index, recipient } => {
+                let hash = storage.hash_piece(info_hash, &info, index);
+
+                let result = match hash {
+                    Ok(hash) if is_valid(&info, index, hash) => Ok(()),
+                    Ok(_) => Err(Error::PieceInvalid),
+                    Err(e) => Err(e),
+                };
+                let valid = result.is_ok();
+                let _ = recipient.send(result);
+
+                IoDone::Validate {
+                    info_hash,
+                    index,
+                    valid,
+                }
+            }
+            IoJob::Move { path, part } => IoDone::Move {
+                info_hash,
+                result: storage.move_to(info_hash, &info, &path, part),
+                path,
+                part,
+            },
+            IoJob::Create { priorities, new } => IoDone::Create {
+                info_hash,
+                new,
+                result: storage.create(info_hash, &info, &priorities),
+            },
+            IoJob::Flush => IoDone::Flush {
+                info_hash,
+                result: storage.flush(info_hash, &info),
+            },
+            IoJob::Remove => {
+                let result = storage
+                    .flush(info_hash, &info)
+                    .and_then(|_| storage.remove(info_hash, &info));
+
+                IoDone::Remove { info_hash, result }
+            }
+            IoJob::SaveResume { mut data, dir } => {
+                data.file_sizes = storage.file_sizes(info_hash, &info);
+
+                IoDone::SaveResume {
+                    info_hash,
+                    result: data.save(&dir),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, IoSlice},
+        path::Path,
+        sync::{Condvar, Mutex},
+        time::Duration,
+    };
+
+    use rand::{distributions::Alphanumeric, Rng};
+    use tokio::{
+        sync::{mpsc, oneshot},
+        time::timeout,
+    };
+
+    use crate::{
+        frontend::FrMsg,
+        metainfo::File,
+        storage::{FsStorage, MemoryStorage},
+        torrent::{Torrent, TorrentCtx},
+    };
+
+    use super::*;
+
+    /// A torrent of 2 pieces of 4 bytes, the first one
+    /// is `[1, 1, 1, 1]` and the second one `[2, 2, 2, 2]`.
+    async fn torrent_ctx() -> Arc<TorrentCtx> {
+        let mut pieces = Vec::new();
+        for i in 1..=2_u8 {
+            pieces.extend(sha1_smol::Sha1::from([i; 4]).digest().bytes());
+        }
+
+        let info = Info {
+            file_length: None,
+            name: "arch".to_owned(),
+            piece_length: 4,
+            pieces,
+            files: Some(vec![File {
+                length: 8,
+                path: vec!["foo.txt".to_owned()],
+            }]),
+        };
+
+        let magnet = "magnet:?xt=urn:btih:9999999999999999999999999999999999999999&amp;dn=arch";
+        let (disk_tx, _disk_rx) = mpsc::channel(10);
+        let (fr_tx, _) = mpsc::channel::<FrMsg>(10);
+        let torrent = Torrent::new(disk_tx, fr_tx, magnet);
+        *torrent.ctx.info.write().await = info;
+
+        torrent.ctx
+    }
+
+    /// Send a job and wait until it is done.
+    async fn run(
+        pool: &mut IoPool,
+        torrent_ctx: &Arc<TorrentCtx>,
+        storage: &Arc<dyn Storage>,
+        job: IoJob,
+    ) -> IoDone {
+        pool.send(torrent_ctx.clone(), storage.clone(), job);
+        pool.recv().await.unwrap()
+    }
+
+    fn write(index: usize, begin: u32, block: Vec<u8>, validate: Validate) -> IoJob {
+        IoJob::Write {
+            index,
+            blocks: BTreeMap::from([(begin, block)]),
+            validate,
+        }
+    }
+
+    fn read(
+        index: u32,
+        begin: u32,
+        len: u32,
+        whole_piece: bool,
+    ) -> (IoJob, oneshot::Receiver<Result<Vec<u8>, Error>>) {
+        let (tx, rx) = oneshot::channel();
+        let job = IoJob::Read {
+            block_info: BlockInfo { index, begin, len },
+            whole_piece,
+            upload: true,
+            recipient: tx,
+        };
+        (job, rx)
+    }
+
+    /// A storage in memory, on which the writes to the first piece
+    /// block until they are released.
+    #[derive(Debug, Default)]
+    struct SlowStorage {
+        inner: MemoryStorage,
+        released: Mutex<bool>,
+        release: Condvar,
+    }
+
+    impl SlowStorage {
+        fn release(&self) {
+            *self.released.lock().unwrap() = true;
+            self.release.notify_all();
+        }
+    }
+
+    impl Storage for SlowStorage {
+        fn create(
+            &self,
+            info_hash: [u8; 20],
+            info: &Info,
+            priorities: &[Priority],
+        ) -> Result<(), Error> {
+            self.inner.create(info_hash, info, priorities)
+        }
+
+        fn read_at(
+            &self,
+            info_hash: [u8; 20],
+            info: &Info,
+            file: usize,
+            offset: u64,
+            buf: &mut [u8],
+        ) -> Result<(), Error> {
+            self.inner.read_at(info_hash, info, file, offset, buf)
+        }
+
+        fn write_at(
+            &self,
+            info_hash: [u8; 20],
+            info: &Info,
+            file: usize,
+            offset: u64,
+            bufs: &mut [IoSlice],
+        ) -> Result<(), Error> {
+            if offset < info.piece_length as u64 {
+                let mut released = self.released.lock().unwrap();
+                while !*released {
+                    released = self.release.wait(released).unwrap();
+                }
+            }
+            self.inner.write_at(info_hash, info, file, offset, bufs)
+        }
+
+        fn flush(&self, info_hash: [u8; 20], info: &Info) -> Result<(), Error> {
+            self.inner.flush(info_hash, info)
+        }
+
+        fn remove(&self, info_hash: [u8; 20], info: &Info) -> Result<(), Error> {
+            self.inner.remove(info_hash, info)
+        }
+
+        fn file_sizes(&self, info_hash: [u8; 20], info: &Info) -> Vec<(u64, u64)> {
+            self.inner.file_sizes(info_hash, info)
+        }
+    }
+
+    /// A storage in memory whose files can't be read.
+    #[derive(Debug, Default)]
+    struct WriteOnlyStorage {
+        inner: MemoryStorage,
+    }
+
+    impl Storage for WriteOnlyStorage {
+        fn create(
+            &self,
+            info_hash: [u8; 20],
+            info: &Info,
+            priorities: &[Priority],
+        ) -> Result<(), Error> {
+            self.inner.create(info_hash, info, priorities)
+        }
+
+        fn read_at(
+            &self,
+            _: [u8; 20],
+            _: &Info,
+            _: usize,
+            _: u64,
+            _: &mut [u8],
+        ) -> Result<(), Error> {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied).into())
+        }
+
+        fn write_at(
+            &self,
+            info_hash: [u8; 20],
+            info: &Info,
+            file: usize,
+            offset: u64,
+            bufs: &mut [IoSlice],
+        ) -> Result<(), Error> {
+            self.inner.write_at(info_hash, info, file, offset, bufs)
+        }
+
+        fn flush(&self, info_hash: [u8; 20], info: &Info) -> Result<(), Error> {
+            self.inner.flush(info_hash, info)
+        }
+
+        fn remove(&self, info_hash: [u8; 20], info: &Info) -> Result<(), Error> {
+            self.inner.remove(info_hash, info)
+        }
+
+        fn file_sizes(&self, info_hash: [u8; 20], info: &Info) -> Vec<(u64, u64)> {
+            self.inner.file_sizes(info_hash, info)
+        }
+    }
+
+    /// A storage that panics, like it would on a poisoned lock.
+    #[derive(Debug)]
+    struct PanicStorage;
+
+    impl Storage for PanicStorage {
+        fn create(&self, _: [u8; 20], _: &Info, _: &[Priority]) -> Result<(), Error> {
+            panic!("create")
+        }
+
+        fn read_at(
+            &self,
+            _: [u8; 20],
+            _: &Info,
+            _: usize,
+            _: u64,
+            _: &mut [u8],
+        ) -> Result<(), Error> {
+            panic!("read")
+        }
+
+        fn write_at(
+            &self,
+            _: [u8; 20],
+            _: &Info,
+            _: usize,
+            _: u64,
+            _: &mut [IoSlice],
+        ) -> Result<(), Error> {
+            panic!("write")
+        }
+
+        fn flush(&self, _: [u8; 20], _: &Info) -> Result<(), Error> {
+            panic!("flush")
+        }
+
+        fn remove(&self, _: [u8; 20], _: &Info) -> Result<(), Error> {
+            panic!("remove")
+        }
+
+        fn file_sizes(&self, _: [u8; 20], _: &Info) -> Vec<(u64, u64)> {
+            panic!("file sizes")
+        }
+    }
+
+    // the jobs of the same piece run in the order they were sent
+    #[tokio::test]
+    async fn jobs_of_a_piece_are_ordered() {
+        let torrent_ctx = torrent_ctx().await;
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let mut pool = IoPool::new(4);
+        assert_eq!(pool.len(), 4);
+
+        let mut reads = Vec::new();
+
+        for i in 0..50_u8 {
+            pool.send(
+                torrent_ctx.clone(),
+                storage.clone(),
+                write(1, 0, vec![i; 4], Validate::No),
+            );
+
+            let (job, rx) = read(1, 0, 4, false);
+            pool.send(torrent_ctx.clone(), storage.clone(), job);
+            reads.push((i, rx));
+        }
+
+        assert_eq!(pool.in_flight(), 100);
+
+        for (i, rx) in reads {
+            assert_eq!(rx.await.unwrap().unwrap(), vec![i; 4]);
+        }
+
+        while pool.in_flight() > 0 {
+            pool.recv().await.unwrap();
+        }
+    }
+
+    // a write that blocks on the storage does not stall the other pieces
+    #[tokio::test]
+    async fn reads_are_not_blocked_by_a_slow_write() {
+        let torrent_ctx = torrent_ctx().await;
+        let slow = Arc::new(SlowStorage::default());
+        let storage: Arc<dyn Storage> = slow.clone();
+        let mut pool = IoPool::new(2);
+
+        pool.send(
+            torrent_ctx.clone(),
+            storage.clone(),
+            write(0, 0, vec![1; 4], Validate::No),
+        );
+        pool.send(
+            torrent_ctx.clone(),
+            storage.clone(),
+            write(1, 0, vec![2; 4], Validate::No),
+        );
+        let (job, rx) = read(1, 0, 4, true);
+        pool.send(torrent_ctx.clone(), storage.clone(), job);
+
+        let block = timeout(Duration::from_secs(5), rx).await;
+        assert_eq!(block.unwrap().unwrap().unwrap(), vec![2; 4]);
+
+        // the read of the blocked piece waits for its write
+        let (job, mut rx) = read(0, 0, 4, false);
+        pool.send(torrent_ctx.clone(), storage.clone(), job);
+        assert!(timeout(Duration::from_millis(50), &mut rx).await.is_err());
+        assert_eq!(pool.in_flight(), 4);
+        assert_eq!(pool.torrent_in_flight(torrent_ctx.info_hash), 4);
+        assert_eq!(pool.piece_in_flight(torrent_ctx.info_hash, 0), 2);
+
+        slow.release();
+        assert_eq!(rx.await.unwrap().unwrap(), vec![1; 4]);
+
+        while pool.in_flight() > 0 {
+            pool.recv().await.unwrap();
+        }
+        assert_eq!(pool.torrent_in_flight(torrent_ctx.info_hash), 0);
+        assert_eq!(pool.piece_in_flight(torrent_ctx.info_hash, 0), 0);
+    }
+
+    #[tokio::test]
+    async fn validate_pieces() {
+        let torrent_ctx = torrent_ctx().await;
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let mut pool = IoPool::new(2);
+
+        let write_result = |done: IoDone| match done {
+            IoDone::Write { index, result, .. } => (index, result.unwrap()),
+            done => panic!("{done:?}"),
+        };
+
+        // all the blocks are in memory
+        let done = run(
+            &mut pool,
+            &torrent_ctx,
+            &storage,
+            write(1, 0, vec![9; 4], Validate::Memory),
+        )
+        .await;
+        assert_eq!(write_result(done), (1, Some(false)));
+
+        // an invalid piece is not written
+        let (job, rx) = read(1, 0, 4, false);
+        run(&mut pool, &torrent_ctx, &storage, job).await;
+        assert!(rx.await.unwrap().is_err());
+
+        let done = run(
+            &mut pool,
+            &torrent_ctx,
+            &storage,
+            write(0, 0, vec![1; 4], Validate::Memory),
+        )
+        .await;
+        assert_eq!(write_result(done), (0, Some(true)));
+
+        // half of the piece is already on the storage
+        let done = run(
+            &mut pool,
+            &torrent_ctx,
+            &storage,
+            write(1, 0, vec![2; 2], Validate::No),
+        )
+        .await;
+        assert_eq!(write_result(done), (1, None));
+
+        let done = run(
+            &mut pool,
+            &torrent_ctx,
+            &storage,
+            write(1, 2, vec![2; 2], Validate::Storage),
+        )
+        .await;
+        assert_eq!(write_result(done), (1, Some(true)));
+
+        let done = run(
+            &mut pool,
+            &torrent_ctx,
+            &storage,
+            write(0, 2, vec![7; 2], Validate::Storage),
+        )
+        .await;
+        assert_eq!(write_result(done), (0, Some(false)));
+
+        // the pieces on the storage
+        for (index, expected) in [(0, false), (1, true)] {
+            match run(&mut pool, &torrent_ctx, &storage, IoJob::Hash { index }).await {
+                IoDone::Hash {
+                    index: i, valid, ..
+                } => assert_eq!((i, valid), (index, expected)),
+                done => panic!("{done:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn move_files() {
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
+        let save_path = Path::new(&download_dir).join("complete");
+
+        let torrent_ctx = torrent_ctx().await;
+        let storage: Arc<dyn Storage> = Arc::new(FsStorage::new(download_dir.clone()));
+        let mut pool = IoPool::new(2);
+
+        storage.set_part_files(torrent_ctx.info_hash, true);
+        storage
+            .create(
+                torrent_ctx.info_hash,
+                &*torrent_ctx.info.read().await,
+                &[Priority::Normal],
+            )
+            .unwrap();
+        run(
+            &mut pool,
+            &torrent_ctx,
+            &storage,
+            write(0, 0, vec![1; 4], Validate::No),
+        )
+        .await;
+
+        let job = IoJob::Move {
+            path: save_path.clone(),
+            part: false,
+        };
+        match run(&mut pool, &torrent_ctx, &storage, job).await {
+            IoDone::Move {
+                path, part, result, ..
+            } => {
+                assert_eq!(path, save_path);
+                assert!(!part);
+                result.unwrap();
+            }
+            done => panic!("{done:?}"),
+        }
+
+        assert_eq!(
+            std::fs::read(save_path.join("arch/foo.txt")).unwrap(),
+            vec![1; 4]
+        );
+        assert!(!Path::new(&download_dir).join("arch/foo.txt.part").exists());
+
+        // the files are read from the new path
+        let (job, rx) = read(0, 0, 4, false);
+        run(&mut pool, &torrent_ctx, &storage, job).await;
+        assert_eq!(rx.await.unwrap().unwrap(), vec![1; 4]);
+
+        std::fs::remove_dir_all(download_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        let torrent_ctx = torrent_ctx().await;
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let mut pool = IoPool::new(2);
+
+        run(
+            &mut pool,
+            &torrent_ctx,
+            &storage,
+            write(0, 0, vec![1; 4], Validate::No),
+        )
+        .await;
+
+        // a block outside of the torrent, and a block
+        // outside of its piece, when the whole piece is read
+        for (index, begin, whole_piece) in [(5, 0, false), (0, 2, true)] {
+            let (job, rx) = read(index, begin, 4, whole_piece);
+            match run(&mut pool, &torrent_ctx, &storage, job).await {
+                IoDone::Read { result, .. } => assert_eq!(result, Err(None)),
+                done => panic!("{done:?}"),
+            }
+            assert!(matches!(rx.await.unwrap(), Err(Error::BlockInvalid)));
+        }
+
+        // a piece that can't be read back to be hashed is an error, not an invalid piece
+        let storage: Arc<dyn Storage> = Arc::new(WriteOnlyStorage::default());
+        let job = write(0, 0, vec![1; 4], Validate::Storage);
+        match run(&mut pool, &torrent_ctx, &storage, job).await {
+            IoDone::Write { result, .. } => assert!(matches!(result, Err(Error::Io(_)))),
+            done => panic!("{done:?}"),
+        }
+
+        // a job that panics is done, and the worker runs the next jobs
+        let storage: Arc<dyn Storage> = Arc::new(PanicStorage);
+
+        for _ in 0..2 {
+            let (job, rx) = read(0, 0, 4, false);
+            match run(&mut pool, &torrent_ctx, &storage, job).await {
+                IoDone::Read { result, .. } => assert_eq!(result, Err(None)),
+                done => panic!("{done:?}"),
+            }
+            assert!(rx.await.is_err());
+
+            let job = write(0, 0, vec![1; 4], Validate::No);
+            match run(&mut pool, &torrent_ctx, &storage, job).await {
+                IoDone::Write { result, .. } => {
+                    assert!(matches!(result, Err(Error::IoJobPanicked)))
+                }
+                done => panic!("{done:?}"),
+            }
+        }
+
+        assert_eq!(pool.in_flight(), 0);
+    }
+}
@@ -1,16 +1,25 @@
 #![allow(missing_docs)]
 pub mod avg;
 pub mod bitfield;
+pub mod cache;
+pub mod choker;
 pub mod cli;
 pub mod config;
 pub mod counter;
+pub mod dht;
 pub mod disk;
 pub mod error;
 pub mod extension;
+pub mod file_pool;
 pub mod frontend;
+pub mod io_pool;
 pub mod magnet_parser;
 pub mod metainfo;
 pub mod peer;
+pub mod picker;
+pub mod resume;
+pub mod storage;
+pub mod stream;
 pub mod tcp_wire;
 pub mod torrent;
 pub mod tracker;
@@ -10,18 +10,13 @@ pub fn get_magnet(str: &str) -> Result<Magnet, MagnetError> {
         }
     }
 
-    // Remove URL encoding of trackers URLs
+    // Remove URL encoding of trackers URLs,
+    // the scheme is kept to know which protocol the tracker speaks.
     let tr: Vec<String> =
-        m.tr.iter_mut()
-            .map(|x| {
-                *x = urlencoding::decode(x).unwrap().to_string();
-                *x = x.replace("http://", "");
-                *x = x.replace("udp://", "");
-                // remove any /announce
-                if let Some(i) = x.find('/') {
-                    *x = x[..i].to_string();
-                };
-                x.to_owned()
+        m.tr.iter()
+            .map(|x| match urlencoding::decode(x) {
+                Ok(x) => x.to_string(),
+                Err(_) => x.to_owned(),
             })
             .collect();
     m.tr = tr;
@@ -29,6 +24,18 @@ pub fn get_magnet(str: &str) -> Result<Magnet, MagnetError> {
     Ok(m)
 }
 
+/// Transform the URL of an UDP tracker into an address
+/// that can be used to connect to it.
+/// i.e: "udp://tracker.opentrackr.org:1337/announce" -> "tracker.opentrackr.org:1337"
+pub fn get_tracker_addr(url: &str) -> String {
+    let mut x = url.replace("udp://", "");
+    // remove any /announce
+    if let Some(i) = x.find('/') {
+        x = x[..i].to_string();
+    };
+    x
+}
+
 /// The info_hash from the magnet link is already
 /// encoded in SHA1
 pub fn get_info_hash(info: &str) -> [u8; 20] {
@@ -39,3 +46,26 @@ pub fn get_info_hash(info: &str) -> [u8; 20] {
 
     x
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn magnet_keeps_tracker_urls() {
+        let magnet = "magnet:?xt=urn:btih:9999999999999999999999999999999999999999&dn=foo&tr=udp%3A%2F%2Ftracker.opentrackr.org%3A1337%2Fannounce&tr=http%3A%2F%2Fbttracker.debian.org%3A6969%2Fannounce";
+        let magnet = get_magnet(magnet).unwrap();
+
+        assert_eq!(
+            magnet.tr,
+            vec![
+                "udp://tracker.opentrackr.org:1337/announce".to_owned(),
+                "http://bttracker.debian.org:6969/announce".to_owned(),
+            ]
+        );
+        assert_eq!(
+            get_tracker_addr(&magnet.tr[0]),
+            "tracker.opentrackr.org:1337".to_owned()
+        );
+    }
+}
@@ -1,6 +1,10 @@
 #![allow(missing_docs)]
 #![allow(rustdoc::missing_doc_code_examples)]
-use std::path::Path;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+};
 
 use tokio::{
     fs::{create_dir_all, OpenOptions},
@@ -10,13 +14,17 @@ use tokio::{
 use clap::Parser;
 use directories::{ProjectDirs, UserDirs};
 use tokio::{io::AsyncReadExt, runtime::Runtime, spawn, sync::mpsc};
+use tracing::warn;
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
 use vcz::{
     cli::Args,
     config::Config,
+    dht::{Dht, BOOTSTRAP_NODES},
     disk::{Disk, DiskMsg},
     error::Error,
     frontend::{FrMsg, Frontend},
+    storage::FsStorage,
+    stream::StreamServer,
 };
 
 #[tokio::main]
@@ -57,6 +65,7 @@ async fn main() -> Result<(), Error> {
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open(&config_path)
         .await
         .expect("Error while trying to open the project config folder, please make sure this program has the right permissions.");
@@ -85,6 +94,12 @@ async fn main() -> Result<(), Error> {
         let config_local = Config {
             download_dir,
             listen: None,
+            stream: None,
+            cache_size: None,
+            read_cache_size: None,
+            allocation: None,
+            incomplete_dir: None,
+            part_files: None,
         };
 
         let config_str = toml::to_string(&config_local).unwrap();
@@ -104,6 +119,24 @@ async fn main() -> Result<(), Error> {
     let (disk_tx, disk_rx) = mpsc::channel::<DiskMsg>(300);
     let mut disk = Disk::new(disk_rx, d.clone());
 
+    // the state of the torrents is saved in the data dir, to resume them
+    let resume_dir = dotfile.data_dir().join("resume");
+    disk.resume_dir = Some(resume_dir.clone());
+
+    if let Some(cache_size) = config.cache_size {
+        disk.write_cache.capacity = cache_size * 1024 * 1024;
+    }
+
+    if let Some(read_cache_size) = config.read_cache_size {
+        disk.read_cache.capacity = read_cache_size * 1024 * 1024;
+    }
+
+    if let Some(allocation) = config.allocation {
+        let mut storage = FsStorage::new(d.clone());
+        storage.allocation = allocation;
+        disk.storage = Arc::new(storage);
+    }
+
     if !Path::new(&d).exists() {
         return Err(Error::FolderOpenError(d));
     }
@@ -115,9 +148,53 @@ async fn main() -> Result<(), Error> {
         });
     });
 
+    // Start the DHT node, on UDP, with the same port that we use
+    // to listen for peers. The routing table is saved in the data dir.
+    let listen = args.listen.or(config.listen);
+    let dht_addr = SocketAddr::new(
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        listen.map(|l| l.port()).unwrap_or(0),
+    );
+    let bootstrap = BOOTSTRAP_NODES.iter().map(|n| n.to_string()).collect();
+
+    let dht_tx = match Dht::new(
+        dht_addr,
+        Some(dotfile.data_dir().join("dht.dat")),
+        bootstrap,
+    )
+    .await
+    {
+        Ok(mut dht) => {
+            let dht_tx = dht.tx.clone();
+            spawn(async move {
+                dht.run().await.unwrap();
+            });
+            Some(dht_tx)
+        }
+        Err(e) => {
+            warn!("could not start the DHT: {e}");
+            None
+        }
+    };
+
+    // Serve the files of the torrents over HTTP, to play them while they are downloaded.
+    if let Some(stream) = args.stream.or(config.stream) {
+        match StreamServer::new(stream, disk_tx.clone()).await {
+            Ok(server) => {
+                spawn(async move {
+                    server.run().await.unwrap();
+                });
+            }
+            Err(e) => {
+                warn!("could not start the streaming server: {e}");
+            }
+        }
+    }
+
     // Start and run the terminal UI
     let (fr_tx, fr_rx) = mpsc::channel::<FrMsg>(300);
-    let mut fr = Frontend::new(fr_tx.clone(), disk_tx.clone(), config.clone());
+    let mut fr = Frontend::new(fr_tx.clone(), disk_tx.clone(), dht_tx, config.clone());
+    fr.resume_dir = Some(resume_dir);
 
     spawn(async move {
         fr.run(fr_rx).await.unwrap();
@@ -126,7 +203,18 @@ async fn main() -> Result<(), Error> {
     // If the user passed a magnet through the CLI,
     // start this torrent immediately
     if let Some(magnet) = args.magnet {
-        fr_tx.send(FrMsg::NewTorrent(magnet)).await.unwrap();
+        fr_tx
+            .send(FrMsg::NewTorrent(magnet, args.save_path.clone()))
+            .await
+            .unwrap();
+    }
+
+    // Same thing for a .torrent file
+    if let Some(torrent) = args.torrent {
+        fr_tx
+            .send(FrMsg::NewTorrent(torrent, args.save_path))
+            .await
+            .unwrap();
     }
 
     handle.join().unwrap();
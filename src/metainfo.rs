@@ -1,7 +1,7 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, ops::Range, str::FromStr};
 
 use bendy::{
-    decoding::{self, FromBencode, Object, ResultExt},
+    decoding::{self, Decoder, FromBencode, Object, ResultExt},
     encoding::{self, AsString, Error, SingleItemEncoder, ToBencode},
 };
 
@@ -20,6 +20,78 @@ pub struct MetaInfo {
     pub http_seeds: Option<Vec<String>>,
 }
 
+impl MetaInfo {
+    /// Get the URLs of all trackers of the torrent, without duplicates.
+    /// `announce_list` takes precedence over `announce`, as per BEP 12.
+    pub fn trackers(&self) -> Vec<String> {
+        let mut trackers: Vec<String> = Vec::new();
+
+        let urls = self
+            .announce_list
+            .iter()
+            .flatten()
+            .flatten()
+            .chain(std::iter::once(&self.announce));
+
+        for url in urls {
+            if !url.is_empty() && !trackers.contains(url) {
+                trackers.push(url.to_owned());
+            }
+        }
+
+        trackers
+    }
+
+    /// Get the URLs of the trackers grouped by tiers, without duplicates,
+    /// as per BEP 12. Without an `announce_list`, `announce` is the only tier.
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        let mut seen: Vec<&String> = Vec::new();
+        let mut tiers: Vec<Vec<String>> = Vec::new();
+
+        let announce_list = self
+            .announce_list
+            .clone()
+            .filter(|list| list.iter().flatten().any(|url| !url.is_empty()))
+            .unwrap_or_else(|| vec![vec![self.announce.clone()]]);
+
+        for tier in &announce_list {
+            let mut urls = Vec::new();
+
+            for url in tier {
+                if !url.is_empty() && !seen.contains(&url) {
+                    seen.push(url);
+                    urls.push(url.to_owned());
+                }
+            }
+
+            if !urls.is_empty() {
+                tiers.push(urls);
+            }
+        }
+
+        tiers
+    }
+
+    /// Get the raw bytes of the "info" dict of a bencoded .torrent file.
+    /// The info_hash must be computed on these exact bytes,
+    /// and not on a re-encoded [`Info`], which may drop unknown keys.
+    pub fn raw_info(buf: &[u8]) -> Result<&[u8], decoding::Error> {
+        let mut decoder = Decoder::new(buf);
+        let object = decoder
+            .next_object()?
+            .ok_or_else(|| decoding::Error::missing_field("info"))?;
+        let mut dict_dec = object.try_into_dictionary()?;
+
+        while let Some(pair) = dict_dec.next_pair()? {
+            if let (b"info", value) = pair {
+                return value.try_into_dictionary()?.into_raw();
+            }
+        }
+
+        Err(decoding::Error::missing_field("info"))
+    }
+}
+
 /// File related information (Single-file format)
 /// https://fileformats.fandom.com/wiki/Torrent_file
 /// in a multi file format, `name` is name of the directory
@@ -34,7 +106,7 @@ pub struct Info {
     /// name of the file
     pub name: String,
     /// length - bytes of the entire file
-    pub file_length: Option<u32>,
+    pub file_length: Option<u64>,
     pub files: Option<Vec<File>>,
 }
 
@@ -55,13 +127,20 @@ impl Info {
     pub fn blocks_per_piece(&self) -> u32 {
         self.piece_length / BLOCK_LEN
     }
+    /// Get the size of the given piece, in bytes.
+    /// The last piece of the torrent may be smaller than `piece_length`.
+    pub fn piece_size(&self, index: usize) -> u32 {
+        let begin = index as u64 * self.piece_length as u64;
+        let left = self.get_size().saturating_sub(begin);
+        left.min(self.piece_length as u64) as u32
+    }
     /// Get all block_infos of a torrent
     /// Returns an Err if the Info is malformed, if it does not have `files` or `file_length`.
     pub fn get_block_infos(&self) -> Result<VecDeque<BlockInfo>, error::Error> {
         // multi file torrent
         if let Some(files) = &self.files {
             let mut infos: VecDeque<BlockInfo> = VecDeque::new();
-            for (_, file) in files.iter().enumerate() {
+            for file in files.iter() {
                 let back = infos.back();
                 let r = file.get_block_infos(self.piece_length, back);
                 infos.extend(r);
@@ -79,42 +158,155 @@ impl Info {
         }
         Err(error::Error::FileOpenError("".to_owned()))
     }
+    /// Get the files of the torrent, a single file torrent
+    /// has one file with the name of the torrent.
+    pub fn get_files(&self) -> Vec<File> {
+        if let Some(files) = &self.files {
+            return files.clone();
+        }
+
+        vec![File {
+            length: self.file_length.unwrap_or(0),
+            path: vec![self.name.to_owned()],
+        }]
+    }
+    /// Get the files of the torrent, with the range of pieces that each file
+    /// is part of. The first and last pieces may be shared with other files.
+    pub fn get_files_pieces(&self) -> Vec<(File, Range<usize>)> {
+        let piece_length = self.piece_length.max(1) as u64;
+        let mut offset: u64 = 0;
+
+        self.get_files()
+            .into_iter()
+            .map(|file| {
+                let start = (offset / piece_length) as usize;
+                // empty files have an empty range
+                let end = if file.length == 0 {
+                    start
+                } else {
+                    (offset + file.length).div_ceil(piece_length) as usize
+                };
+                offset += file.length;
+                (file, start..end)
+            })
+            .collect()
+    }
+    /// Get the priority of each piece, given the priority of each file.
+    /// A piece shared by more than one file has the highest priority of
+    /// those files, so a piece is only skipped if all of its files are
+    /// skipped. Files without a priority in `files` are `Normal`.
+    pub fn piece_priorities(&self, files: &[Priority]) -> Vec<Priority> {
+        let files_pieces = self.get_files_pieces();
+        let len = files_pieces.iter().map(|(_, r)| r.end).max().unwrap_or(0);
+        let mut pieces = vec![Priority::Skip; len];
+
+        for (i, (_, range)) in files_pieces.into_iter().enumerate() {
+            let priority = files.get(i).copied().unwrap_or_default();
+
+            for piece in &mut pieces[range] {
+                *piece = (*piece).max(priority);
+            }
+        }
+
+        pieces
+    }
+    /// Get the number of bytes that will be downloaded, of the pieces that
+    /// are not skipped, given the priority of each file.
+    pub fn wanted_size(&self, files: &[Priority]) -> u64 {
+        self.piece_priorities(files)
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| **p != Priority::Skip)
+            .map(|(i, _)| self.piece_size(i) as u64)
+            .sum()
+    }
     /// Get the total size of the torrent, in bytes.
     pub fn get_size(&self) -> u64 {
         // multi file torrent
         if let Some(files) = &self.files {
-            return files.iter().fold(0, |acc, x| acc + x.length as u64);
+            return files.iter().fold(0, |acc, x| acc + x.length);
         }
 
         // single file torrent
         if let Some(f) = self.file_length {
-            return f as u64;
+            return f;
         }
 
         0
     }
 }
 
+/// The priority of a file, set by the user. The pieces of files
+/// with a higher priority are downloaded first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// The file is not downloaded.
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(Self::Skip),
+            "low" => Ok(Self::Low),
+            "normal" => Ok(Self::Normal),
+            "high" => Ok(Self::High),
+            _ => Err(format!(
+                "invalid priority `{s}`, expected one of: skip, low, normal, high"
+            )),
+        }
+    }
+}
+
+impl From<Priority> for &str {
+    fn from(val: Priority) -> Self {
+        match val {
+            Priority::Skip => "Skip",
+            Priority::Low => "Low",
+            Priority::Normal => "Normal",
+            Priority::High => "High",
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Default)]
 pub struct File {
-    pub length: u32,
+    pub length: u64,
     pub path: Vec<String>,
 }
 
 impl File {
     /// Get the len of the given piece in the file, in bytes..
     pub fn get_piece_len(&self, piece: u32, piece_length: u32) -> u32 {
-        let b = (piece * piece_length) + piece_length;
+        let b = (piece as u64 * piece_length as u64) + piece_length as u64;
         if b <= self.length {
             piece_length
         } else {
-            self.length % piece_length
+            (self.length % piece_length as u64) as u32
         }
     }
     /// Return the number of pieces in the file, rounded up.
     pub fn pieces(&self, piece_length: u32) -> u32 {
-        let pieces = self.length as f32 / piece_length as f32;
-        pieces.ceil() as u32
+        self.length.div_ceil(piece_length as u64) as u32
+    }
+    /// If the file is a video or music, by the extension of its name.
+    pub fn is_media(&self) -> bool {
+        const EXTENSIONS: [&str; 16] = [
+            "mkv", "mp4", "m4v", "avi", "webm", "mov", "wmv", "flv", "ts", "mpg", "mp3", "flac",
+            "ogg", "opus", "m4a", "wav",
+        ];
+
+        self.path
+            .last()
+            .and_then(|name| name.rsplit_once('.'))
+            .map(|(_, ext)| EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+            .unwrap_or(false)
     }
     /// Get all block infos of the File.
     pub fn get_block_infos(
@@ -124,16 +316,16 @@ impl File {
     ) -> VecDeque<BlockInfo> {
         #[decurse::decurse_unsound]
         fn partition(
-            file: &File,
             piece_length: u32,
             infos: &mut VecDeque<BlockInfo>,
             prev_block_file: Option<&BlockInfo>,
-            file_length: u32,
+            file_length: u64,
         ) -> VecDeque<BlockInfo> {
             let prev_block = infos.back();
             if let Some(prev) = prev_block {
                 // global cursor, where we are in the torrent (all files)
-                let mut cursor: u64 = (prev.index * piece_length + prev.begin + prev.len).into();
+                let mut cursor: u64 =
+                    prev.index as u64 * piece_length as u64 + prev.begin as u64 + prev.len as u64;
 
                 // transform global cursor into a
                 // local cursor, where we are in the file
@@ -143,7 +335,7 @@ impl File {
                     cursor -= prev_bytes;
                 }
 
-                if cursor >= file_length as u64 {
+                if cursor >= file_length {
                     return std::mem::take(infos);
                 }
 
@@ -154,15 +346,13 @@ impl File {
                 };
 
                 // free space available in the file
-                let available = file_length as u64 - cursor;
+                let available = file_length - cursor;
 
                 // align len with file boundary
-                let mut len = BLOCK_LEN.min(available as u32);
+                let mut len = available.min(BLOCK_LEN as u64) as u32;
 
                 // align len with piece boundary
-                if (begin + len) > piece_length {
-                    len -= (begin + len) % piece_length;
-                }
+                len = len.min(piece_length - begin);
 
                 let index = if prev.begin + prev.len >= piece_length {
                     prev.index + 1
@@ -171,13 +361,9 @@ impl File {
                 };
 
                 infos.push_back(BlockInfo { index, begin, len });
-                partition(file, piece_length, infos, prev_block_file, file_length)
+                partition(piece_length, infos, prev_block_file, file_length)
             } else {
-                let len = if file_length >= BLOCK_LEN {
-                    BLOCK_LEN
-                } else {
-                    file_length % BLOCK_LEN
-                };
+                let len = file_length.min(BLOCK_LEN.min(piece_length) as u64) as u32;
                 let mut b = BlockInfo {
                     index: 0,
                     begin: 0,
@@ -191,16 +377,10 @@ impl File {
                     };
 
                     // file boundary
-                    let mut len = if file_length >= BLOCK_LEN {
-                        BLOCK_LEN
-                    } else {
-                        file_length % BLOCK_LEN
-                    };
+                    let mut len = file_length.min(BLOCK_LEN as u64) as u32;
 
                     // piece boundary
-                    if (begin + len) > piece_length {
-                        len -= (begin + len) % piece_length;
-                    }
+                    len = len.min(piece_length - begin);
 
                     b = BlockInfo {
                         index: if prev.begin + prev.len >= piece_length {
@@ -213,11 +393,11 @@ impl File {
                     };
                 };
                 infos.push_back(b);
-                return partition(file, piece_length, infos, prev_block_file, file_length);
+                partition(piece_length, infos, prev_block_file, file_length)
             }
         }
         let mut infos = VecDeque::new();
-        partition(self, piece_length, &mut infos, prev_block_file, self.length)
+        partition(piece_length, &mut infos, prev_block_file, self.length)
     }
 }
 
@@ -245,7 +425,7 @@ impl FromBencode for File {
         while let Some(pair) = dict_dec.next_pair()? {
             match pair {
                 (b"length", value) => {
-                    length = u32::decode_bencode_object(value).context("length")?;
+                    length = u64::decode_bencode_object(value).context("length")?;
                 }
                 (b"path", value) => {
                     path = Vec::<String>::decode_bencode_object(value).context("path")?;
@@ -390,7 +570,7 @@ impl FromBencode for Info {
                         .map(Some)?;
                 }
                 (b"length", value) => {
-                    file_length = u32::decode_bencode_object(value)
+                    file_length = u64::decode_bencode_object(value)
                         .context("file.length")
                         .map(Some)?;
                 }
@@ -643,6 +823,181 @@ mod tests {
         );
     }
 
+    /// piece_length: 1048576
+    /// -----------------------------------------------
+    /// | f: 3 GiB       | f: 2 GiB + 100     | f: 46 |
+    /// ---------------------------p-------------------
+    ///                            ^ 4 GiB, piece 4096
+    #[test]
+    fn get_files_pieces() {
+        let info = Info {
+            piece_length: 10,
+            files: Some(vec![
+                File {
+                    length: 25,
+                    path: vec!["movie.mkv".to_owned()],
+                },
+                File {
+                    length: 0,
+                    path: vec!["empty".to_owned()],
+                },
+                File {
+                    length: 10,
+                    path: vec!["subs".to_owned(), "en.srt".to_owned()],
+                },
+            ]),
+            ..Default::default()
+        };
+
+        let ranges: Vec<Range<usize>> = info
+            .get_files_pieces()
+            .into_iter()
+            .map(|(_, range)| range)
+            .collect();
+
+        assert_eq!(ranges, vec![0..3, 2..2, 2..4]);
+
+        let files = info.get_files();
+        assert!(files[0].is_media());
+        assert!(!files[1].is_media());
+        assert!(!files[2].is_media());
+
+        let info = Info {
+            name: "song.FLAC".to_owned(),
+            piece_length: 10,
+            file_length: Some(5),
+            ..Default::default()
+        };
+
+        let files = info.get_files_pieces();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].1, 0..1);
+        assert!(files[0].0.is_media());
+    }
+
+    #[test]
+    fn piece_priorities() {
+        // pieces:  |0        |1        |2        |3   |
+        // files:   |a             |b   |c             |
+        let info = Info {
+            piece_length: 10,
+            files: Some(vec![
+                File {
+                    length: 15,
+                    path: vec!["a".to_owned()],
+                },
+                File {
+                    length: 5,
+                    path: vec!["b".to_owned()],
+                },
+                File {
+                    length: 15,
+                    path: vec!["c".to_owned()],
+                },
+            ]),
+            ..Default::default()
+        };
+
+        let files = [Priority::Skip, Priority::Low, Priority::Skip];
+
+        assert_eq!(
+            info.piece_priorities(&files),
+            vec![
+                Priority::Skip,
+                Priority::Low,
+                Priority::Skip,
+                Priority::Skip
+            ]
+        );
+        assert_eq!(info.wanted_size(&files), 10);
+
+        // a piece shared by a wanted and a skipped file is downloaded
+        let files = [Priority::High, Priority::Skip];
+
+        assert_eq!(
+            info.piece_priorities(&files),
+            vec![
+                Priority::High,
+                Priority::High,
+                Priority::Normal,
+                Priority::Normal
+            ]
+        );
+        assert_eq!(info.wanted_size(&[]), info.get_size());
+
+        assert_eq!("HIGH".parse(), Ok(Priority::High));
+        assert!("urgent".parse::<Priority>().is_err());
+    }
+
+    #[test]
+    fn get_block_infos_larger_than_4_gib() {
+        const GIB: u64 = 1024 * 1024 * 1024;
+
+        let info = Info {
+            piece_length: 1048576,
+            files: Some(vec![
+                File {
+                    length: 3 * GIB,
+                    path: vec!["a.iso".to_owned()],
+                },
+                File {
+                    length: 2 * GIB + 100,
+                    path: vec!["b.iso".to_owned()],
+                },
+                File {
+                    length: 46,
+                    path: vec!["c.txt".to_owned()],
+                },
+            ]),
+            ..Default::default()
+        };
+
+        assert_eq!(info.get_size(), 5 * GIB + 146);
+        assert_eq!(info.piece_size(4096), 1048576);
+        assert_eq!(info.piece_size(5120), 146);
+        assert_eq!(info.piece_size(5121), 0);
+
+        let files = info.files.as_ref().unwrap();
+        assert_eq!(files[0].pieces(info.piece_length), 3072);
+        assert_eq!(files[1].pieces(info.piece_length), 2049);
+        assert_eq!(files[1].get_piece_len(2048, info.piece_length), 100);
+
+        let blocks = info.get_block_infos().unwrap();
+
+        let total = blocks.iter().fold(0, |acc, b| acc + b.len as u64);
+        assert_eq!(total, info.get_size());
+
+        // the first block after the 4 GiB boundary
+        let block = blocks
+            .iter()
+            .find(|b| b.index as u64 * info.piece_length as u64 + b.begin as u64 == 4 * GIB);
+        assert_eq!(
+            block,
+            Some(&BlockInfo {
+                index: 4096,
+                begin: 0,
+                len: BLOCK_LEN,
+            })
+        );
+
+        // the last block of the second file and the only block of the last file
+        assert_eq!(
+            blocks.iter().rev().take(2).collect::<Vec<_>>(),
+            vec![
+                &BlockInfo {
+                    index: 5120,
+                    begin: 100,
+                    len: 46,
+                },
+                &BlockInfo {
+                    index: 5120,
+                    begin: 0,
+                    len: 100,
+                },
+            ]
+        );
+    }
+
     #[test]
     fn utility_functions_complex_multi() -> Result<(), Error> {
         //
@@ -694,14 +1049,14 @@ mod tests {
         assert_eq!(26, pieces_file);
         assert_eq!(26384160, file_bytes);
         assert_eq!(26384160, file0.length);
-        assert_eq!(file0_len as u64, file0.length as u64);
+        assert_eq!(file0_len as u64, file0.length);
 
         println!("infos_file {:#?}", file_infos.len());
         println!("blocks {:#?}", blocks_file);
         println!("pieces {:#?}", pieces_file);
         println!("blocks per piece {:#?}", pieces_file);
 
-        let block = bi.get(0).unwrap();
+        let block = bi.front().unwrap();
         // println!("--- piece 0, block 0 (first) ---");
         assert_eq!(
             *block,
@@ -769,7 +1124,7 @@ mod tests {
         println!("--- piece 25, block 1610 (last of file) ---");
         println!("{block:#?}");
 
-        let bytes_so_far = bi.iter().take(1611).fold(0, |acc, x| acc + x.len);
+        let bytes_so_far = bi.iter().take(1611).fold(0, |acc, x| acc + x.len as u64);
         assert_eq!(bytes_so_far, file0.length);
 
         assert_eq!(
@@ -787,7 +1142,7 @@ mod tests {
 
         let pieces_file = file1.pieces(info.piece_length);
         let blocks_file = pieces_file * per_piece;
-        let file_len = infos1.iter().fold(0, |acc, x| acc + x.len);
+        let file_len = infos1.iter().fold(0, |acc, x| acc + x.len as u64);
         println!("--- file[1] ---");
         println!("{file1:#?}");
 
@@ -871,7 +1226,7 @@ mod tests {
         assert_eq!(per_piece, 1);
         assert_eq!(pieces, 250);
 
-        let block = bi.get(0).unwrap();
+        let block = bi.front().unwrap();
         println!("--- piece 0, block 0 (only one) ---");
         println!("{block:#?}");
         assert_eq!(
@@ -944,7 +1299,7 @@ mod tests {
         assert_eq!(per_piece, 16);
         assert_eq!(pieces, 1164);
 
-        let block = bi.get(0).unwrap();
+        let block = bi.front().unwrap();
         println!("--- piece 0, block 0 (first) ---");
         println!("{block:#?}");
         assert_eq!(
@@ -1166,4 +1521,86 @@ mod tests {
 
         Ok(())
     }
+
+    /// The raw info of a .torrent file must hash to the real info_hash of the torrent
+    #[test]
+    fn raw_info() -> Result<(), decoding::Error> {
+        let torrent = include_bytes!("../test-files/book.torrent");
+        let raw = MetaInfo::raw_info(torrent)?;
+
+        let mut hash = sha1_smol::Sha1::new();
+        hash.update(raw);
+
+        assert_eq!(
+            hash.digest().to_string(),
+            "9ae9b0407687ffc7b71c11f32e2f961198cc3940"
+        );
+        assert_eq!(
+            Info::from_bencode(raw)?,
+            MetaInfo::from_bencode(torrent)?.info
+        );
+
+        let torrent = include_bytes!("../test-files/debian.torrent");
+        let raw = MetaInfo::raw_info(torrent)?;
+
+        let mut hash = sha1_smol::Sha1::new();
+        hash.update(raw);
+
+        assert_eq!(
+            hash.digest().to_string(),
+            "7431a969b347e14bba641b3517c024f7b40dfb7f"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn trackers() -> Result<(), decoding::Error> {
+        let torrent = include_bytes!("../test-files/debian.torrent");
+        let torrent = MetaInfo::from_bencode(torrent)?;
+
+        assert_eq!(
+            torrent.trackers(),
+            vec!["http://bttracker.debian.org:6969/announce".to_owned()]
+        );
+
+        let torrent = include_bytes!("../test-files/book.torrent");
+        let torrent = MetaInfo::from_bencode(torrent)?;
+        let trackers = torrent.trackers();
+
+        // the announce is also the first url of the announce-list
+        assert_eq!(trackers.len(), 33);
+        assert_eq!(
+            trackers[0],
+            "udp://tracker.leechers-paradise.org:6969/announce"
+        );
+        assert_eq!(trackers[32], "http://tracker.tfile.co:80/announce");
+
+        Ok(())
+    }
+
+    #[test]
+    fn tiers() -> Result<(), decoding::Error> {
+        let torrent = include_bytes!("../test-files/debian.torrent");
+        let torrent = MetaInfo::from_bencode(torrent)?;
+
+        assert_eq!(
+            torrent.tiers(),
+            vec![vec!["http://bttracker.debian.org:6969/announce".to_owned()]]
+        );
+
+        let torrent = include_bytes!("../test-files/book.torrent");
+        let torrent = MetaInfo::from_bencode(torrent)?;
+        let tiers = torrent.tiers();
+
+        assert_eq!(tiers.concat(), torrent.trackers());
+        // each tracker of the book is on its own tier
+        assert_eq!(tiers.len(), 33);
+        assert_eq!(
+            tiers[0],
+            vec!["udp://tracker.leechers-paradise.org:6969/announce".to_owned()]
+        );
+
+        Ok(())
+    }
 }
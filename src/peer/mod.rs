@@ -21,7 +21,7 @@ use crate::{
     bitfield::Bitfield,
     disk::DiskMsg,
     error::Error,
-    extension::{Extension, Metadata},
+    extension::{Extension, Metadata, Pex, UT_METADATA, UT_PEX},
     peer::session::ConnectionState,
     tcp_wire::{
         lib::{Block, BlockInfo, BLOCK_LEN},
@@ -55,6 +55,9 @@ pub enum PeerMsg {
     /// Tell this peer that we are not interested,
     /// update the local state and send a message to the peer
     NotInterested,
+    /// The torrent wants pieces again, after it was complete,
+    /// e.g. when a file that was skipped is not skipped anymore.
+    Interested,
     CancelBlock(BlockInfo),
     CancelMetadata(u32),
     /// Sometimes a peer either takes too long to answer,
@@ -63,6 +66,19 @@ pub enum PeerMsg {
     ReRequest(BlockInfo),
     /// Sent when the torrent has downloaded the entire info
     HaveInfo,
+    /// Sent by the Disk when a piece, which has blocks that
+    /// were sent by this peer, failed the hash check.
+    PieceInvalid(usize),
+    /// The addresses of the peers that the torrent is connected to,
+    /// sent about once a minute, the changes since the last time
+    /// are sent to this peer in a PEX message.
+    Pex(Vec<SocketAddr>),
+    /// Sent by the choker of the torrent, we stop
+    /// allowing the peer to download from us.
+    Choke,
+    /// Sent by the choker of the torrent, we allow
+    /// the peer to download from us.
+    Unchoke,
     Pause,
     Resume,
     /// When the program is being gracefuly shutdown, we need to kill the tokio green thread
@@ -106,6 +122,9 @@ pub struct Peer {
     /// This is a cache of Torrent::have_info,
     /// to avoid using locks or atomics.
     have_info: bool,
+    /// The peers that this peer knows about from our PEX messages,
+    /// the next PEX message is the delta from these peers.
+    pex_sent: HashSet<SocketAddr>,
 }
 
 /// Ctx that is shared with Torrent and Disk;
@@ -118,6 +137,24 @@ pub struct PeerCtx {
     /// Updated when the peer sends us its peer
     /// id, in the handshake.
     pub id: RwLock<Option<[u8; 20]>>,
+    /// TCP addr of the peer, the same as `Peer::addr`.
+    pub addr: SocketAddr,
+    /// Updated by the peer every second.
+    pub stats: RwLock<PeerStats>,
+}
+
+/// A snapshot of the session of a peer, used by
+/// the torrent to choke and unchoke peers.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PeerStats {
+    /// How many bytes per second we download from the peer.
+    pub download_rate: u64,
+    /// How many bytes per second we upload to the peer.
+    pub upload_rate: u64,
+    /// If the peer wants to download pieces that we have.
+    pub peer_interested: bool,
+    /// If the peer is not sending us the blocks that we requested.
+    pub snubbed: bool,
 }
 
 impl Peer {
@@ -133,6 +170,8 @@ impl Peer {
             pieces: RwLock::new(Bitfield::new()),
             id: RwLock::new(None),
             tx: peer_tx,
+            addr,
+            stats: RwLock::new(PeerStats::default()),
         });
 
         Peer {
@@ -140,6 +179,7 @@ impl Peer {
             outgoing_requests: HashSet::default(),
             session: Session::default(),
             have_info: false,
+            pex_sent: HashSet::default(),
             extension: Extension::default(),
             reserved: [0_u8; 8],
             addr,
@@ -159,6 +199,10 @@ impl Peer {
     ) -> Result<Framed<TcpStream, PeerCodec>, Error> {
         let our_handshake = Handshake::new(self.torrent_ctx.info_hash, self.tracker_ctx.peer_id);
 
+        // the torrent might have been created from a .torrent file,
+        // in that case, we already know the info.
+        self.have_info = self.torrent_ctx.info.read().await.piece_length > 0;
+
         self.session.state.connection = ConnectionState::Handshaking;
 
         // we are connecting, send the first handshake
@@ -236,11 +280,8 @@ impl Peer {
             socket.send(Message::Bitfield(bitfield.clone())).await?;
         }
 
-        // todo: implement choke algorithm
-        // send Unchoke
-        self.session.state.am_choking = false;
-        socket.send(Message::Unchoke).await?;
-
+        // the peer starts choked, until the choker of
+        // the torrent decides to unchoke it.
         Ok(socket)
     }
 
@@ -287,6 +328,10 @@ impl Peer {
                             *b = bitfield.clone();
                             drop(b);
 
+                            if self.have_info {
+                                self.send_bitfield_to_disk().await?;
+                            }
+
                             for x in bitfield.into_iter() {
                                 if x.bit == 0 {
                                     info!("{:?} we are interested due to Bitfield", self.addr);
@@ -305,7 +350,8 @@ impl Peer {
                             info!("------------------------------\n");
                         }
                         Message::Unchoke => {
-                            self.session.state.peer_choking = false;
+                            self.session.state.am_choking = false;
+                            self.session.last_unchoke_time = Some(std::time::Instant::now());
                             info!("---------------------------------");
                             info!("| {:?} Unchoke  |", self.addr);
                             info!("---------------------------------");
@@ -317,7 +363,7 @@ impl Peer {
                             info!("---------------------------------\n");
                         }
                         Message::Choke => {
-                            self.session.state.peer_choking = true;
+                            self.session.state.am_choking = true;
                             info!("--------------------------------");
                             info!("| {:?} Choke  |", self.addr);
                             info!("---------------------------------");
@@ -349,6 +395,16 @@ impl Peer {
                             pieces.set(piece);
                             drop(pieces);
 
+                            if self.have_info {
+                                self.disk_tx
+                                    .send(DiskMsg::PeerHave {
+                                        info_hash: self.torrent_ctx.info_hash,
+                                        peer_id: self.ctx.id.read().await.ok_or(Error::PeerIdInvalid)?,
+                                        index: piece,
+                                    })
+                                    .await?;
+                            }
+
                             let torrent_ctx = self.torrent_ctx.clone();
                             let torrent_p = torrent_ctx.pieces.read().await;
                             let bit_item = torrent_p.get(piece);
@@ -420,7 +476,7 @@ impl Peer {
 
                                 self.disk_tx.send(
                                     DiskMsg::ReadBlock {
-                                        b: block_info,
+                                        b: block_info.clone(),
                                         recipient: tx,
                                         info_hash: self.torrent_ctx.info_hash,
                                     }
@@ -429,6 +485,14 @@ impl Peer {
 
                                 let bytes = rx.await??;
 
+                                // the peer may have canceled the request,
+                                // or have been choked, while we read the disk
+                                if !self.incoming_requests.remove(&block_info) {
+                                    continue;
+                                }
+
+                                self.session.update_upload_stats(bytes.len() as u32);
+
                                 let block = Block {
                                     index,
                                     begin,
@@ -467,13 +531,28 @@ impl Peer {
                                 }
                             }
 
+                            // the peer sent us the peers that it connected to
+                            if ext_id == UT_PEX {
+                                info!("-------------------------------------");
+                                info!("| {:?} Pex  |", self.addr);
+                                info!("-------------------------------------");
+
+                                if let Ok(pex) = Pex::from_bencode(&payload) {
+                                    info!("added {} peers, dropped {} peers", pex.added.len(), pex.dropped.len());
+
+                                    if !pex.added.is_empty() {
+                                        self.torrent_ctx.tx.send(TorrentMsg::PexPeers(pex.added)).await?;
+                                    }
+                                }
+                            }
+
                             match self.extension.m.ut_metadata {
                                 // when we send msgs, use the ext_id of the peer
                                 // when we receive msgs, ext_id equals to our ext_id (3)
                                 // if outbound, the peer will set ext_id to MY ut_metadata
                                 // which is 3
                                 // if inbound, i send the data with the ext_id of THE PEER
-                                Some(ut_metadata) if ext_id == 3 => {
+                                Some(ut_metadata) if ext_id == UT_METADATA => {
                                     let t = self.extension.metadata_size.unwrap();
                                     let (metadata, info) = Metadata::extract(payload.clone())?;
 
@@ -595,6 +674,27 @@ impl Peer {
                             self.session.state.am_interested = false;
                             sink.send(Message::NotInterested).await?;
                         }
+                        PeerMsg::Interested => {
+                            if self.session.state.am_interested {
+                                continue;
+                            }
+
+                            let p = self.ctx.pieces.read().await.clone();
+                            for x in p {
+                                if x.bit == 0 {
+                                    info!("{:?} we are interested due to file priorities", self.addr);
+
+                                    self.session.state.am_interested = true;
+                                    sink.send(Message::Interested).await?;
+
+                                    if self.can_request() {
+                                        self.request_block_infos(&mut sink).await?;
+                                    }
+
+                                    break;
+                                }
+                            }
+                        }
                         PeerMsg::Pause => {
                             self.session.state.prev_peer_choking = self.session.state.peer_choking;
 
@@ -647,22 +747,56 @@ impl Peer {
                             let metadata_reject = Metadata::reject(index);
                             let metadata_reject = metadata_reject.to_bencode().unwrap();
 
-                            sink.send(Message::Extended((3, metadata_reject))).await?;
+                            sink.send(Message::Extended((UT_METADATA, metadata_reject))).await?;
                         }
                         PeerMsg::Quit => {
                             info!("{:?} quitting", self.addr);
                             self.session.state.connection = ConnectionState::Quitting;
                             return Ok(());
                         }
+                        PeerMsg::PieceInvalid(piece) => {
+                            warn!("{:?} sent blocks of piece {piece} which failed the hash check", self.addr);
+
+                            if self.session.register_hash_fail() {
+                                warn!("{:?} sent too many invalid pieces, disconnecting", self.addr);
+                                return Err(Error::PieceInvalid);
+                            }
+                        }
+                        PeerMsg::Pex(connected) => {
+                            if let Some(ut_pex) = self.extension.m.ut_pex {
+                                if let Some(pex) = Pex::delta(&mut self.pex_sent, &connected, self.addr) {
+                                    info!("{:?} sending pex, added {} dropped {}", self.addr, pex.added.len(), pex.dropped.len());
+
+                                    let pex = pex.to_bencode().map_err(|_| Error::BencodeError)?;
+                                    sink.send(Message::Extended((ut_pex, pex))).await?;
+                                }
+                            }
+                        }
                         PeerMsg::HaveInfo => {
                             self.have_info = true;
-                            let am_interested = self.session.state.am_interested;
-                            let peer_choking = self.session.state.peer_choking;
+                            self.send_bitfield_to_disk().await?;
 
-                            if am_interested && !peer_choking {
+                            if self.can_request() {
+                                self.session.prepare_for_download(self.extension.reqq);
                                 self.request_block_infos(&mut sink).await?;
                             }
                         }
+                        PeerMsg::Choke => {
+                            if !self.session.state.peer_choking {
+                                info!("{:?} choking peer", self.addr);
+                                self.session.state.peer_choking = true;
+                                // we won't serve the requests of a choked peer
+                                self.incoming_requests.clear();
+                                sink.send(Message::Choke).await?;
+                            }
+                        }
+                        PeerMsg::Unchoke => {
+                            if self.session.state.peer_choking {
+                                info!("{:?} unchoking peer", self.addr);
+                                self.session.state.peer_choking = false;
+                                sink.send(Message::Unchoke).await?;
+                            }
+                        }
                     }
                 }
             }
@@ -702,6 +836,7 @@ impl Peer {
                 b: block,
                 recipient: tx,
                 info_hash: self.torrent_ctx.info_hash,
+                peer_id: self.ctx.id.read().await.ok_or(Error::PeerIdInvalid)?,
             })
             .await?;
 
@@ -710,12 +845,28 @@ impl Peer {
         // update stats
         self.session.update_download_stats(len as u32);
 
+        if self.session.snubbed {
+            info!("{:?} is not snubbed anymore", self.addr);
+            self.session.unsnub(self.extension.reqq);
+        }
+
         Ok(())
     }
     pub async fn tick<T>(&mut self, sink: &mut T) -> Result<(), Error>
     where
         T: SinkExt<Message> + Sized + std::marker::Unpin,
     {
+        // the peer is snubbing us if we are waiting on blocks for too long,
+        // give its blocks to other peers and only keep one request to it.
+        if !self.outgoing_requests.is_empty()
+            && self.can_request()
+            && self.session.check_snubbed(std::time::Instant::now())
+        {
+            warn!("{:?} is snubbing us", self.addr);
+            self.free_pending_blocks().await;
+            self.request_block_infos(sink).await?;
+        }
+
         // resend requests if we have pending requests and more time has elapsed
         // since the last request than the current timeout value
         if !self.outgoing_requests.is_empty() {
@@ -724,6 +875,13 @@ impl Peer {
 
         self.session.counters.reset();
 
+        *self.ctx.stats.write().await = PeerStats {
+            download_rate: self.session.counters.payload.down.avg(),
+            upload_rate: self.session.counters.payload.up.avg(),
+            peer_interested: self.session.state.peer_interested,
+            snubbed: self.session.snubbed,
+        };
+
         Ok(())
     }
 
@@ -822,6 +980,35 @@ impl Peer {
         }
     }
 
+    /// Send the pieces of the peer to the picker of the Disk.
+    pub async fn send_bitfield_to_disk(&self) -> Result<(), Error> {
+        let bitfield = self.ctx.pieces.read().await.clone();
+
+        self.disk_tx
+            .send(DiskMsg::PeerBitfield {
+                info_hash: self.torrent_ctx.info_hash,
+                peer_id: self.ctx.id.read().await.ok_or(Error::PeerIdInvalid)?,
+                bitfield,
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// The session with the peer has ended, the Disk
+    /// won't count the pieces of the peer anymore.
+    pub async fn delete_from_disk(&self) {
+        if let Some(peer_id) = *self.ctx.id.read().await {
+            let _ = self
+                .disk_tx
+                .send(DiskMsg::DeletePeer {
+                    info_hash: self.torrent_ctx.info_hash,
+                    peer_id,
+                })
+                .await;
+        }
+    }
+
     #[tracing::instrument(skip(self, sink))]
     pub async fn request_block_infos<T>(&mut self, sink: &mut T) -> Result<(), Error>
     where
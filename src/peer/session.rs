@@ -86,6 +86,8 @@ pub struct Session {
     pub last_incoming_block_time: Option<Instant>,
     /// Updated with the time of receipt of the most recently uploaded block.
     pub last_outgoing_block_time: Option<Instant>,
+    /// The last time that the peer unchoked us.
+    pub last_unchoke_time: Option<Instant>,
     /// This is the average network round-trip-time between the last issued
     /// a request and receiving the next block.
     ///
@@ -96,6 +98,15 @@ pub struct Session {
     pub request_timed_out: bool,
     pub timed_out_request_count: usize,
 
+    /// If the peer has not sent us any of the requested blocks in
+    /// [`Session::SNUB_TIMEOUT`]. A snubbed peer only has one outstanding
+    /// request, and can only be unchoked by the optimistic unchoke.
+    pub snubbed: bool,
+
+    /// How many pieces, with blocks sent by this peer,
+    /// failed the hash check.
+    pub hash_fail_count: usize,
+
     /// The time the BitTorrent connection was established (i.e. after
     /// handshaking)
     pub connected_time: Option<Instant>,
@@ -113,6 +124,14 @@ impl Session {
     /// timeouts.
     const MIN_TIMEOUT: Duration = Duration::from_secs(2);
 
+    /// After this many pieces with an invalid hash, we assume the peer is
+    /// malicious or broken, and the connection is closed.
+    pub const MAX_HASH_FAILS: usize = 3;
+
+    /// If the peer doesn't send any of the requested blocks
+    /// for this long, it is snubbed.
+    pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
     /// Returns the current request timeout value, based on the running average
     /// of past request round trip times.
     pub fn request_timeout(&self) -> Duration {
@@ -141,6 +160,41 @@ impl Session {
 
         // reset the target request queue size, which will be adjusted as the
         // download progresses
+        self.target_request_queue_len = if self.snubbed {
+            1
+        } else {
+            reqq.unwrap_or(Self::START_REQUEST_QUEUE_LEN)
+        };
+    }
+
+    /// Mark the peer as snubbed if, while we are waiting on requested
+    /// blocks, it didn't send any block since it unchoked us, or in the
+    /// last [`Session::SNUB_TIMEOUT`]. Returns true if the peer has just
+    /// been snubbed.
+    pub fn check_snubbed(&mut self, now: Instant) -> bool {
+        if self.snubbed {
+            return false;
+        }
+
+        let since = self.last_incoming_block_time.max(self.last_unchoke_time);
+
+        let Some(since) = since else {
+            return false;
+        };
+
+        if now.saturating_duration_since(since) < Self::SNUB_TIMEOUT {
+            return false;
+        }
+
+        self.snubbed = true;
+        self.target_request_queue_len = 1;
+
+        true
+    }
+
+    /// The snubbed peer sent us a block, go back to the normal pipeline.
+    pub fn unsnub(&mut self, reqq: Option<u16>) {
+        self.snubbed = false;
         self.target_request_queue_len = reqq.unwrap_or(Self::START_REQUEST_QUEUE_LEN);
     }
 
@@ -175,6 +229,13 @@ impl Session {
         self.last_incoming_block_time = Some(now);
     }
 
+    /// Updates state to reflect that a piece with blocks of this peer
+    /// failed the hash check. Returns true if the peer should be disconnected.
+    pub fn register_hash_fail(&mut self) -> bool {
+        self.hash_fail_count += 1;
+        self.hash_fail_count >= Self::MAX_HASH_FAILS
+    }
+
     pub fn record_waste(&mut self, block_len: u32) {
         self.counters.waste += block_len as u64;
     }
@@ -184,3 +245,44 @@ impl Session {
         self.counters.payload.up += block_len as u64;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snub_and_unsnub() {
+        let mut session = Session::default();
+        let now = Instant::now();
+
+        // we were never unchoked, nothing to wait for
+        assert!(!session.check_snubbed(now + Session::SNUB_TIMEOUT));
+
+        session.state.am_interested = true;
+        session.state.am_choking = false;
+        session.last_unchoke_time = Some(now);
+        session.prepare_for_download(Some(100));
+
+        assert!(!session.check_snubbed(now + Duration::from_secs(59)));
+        assert!(session.check_snubbed(now + Session::SNUB_TIMEOUT));
+        assert!(session.snubbed);
+        assert_eq!(session.target_request_queue_len, 1);
+
+        // only reported once
+        assert!(!session.check_snubbed(now + Session::SNUB_TIMEOUT * 2));
+
+        // being unchoked again does not restore the pipeline
+        session.prepare_for_download(Some(100));
+        assert_eq!(session.target_request_queue_len, 1);
+
+        session.update_download_stats(16384);
+        session.unsnub(Some(100));
+        assert!(!session.snubbed);
+        assert_eq!(session.target_request_queue_len, 100);
+
+        // the timeout restarts from the last block
+        let last = session.last_incoming_block_time.unwrap();
+        assert!(!session.check_snubbed(last + Duration::from_secs(30)));
+        assert!(session.check_snubbed(last + Session::SNUB_TIMEOUT));
+    }
+}
@@ -0,0 +1,650 @@
+//! The piece picker of a torrent, decides which blocks
+//! are requested from each peer.
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, VecDeque},
+};
+
+use hashbrown::{HashMap, HashSet};
+use rand::Rng;
+
+use crate::{
+    bitfield::Bitfield,
+    metainfo::{Info, Priority},
+    tcp_wire::lib::BlockInfo,
+};
+
+/// The bucket of a piece, the pieces are picked from the first bucket:
+/// the highest priority, the partial pieces, and then the rarest.
+type Key = (Reverse<Priority>, bool, u32);
+
+/// A piece of the torrent, from the perspective of the picker.
+#[derive(Debug, Clone, Default)]
+struct Piece {
+    /// The blocks of the piece that were not requested yet,
+    /// ordered by `begin`.
+    free: VecDeque<BlockInfo>,
+    /// How many blocks the piece has.
+    blocks: usize,
+    /// How many peers have this piece.
+    availability: u32,
+    /// The highest priority of the files of the piece.
+    priority: Priority,
+    /// The bucket of the piece, `None` if it can't be picked,
+    /// and its position inside the bucket.
+    bucket: Option<(Key, usize)>,
+}
+
+impl Piece {
+    /// Some blocks of the piece were requested or downloaded,
+    /// but not all of them.
+    fn is_partial(&self) -> bool {
+        !self.free.is_empty() && self.free.len() < self.blocks
+    }
+
+    /// The bucket in which the piece should be.
+    fn key(&self) -> Option<Key> {
+        if self.free.is_empty() || self.priority == Priority::Skip {
+            return None;
+        }
+        Some((
+            Reverse(self.priority),
+            !self.is_partial(),
+            self.availability,
+        ))
+    }
+}
+
+/// The picker prefers to finish the pieces that are partially downloaded,
+/// and after that, the rarest pieces among the peers, with random tie-breaking,
+/// so that peers don't download the pieces in the same order, and the rare
+/// pieces spread through the swarm before the peers that have them leave.
+///
+/// In sequential mode, used to stream media while it is downloaded, the first
+/// and last pieces of the media files are picked first, so that players can
+/// read the headers of the containers. And then, the pieces in a window ahead
+/// of the playback cursor, in order. The pieces out of the window are picked
+/// as usual.
+///
+/// Pieces of files with a higher priority are picked first, and pieces that
+/// only have skipped files are never picked. Urgent pieces, that are being
+/// read by the streaming server, always come first, even if skipped.
+///
+/// The pieces are kept in buckets by their priority, if they are partial, and
+/// their availability, the buckets are updated when one of those changes, so
+/// a pick only looks at the pieces of the first buckets.
+#[derive(Debug, Clone, Default)]
+pub struct Picker {
+    /// k: piece index
+    pieces: Vec<Piece>,
+    /// The indices of the pieces that can be picked, in the order
+    /// that they are picked, see [`Piece::key`].
+    buckets: BTreeMap<Key, Vec<usize>>,
+    /// How many blocks were not requested yet.
+    free: usize,
+    /// The pieces of each peer that are counted in the availability.
+    /// k: peer_id
+    peers: HashMap<[u8; 20], Bitfield>,
+    /// If the pieces are picked in order.
+    pub sequential: bool,
+    /// The piece that is being played, the window starts
+    /// at the first piece after it that was not picked.
+    cursor: usize,
+    /// The first and last pieces of the media files,
+    /// picked first in sequential mode.
+    headers: Vec<usize>,
+    /// Pieces that someone is waiting for, picked before any other piece.
+    urgent: HashSet<usize>,
+}
+
+impl Picker {
+    /// Create a picker with all blocks of the torrent, as given by
+    /// [`crate::metainfo::Info::get_block_infos`].
+    pub fn new(block_infos: VecDeque<BlockInfo>) -> Self {
+        let mut pieces: Vec<Piece> = Vec::new();
+        let free = block_infos.len();
+
+        for block_info in block_infos {
+            let index = block_info.index as usize;
+
+            if pieces.len() <= index {
+                pieces.resize(index + 1, Piece::default());
+            }
+
+            let piece = &mut pieces[index];
+            piece.blocks += 1;
+            piece.free.push_back(block_info);
+        }
+
+        let mut picker = Self {
+            pieces,
+            free,
+            ..Default::default()
+        };
+
+        for index in 0..picker.pieces.len() {
+            picker.update(index);
+        }
+
+        picker
+    }
+
+    /// How many pieces ahead of the cursor are picked in order.
+    pub const SEQUENTIAL_WINDOW: usize = 20;
+
+    /// Enable or disable the sequential mode, the `info` is used to find
+    /// the first and last pieces of the media files.
+    pub fn set_sequential(&mut self, sequential: bool, info: &Info) {
+        self.sequential = sequential;
+        self.headers.clear();
+
+        if !sequential {
+            return;
+        }
+
+        for (file, pieces) in info.get_files_pieces() {
+            if !file.is_media() || pieces.is_empty() {
+                continue;
+            }
+            for index in [pieces.start, pieces.end - 1] {
+                if !self.headers.contains(&index) {
+                    self.headers.push(index);
+                }
+            }
+        }
+
+        self.headers.sort_unstable();
+    }
+
+    /// Set the priority of the pieces, given the priority of each file.
+    pub fn set_priorities(&mut self, files: &[Priority], info: &Info) {
+        let priorities = info.piece_priorities(files);
+
+        for index in 0..self.pieces.len() {
+            self.pieces[index].priority = priorities.get(index).copied().unwrap_or_default();
+            self.update(index);
+        }
+    }
+
+    /// Move the playback cursor, for example, when the player seeks.
+    pub fn set_cursor(&mut self, index: usize) {
+        self.cursor = index;
+    }
+
+    /// Pick the piece before any other piece, until [`Picker::remove_urgent`].
+    pub fn set_urgent(&mut self, index: usize) {
+        self.urgent.insert(index);
+    }
+
+    /// The urgent piece was downloaded and validated.
+    pub fn remove_urgent(&mut self, index: usize) {
+        self.urgent.remove(&index);
+    }
+
+    /// The peer sent its bitfield, replacing the pieces
+    /// that were counted before for this peer.
+    pub fn peer_bitfield(&mut self, peer_id: [u8; 20], bitfield: Bitfield) {
+        self.remove_peer(&peer_id);
+
+        for index in 0..self.pieces.len() {
+            if bitfield.has(index) {
+                self.pieces[index].availability += 1;
+                self.update(index);
+            }
+        }
+
+        self.peers.insert(peer_id, bitfield);
+    }
+
+    /// The peer has a new piece.
+    pub fn peer_have(&mut self, peer_id: [u8; 20], index: usize) {
+        let bitfield = self.peers.entry(peer_id).or_default();
+
+        if bitfield.has(index) {
+            return;
+        }
+
+        bitfield.set(index);
+
+        if let Some(piece) = self.pieces.get_mut(index) {
+            piece.availability += 1;
+            self.update(index);
+        }
+    }
+
+    /// The peer disconnected, its pieces are not available anymore.
+    pub fn remove_peer(&mut self, peer_id: &[u8; 20]) {
+        let Some(bitfield) = self.peers.remove(peer_id) else {
+            return;
+        };
+
+        for index in 0..self.pieces.len() {
+            if bitfield.has(index) {
+                let piece = &mut self.pieces[index];
+                piece.availability = piece.availability.saturating_sub(1);
+                self.update(index);
+            }
+        }
+    }
+
+    /// How many of the connected peers have the piece.
+    pub fn availability(&self, index: usize) -> u32 {
+        self.pieces.get(index).map_or(0, |p| p.availability)
+    }
+
+    /// How many blocks of the piece were not requested yet.
+    pub fn free_blocks(&self, index: usize) -> usize {
+        self.pieces.get(index).map_or(0, |p| p.free.len())
+    }
+
+    /// How many blocks of the torrent were not requested yet.
+    pub fn len(&self) -> usize {
+        self.free
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Pick up to `qnt` blocks of the pieces in `has`, the pieces of the peer.
+    /// The blocks are removed from the picker, until they are returned with
+    /// [`Picker::return_blocks`].
+    pub fn pick(&mut self, has: &Bitfield, qnt: usize) -> VecDeque<BlockInfo> {
+        let mut blocks = VecDeque::new();
+
+        // the urgent pieces, and in sequential mode, the headers and
+        // the window come first, in order.
+        let mut first: Vec<usize> = self.urgent.iter().copied().collect();
+        first.sort_unstable();
+
+        if self.sequential {
+            // the window starts at the first piece, from the cursor,
+            // that was not picked entirely.
+            let window_start = (self.cursor..self.pieces.len())
+                .find(|index| !self.pieces[*index].free.is_empty())
+                .unwrap_or(self.pieces.len());
+            let window_end = (window_start + Self::SEQUENTIAL_WINDOW).min(self.pieces.len());
+
+            first.extend(&self.headers);
+            first.extend(window_start..window_end);
+        }
+
+        for index in first {
+            if blocks.len() >= qnt {
+                return blocks;
+            }
+
+            let Some(piece) = self.pieces.get(index) else {
+                continue;
+            };
+
+            if has.has(index) && (piece.priority != Priority::Skip || self.urgent.contains(&index))
+            {
+                self.take(index, qnt - blocks.len(), &mut blocks);
+            }
+        }
+
+        // the other pieces, from the first bucket, starting at a random
+        // piece of each bucket to break the ties.
+        let mut rng = rand::thread_rng();
+        let mut picked: Vec<usize> = Vec::new();
+        let mut needed = qnt.saturating_sub(blocks.len());
+
+        'buckets: for bucket in self.buckets.values() {
+            let start = rng.gen_range(0..bucket.len());
+
+            for i in 0..bucket.len() {
+                if needed == 0 {
+                    break 'buckets;
+                }
+
+                let index = bucket[(start + i) % bucket.len()];
+
+                if has.has(index) {
+                    picked.push(index);
+                    needed = needed.saturating_sub(self.pieces[index].free.len());
+                }
+            }
+        }
+
+        for index in picked {
+            self.take(index, qnt - blocks.len(), &mut blocks);
+        }
+
+        blocks
+    }
+
+    /// Put blocks back into the picker, so that they can be requested again.
+    /// When a peer is choked, disconnected, or when the piece failed the hash check.
+    pub fn return_blocks(&mut self, blocks: impl IntoIterator<Item = BlockInfo>) {
+        for block in blocks {
+            let index = block.index as usize;
+            let Some(piece) = self.pieces.get_mut(index) else {
+                continue;
+            };
+
+            if let Err(pos) = piece.free.binary_search_by_key(&block.begin, |b| b.begin) {
+                piece.free.insert(pos, block);
+                self.free += 1;
+                self.update(index);
+            }
+        }
+    }
+
+    /// Remove blocks that we already have, so that they are never picked,
+    /// e.g. the blocks that were restored from a resume file.
+    pub fn remove_blocks(&mut self, blocks: impl IntoIterator<Item = BlockInfo>) {
+        for block in blocks {
+            let index = block.index as usize;
+            let Some(piece) = self.pieces.get_mut(index) else {
+                continue;
+            };
+
+            if let Ok(pos) = piece.free.binary_search_by_key(&block.begin, |b| b.begin) {
+                piece.free.remove(pos);
+                self.free -= 1;
+                self.update(index);
+            }
+        }
+    }
+
+    /// Move up to `n` free blocks of the piece to `blocks`.
+    fn take(&mut self, index: usize, n: usize, blocks: &mut VecDeque<BlockInfo>) {
+        let free = &mut self.pieces[index].free;
+        let n = free.len().min(n);
+
+        blocks.extend(free.drain(..n));
+        self.free -= n;
+        self.update(index);
+    }
+
+    /// Move the piece to its bucket, after its
+    /// priority, availability or free blocks changed.
+    fn update(&mut self, index: usize) {
+        let piece = &self.pieces[index];
+        let key = piece.key();
+
+        if piece.bucket.map(|(k, _)| k) == key {
+            return;
+        }
+
+        if let Some((old, slot)) = self.pieces[index].bucket.take() {
+            let bucket = self.buckets.get_mut(&old).expect("the bucket of a piece");
+            bucket.swap_remove(slot);
+
+            // the last piece of the bucket took the slot
+            if let Some(&moved) = bucket.get(slot) {
+                self.pieces[moved].bucket = Some((old, slot));
+            }
+            if bucket.is_empty() {
+                self.buckets.remove(&old);
+            }
+        }
+
+        if let Some(key) = key {
+            let bucket = self.buckets.entry(key).or_default();
+            bucket.push(index);
+            self.pieces[index].bucket = Some((key, bucket.len() - 1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp_wire::lib::BLOCK_LEN;
+
+    use super::*;
+
+    /// A torrent with `pieces` pieces of 2 blocks each.
+    fn picker(pieces: u32) -> Picker {
+        let blocks = (0..pieces)
+            .flat_map(|index| {
+                [0, BLOCK_LEN].map(|begin| BlockInfo {
+                    index,
+                    begin,
+                    len: BLOCK_LEN,
+                })
+            })
+            .collect();
+
+        Picker::new(blocks)
+    }
+
+    #[test]
+    fn rarest_first() {
+        let mut picker = picker(3);
+
+        picker.peer_bitfield([1; 20], Bitfield::from(vec![0b1110_0000]));
+        picker.peer_bitfield([2; 20], Bitfield::from(vec![0b1100_0000]));
+        picker.peer_have([3; 20], 0);
+
+        assert_eq!(picker.availability(0), 3);
+        assert_eq!(picker.availability(1), 2);
+        assert_eq!(picker.availability(2), 1);
+
+        let blocks = picker.pick(&Bitfield::from(vec![0b1110_0000]), 6);
+        let order: Vec<u32> = blocks.iter().map(|b| b.index).collect();
+
+        assert_eq!(order, vec![2, 2, 1, 1, 0, 0]);
+        assert!(picker.is_empty());
+
+        // peer 1 left, and a Have of a piece that was counted is ignored
+        picker.remove_peer(&[1; 20]);
+        picker.peer_have([2; 20], 0);
+
+        assert_eq!(picker.availability(0), 2);
+        assert_eq!(picker.availability(1), 1);
+        assert_eq!(picker.availability(2), 0);
+    }
+
+    #[test]
+    fn only_pieces_of_the_peer() {
+        let mut picker = picker(3);
+
+        let blocks = picker.pick(&Bitfield::from(vec![0b0100_0000]), 10);
+
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks.iter().all(|b| b.index == 1));
+        assert_eq!(picker.len(), 4);
+    }
+
+    #[test]
+    fn finish_partial_pieces_first() {
+        let mut picker = picker(3);
+
+        picker.peer_bitfield([1; 20], Bitfield::from(vec![0b1000_0000]));
+        picker.peer_bitfield([2; 20], Bitfield::from(vec![0b1000_0000]));
+
+        // piece 0 is the most common, but one of its blocks was requested
+        let first = picker.pick(&Bitfield::from(vec![0b1000_0000]), 1);
+        assert_eq!(first[0].index, 0);
+
+        let blocks = picker.pick(&Bitfield::from(vec![0b1110_0000]), 1);
+        assert_eq!(blocks[0].index, 0);
+        assert_eq!(blocks[0].begin, BLOCK_LEN);
+    }
+
+    #[test]
+    fn sequential() {
+        use crate::metainfo::File;
+
+        let pieces = Picker::SEQUENTIAL_WINDOW as u32 + 10;
+        let mut picker = picker(pieces);
+        let has = Bitfield::from(vec![0xFF; 8]);
+
+        // the movie starts on piece 1, the other file is not media
+        let info = Info {
+            piece_length: BLOCK_LEN * 2,
+            files: Some(vec![
+                File {
+                    length: BLOCK_LEN as u64 * 2,
+                    path: vec!["readme.txt".to_owned()],
+                },
+                File {
+                    length: BLOCK_LEN as u64 * 2 * (pieces as u64 - 1),
+                    path: vec!["movie.mkv".to_owned()],
+                },
+            ]),
+            ..Default::default()
+        };
+
+        // the first and last pieces are the rarest
+        for index in 1..pieces as usize - 1 {
+            picker.peer_have([1; 20], index);
+        }
+
+        picker.set_sequential(true, &info);
+        picker.set_cursor(3);
+
+        let blocks = picker.pick(&has, 8);
+        let order: Vec<u32> = blocks.iter().map(|b| b.index).collect();
+
+        assert_eq!(order, vec![1, 1, pieces - 1, pieces - 1, 3, 3, 4, 4]);
+
+        // the window moves ahead of the pieces that were picked
+        let blocks = picker.pick(&has, Picker::SEQUENTIAL_WINDOW * 2);
+        let last = blocks.back().unwrap().index;
+
+        assert_eq!(blocks[0].index, 5);
+        assert_eq!(last as usize, 4 + Picker::SEQUENTIAL_WINDOW);
+
+        // pieces out of the window are picked by rarity
+        picker.set_sequential(false, &info);
+        picker.return_blocks(blocks);
+        let blocks = picker.pick(&has, 2);
+
+        assert_eq!(blocks[0].index, 0);
+        assert_eq!(blocks[1].index, 0);
+    }
+
+    #[test]
+    fn urgent_pieces_first() {
+        let mut picker = picker(4);
+        let has = Bitfield::from(vec![0xFF]);
+
+        picker.set_urgent(3);
+        picker.set_urgent(2);
+
+        let blocks = picker.pick(&has, 4);
+        let order: Vec<u32> = blocks.iter().map(|b| b.index).collect();
+        assert_eq!(order, vec![2, 2, 3, 3]);
+
+        // the piece failed the hash check, it is still urgent
+        picker.return_blocks(blocks.into_iter().filter(|b| b.index == 3));
+        picker.remove_urgent(2);
+
+        let blocks = picker.pick(&has, 1);
+        assert_eq!(blocks[0].index, 3);
+    }
+
+    #[test]
+    fn file_priorities() {
+        use crate::metainfo::File;
+
+        let mut picker = picker(4);
+        let has = Bitfield::from(vec![0xFF]);
+
+        // each file has one piece
+        let info = Info {
+            piece_length: BLOCK_LEN * 2,
+            files: Some(
+                (0..4)
+                    .map(|i| File {
+                        length: BLOCK_LEN as u64 * 2,
+                        path: vec![i.to_string()],
+                    })
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+
+        picker.set_priorities(
+            &[
+                Priority::Skip,
+                Priority::Low,
+                Priority::Normal,
+                Priority::High,
+            ],
+            &info,
+        );
+
+        let blocks = picker.pick(&has, 8);
+        let order: Vec<u32> = blocks.iter().map(|b| b.index).collect();
+
+        // the skipped piece is never picked
+        assert_eq!(order, vec![3, 3, 2, 2, 1, 1]);
+        assert_eq!(picker.free_blocks(0), 2);
+
+        // unless the streaming server needs it
+        picker.set_urgent(0);
+        assert_eq!(picker.pick(&has, 8).len(), 2);
+    }
+
+    // a pick only looks at the first buckets, picking all the
+    // blocks of a large torrent one piece at a time is fast.
+    #[test]
+    fn many_pieces() {
+        let pieces = 50_000;
+        let mut picker = picker(pieces);
+        let has = Bitfield::from(vec![0xFF; pieces as usize / 8]);
+
+        for index in 0..pieces as usize {
+            if index != 12_345 {
+                picker.peer_have([1; 20], index);
+            }
+        }
+        picker.peer_have([2; 20], 7);
+
+        // the rarest piece, and then the partial piece, come first
+        let blocks = picker.pick(&has, 3);
+        assert_eq!(blocks[0].index, 12_345);
+        assert_eq!(blocks[1].index, 12_345);
+
+        let partial = blocks[2].index;
+        assert_ne!(partial, 7);
+        assert_eq!(picker.pick(&has, 1)[0].index, partial);
+
+        let mut picked = 4;
+        loop {
+            let blocks = picker.pick(&has, 2);
+            if blocks.is_empty() {
+                break;
+            }
+            picked += blocks.len();
+        }
+
+        assert_eq!(picked, pieces as usize * 2);
+        assert!(picker.is_empty());
+        assert!(picker.buckets.is_empty());
+
+        // a returned block goes back to the buckets
+        picker.return_blocks([BlockInfo {
+            index: 7,
+            begin: 0,
+            len: BLOCK_LEN,
+        }]);
+        assert_eq!(picker.len(), 1);
+        assert_eq!(picker.pick(&has, 2)[0].index, 7);
+    }
+
+    #[test]
+    fn return_blocks() {
+        let mut picker = picker(2);
+        let has = Bitfield::from(vec![0b1100_0000]);
+
+        let blocks = picker.pick(&has, 4);
+        assert!(picker.is_empty());
+
+        picker.return_blocks(blocks.clone());
+        // blocks that are already in the picker are not duplicated
+        picker.return_blocks(blocks.clone());
+
+        assert_eq!(picker.len(), 4);
+        assert_eq!(picker.free_blocks(0), 2);
+        assert_eq!(picker.pieces[0].free[0].begin, 0);
+        assert_eq!(picker.pieces[0].free[1].begin, BLOCK_LEN);
+
+        picker.remove_blocks(blocks);
+        assert!(picker.is_empty());
+    }
+}
@@ -0,0 +1,331 @@
+//! Fast resume, the state of a torrent is saved in a file, so that after
+//! a restart the download continues where it stopped, without hashing
+//! every piece again.
+//!
+//! There is one file per torrent, `<hex info_hash>.resume`, in the state dir.
+//! The files of the torrent are only trusted if their size and modification
+//! time did not change since the resume file was written.
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use bendy::{
+    decoding::{self, FromBencode, Object, ResultExt},
+    encoding::{self, AsString, SingleItemEncoder, ToBencode},
+};
+
+use crate::{error::Error, metainfo::Priority, tcp_wire::lib::BlockInfo};
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ResumeData {
+    pub info_hash: [u8; 20],
+    /// A .torrent file with the trackers and the info of the torrent,
+    /// with the exact bytes of the info, to keep the same info hash.
+    pub metainfo: Vec<u8>,
+    /// The bitfield of the pieces that we have.
+    pub pieces: Vec<u8>,
+    /// The blocks that were written, of the pieces that are not complete.
+    pub unfinished: Vec<BlockInfo>,
+    pub file_priorities: Vec<Priority>,
+    /// How many bytes were uploaded, on all sessions.
+    pub uploaded: u64,
+    /// How many bytes were downloaded, on all sessions.
+    pub downloaded: u64,
+    pub sequential: bool,
+    /// The directory in which the torrent is saved.
+    pub save_path: String,
+    /// The directory in which the torrent is downloaded, before it is
+    /// moved to the save path, `None` if it is in the save path.
+    pub incomplete_dir: Option<String>,
+    /// If the names of the files end with `.part`.
+    pub part_files: bool,
+    /// The size and the modification time, in
+    /// seconds since the epoch, of each file.
+    pub file_sizes: Vec<(u64, u64)>,
+}
+
+impl ResumeData {
+    /// How often the resume file of a torrent is written, it is
+    /// also written when the torrent quits.
+    pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// The path of the resume file of a torrent, inside `dir`.
+    pub fn path(dir: &Path, info_hash: [u8; 20]) -> PathBuf {
+        dir.join(format!("{}.resume", hex::encode(info_hash)))
+    }
+
+    /// Read all resume files of `dir`, files that can't be decoded are ignored.
+    pub async fn load_all(dir: &Path) -> Vec<Self> {
+        let mut all = Vec::new();
+
+        let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+            return all;
+        };
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+
+            if path.extension().is_none_or(|ext| ext != "resume") {
+                continue;
+            }
+
+            if let Some(data) = tokio::fs::read(&path)
+                .await
+                .ok()
+                .and_then(|buf| Self::from_bencode(&buf).ok())
+            {
+                all.push(data);
+            }
+        }
+
+        all
+    }
+
+    /// Write the resume file inside `dir`, the file is written to a temporary
+    /// file first, so that a crash while writing does not corrupt it.
+    /// Blocking, it is written by the I/O workers of the Disk.
+    pub fn save(&self, dir: &Path) -> Result<(), Error> {
+        let path = Self::path(dir, self.info_hash);
+        let tmp = path.with_extension("resume.tmp");
+
+        let buf = self.to_bencode().map_err(|_| Error::BencodeError)?;
+
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(&tmp, buf)?;
+        std::fs::rename(&tmp, &path)?;
+
+        Ok(())
+    }
+
+    /// The size and modification time of a file, `(0, 0)` if it does not exist.
+    pub fn file_size(path: &Path) -> (u64, u64) {
+        let Ok(metadata) = std::fs::metadata(path) else {
+            return (0, 0);
+        };
+
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |m| m.as_secs());
+
+        (metadata.len(), mtime)
+    }
+}
+
+/// Create a .torrent file with the given trackers and the bytes of the info.
+pub fn metainfo(tiers: &[Vec<String>], raw_info: &[u8]) -> Result<Vec<u8>, Error> {
+    let announce = tiers.iter().flatten().next().cloned().unwrap_or_default();
+
+    // the info is not decoded and encoded again,
+    // an unknown key would change the info hash.
+    let mut buf = b"d8:announce".to_vec();
+    buf.extend(announce.to_bencode().map_err(|_| Error::BencodeError)?);
+
+    if tiers.iter().flatten().count() > 1 {
+        buf.extend(b"13:announce-list");
+        buf.extend(
+            tiers
+                .to_vec()
+                .to_bencode()
+                .map_err(|_| Error::BencodeError)?,
+        );
+    }
+
+    buf.extend(b"4:info");
+    buf.extend(raw_info);
+    buf.push(b'e');
+
+    Ok(buf)
+}
+
+impl ToBencode for ResumeData {
+    const MAX_DEPTH: usize = 3;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), encoding::Error> {
+        let priorities: Vec<String> = self
+            .file_priorities
+            .iter()
+            .map(|p| <&str>::from(*p).to_owned())
+            .collect();
+
+        let file_sizes: Vec<Vec<u64>> = self
+            .file_sizes
+            .iter()
+            .map(|(size, mtime)| vec![*size, *mtime])
+            .collect();
+
+        let unfinished: Vec<Vec<u32>> = self
+            .unfinished
+            .iter()
+            .map(|b| vec![b.index, b.begin, b.len])
+            .collect();
+
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"downloaded", self.downloaded)?;
+            e.emit_pair(b"file-priorities", priorities)?;
+            e.emit_pair(b"file-sizes", file_sizes)?;
+            if let Some(incomplete_dir) = &self.incomplete_dir {
+                e.emit_pair(b"incomplete-dir", incomplete_dir)?;
+            }
+            e.emit_pair(b"info-hash", AsString(&self.info_hash))?;
+            e.emit_pair(b"metainfo", AsString(&self.metainfo))?;
+            e.emit_pair(b"part-files", self.part_files as u8)?;
+            e.emit_pair(b"pieces", AsString(&self.pieces))?;
+            e.emit_pair(b"save-path", &self.save_path)?;
+            e.emit_pair(b"sequential", self.sequential as u8)?;
+            e.emit_pair(b"unfinished", unfinished)?;
+            e.emit_pair(b"uploaded", self.uploaded)
+        })
+    }
+}
+
+impl FromBencode for ResumeData {
+    fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let mut data = ResumeData::default();
+        let mut info_hash = None;
+
+        let mut dict_dec = object.try_into_dictionary()?;
+        while let Some(pair) = dict_dec.next_pair()? {
+            match pair {
+                (b"downloaded", value) => {
+                    data.downloaded = u64::decode_bencode_object(value).context("downloaded")?;
+                }
+                (b"file-priorities", value) => {
+                    data.file_priorities = Vec::<String>::decode_bencode_object(value)
+                        .context("file-priorities")?
+                        .iter()
+                        .map(|p| p.parse().unwrap_or_default())
+                        .collect();
+                }
+                (b"file-sizes", value) => {
+                    data.file_sizes = Vec::<Vec<u64>>::decode_bencode_object(value)
+                        .context("file-sizes")?
+                        .into_iter()
+                        .map(|f| (f.first().copied(), f.get(1).copied()))
+                        .map(|(size, mtime)| (size.unwrap_or(0), mtime.unwrap_or(0)))
+                        .collect();
+                }
+                (b"incomplete-dir", value) => {
+                    data.incomplete_dir =
+                        Some(String::decode_bencode_object(value).context("incomplete-dir")?);
+                }
+                (b"info-hash", value) => {
+                    let bytes = value.try_into_bytes().context("info-hash")?;
+                    info_hash = bytes.try_into().ok();
+                }
+                (b"metainfo", value) => {
+                    data.metainfo = value.try_into_bytes().context("metainfo")?.to_vec();
+                }
+                (b"part-files", value) => {
+                    data.part_files = u8::decode_bencode_object(value).context("part-files")? == 1;
+                }
+                (b"pieces", value) => {
+                    data.pieces = value.try_into_bytes().context("pieces")?.to_vec();
+                }
+                (b"save-path", value) => {
+                    data.save_path = String::decode_bencode_object(value).context("save-path")?;
+                }
+                (b"sequential", value) => {
+                    data.sequential = u8::decode_bencode_object(value).context("sequential")? == 1;
+                }
+                (b"unfinished", value) => {
+                    data.unfinished = Vec::<Vec<u32>>::decode_bencode_object(value)
+                        .context("unfinished")?
+                        .into_iter()
+                        .filter_map(|b| match b[..] {
+                            [index, begin, len] => Some(BlockInfo { index, begin, len }),
+                            _ => None,
+                        })
+                        .collect();
+                }
+                (b"uploaded", value) => {
+                    data.uploaded = u64::decode_bencode_object(value).context("uploaded")?;
+                }
+                _ => {}
+            }
+        }
+
+        data.info_hash = info_hash.ok_or_else(|| decoding::Error::missing_field("info-hash"))?;
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metainfo::MetaInfo;
+
+    use super::*;
+
+    #[test]
+    fn encode_and_decode() {
+        let data = ResumeData {
+            info_hash: [7; 20],
+            metainfo: b"d8:announce0:4:infod4:name1:aee".to_vec(),
+            pieces: vec![0b1010_0000],
+            unfinished: vec![BlockInfo {
+                index: 1,
+                begin: 16384,
+                len: 16384,
+            }],
+            file_priorities: vec![Priority::Skip, Priority::High],
+            uploaded: 5,
+            downloaded: 1 << 40,
+            sequential: true,
+            save_path: "/tmp/btr".to_owned(),
+            incomplete_dir: Some("/tmp/incomplete".to_owned()),
+            part_files: true,
+            file_sizes: vec![(10, 1700000000), (0, 0)],
+        };
+
+        let buf = data.to_bencode().unwrap();
+        assert_eq!(ResumeData::from_bencode(&buf).unwrap(), data);
+    }
+
+    #[test]
+    fn metainfo_keeps_the_info() {
+        let raw_info = b"d6:lengthi10e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1ee";
+        let tiers = vec![
+            vec!["udp://a:1".to_owned(), "udp://b:1".to_owned()],
+            vec!["udp://c:1".to_owned()],
+        ];
+
+        let buf = metainfo(&tiers, raw_info).unwrap();
+
+        assert_eq!(MetaInfo::raw_info(&buf).unwrap(), raw_info);
+
+        let metainfo = MetaInfo::from_bencode(&buf).unwrap();
+        assert_eq!(metainfo.tiers(), tiers);
+        assert_eq!(metainfo.info.name, "a");
+
+        // a magnet without trackers
+        let buf = super::metainfo(&[], raw_info).unwrap();
+        let metainfo = MetaInfo::from_bencode(&buf).unwrap();
+        assert!(metainfo.trackers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn save_and_load_all() {
+        let dir = std::env::temp_dir().join(format!("vcz-resume-{}", rand::random::<u32>()));
+
+        let data = ResumeData {
+            info_hash: [3; 20],
+            ..Default::default()
+        };
+
+        data.save(&dir).unwrap();
+        tokio::fs::write(dir.join("other.txt"), b"not a resume file")
+            .await
+            .unwrap();
+
+        assert!(ResumeData::path(&dir, [3; 20]).is_file());
+        assert_eq!(ResumeData::load_all(&dir).await, vec![data]);
+
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+}
@@ -0,0 +1,1053 @@
+//! Where the Disk stores the data of the torrents.
+//!
+//! The Disk does not know about files on disk, it reads and writes bytes
+//! at an offset of a file of a torrent, through a [`Storage`]. The files
+//! are identified by their index on `Info::files`, a single file torrent
+//! has one file with the index 0.
+//!
+//! - [`FsStorage`], the default, stores the files under `save_path/name`,
+//!   where the save path of a torrent is the download dir, unless it was
+//!   set for the torrent. The files are allocated on disk according to its
+//!   [`Allocation`]. The files of a torrent that is still downloading
+//!   can end with `.part`, they are renamed when the download is complete.
+//! - [`MemoryStorage`] stores the files in memory, used by tests.
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    fs::{self, create_dir_all, OpenOptions},
+    io::{IoSlice, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use hashbrown::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    file_pool::FilePool,
+    metainfo::{Info, Priority},
+    resume::ResumeData,
+    tcp_wire::lib::BlockInfo,
+};
+
+/// The part of a block that is inside one file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    /// The index of the file, on `Info::files`.
+    pub file: usize,
+    /// The offset of the segment in the file.
+    pub offset: u64,
+    pub len: usize,
+}
+
+/// A backend of the Disk, the methods are blocking, they are called
+/// from the I/O workers of the Disk, from many threads at the same time.
+pub trait Storage: Debug + Send + Sync {
+    /// Create the skeleton of a torrent, the files that are not skipped.
+    /// Called again when the priorities of the files change.
+    fn create(
+        &self,
+        info_hash: [u8; 20],
+        info: &Info,
+        priorities: &[Priority],
+    ) -> Result<(), Error>;
+
+    /// Read `buf.len()` bytes of the file `file`, starting at `offset`.
+    fn read_at(
+        &self,
+        info_hash: [u8; 20],
+        info: &Info,
+        file: usize,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<(), Error>;
+
+    /// Write all the bytes of `bufs`, one after the other,
+    /// to the file `file`, starting at `offset`.
+    fn write_at(
+        &self,
+        info_hash: [u8; 20],
+        info: &Info,
+        file: usize,
+        offset: u64,
+        bufs: &mut [IoSlice],
+    ) -> Result<(), Error>;
+
+    /// The SHA1 hash of the piece `index`.
+    fn hash_piece(
+        &self,
+        info_hash: [u8; 20],
+        info: &Info,
+        index: usize,
+    ) -> Result<[u8; 20], Error> {
+        let piece = BlockInfo {
+            index: index as u32,
+            begin: 0,
+            len: info.piece_size(index),
+        };
+
+        let mut hash = sha1_smol::Sha1::new();
+
+        for segment in segments(info, &piece)? {
+            let mut buf = vec![0; segment.len];
+            self.read_at(info_hash, info, segment.file, segment.offset, &mut buf)?;
+            hash.update(&buf);
+        }
+
+        Ok(hash.digest().bytes())
+    }
+
+    /// Make sure that the writes to the files of the torrent are persisted.
+    fn flush(&self, info_hash: [u8; 20], info: &Info) -> Result<(), Error>;
+
+    /// The torrent was removed from the Disk, release what is held for it.
+    fn remove(&self, info_hash: [u8; 20], info: &Info) -> Result<(), Error>;
+
+    /// The size and the modification time, in seconds since the epoch, of
+    /// each file, used to know if the files changed since the resume data
+    /// of the torrent was written. `(0, 0)` if the file does not exist.
+    fn file_sizes(&self, info_hash: [u8; 20], info: &Info) -> Vec<(u64, u64)>;
+
+    /// Set the directory in which the files of a torrent are saved,
+    /// before they are created.
+    fn set_save_path(&self, _info_hash: [u8; 20], _path: PathBuf) {}
+
+    /// Set if the names of the files of a torrent end with `.part`,
+    /// before they are created.
+    fn set_part_files(&self, _info_hash: [u8; 20], _part: bool) {}
+
+    /// Move the files of a torrent to the directory `path`, and add or
+    /// remove the `.part` suffix of their names, the files are read and
+    /// written from there after. The Disk does not read or write the
+    /// torrent while its files are moved.
+    fn move_to(
+        &self,
+        _info_hash: [u8; 20],
+        _info: &Info,
+        _path: &Path,
+        _part: bool,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// The lengths of the files of a torrent, in the order of `Info::files`.
+fn file_lengths(info: &Info) -> Vec<u64> {
+    match &info.files {
+        Some(files) => files.iter().map(|f| f.length).collect(),
+        None => vec![info.file_length.unwrap_or(0)],
+    }
+}
+
+/// The range of bytes of the torrent that are in each file.
+fn file_ranges(info: &Info) -> Vec<Range<u64>> {
+    let mut offset = 0;
+
+    file_lengths(info)
+        .into_iter()
+        .map(|length| {
+            let range = offset..offset + length;
+            offset += length;
+            range
+        })
+        .collect()
+}
+
+/// Split a block into the parts that are inside each file of the torrent,
+/// a block can start at the end of a file and end at the start of the
+/// next one, or even contain entire files.
+pub fn segments(info: &Info, block_info: &BlockInfo) -> Result<Vec<Segment>, Error> {
+    // the bytes of the block in the torrent
+    let start = block_info.index as u64 * info.piece_length as u64 + block_info.begin as u64;
+    let end = start + block_info.len as u64;
+
+    if end > info.get_size() {
+        return Err(Error::BlockInvalid);
+    }
+
+    Ok(file_ranges(info)
+        .into_iter()
+        .enumerate()
+        .filter(|(_, range)| range.start < end && start < range.end)
+        .map(|(file, range)| {
+            let s = start.max(range.start);
+            let e = end.min(range.end);
+
+            Segment {
+                file,
+                offset: s - range.start,
+                len: (e - s) as usize,
+            }
+        })
+        .collect())
+}
+
+/// Read a block from the storage, from all the files of the block.
+pub fn read_block(
+    storage: &dyn Storage,
+    info_hash: [u8; 20],
+    info: &Info,
+    block_info: &BlockInfo,
+) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0; block_info.len as usize];
+    let mut start = 0;
+
+    for segment in segments(info, block_info)? {
+        let end = start + segment.len;
+        storage.read_at(
+            info_hash,
+            info,
+            segment.file,
+            segment.offset,
+            &mut buf[start..end],
+        )?;
+        start = end;
+    }
+
+    Ok(buf)
+}
+
+/// Write the given blocks of the piece `index` to the files
+/// where they belong, blocks that are next to each other in
+/// a file are written with one vectored write.
+/// k: begin
+pub fn write_blocks(
+    storage: &dyn Storage,
+    info_hash: [u8; 20],
+    info: &Info,
+    index: usize,
+    blocks: &BTreeMap<u32, Vec<u8>>,
+) -> Result<(), Error> {
+    // the contiguous runs of bytes inside each file,
+    // (file, offset in the file, len, slices)
+    let mut runs: Vec<(usize, u64, usize, Vec<IoSlice>)> = Vec::new();
+
+    for (begin, block) in blocks {
+        let block_info = BlockInfo {
+            index: index as u32,
+            begin: *begin,
+            len: block.len() as u32,
+        };
+
+        let mut start = 0;
+
+        for segment in segments(info, &block_info)? {
+            let slice = IoSlice::new(&block[start..start + segment.len]);
+            start += segment.len;
+
+            match runs.last_mut() {
+                Some((file, offset, len, slices))
+                    if *file == segment.file && *offset + *len as u64 == segment.offset =>
+                {
+                    *len += segment.len;
+                    slices.push(slice);
+                }
+                _ => runs.push((segment.file, segment.offset, segment.len, vec![slice])),
+            }
+        }
+    }
+
+    for (file, offset, _, mut slices) in runs {
+        storage.write_at(info_hash, info, file, offset, &mut slices)?;
+    }
+
+    Ok(())
+}
+
+/// The reason of an error that happened on the storage, to show it to the
+/// user. `None` if the error did not happen while using the storage,
+/// e.g. a block that is not inside the torrent.
+pub fn error_reason(e: &Error) -> Option<String> {
+    match e {
+        // the I/O errors
+        Error::Io(_)
+        | Error::FileOpenError(_)
+        | Error::FolderOpenError(_)
+        | Error::NotEnoughSpace(..) => Some(e.to_string()),
+        _ => None,
+    }
+}
+
+/// Write all the bytes of `bufs` to the writer, the same as `write_all`,
+/// but with vectored writes.
+fn write_all_vectored(writer: &mut impl Write, mut bufs: &mut [IoSlice]) -> std::io::Result<()> {
+    while !bufs.is_empty() {
+        match writer.write_vectored(bufs) {
+            Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// How the files of a torrent are allocated on disk, when they are created.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Allocation {
+    /// The files are created empty, and grow as the blocks are written.
+    /// The parts of the files that were not written don't use disk space.
+    #[default]
+    Sparse,
+    /// All the disk space of the files is allocated when they are created,
+    /// which avoids fragmentation.
+    Full,
+}
+
+/// How many bytes are free on the filesystem of `path`,
+/// `None` if it is not known.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+pub fn available_space(path: &Path) -> Option<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    // the directory may not be created yet
+    let path = path
+        .ancestors()
+        .find(|p| p.exists())
+        .unwrap_or(Path::new("."));
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Allocate the disk space of the first `len` bytes of the file.
+#[cfg(target_os = "linux")]
+fn allocate(file: &std::fs::File, len: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len as libc::off_t) } {
+        0 => Ok(()),
+        // the filesystem does not support it
+        libc::EOPNOTSUPP | libc::EINVAL => file.set_len(len),
+        e => Err(std::io::Error::from_raw_os_error(e)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate(file: &std::fs::File, len: u64) -> std::io::Result<()> {
+    file.set_len(len)
+}
+
+/// Move a file to `to`, creating its directory. Files on another
+/// filesystem can't be renamed, they are copied and then removed.
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if let Some(dir) = to.parent() {
+        create_dir_all(dir)?;
+    }
+
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    fs::copy(from, to)?;
+    fs::remove_file(from)
+}
+
+/// Add the `.part` suffix to the name of a file.
+fn with_part(path: PathBuf) -> PathBuf {
+    let mut path = path.into_os_string();
+    path.push(".part");
+    path.into()
+}
+
+/// The directory of a torrent in `save_path`, or the file of a single
+/// file torrent.
+fn base_in(save_path: &Path, info: &Info, part: bool) -> PathBuf {
+    let base = save_path.join(&info.name);
+
+    if part && info.files.is_none() {
+        with_part(base)
+    } else {
+        base
+    }
+}
+
+/// The paths of the files of a torrent in `save_path`,
+/// in the order of `Info::files`.
+fn paths_in(save_path: &Path, info: &Info, part: bool) -> Vec<PathBuf> {
+    let base = base_in(save_path, info, part);
+
+    let Some(files) = &info.files else {
+        return vec![base];
+    };
+
+    files
+        .iter()
+        .map(|file| {
+            let mut path = base.clone();
+            for p in &file.path {
+                path.push(p);
+            }
+
+            // a file without a path, of a malformed torrent
+            if part && !file.path.is_empty() {
+                with_part(path)
+            } else {
+                path
+            }
+        })
+        .collect()
+}
+
+/// Remove `dir` and its directories, if they don't have files.
+fn remove_empty_dirs(dir: &Path) {
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                remove_empty_dirs(&entry.path());
+            }
+        }
+    }
+    let _ = fs::remove_dir(dir);
+}
+
+/// Store the files of a torrent in `save_path/name`, the files
+/// are kept open in a pool.
+#[derive(Debug)]
+pub struct FsStorage {
+    /// The save path of the torrents that don't have one.
+    pub download_dir: String,
+    /// The directory in which the files of each torrent are saved.
+    /// K: info_hash
+    save_paths: RwLock<HashMap<[u8; 20], PathBuf>>,
+    /// The torrents whose files end with `.part`.
+    part_files: RwLock<HashSet<[u8; 20]>>,
+    /// The paths of the files of each torrent, computed on the first read
+    /// or write of the torrent, and again after its files are moved.
+    /// K: info_hash
+    paths: RwLock<HashMap<[u8; 20], Arc<Vec<PathBuf>>>>,
+    /// The files that are open, to read and write blocks.
+    pub file_pool: FilePool,
+    pub allocation: Allocation,
+}
+
+impl FsStorage {
+    pub fn new(download_dir: String) -> Self {
+        Self {
+            download_dir,
+            save_paths: RwLock::new(HashMap::new()),
+            part_files: RwLock::new(HashSet::new()),
+            paths: RwLock::new(HashMap::new()),
+            file_pool: FilePool::default(),
+            allocation: Allocation::default(),
+        }
+    }
+
+    /// The directory in which the files of a torrent are saved.
+    pub fn save_path(&self, info_hash: [u8; 20]) -> PathBuf {
+        self.save_paths
+            .read()
+            .unwrap()
+            .get(&info_hash)
+            .cloned()
+            .unwrap_or_else(|| PathBuf::from(&self.download_dir))
+    }
+
+    /// If the names of the files of a torrent end with `.part`.
+    pub fn is_part(&self, info_hash: [u8; 20]) -> bool {
+        self.part_files.read().unwrap().contains(&info_hash)
+    }
+
+    /// The directory of a torrent, or the file of a single file torrent.
+    pub fn base(&self, info_hash: [u8; 20], info: &Info) -> PathBuf {
+        base_in(&self.save_path(info_hash), info, self.is_part(info_hash))
+    }
+
+    /// The paths of the files of a torrent, in the order of `Info::files`.
+    pub fn file_paths(&self, info_hash: [u8; 20], info: &Info) -> Arc<Vec<PathBuf>> {
+        if let Some(paths) = self.paths.read().unwrap().get(&info_hash) {
+            return paths.clone();
+        }
+
+        let paths = Arc::new(paths_in(
+            &self.save_path(info_hash),
+            info,
+            self.is_part(info_hash),
+        ));
+        self.paths.write().unwrap().insert(info_hash, paths.clone());
+        paths
+    }
+
+    fn file_path(&self, info_hash: [u8; 20], info: &Info, file: usize) -> Result<PathBuf, Error> {
+        self.file_paths(info_hash, info)
+            .get(file)
+            .cloned()
+            .ok_or(Error::BlockInvalid)
+    }
+}
+
+impl Storage for FsStorage {
+    /// Create the "skeleton" of the torrent, the files and directories.
+    /// Skipped files are not created, unless a piece that is shared with
+    /// a wanted file is written to them, that is why their directories are.
+    ///
+    /// Fails with [`Error::NotEnoughSpace`] if the files that are not
+    /// on disk yet don't fit in the free space of the filesystem.
+    fn create(
+        &self,
+        info_hash: [u8; 20],
+        info: &Info,
+        priorities: &[Priority],
+    ) -> Result<(), Error> {
+        let paths = self.file_paths(info_hash, info);
+        let lengths = file_lengths(info);
+
+        // a file without a path, of a malformed torrent
+        let no_path = |i: usize| info.files.as_ref().is_some_and(|f| f[i].path.is_empty());
+        let wanted = |i: usize| priorities.get(i) != Some(&Priority::Skip) && !no_path(i);
+
+        // the bytes of the wanted files that are not on disk yet
+        let needed: u64 = (0..paths.len())
+            .filter(|i| wanted(*i))
+            .map(|i| {
+                let on_disk = std::fs::metadata(&paths[i]).map_or(0, |m| m.len());
+                lengths[i].saturating_sub(on_disk)
+            })
+            .sum();
+
+        if let Some(available) = available_space(&self.save_path(info_hash)) {
+            if needed > available {
+                return Err(Error::NotEnoughSpace(needed, available));
+            }
+        }
+
+        for (i, path) in paths.iter().enumerate() {
+            // the directory of the current file
+            // save_path/name_of_torrent/name_of_dir
+            if let Some(dir) = path.parent() {
+                create_dir_all(dir)?;
+            }
+
+            if !wanted(i) {
+                continue;
+            }
+
+            // now with the dirs created, we create the file
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .map_err(|_| Error::FileOpenError(path.to_string_lossy().into_owned()))?;
+
+            if self.allocation == Allocation::Full && file.metadata()?.len() < lengths[i] {
+                allocate(&file, lengths[i])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_at(
+        &self,
+        info_hash: [u8; 20],
+        info: &Info,
+        file: usize,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        let path = self.file_path(info_hash, info, file)?;
+        let file = self.file_pool.get(&path, false)?;
+        let mut file = file.lock().unwrap();
+
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)?;
+
+        Ok(())
+    }
+
+    fn write_at(
+        &self,
+        info_hash: [u8; 20],
+        info: &Info,
+        file: usize,
+        offset: u64,
+        bufs: &mut [IoSlice],
+    ) -> Result<(), Error> {
+        let path = self.file_path(info_hash, info, file)?;
+        let file = self.file_pool.get(&path, true)?;
+        let mut file = file.lock().unwrap();
+
+        file.seek(SeekFrom::Start(offset))?;
+        write_all_vectored(&mut *file, bufs)?;
+
+        Ok(())
+    }
+
+    fn flush(&self, info_hash: [u8; 20], info: &Info) -> Result<(), Error> {
+        let base = self.base(info_hash, info);
+        self.file_pool.sync_dir(&base)
+    }
+
+    /// Close the files of the torrent, the files stay on disk.
+    fn remove(&self, info_hash: [u8; 20], info: &Info) -> Result<(), Error> {
+        let base = self.base(info_hash, info);
+        self.file_pool.close_dir(&base);
+        self.save_paths.write().unwrap().remove(&info_hash);
+        self.part_files.write().unwrap().remove(&info_hash);
+        self.paths.write().unwrap().remove(&info_hash);
+        Ok(())
+    }
+
+    fn file_sizes(&self, info_hash: [u8; 20], info: &Info) -> Vec<(u64, u64)> {
+        self.file_paths(info_hash, info)
+            .iter()
+            .map(|path| ResumeData::file_size(path))
+            .collect()
+    }
+
+    fn set_save_path(&self, info_hash: [u8; 20], path: PathBuf) {
+        self.save_paths.write().unwrap().insert(info_hash, path);
+        self.paths.write().unwrap().remove(&info_hash);
+    }
+
+    fn set_part_files(&self, info_hash: [u8; 20], part: bool) {
+        if part {
+            self.part_files.write().unwrap().insert(info_hash);
+        } else {
+            self.part_files.write().unwrap().remove(&info_hash);
+        }
+        self.paths.write().unwrap().remove(&info_hash);
+    }
+
+    /// Move the files that are on disk, the files that were moved are
+    /// moved back if one of them can't be moved, and the torrent stays
+    /// where it was.
+    fn move_to(
+        &self,
+        info_hash: [u8; 20],
+        info: &Info,
+        path: &Path,
+        part: bool,
+    ) -> Result<(), Error> {
+        let old_base = self.base(info_hash, info);
+        let new_base = base_in(path, info, part);
+        let new_paths = paths_in(path, info, part);
+
+        if *self.file_paths(info_hash, info) == new_paths {
+            return Ok(());
+        }
+
+        // the files are opened again from the new paths
+        self.file_pool.close_dir(&old_base);
+
+        let mut moved: Vec<(PathBuf, PathBuf)> = Vec::new();
+
+        let old_paths = self.file_paths(info_hash, info);
+
+        for (from, to) in old_paths.iter().cloned().zip(new_paths) {
+            if !from.exists() {
+                continue;
+            }
+
+            if let Err(e) = move_file(&from, &to) {
+                for (from, to) in moved.iter().rev() {
+                    let _ = move_file(to, from);
+                }
+                return Err(e.into());
+            }
+
+            moved.push((from, to));
+        }
+
+        if old_base != new_base && old_base.is_dir() {
+            remove_empty_dirs(&old_base);
+        }
+
+        self.set_save_path(info_hash, path.to_owned());
+        self.set_part_files(info_hash, part);
+
+        Ok(())
+    }
+}
+
+/// (info_hash, file index)
+type FileKey = ([u8; 20], usize);
+
+/// Store the files of the torrents in memory,
+/// the data of a torrent is dropped when it is removed.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    files: Mutex<HashMap<FileKey, Vec<u8>>>,
+}
+
+impl Storage for MemoryStorage {
+    fn create(
+        &self,
+        info_hash: [u8; 20],
+        info: &Info,
+        priorities: &[Priority],
+    ) -> Result<(), Error> {
+        for file in 0..file_lengths(info).len() {
+            if priorities.get(file) != Some(&Priority::Skip) {
+                self.files
+                    .lock()
+                    .unwrap()
+                    .entry((info_hash, file))
+                    .or_default();
+            }
+        }
+        Ok(())
+    }
+
+    fn read_at(
+        &self,
+        info_hash: [u8; 20],
+        _info: &Info,
+        file: usize,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        let files = self.files.lock().unwrap();
+        let data = files.get(&(info_hash, file)).ok_or(Error::BlockInvalid)?;
+
+        let start = offset as usize;
+        let bytes = data
+            .get(start..start + buf.len())
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_at(
+        &self,
+        info_hash: [u8; 20],
+        _info: &Info,
+        file: usize,
+        offset: u64,
+        bufs: &mut [IoSlice],
+    ) -> Result<(), Error> {
+        let mut files = self.files.lock().unwrap();
+        let data = files.entry((info_hash, file)).or_default();
+        let mut start = offset as usize;
+
+        for buf in bufs.iter() {
+            let end = start + buf.len();
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[start..end].copy_from_slice(buf);
+            start = end;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self, _info_hash: [u8; 20], _info: &Info) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn remove(&self, info_hash: [u8; 20], _info: &Info) -> Result<(), Error> {
+        self.files
+            .lock()
+            .unwrap()
+            .retain(|(i, _), _| *i != info_hash);
+        Ok(())
+    }
+
+    fn file_sizes(&self, info_hash: [u8; 20], info: &Info) -> Vec<(u64, u64)> {
+        (0..file_lengths(info).len())
+            .map(|file| {
+                let files = self.files.lock().unwrap();
+                let len = files.get(&(info_hash, file)).map_or(0, |f| f.len());
+                (len as u64, 0)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{distributions::Alphanumeric, Rng};
+
+    use crate::metainfo::File;
+
+    use super::*;
+
+    fn info() -> Info {
+        Info {
+            file_length: None,
+            name: "arch".to_owned(),
+            piece_length: 8,
+            pieces: vec![0; 40],
+            files: Some(vec![
+                File {
+                    length: 6,
+                    path: vec!["foo.txt".to_owned()],
+                },
+                File {
+                    length: 6,
+                    path: vec!["bar".to_owned(), "baz.txt".to_owned()],
+                },
+            ]),
+        }
+    }
+
+    // both storages must behave the same
+    fn read_write(storage: &dyn Storage) {
+        let info = info();
+
+        storage
+            .create([0; 20], &info, &[Priority::Normal, Priority::Skip])
+            .unwrap();
+        assert_eq!(storage.file_sizes([0; 20], &info)[0].0, 0);
+
+        let mut buf = [0; 2];
+        assert!(storage.read_at([0; 20], &info, 0, 0, &mut buf).is_err());
+
+        let a = [1, 2, 3, 4];
+        let b = [5, 6];
+        storage
+            .write_at(
+                [0; 20],
+                &info,
+                0,
+                0,
+                &mut [IoSlice::new(&a), IoSlice::new(&b)],
+            )
+            .unwrap();
+        storage
+            .write_at([0; 20], &info, 1, 0, &mut [IoSlice::new(&[7, 8])])
+            .unwrap();
+        storage.flush([0; 20], &info).unwrap();
+
+        storage.read_at([0; 20], &info, 0, 1, &mut buf).unwrap();
+        assert_eq!(buf, [2, 3]);
+
+        let sizes = storage.file_sizes([0; 20], &info);
+        assert_eq!(sizes.iter().map(|s| s.0).collect::<Vec<_>>(), vec![6, 2]);
+
+        // the first piece is in both files
+        let mut hash = sha1_smol::Sha1::new();
+        hash.update(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(
+            storage.hash_piece([0; 20], &info, 0).unwrap(),
+            hash.digest().bytes()
+        );
+
+        storage.remove([0; 20], &info).unwrap();
+    }
+
+    #[test]
+    fn memory_storage() {
+        let storage = MemoryStorage::default();
+        read_write(&storage);
+        assert!(storage.files.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn fs_storage() {
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
+
+        let storage = FsStorage::new(download_dir.clone());
+        read_write(&storage);
+
+        // the files stay on disk, but they are closed
+        assert!(storage.file_pool.is_empty());
+        assert_eq!(
+            std::fs::read(format!("{download_dir}/arch/bar/baz.txt")).unwrap(),
+            vec![7, 8]
+        );
+
+        std::fs::remove_dir_all(download_dir).unwrap();
+    }
+
+    #[test]
+    fn move_files() {
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
+        let save_path = Path::new(&download_dir).join("moved");
+
+        let storage = FsStorage::new(download_dir.clone());
+        let info = info();
+
+        storage
+            .create([0; 20], &info, &[Priority::Normal, Priority::Skip])
+            .unwrap();
+        storage
+            .write_at([0; 20], &info, 0, 0, &mut [IoSlice::new(&[1, 2, 3])])
+            .unwrap();
+
+        // the paths are computed once, until the files are moved
+        let paths = storage.file_paths([0; 20], &info);
+        assert!(Arc::ptr_eq(&paths, &storage.file_paths([0; 20], &info)));
+
+        storage.move_to([0; 20], &info, &save_path, false).unwrap();
+        assert_eq!(
+            storage.file_paths([0; 20], &info)[0],
+            save_path.join("arch/foo.txt")
+        );
+
+        // the skipped file was not created, and is not moved
+        assert_eq!(
+            std::fs::read(save_path.join("arch/foo.txt")).unwrap(),
+            vec![1, 2, 3]
+        );
+        assert!(!save_path.join("arch/bar/baz.txt").exists());
+        assert!(!Path::new(&format!("{download_dir}/arch")).exists());
+
+        // the files are read and written on the new path, the skipped
+        // file is created there when it is wanted
+        storage
+            .create([0; 20], &info, &[Priority::Normal, Priority::Normal])
+            .unwrap();
+        storage
+            .write_at([0; 20], &info, 1, 0, &mut [IoSlice::new(&[7, 8])])
+            .unwrap();
+        let mut buf = [0; 2];
+        storage.read_at([0; 20], &info, 0, 1, &mut buf).unwrap();
+        assert_eq!(buf, [2, 3]);
+        assert!(save_path.join("arch/bar/baz.txt").exists());
+
+        // other torrents are still saved in the download dir
+        assert_eq!(
+            storage.base([1; 20], &info),
+            Path::new(&download_dir).join("arch")
+        );
+
+        std::fs::remove_dir_all(download_dir).unwrap();
+    }
+
+    #[test]
+    fn part_files() {
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
+        let complete = Path::new(&download_dir).join("complete");
+
+        let storage = FsStorage::new(download_dir.clone());
+        let info = info();
+
+        storage.set_part_files([0; 20], true);
+        storage
+            .create([0; 20], &info, &[Priority::Normal, Priority::Normal])
+            .unwrap();
+        storage
+            .write_at([0; 20], &info, 0, 0, &mut [IoSlice::new(&[1, 2, 3])])
+            .unwrap();
+
+        assert!(Path::new(&format!("{download_dir}/arch/foo.txt.part")).exists());
+        assert!(Path::new(&format!("{download_dir}/arch/bar/baz.txt.part")).exists());
+
+        // the files lose the suffix when they are moved
+        storage.move_to([0; 20], &info, &complete, false).unwrap();
+
+        assert!(!storage.is_part([0; 20]));
+        assert_eq!(
+            std::fs::read(complete.join("arch/foo.txt")).unwrap(),
+            vec![1, 2, 3]
+        );
+        assert!(complete.join("arch/bar/baz.txt").exists());
+        assert!(!complete.join("arch/foo.txt.part").exists());
+
+        // the suffix of a single file torrent is on the torrent
+        let single = Info {
+            file_length: Some(4),
+            name: "single".to_owned(),
+            piece_length: 8,
+            pieces: vec![0; 20],
+            files: None,
+        };
+
+        storage.set_part_files([1; 20], true);
+        storage.create([1; 20], &single, &[]).unwrap();
+        assert!(Path::new(&format!("{download_dir}/single.part")).exists());
+
+        storage
+            .move_to([1; 20], &single, Path::new(&download_dir), false)
+            .unwrap();
+        assert!(Path::new(&format!("{download_dir}/single")).exists());
+        assert!(!Path::new(&format!("{download_dir}/single.part")).exists());
+
+        std::fs::remove_dir_all(download_dir).unwrap();
+    }
+
+    #[test]
+    fn full_allocation() {
+        let mut rng = rand::thread_rng();
+        let download_dir: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
+
+        let mut storage = FsStorage::new(download_dir.clone());
+        storage.allocation = Allocation::Full;
+
+        let mut info = info();
+        storage
+            .create([0; 20], &info, &[Priority::Normal, Priority::Skip])
+            .unwrap();
+
+        let foo = format!("{download_dir}/arch/foo.txt");
+        assert_eq!(std::fs::metadata(foo).unwrap().len(), 6);
+        assert!(!Path::new(&format!("{download_dir}/arch/bar/baz.txt")).exists());
+
+        // a file larger than any disk
+        info.files.as_mut().unwrap()[1].length = u64::MAX / 2;
+        let r = storage.create([0; 20], &info, &[Priority::Normal, Priority::Normal]);
+
+        if cfg!(unix) {
+            assert!(matches!(r, Err(Error::NotEnoughSpace(..))));
+        }
+
+        std::fs::remove_dir_all(download_dir).unwrap();
+    }
+
+    #[test]
+    fn error_reasons() {
+        let e: Error = std::io::Error::from(std::io::ErrorKind::PermissionDenied).into();
+        assert!(matches!(e, Error::Io(_)));
+        assert_eq!(
+            error_reason(&e),
+            Some("I/O error: permission denied".to_owned())
+        );
+
+        assert_eq!(error_reason(&Error::BlockInvalid), None);
+    }
+
+    #[test]
+    fn segments_of_blocks() {
+        let info = info();
+
+        let block = |index, begin, len| BlockInfo { index, begin, len };
+
+        assert_eq!(
+            segments(&info, &block(0, 4, 4)).unwrap(),
+            vec![
+                Segment {
+                    file: 0,
+                    offset: 4,
+                    len: 2
+                },
+                Segment {
+                    file: 1,
+                    offset: 0,
+                    len: 2
+                },
+            ]
+        );
+        assert_eq!(
+            segments(&info, &block(1, 0, 4)).unwrap(),
+            vec![Segment {
+                file: 1,
+                offset: 2,
+                len: 4
+            }]
+        );
+        assert!(segments(&info, &block(1, 0, 5)).is_err());
+    }
+}
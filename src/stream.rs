@@ -0,0 +1,361 @@
+//! HTTP server to stream the files of torrents while they are downloaded.
+//!
+//! A file is served at `http://<addr>/<info_hash>/<path>`, where the info hash
+//! is in hex, and the path is the path of the file inside the torrent, for
+//! example: `mpv http://127.0.0.1:3000/<info_hash>/dir/movie.mkv`. Requests
+//! with a `Range` header are supported, so that players can seek.
+use std::{net::SocketAddr, ops::RangeInclusive};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    spawn,
+    sync::{mpsc, oneshot},
+};
+use tracing::{info, warn};
+
+use crate::{
+    disk::DiskMsg,
+    error::Error,
+    tcp_wire::lib::{BlockInfo, BLOCK_LEN},
+};
+
+/// The maximum size of the head of a request.
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+#[derive(Debug)]
+pub struct StreamServer {
+    listener: TcpListener,
+    disk_tx: mpsc::Sender<DiskMsg>,
+}
+
+/// The parts of a request that we care about.
+#[derive(Debug, Clone, PartialEq)]
+struct Request {
+    method: String,
+    path: String,
+    range: Option<String>,
+}
+
+impl StreamServer {
+    pub async fn new(addr: SocketAddr, disk_tx: mpsc::Sender<DiskMsg>) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self { listener, disk_tx })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept connections, each request is handled in its own task.
+    pub async fn run(self) -> Result<(), Error> {
+        info!("streaming server on {:?}", self.listener.local_addr());
+
+        loop {
+            let (socket, addr) = self.listener.accept().await?;
+            let disk_tx = self.disk_tx.clone();
+
+            spawn(async move {
+                if let Err(e) = handle(socket, disk_tx).await {
+                    warn!("stream request from {addr} failed: {e}");
+                }
+            });
+        }
+    }
+}
+
+/// Answer a single request, the connection is closed after the response.
+async fn handle(mut socket: TcpStream, disk_tx: mpsc::Sender<DiskMsg>) -> Result<(), Error> {
+    let mut buf = Vec::new();
+
+    // read until the end of the head, we don't care about the body
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_HEAD_LEN {
+            return respond(&mut socket, "431 Request Header Fields Too Large", &[]).await;
+        }
+        let mut chunk = [0; 1024];
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let Some(req) = parse_request(&buf) else {
+        return respond(&mut socket, "400 Bad Request", &[]).await;
+    };
+
+    if req.method != "GET" && req.method != "HEAD" {
+        return respond(&mut socket, "405 Method Not Allowed", &[]).await;
+    }
+
+    // the path is /<info_hash>/<path of the file>
+    let Some((info_hash, file_path)) = req.path.trim_start_matches('/').split_once('/') else {
+        return respond(&mut socket, "404 Not Found", &[]).await;
+    };
+
+    let Some(info_hash) = hex::decode(info_hash)
+        .ok()
+        .and_then(|h| <[u8; 20]>::try_from(h).ok())
+    else {
+        return respond(&mut socket, "404 Not Found", &[]).await;
+    };
+
+    let Ok(file_path) = urlencoding::decode(file_path) else {
+        return respond(&mut socket, "400 Bad Request", &[]).await;
+    };
+
+    // the torrent only exists on Disk after we have its info
+    let (otx, orx) = oneshot::channel();
+    disk_tx.send(DiskMsg::GetTorrentCtx(info_hash, otx)).await?;
+
+    let Some(torrent_ctx) = orx.await? else {
+        return respond(&mut socket, "404 Not Found", &[]).await;
+    };
+
+    let info = torrent_ctx.info.read().await.clone();
+
+    // find the file, and where it begins in the torrent
+    let mut file_offset = 0;
+    let mut file = None;
+
+    for f in info.get_files() {
+        if f.path.join("/") == file_path {
+            file = Some(f);
+            break;
+        }
+        file_offset += f.length;
+    }
+
+    let Some(file) = file else {
+        return respond(&mut socket, "404 Not Found", &[]).await;
+    };
+
+    let content_type = ("Content-Type", content_type(&file_path).to_owned());
+    let accept_ranges = ("Accept-Ranges", "bytes".to_owned());
+
+    let (status, range) = match &req.range {
+        Some(header) => match parse_range(header, file.length) {
+            Some(range) => ("206 Partial Content", range),
+            None => {
+                let content_range = ("Content-Range", format!("bytes */{}", file.length));
+                return respond(
+                    &mut socket,
+                    "416 Range Not Satisfiable",
+                    &[content_range, accept_ranges],
+                )
+                .await;
+            }
+        },
+        None if file.length == 0 => {
+            return respond(&mut socket, "200 OK", &[content_type, accept_ranges]).await;
+        }
+        None => ("200 OK", 0..=file.length - 1),
+    };
+
+    let mut headers = vec![
+        content_type,
+        accept_ranges,
+        (
+            "Content-Length",
+            (range.end() - range.start() + 1).to_string(),
+        ),
+    ];
+
+    if req.range.is_some() {
+        headers.push((
+            "Content-Range",
+            format!("bytes {}-{}/{}", range.start(), range.end(), file.length),
+        ));
+    }
+
+    write_head(&mut socket, status, &headers).await?;
+
+    if req.method == "HEAD" {
+        return Ok(());
+    }
+
+    // read the range block by block, each block is only sent
+    // by the Disk after its piece is downloaded and validated.
+    let piece_length = info.piece_length as u64;
+    let mut offset = file_offset + range.start();
+    let end = file_offset + range.end() + 1;
+
+    while offset < end {
+        let index = offset / piece_length;
+        let begin = offset % piece_length;
+        let len = (BLOCK_LEN as u64)
+            .min(piece_length - begin)
+            .min(end - offset);
+
+        let (otx, orx) = oneshot::channel();
+
+        disk_tx
+            .send(DiskMsg::ReadStreamBlock {
+                b: BlockInfo {
+                    index: index as u32,
+                    begin: begin as u32,
+                    len: len as u32,
+                },
+                recipient: otx,
+                info_hash,
+            })
+            .await?;
+
+        let block = orx.await??;
+        socket.write_all(&block).await?;
+
+        offset += len;
+    }
+
+    Ok(())
+}
+
+async fn write_head(
+    socket: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, String)],
+) -> Result<(), Error> {
+    let mut head = format!("HTTP/1.1 {status}\r\nConnection: close\r\n");
+
+    for (k, v) in headers {
+        head.push_str(&format!("{k}: {v}\r\n"));
+    }
+    head.push_str("\r\n");
+
+    socket.write_all(head.as_bytes()).await?;
+
+    Ok(())
+}
+
+/// Respond without a body.
+async fn respond(
+    socket: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, String)],
+) -> Result<(), Error> {
+    let mut headers = headers.to_vec();
+    headers.push(("Content-Length", "0".to_owned()));
+    write_head(socket, status, &headers).await
+}
+
+/// Parse the request line and the `Range` header of the head of a request.
+fn parse_request(buf: &[u8]) -> Option<Request> {
+    let head = std::str::from_utf8(buf).ok()?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_owned();
+    let path = request_line.next()?.to_owned();
+
+    let range = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("range"))
+        .map(|(_, v)| v.trim().to_owned());
+
+    Some(Request {
+        method,
+        path,
+        range,
+    })
+}
+
+/// Parse the value of a `Range` header, with a single range, into an inclusive
+/// range of bytes of a file with `len` bytes. None if the range can't be satisfied.
+fn parse_range(header: &str, len: u64) -> Option<RangeInclusive<u64>> {
+    let (start, end) = header.strip_prefix("bytes=")?.trim().split_once('-')?;
+
+    let range = match (start.trim(), end.trim()) {
+        // the last `n` bytes
+        ("", n) => {
+            let n: u64 = n.parse().ok()?;
+            if n == 0 {
+                return None;
+            }
+            len.saturating_sub(n)..=len.checked_sub(1)?
+        }
+        // from `start` until the end of the file
+        (start, "") => start.parse().ok()?..=len.checked_sub(1)?,
+        (start, end) => {
+            let end: u64 = end.parse().ok()?;
+            start.parse().ok()?..=end.min(len.checked_sub(1)?)
+        }
+    };
+
+    if range.start() > range.end() {
+        return None;
+    }
+
+    Some(range)
+}
+
+/// The MIME type of a file, by its extension.
+fn content_type(path: &str) -> &'static str {
+    let ext = path
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_lowercase())
+        .unwrap_or_default();
+
+    match ext.as_str() {
+        "mkv" => "video/x-matroska",
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mov" => "video/quicktime",
+        "ts" => "video/mp2t",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" | "opus" => "audio/ogg",
+        "m4a" => "audio/mp4",
+        "wav" => "audio/wav",
+        "txt" | "srt" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request() {
+        let buf =
+            b"GET /aa/dir/my%20movie.mkv HTTP/1.1\r\nHost: 127.0.0.1\r\nrange: bytes=0-\r\n\r\n";
+
+        assert_eq!(
+            parse_request(buf),
+            Some(Request {
+                method: "GET".to_owned(),
+                path: "/aa/dir/my%20movie.mkv".to_owned(),
+                range: Some("bytes=0-".to_owned()),
+            })
+        );
+
+        let buf = b"HEAD /aa/b HTTP/1.1\r\n\r\n";
+        assert_eq!(parse_request(buf).unwrap().range, None);
+    }
+
+    #[test]
+    fn range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(0..=99));
+        assert_eq!(parse_range("bytes=500-", 1000), Some(500..=999));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(900..=999));
+        assert_eq!(parse_range("bytes=-2000", 1000), Some(0..=999));
+        // the end is clamped to the end of the file
+        assert_eq!(parse_range("bytes=900-5000", 1000), Some(900..=999));
+
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=50-10", 1000), None);
+        assert_eq!(parse_range("bytes=0-", 0), None);
+        assert_eq!(parse_range("bytes=-0", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+    }
+
+    #[test]
+    fn mime_types() {
+        assert_eq!(content_type("dir/movie.MKV"), "video/x-matroska");
+        assert_eq!(content_type("song.flac"), "audio/flac");
+        assert_eq!(content_type("no_extension"), "application/octet-stream");
+    }
+}
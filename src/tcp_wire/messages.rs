@@ -324,7 +324,7 @@ impl Decoder for HandshakeCodec {
         // we just want to peek at this value.
         let mut tmp_buf = Cursor::new(&buf);
         let prot_len = tmp_buf.get_u8() as usize;
-        if prot_len != PROTOCOL_STRING.as_bytes().len() {
+        if prot_len != PROTOCOL_STRING.len() {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "Handshake must have the string \"BitTorrent protocol\"",
@@ -1,34 +1,41 @@
 use crate::frontend::{FrMsg, TorrentInfo};
 use crate::magnet_parser::get_magnet;
 use crate::peer::session::ConnectionState;
-use crate::tcp_wire::lib::BlockInfo;
+use crate::tcp_wire::lib::{BlockInfo, BLOCK_LEN};
 use crate::tcp_wire::messages::HandshakeCodec;
 use crate::{
     bitfield::Bitfield,
+    choker::Choker,
     cli::Args,
+    dht::DhtMsg,
     disk::DiskMsg,
     error::Error,
     magnet_parser::get_info_hash,
-    metainfo::Info,
+    metainfo::{Info, MetaInfo, Priority},
     peer::{Direction, Peer, PeerCtx, PeerMsg},
+    resume::{self, ResumeData},
     tracker::{
+        announce,
         event::Event,
-        {Tracker, TrackerCtx, TrackerMsg},
+        spawn_tracker,
+        tiers::Tiers,
+        {TrackerCtx, TrackerMsg},
     },
 };
 use bendy::decoding::FromBencode;
 use clap::Parser;
-use hashbrown::HashMap;
+use futures::future::join_all;
+use hashbrown::{HashMap, HashSet};
 use magnet_url::Magnet;
 use std::collections::BTreeMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::{sync::Arc, time::Duration};
-use tokio::time::interval;
+use tokio::time::{interval, interval_at, Instant};
 use tokio::{
     net::{TcpListener, TcpStream},
     select, spawn,
     sync::{mpsc, oneshot, RwLock},
-    time::{interval_at, Instant},
+    time::timeout,
 };
 use tokio_util::codec::Framed;
 use tracing::{info, warn};
@@ -64,8 +71,46 @@ pub enum TorrentMsg {
     /// index, recipient
     RequestInfoPiece(u32, oneshot::Sender<Option<Vec<u8>>>),
     IncrementDownloaded(u64),
+    /// When a piece fails the hash check, the bytes of the piece
+    /// were counted as downloaded, but they will be downloaded again.
+    DecrementDownloaded(u64),
     IncrementUploaded(u64),
+    /// Sent by the Disk after a block is read for a peer,
+    /// `true` if the block was in memory.
+    CacheRead(bool),
+    /// The result of an announce to the tracker `url`. `tracker_tx` is the
+    /// sender of the tracker task, if we could connect to the tracker.
+    TrackerAnnounced {
+        url: String,
+        tracker_tx: Option<mpsc::Sender<TrackerMsg>>,
+        result: Result<(announce::Response, Vec<SocketAddr>), Error>,
+    },
+    /// Peers of the torrent found on the DHT.
+    DhtPeers(Vec<SocketAddr>),
+    /// Peers of the torrent that a peer sent us in a PEX message.
+    PexPeers(Vec<SocketAddr>),
     TogglePause,
+    /// Toggle the sequential mode, to stream the media
+    /// files of the torrent while they are downloaded.
+    ToggleSequential,
+    /// Set the priority of the file with the given index, on `Info::files`.
+    SetFilePriority(usize, Priority),
+    /// Hash every piece that is on disk, to know which pieces we have.
+    /// Used when the files were copied before adding the torrent,
+    /// or when the data on disk may be corrupt.
+    Check,
+    /// Sent by the Disk while checking, with how many pieces were checked.
+    CheckProgress(usize),
+    /// Sent by the Disk after all pieces were checked, or after the check
+    /// failed, in which case it follows a `DiskError` if the storage failed.
+    CheckComplete,
+    /// Move the files of the torrent to another directory, the torrent
+    /// keeps downloading and seeding while they are moved.
+    MoveStorage(String),
+    /// Sent by the Disk when the files of the torrent could not be created,
+    /// read or written, with the reason. The torrent stops with an error,
+    /// until the user fixes the problem and resumes it with `TogglePause`.
+    DiskError(String),
     /// When torrent is being gracefully shutdown
     Quit,
 }
@@ -79,7 +124,26 @@ pub struct Torrent {
     pub disk_tx: mpsc::Sender<DiskMsg>,
     pub rx: mpsc::Receiver<TorrentMsg>,
     pub peer_ctxs: HashMap<[u8; 20], Arc<PeerCtx>>,
-    pub tracker_tx: Option<mpsc::Sender<TrackerMsg>>,
+    /// The trackers of the torrent grouped in tiers, with
+    /// the status of the announces of each tracker.
+    pub trackers: Tiers,
+    /// Addresses of the peers that we already tried to connect to,
+    /// peers returned by more than one announce are connected only once.
+    pub known_peers: HashSet<SocketAddr>,
+    /// Sender of the DHT node, used to find peers of the torrent,
+    /// `None` if the DHT is disabled.
+    pub dht_tx: Option<mpsc::Sender<DhtMsg>>,
+    /// Decides which peers are allowed to download from us.
+    pub choker: Choker,
+    /// If the pieces are downloaded in order.
+    pub sequential: bool,
+    /// If the pieces on disk are checked after we have the info.
+    pub check: bool,
+    /// How many pieces were checked, while the status is `Checking`.
+    pub checked: usize,
+    /// The resume data of the previous run, the pieces
+    /// and blocks are restored by the Disk on `start`.
+    pub resume: Option<ResumeData>,
     /// If using a Magnet link, the info will be downloaded in pieces
     /// and those pieces may come in different order,
     /// hence the HashMap (dictionary), and not a vec.
@@ -100,8 +164,8 @@ pub struct Torrent {
     pub last_second_downloaded: u64,
     /// The download rate of the torrent, in bytes
     pub download_rate: u64,
-    /// The total size of the torrent files, in bytes,
-    /// this is a cache of ctx.info.get_size()
+    /// The size of the pieces that will be downloaded, of the files that
+    /// are not skipped, in bytes. This is a cache of ctx.info.wanted_size()
     pub size: u64,
     pub name: String,
 }
@@ -114,6 +178,18 @@ pub struct TorrentCtx {
     pub info_hash: [u8; 20],
     pub pieces: RwLock<Bitfield>,
     pub info: RwLock<Info>,
+    /// The priority of each file, in the order of `Info::files`.
+    /// Files without a priority are `Normal`.
+    pub file_priorities: RwLock<Vec<Priority>>,
+    /// The directory in which the files of the torrent are
+    /// saved, the download dir of the Disk if `None`.
+    pub save_path: RwLock<Option<String>>,
+    /// The directory in which the files are downloaded, they are moved to
+    /// the save path when the download is complete. `None` if the files
+    /// are in the save path.
+    pub incomplete_dir: RwLock<Option<String>>,
+    /// If the names of the files end with `.part`, until the download is complete.
+    pub part_files: RwLock<bool>,
 }
 
 // Status of the current Torrent, updated at every announce request.
@@ -122,6 +198,10 @@ pub struct Stats {
     pub interval: u32,
     pub leechers: u32,
     pub seeders: u32,
+    /// How many blocks read for peers were in the caches of the Disk.
+    pub cache_hits: u64,
+    /// How many blocks read for peers were read from disk.
+    pub cache_misses: u64,
 }
 
 impl Torrent {
@@ -132,11 +212,6 @@ impl Torrent {
             std::process::exit(exitcode::USAGE)
         });
 
-        if magnet.tr.is_empty() {
-            eprintln!("This magnet link does not have any addresses to announce. Currently, we do not support DHT, please used a different magnet link.");
-            std::process::exit(exitcode::DATAERR)
-        }
-
         let xt = magnet
             .xt
             .clone()
@@ -152,6 +227,9 @@ impl Torrent {
         let info_hash = get_info_hash(&xt);
         let (tx, rx) = mpsc::channel::<TorrentMsg>(300);
 
+        // a magnet does not have tiers, each tracker is on its own tier.
+        let trackers = Tiers::new(magnet.tr.iter().map(|tr| vec![tr.clone()]).collect());
+
         let ctx = Arc::new(TorrentCtx {
             tx: tx.clone(),
             tracker_tx: RwLock::new(None),
@@ -159,6 +237,10 @@ impl Torrent {
             pieces,
             magnet,
             info,
+            file_priorities: RwLock::new(Vec::new()),
+            save_path: RwLock::new(None),
+            incomplete_dir: RwLock::new(None),
+            part_files: RwLock::new(false),
         });
 
         Self {
@@ -173,7 +255,14 @@ impl Torrent {
             downloaded: 0,
             info_pieces,
             tracker_ctx,
-            tracker_tx: None,
+            trackers,
+            known_peers: HashSet::new(),
+            dht_tx: None,
+            choker: Choker::default(),
+            sequential: false,
+            resume: None,
+            check: false,
+            checked: 0,
             ctx,
             disk_tx,
             rx,
@@ -182,24 +271,280 @@ impl Torrent {
         }
     }
 
-    /// Start the Torrent, by sending `connect` and `announce_exchange`
-    /// messages to one of the trackers, and returning a list of peers.
-    #[tracing::instrument(skip(self), name = "torrent::start")]
-    pub async fn start(&mut self, listen: Option<SocketAddr>) -> Result<Vec<Peer>, Error> {
-        let mut tracker = Tracker::connect(self.ctx.magnet.tr.clone()).await?;
-        let info_hash = self.ctx.clone().info_hash;
-        let (res, peers) = tracker.announce_exchange(info_hash, listen).await?;
-
-        self.stats = Stats {
-            interval: res.interval,
-            seeders: res.seeders,
-            leechers: res.leechers,
+    /// Create a Torrent from the bytes of a .torrent file. Unlike
+    /// a magnet link, the file already has the `Info`, so the torrent
+    /// can skip the metadata phase and go straight to downloading.
+    #[tracing::instrument(skip(disk_tx, fr_tx, buf), name = "torrent::new_from_metainfo")]
+    pub fn new_from_metainfo(
+        disk_tx: mpsc::Sender<DiskMsg>,
+        fr_tx: mpsc::Sender<FrMsg>,
+        buf: &[u8],
+    ) -> Result<Self, Error> {
+        let metainfo = MetaInfo::from_bencode(buf).map_err(|_| Error::BencodeError)?;
+        let raw_info = MetaInfo::raw_info(buf).map_err(|_| Error::BencodeError)?;
+
+        let tr = metainfo.trackers();
+        let trackers = Tiers::new(metainfo.tiers());
+
+        let mut hash = sha1_smol::Sha1::new();
+        hash.update(raw_info);
+        let info_hash = hash.digest().bytes();
+
+        let info = metainfo.info;
+        let name = info.name.clone();
+        let size = info.get_size();
+
+        // we can serve the metadata to peers that
+        // are downloading this torrent with a magnet link.
+        let info_pieces: BTreeMap<u32, Vec<u8>> = raw_info
+            .chunks(BLOCK_LEN as usize)
+            .enumerate()
+            .map(|(i, chunk)| (i as u32, chunk.to_vec()))
+            .collect();
+
+        // used by the torrent to know the info_hash and trackers,
+        // in the same way as a torrent created from a magnet link.
+        let magnet = Magnet {
+            dn: Some(name.clone()),
+            hash_type: Some("btih".to_owned()),
+            xt: Some(hex::encode(info_hash)),
+            xl: Some(size),
+            xs: None,
+            tr,
+            kt: None,
+            ws: None,
+            acceptable_source: None,
+            mt: None,
+        };
+
+        let pieces = RwLock::new(Bitfield::from(vec![0_u8; info.pieces() as usize * 8]));
+        let tracker_ctx = Arc::new(TrackerCtx::default());
+        let (tx, rx) = mpsc::channel::<TorrentMsg>(300);
+
+        let ctx = Arc::new(TorrentCtx {
+            tx: tx.clone(),
+            tracker_tx: RwLock::new(None),
+            info_hash,
+            pieces,
+            magnet,
+            info: RwLock::new(info),
+            file_priorities: RwLock::new(Vec::new()),
+            save_path: RwLock::new(None),
+            incomplete_dir: RwLock::new(None),
+            part_files: RwLock::new(false),
+        });
+
+        Ok(Self {
+            name,
+            size,
+            last_second_downloaded: 0,
+            download_rate: 0,
+            status: TorrentStatus::Downloading,
+            stats: Stats::default(),
+            fr_tx,
+            uploaded: 0,
+            downloaded: 0,
+            info_pieces,
+            tracker_ctx,
+            trackers,
+            known_peers: HashSet::new(),
+            dht_tx: None,
+            choker: Choker::default(),
+            sequential: false,
+            resume: None,
+            check: false,
+            checked: 0,
+            ctx,
+            disk_tx,
+            rx,
+            peer_ctxs: HashMap::new(),
+            have_info: true,
+        })
+    }
+
+    /// If we have all the pieces that are not skipped.
+    pub async fn is_complete(&self) -> bool {
+        let info = self.ctx.info.read().await;
+        let priorities = self.ctx.file_priorities.read().await;
+        let pieces = self.ctx.pieces.read().await;
+
+        info.piece_priorities(&priorities)
+            .iter()
+            .enumerate()
+            .all(|(index, p)| *p == Priority::Skip || pieces.has(index))
+    }
+
+    /// Set the priority of a file. If files were skipped after the download
+    /// was complete, the torrent starts downloading again, and if all the
+    /// files that are left were skipped, the download is complete.
+    pub async fn set_file_priority(
+        &mut self,
+        index: usize,
+        priority: Priority,
+    ) -> Result<(), Error> {
+        let info = self.ctx.info.read().await;
+        let files = info.get_files().len();
+
+        if self.have_info && index >= files {
+            return Ok(());
+        }
+
+        let mut priorities = self.ctx.file_priorities.write().await;
+
+        if priorities.len() <= index {
+            priorities.resize(index + 1, Priority::default());
+        }
+        priorities[index] = priority;
+
+        if !self.have_info {
+            return Ok(());
+        }
+
+        self.size = info.wanted_size(&priorities);
+        drop(priorities);
+        drop(info);
+
+        self.disk_tx
+            .send(DiskMsg::SetFilePriorities(self.ctx.info_hash))
+            .await?;
+
+        let is_complete = self.is_complete().await;
+
+        if self.status == TorrentStatus::Seeding && !is_complete {
+            self.status = TorrentStatus::Downloading;
+
+            for peer in self.peer_ctxs.values() {
+                let _ = peer.tx.send(PeerMsg::Interested).await;
+            }
+        } else if self.status == TorrentStatus::Downloading && is_complete {
+            self.ctx.tx.send(TorrentMsg::DownloadComplete).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Restore the pieces of a previous run from the resume data,
+    /// a torrent that was already complete starts seeding.
+    async fn restore(&mut self, resume: ResumeData) -> Result<(), Error> {
+        let (otx, orx) = oneshot::channel();
+
+        self.disk_tx
+            .send(DiskMsg::RestoreResumeData(resume, otx))
+            .await?;
+
+        let restored = orx.await??;
+        info!("restored {restored} pieces from the resume data");
+
+        if self.is_complete().await {
+            self.status = TorrentStatus::Seeding;
+
+            // the previous run quit before the files were moved
+            self.disk_tx
+                .send(DiskMsg::CompleteStorage(self.ctx.clone()))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Send the state of the torrent to the Disk, which writes the
+    /// resume file. Only after we have the info of the torrent.
+    async fn save_resume_data(&self) -> Result<(), Error> {
+        if !self.have_info {
+            return Ok(());
+        }
+
+        // the info is in the pieces that we serve to other peers
+        let raw_info: Vec<u8> = self.info_pieces.values().flatten().copied().collect();
+
+        let tiers: Vec<Vec<String>> = self
+            .trackers
+            .tiers
+            .iter()
+            .map(|tier| tier.iter().map(|t| t.url.clone()).collect())
+            .collect();
+
+        let data = ResumeData {
+            info_hash: self.ctx.info_hash,
+            metainfo: resume::metainfo(&tiers, &raw_info)?,
+            file_priorities: self.ctx.file_priorities.read().await.clone(),
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+            sequential: self.sequential,
+            ..Default::default()
         };
 
-        info!("new stats {:#?}", self.stats);
+        self.disk_tx.send(DiskMsg::SaveResumeData(data)).await?;
+
+        Ok(())
+    }
 
-        let peers: Vec<Peer> = peers
+    /// Create the skeleton of the torrent on disk, and set the
+    /// sequential mode of the picker, after we have the info.
+    async fn new_torrent_on_disk(&self) -> Result<(), Error> {
+        self.disk_tx
+            .send(DiskMsg::NewTorrent(self.ctx.clone()))
+            .await?;
+
+        if self.sequential {
+            self.disk_tx
+                .send(DiskMsg::SetSequential(self.ctx.info_hash, true))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Start the Torrent. The announces to the trackers are done
+    /// by the event loop, in [`Torrent::run`].
+    #[tracing::instrument(skip(self), name = "torrent::start")]
+    pub async fn start(&mut self, listen: Option<SocketAddr>) -> Result<(), Error> {
+        // a torrent created from a .torrent file already has the info,
+        // create the skeleton of the files before any peer connects.
+        if self.have_info {
+            let info = self.ctx.info.read().await;
+            self.size = info.wanted_size(&self.ctx.file_priorities.read().await);
+            drop(info);
+
+            self.new_torrent_on_disk().await?;
+
+            if let Some(resume) = self.resume.take() {
+                self.restore(resume).await?;
+            }
+
+            if self.check {
+                self.ctx.tx.send(TorrentMsg::Check).await?;
+            }
+        }
+
+        // all trackers share the same peer_id and the address
+        // in which we listen for connections of peers.
+        let port = listen.map(|listen| listen.port()).unwrap_or(0);
+
+        self.tracker_ctx = Arc::new(TrackerCtx {
+            local_peer_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port),
+            ..(*self.tracker_ctx).clone()
+        });
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), name = "torrent::start_and_run")]
+    pub async fn start_and_run(&mut self, listen: Option<SocketAddr>) -> Result<(), Error> {
+        self.start(listen).await?;
+
+        self.spawn_inbound_peers().await?;
+        self.run().await?;
+
+        Ok(())
+    }
+
+    /// Create the peers of the given addresses, ignoring the
+    /// addresses that we already tried to connect to.
+    pub fn new_peers(&mut self, addrs: Vec<SocketAddr>) -> Vec<Peer> {
+        addrs
             .into_iter()
+            .filter(|addr| self.known_peers.insert(*addr))
             .map(|addr| {
                 let (peer_tx, peer_rx) = mpsc::channel::<PeerMsg>(300);
                 let torrent_ctx = self.ctx.clone();
@@ -208,35 +553,100 @@ impl Torrent {
 
                 Peer::new(addr, peer_tx, torrent_ctx, peer_rx, disk_tx, tracker_ctx)
             })
-            .collect();
-
-        info!("tracker.ctx peer {:?}", self.tracker_ctx.local_peer_addr);
+            .collect()
+    }
 
-        self.tracker_ctx = tracker.ctx.clone().into();
-        self.tracker_tx = Some(tracker.tx.clone());
-        let mut ctx_tracker_tx = self.ctx.tracker_tx.write().await;
-        *ctx_tracker_tx = Some(tracker.tx.clone());
-        drop(ctx_tracker_tx);
+    /// Announce to the tracker `url` in a new task, connecting to it first
+    /// if needed, so that a slow tracker does not block the event loop.
+    /// The result is sent back in a [`TorrentMsg::TrackerAnnounced`].
+    pub fn spawn_announce(&mut self, url: String, event: Event) {
+        let Some(tracker) = self.trackers.get_mut(&url) else {
+            return;
+        };
 
-        self.tracker_tx = Some(tracker.tx.clone());
+        let tracker_tx = tracker.tx.clone();
+        self.trackers.announcing(&url);
+
+        let tracker_ctx = (*self.tracker_ctx).clone();
+        let tx = self.ctx.tx.clone();
+        let info_hash = self.ctx.info_hash;
+        let downloaded = self.downloaded;
+        let uploaded = self.uploaded;
+        // a magnet without the info yet doesn't know its size
+        let left = if self.have_info {
+            self.size.saturating_sub(self.downloaded)
+        } else {
+            0
+        };
 
         spawn(async move {
-            tracker.run().await?;
-            Ok::<(), Error>(())
-        });
+            let tracker_tx = match tracker_tx {
+                Some(tracker_tx) => Ok(tracker_tx),
+                None => spawn_tracker(&url, tracker_ctx).await,
+            };
+
+            let (tracker_tx, result) = match tracker_tx {
+                Ok(tracker_tx) => {
+                    let (otx, orx) = oneshot::channel();
+
+                    let r = tracker_tx
+                        .send(TrackerMsg::Announce {
+                            event,
+                            info_hash,
+                            downloaded,
+                            uploaded,
+                            left,
+                            recipient: Some(otx),
+                        })
+                        .await;
 
-        Ok(peers)
+                    let result = match r {
+                        Ok(_) => orx.await.map_err(Error::from).and_then(|r| r),
+                        Err(e) => Err(e.into()),
+                    };
+
+                    (Some(tracker_tx), result)
+                }
+                Err(e) => (None, Err(e)),
+            };
+
+            let _ = tx
+                .send(TorrentMsg::TrackerAnnounced {
+                    url,
+                    tracker_tx,
+                    result,
+                })
+                .await;
+        });
     }
 
-    #[tracing::instrument(skip(self), name = "torrent::start_and_run")]
-    pub async fn start_and_run(&mut self, listen: Option<SocketAddr>) -> Result<(), Error> {
-        let peers = self.start(listen).await?;
+    /// Look up peers of the torrent on the DHT in a new task, and announce
+    /// that we are a peer. The peers are sent back in a [`TorrentMsg::DhtPeers`].
+    pub fn spawn_get_peers(&self) {
+        let Some(dht_tx) = self.dht_tx.clone() else {
+            return;
+        };
 
-        self.spawn_outbound_peers(peers).await?;
-        self.spawn_inbound_peers().await?;
-        self.run().await?;
+        let tx = self.ctx.tx.clone();
+        let info_hash = self.ctx.info_hash;
+        let port = self.tracker_ctx.local_peer_addr.port();
 
-        Ok(())
+        spawn(async move {
+            let (otx, orx) = oneshot::channel();
+
+            dht_tx
+                .send(DhtMsg::GetPeers {
+                    info_hash,
+                    port: Some(port),
+                    recipient: otx,
+                })
+                .await?;
+
+            let peers = orx.await?;
+            tx.send(TorrentMsg::DhtPeers(peers)).await?;
+
+            Ok::<(), Error>(())
+        });
     }
 
     /// Spawn an event loop for each peer to listen/send messages.
@@ -272,6 +682,7 @@ impl Torrent {
                 // that we wish to end the connection.
                 if peer.session.state.connection != ConnectionState::Quitting {
                     peer.free_pending_blocks().await;
+                    peer.delete_from_disk().await;
                 }
                 Ok::<(), Error>(())
             });
@@ -280,7 +691,7 @@ impl Torrent {
     }
 
     #[tracing::instrument(skip(self))]
-    pub async fn spawn_inbound_peers(&self) -> Result<(), Error> {
+    pub async fn spawn_inbound_peers(&mut self) -> Result<(), Error> {
         info!("running spawn inbound peers...");
         info!(
             "accepting requests in {:?}",
@@ -289,6 +700,13 @@ impl Torrent {
 
         let local_peer_socket = TcpListener::bind(self.tracker_ctx.local_peer_addr).await?;
 
+        // if we are listening on a random port, the trackers
+        // must announce the port that was given to us.
+        self.tracker_ctx = Arc::new(TrackerCtx {
+            local_peer_addr: local_peer_socket.local_addr()?,
+            ..(*self.tracker_ctx).clone()
+        });
+
         let torrent_ctx = Arc::clone(&self.ctx);
         let tracker_ctx = self.tracker_ctx.clone();
         let disk_tx = self.disk_tx.clone();
@@ -332,6 +750,7 @@ impl Torrent {
                         // that we wish to end the connection.
                         if peer.session.state.connection != ConnectionState::Quitting {
                             peer.free_pending_blocks().await;
+                            peer.delete_from_disk().await;
                         }
 
                         Ok::<(), Error>(())
@@ -344,15 +763,28 @@ impl Torrent {
 
     #[tracing::instrument(name = "torrent::run", skip(self))]
     pub async fn run(&mut self) -> Result<(), Error> {
-        let tracker_tx = self.tracker_tx.clone().unwrap();
+        // check which trackers are due for an announce
+        let mut announce_interval = interval(Duration::from_secs(1));
+
+        // look up peers on the DHT, the first tick is immediate
+        let mut dht_interval = interval(Duration::from_secs(5 * 60));
 
-        let mut announce_interval = interval_at(
-            Instant::now() + Duration::from_secs(self.stats.interval.max(500).into()),
-            Duration::from_secs((self.stats.interval as u64).max(500)),
+        // send the connected peers to the peers that support PEX
+        let mut pex_interval = interval_at(
+            Instant::now() + Duration::from_secs(60),
+            Duration::from_secs(60),
         );
 
+        // choke and unchoke peers by their rates
+        let mut choke_interval = interval(Choker::INTERVAL);
+
         let mut frontend_interval = interval(Duration::from_secs(1));
 
+        let mut resume_interval = interval_at(
+            Instant::now() + ResumeData::SAVE_INTERVAL,
+            ResumeData::SAVE_INTERVAL,
+        );
+
         loop {
             select! {
                 Some(msg) = self.rx.recv() => {
@@ -362,31 +794,33 @@ impl Torrent {
                             for peer in self.peer_ctxs.values() {
                                 let _ = peer.tx.send(PeerMsg::HavePiece(piece)).await;
                             }
+
+                            if self.status == TorrentStatus::Downloading && self.is_complete().await {
+                                info!("download completed, sending DownloadComplete");
+                                self.ctx.tx.send(TorrentMsg::DownloadComplete).await?;
+                            }
                         }
                         TorrentMsg::PeerConnected(id, ctx) => {
                             info!("connected with new peer");
                             self.peer_ctxs.insert(id, ctx);
                         }
+                        // the message can be sent more than once, when the
+                        // last pieces are downloaded close to each other.
+                        TorrentMsg::DownloadComplete if self.status == TorrentStatus::Seeding => {}
                         TorrentMsg::DownloadComplete => {
                             info!("received msg download complete");
-                            let (otx, orx) = oneshot::channel();
 
                             self.status = TorrentStatus::Seeding;
 
-                            let _ = tracker_tx.send(
-                                TrackerMsg::Announce {
-                                    event: Event::Completed,
-                                    info_hash: self.ctx.info_hash,
-                                    downloaded: self.downloaded,
-                                    uploaded: self.uploaded,
-                                    left: 0,
-                                    recipient: Some(otx),
-                                })
-                            .await;
-
-                            if let Ok(Ok(r)) = orx.await {
-                                info!("announced completion with success {r:#?}");
-                                self.stats = r.into();
+                            // the files are moved out of the incomplete dir,
+                            // and seeded from their save path.
+                            self.disk_tx
+                                .send(DiskMsg::CompleteStorage(self.ctx.clone()))
+                                .await?;
+
+                            // every working tracker must know that we are a seeder now
+                            for (url, _) in self.trackers.working() {
+                                self.spawn_announce(url, Event::Completed);
                             }
 
                             // tell all peers that we are not interested,
@@ -459,7 +893,7 @@ impl Torrent {
                                     let mut pieces = self.ctx.pieces.write().await;
                                     *pieces = Bitfield::from(vec![0_u8; info.pieces() as usize * 8]);
 
-                                    self.size = info.get_size();
+                                    self.size = info.wanted_size(&self.ctx.file_priorities.read().await);
                                     self.have_info = true;
 
                                     let mut info_l = self.ctx.info.write().await;
@@ -469,7 +903,11 @@ impl Torrent {
 
                                     self.status = TorrentStatus::Downloading;
 
-                                    self.disk_tx.send(DiskMsg::NewTorrent(self.ctx.clone())).await?;
+                                    self.new_torrent_on_disk().await?;
+
+                                    if self.check {
+                                        self.ctx.tx.send(TorrentMsg::Check).await?;
+                                    }
                                 } else {
                                     warn!("a peer sent a valid Info, but the hash does not match the hash of the provided magnet link, panicking");
                                     return Err(Error::PieceInvalid);
@@ -482,25 +920,93 @@ impl Torrent {
                         }
                         TorrentMsg::IncrementDownloaded(n) => {
                             self.downloaded += n;
-
-                            // check if the torrent download is complete
-                            let is_download_complete = self.downloaded >= self.size;
                             info!("IncrementDownloaded {:?}", self.downloaded);
-
-                            if is_download_complete {
-                                info!("download completed, sending DownloadComplete");
-                                self.ctx.tx.send(TorrentMsg::DownloadComplete).await?;
-                            }
+                        }
+                        TorrentMsg::DecrementDownloaded(n) => {
+                            self.downloaded = self.downloaded.saturating_sub(n);
+                            // so that the rate of this second is not lowered by it
+                            self.last_second_downloaded =
+                                self.last_second_downloaded.saturating_sub(n);
                         }
                         TorrentMsg::IncrementUploaded(n) => {
                             self.uploaded += n;
                         }
+                        TorrentMsg::CacheRead(hit) => {
+                            if hit {
+                                self.stats.cache_hits += 1;
+                            } else {
+                                self.stats.cache_misses += 1;
+                            }
+                        }
+                        TorrentMsg::TrackerAnnounced { url, tracker_tx, result } => {
+                            let now = std::time::Instant::now();
+
+                            match result {
+                                Ok((res, peers)) => {
+                                    info!("announced to tracker {url}, {} peers", peers.len());
+
+                                    if self.ctx.tracker_tx.read().await.is_none() {
+                                        *self.ctx.tracker_tx.write().await = tracker_tx.clone();
+                                    }
+
+                                    self.trackers.success(&url, tracker_tx, res.interval, peers.len(), now);
+                                    self.stats = Stats {
+                                        cache_hits: self.stats.cache_hits,
+                                        cache_misses: self.stats.cache_misses,
+                                        ..res.into()
+                                    };
+
+                                    let peers = self.new_peers(peers);
+                                    self.spawn_outbound_peers(peers).await?;
+                                }
+                                Err(e) => {
+                                    warn!("could not announce to tracker {url}: {e}");
+                                    self.trackers.failure(&url, e.to_string(), now);
+                                }
+                            }
+                        }
+                        TorrentMsg::DhtPeers(peers) => {
+                            info!("found {} peers on the DHT", peers.len());
+
+                            let peers = self.new_peers(peers);
+                            self.spawn_outbound_peers(peers).await?;
+                        }
+                        TorrentMsg::PexPeers(peers) => {
+                            let peers = self.new_peers(peers);
+
+                            if !peers.is_empty() {
+                                info!("found {} peers with PEX", peers.len());
+                            }
+
+                            self.spawn_outbound_peers(peers).await?;
+                        }
+                        TorrentMsg::TogglePause if matches!(self.status, TorrentStatus::Error(_)) => {
+                            info!("resuming the torrent after an error");
+
+                            // the Disk creates the files again, if they are still
+                            // not usable, the torrent receives another `DiskError`.
+                            if self.have_info {
+                                self.new_torrent_on_disk().await?;
+                            }
+
+                            self.status = if !self.have_info {
+                                TorrentStatus::DownloadingMetainfo
+                            } else if self.is_complete().await {
+                                TorrentStatus::Seeding
+                            } else {
+                                TorrentStatus::Downloading
+                            };
+
+                            for peer in self.peer_ctxs.values() {
+                                let _ = peer.tx.send(PeerMsg::Resume).await;
+                            }
+                        }
                         TorrentMsg::TogglePause => {
                             // can only pause if the torrent is not connecting, or not erroring
                             if self.status == TorrentStatus::Downloading || self.status == TorrentStatus::Seeding || self.status == TorrentStatus::Paused {
                                 info!("received pause on torrent {:?}", self.status);
                                 if self.status == TorrentStatus::Paused {
-                                    if self.downloaded >= self.size {
+                                    if self.is_complete().await {
                                         self.status = TorrentStatus::Seeding;
                                     } else {
                                         self.status = TorrentStatus::Downloading;
@@ -517,25 +1023,90 @@ impl Torrent {
                                 }
                             }
                         }
+                        TorrentMsg::Check => {
+                            if self.have_info && self.status != TorrentStatus::Checking {
+                                info!("checking the pieces on disk");
+                                self.status = TorrentStatus::Checking;
+                                self.checked = 0;
+                                self.disk_tx.send(DiskMsg::Check(self.ctx.info_hash)).await?;
+                            }
+                        }
+                        TorrentMsg::MoveStorage(path) => {
+                            info!("moving the files to {path}");
+                            self.disk_tx
+                                .send(DiskMsg::MoveStorage(self.ctx.clone(), path))
+                                .await?;
+                        }
+                        TorrentMsg::DiskError(reason) => {
+                            warn!("the torrent stopped: {reason}");
+
+                            // the peers are paused on the first error
+                            if !matches!(self.status, TorrentStatus::Error(_)) {
+                                for peer in self.peer_ctxs.values() {
+                                    let _ = peer.tx.send(PeerMsg::Pause).await;
+                                }
+                            }
+
+                            self.status = TorrentStatus::Error(reason);
+                        }
+                        TorrentMsg::CheckProgress(checked) => {
+                            self.checked = checked;
+                        }
+                        // the torrent stays stopped if the check failed
+                        TorrentMsg::CheckComplete
+                            if matches!(self.status, TorrentStatus::Error(_) | TorrentStatus::Paused) => {}
+                        TorrentMsg::CheckComplete => {
+                            self.status = TorrentStatus::Downloading;
+
+                            // seed straight from the files that were on disk
+                            if self.is_complete().await {
+                                self.ctx.tx.send(TorrentMsg::DownloadComplete).await?;
+                            } else {
+                                for peer in self.peer_ctxs.values() {
+                                    let _ = peer.tx.send(PeerMsg::Interested).await;
+                                }
+                            }
+                        }
+                        TorrentMsg::SetFilePriority(index, priority) => {
+                            self.set_file_priority(index, priority).await?;
+                        }
+                        TorrentMsg::ToggleSequential => {
+                            self.sequential = !self.sequential;
+                            info!("sequential mode: {}", self.sequential);
+
+                            // without the info, the mode is set
+                            // when the torrent is created on disk.
+                            if self.have_info {
+                                self.disk_tx
+                                    .send(DiskMsg::SetSequential(self.ctx.info_hash, self.sequential))
+                                    .await?;
+                            }
+                        }
                         TorrentMsg::Quit => {
                             info!("torrent is quitting");
-                            let (otx, orx) = oneshot::channel();
-                            let info = self.ctx.info.read().await;
-                            let left =
-                                if self.downloaded > info.get_size()
-                                    { self.downloaded - info.get_size() }
-                                else { 0 };
-
-                            let _ = tracker_tx.send(
-                                TrackerMsg::Announce {
-                                    event: Event::Stopped,
-                                    info_hash: self.ctx.info_hash,
-                                    downloaded: self.downloaded,
-                                    uploaded: self.uploaded,
-                                    left,
-                                    recipient: Some(otx),
-                                })
-                            .await;
+                            self.save_resume_data().await?;
+
+                            let left = self.size.saturating_sub(self.downloaded);
+
+                            // announce to all working trackers that we are stopping
+                            let mut stopped = Vec::new();
+
+                            for (_, tracker_tx) in self.trackers.working() {
+                                let (otx, orx) = oneshot::channel();
+
+                                let _ = tracker_tx.send(
+                                    TrackerMsg::Announce {
+                                        event: Event::Stopped,
+                                        info_hash: self.ctx.info_hash,
+                                        downloaded: self.downloaded,
+                                        uploaded: self.uploaded,
+                                        left,
+                                        recipient: Some(otx),
+                                    })
+                                .await;
+
+                                stopped.push(timeout(Duration::from_secs(5), orx));
+                            }
 
                             for peer in self.peer_ctxs.values() {
                                 let tx = peer.tx.clone();
@@ -544,14 +1115,33 @@ impl Torrent {
                                 });
                             }
 
-                            orx.await??;
+                            join_all(stopped).await;
+
+                            // the Disk writes the blocks of the
+                            // torrent that are in memory, and closes its files
+                            let _ = self.disk_tx.send(DiskMsg::RemoveTorrent(self.ctx.info_hash)).await;
 
                             return Ok(());
                         }
                     }
                 }
                 _ = frontend_interval.tick() => {
-                    self.download_rate = self.downloaded - self.last_second_downloaded;
+                    self.download_rate =
+                        self.downloaded.saturating_sub(self.last_second_downloaded);
+
+                    let mut files = Vec::new();
+
+                    if self.have_info {
+                        let info = self.ctx.info.read().await;
+                        let priorities = self.ctx.file_priorities.read().await;
+
+                        files = info
+                            .get_files()
+                            .into_iter()
+                            .enumerate()
+                            .map(|(i, f)| (f, priorities.get(i).copied().unwrap_or_default()))
+                            .collect();
+                    }
 
                     let torrent_info = TorrentInfo {
                         name: self.name.clone(),
@@ -562,46 +1152,73 @@ impl Torrent {
                         status: self.status.clone(),
                         download_rate: self.download_rate,
                         info_hash: self.ctx.info_hash,
+                        sequential: self.sequential,
+                        files,
+                        checked: self.checked,
+                        pieces: self.ctx.info.read().await.pieces() as usize,
                     };
 
                     self.last_second_downloaded = self.downloaded;
                     self.fr_tx.send(FrMsg::Draw(self.ctx.info_hash, torrent_info)).await?;
                 }
-                // periodically announce to tracker, at the specified interval
-                // to update the tracker about the client's stats.
-                // let have_info = self.ctx.info_dict;
+                // announce to the trackers that are due, the first announce of
+                // each tracker is "started", then periodic announces to update
+                // the trackers about the client's stats.
                 _ = announce_interval.tick() => {
-                    let info = self.ctx.info.read().await;
-
-                    // we know if the info is downloaded if the piece_length is > 0
-                    if info.piece_length > 0 {
-                        info!("sending periodic announce, interval {announce_interval:?}");
-                        let left = if self.downloaded < info.get_size() { info.get_size() } else { self.downloaded - info.get_size() };
-
-                        let (otx, orx) = oneshot::channel();
-
-                        let _ = tracker_tx.send(
-                            TrackerMsg::Announce {
-                                event: Event::None,
-                                info_hash: self.ctx.info_hash,
-                                downloaded: self.downloaded,
-                                uploaded: self.uploaded,
-                                left,
-                                recipient: Some(otx),
-                            })
-                        .await;
+                    for url in self.trackers.due(std::time::Instant::now()) {
+                        let event = match self.trackers.get_mut(&url) {
+                            Some(tracker) if tracker.last_announce.is_none() => Event::Started,
+                            _ => Event::None,
+                        };
+                        self.spawn_announce(url, event);
+                    }
+                }
+                _ = dht_interval.tick(), if self.dht_tx.is_some() => {
+                    self.spawn_get_peers();
+                }
+                _ = pex_interval.tick() => {
+                    // forget the peers whose session has ended
+                    self.peer_ctxs.retain(|_, peer| !peer.tx.is_closed());
+
+                    // only the peers that we connected to, the address of an
+                    // inbound peer is not the address in which it listens.
+                    let connected: Vec<SocketAddr> = self
+                        .peer_ctxs
+                        .values()
+                        .map(|peer| peer.addr)
+                        .filter(|addr| self.known_peers.contains(addr))
+                        .collect();
+
+                    for peer in self.peer_ctxs.values() {
+                        let _ = peer.tx.send(PeerMsg::Pex(connected.clone())).await;
+                    }
+                }
+                _ = resume_interval.tick() => {
+                    self.save_resume_data().await?;
+                }
+                _ = choke_interval.tick(), if !matches!(self.status, TorrentStatus::Paused | TorrentStatus::Error(_)) => {
+                    let mut peers = Vec::with_capacity(self.peer_ctxs.len());
 
-                        let r = orx.await??;
-                        info!("new stats {r:#?}");
+                    for (id, peer) in &self.peer_ctxs {
+                        if !peer.tx.is_closed() {
+                            peers.push((*id, *peer.stats.read().await));
+                        }
+                    }
 
-                        // update our stats, received from the tracker
-                        self.stats = r.into();
+                    let seeding = self.status == TorrentStatus::Seeding;
+                    let (unchoke, choke) =
+                        self.choker.run(&peers, seeding, std::time::Instant::now());
 
-                        announce_interval = interval(
-                            Duration::from_secs(self.stats.interval as u64),
-                        );
+                    for id in choke {
+                        if let Some(peer) = self.peer_ctxs.get(&id) {
+                            let _ = peer.tx.send(PeerMsg::Choke).await;
+                        }
+                    }
+                    for id in unchoke {
+                        if let Some(peer) = self.peer_ctxs.get(&id) {
+                            let _ = peer.tx.send(PeerMsg::Unchoke).await;
+                        }
                     }
-                    drop(info);
                 }
             }
         }
@@ -615,11 +1232,14 @@ pub enum TorrentStatus {
     DownloadingMetainfo,
     Downloading,
     Seeding,
+    /// The pieces on disk are being hashed, see [`TorrentMsg::Check`].
+    Checking,
     Paused,
-    Error,
+    /// The torrent stopped, with the reason.
+    Error(String),
 }
 
-impl<'a> From<TorrentStatus> for &'a str {
+impl From<TorrentStatus> for &str {
     fn from(val: TorrentStatus) -> Self {
         use TorrentStatus::*;
         match val {
@@ -627,8 +1247,9 @@ impl<'a> From<TorrentStatus> for &'a str {
             DownloadingMetainfo => "Downloading metainfo",
             Downloading => "Downloading",
             Seeding => "Seeding",
+            Checking => "Checking",
             Paused => "Paused",
-            Error => "Error",
+            Error(_) => "Error",
         }
     }
 }
@@ -641,8 +1262,9 @@ impl From<TorrentStatus> for String {
             DownloadingMetainfo => "Downloading metainfo".to_owned(),
             Downloading => "Downloading".to_owned(),
             Seeding => "Seeding".to_owned(),
+            Checking => "Checking".to_owned(),
             Paused => "Paused".to_owned(),
-            Error => "Error".to_owned(),
+            Error(reason) => format!("Error: {reason}"),
         }
     }
 }
@@ -655,8 +1277,9 @@ impl From<&str> for TorrentStatus {
             "Downloading metainfo" => DownloadingMetainfo,
             "Downloading" => Downloading,
             "Seeding" => Seeding,
+            "Checking" => Checking,
             "Paused" => Paused,
-            _ => Error,
+            _ => Error(value.to_owned()),
         }
     }
 }
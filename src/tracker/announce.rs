@@ -1,3 +1,6 @@
+// the code derived by speedy takes raw pointers in public functions.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
 use rand::Rng;
 use speedy::{BigEndian, Readable, Writable};
 
@@ -79,6 +82,7 @@ impl From<Response> for Stats {
             interval: value.interval,
             seeders: value.seeders,
             leechers: value.leechers,
+            ..Default::default()
         }
     }
 }
@@ -1,3 +1,6 @@
+// the code derived by speedy takes raw pointers in public functions.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
 use speedy::{BigEndian, Readable, Writable};
 use tracing::debug;
 
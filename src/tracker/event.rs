@@ -7,6 +7,19 @@ pub enum Event {
     Stopped,
 }
 
+impl Event {
+    /// The value of the `event` key of an HTTP announce,
+    /// `None` means that the key is omitted.
+    pub fn as_http_str(&self) -> Option<&'static str> {
+        match self {
+            Event::None => None,
+            Event::Completed => Some("completed"),
+            Event::Started => Some("started"),
+            Event::Stopped => Some("stopped"),
+        }
+    }
+}
+
 impl From<Event> for u64 {
     fn from(a: Event) -> Self {
         match a {
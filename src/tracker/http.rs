@@ -0,0 +1,481 @@
+//! HTTP and HTTPS trackers, as described in BEP 3 and BEP 23.
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use bendy::decoding::{self, FromBencode, Object, ResultExt};
+use tokio::{select, sync::mpsc};
+use tracing::{info, warn};
+
+use crate::error::Error;
+
+use super::{action::Action, announce, event::Event, Tracker, TrackerCtx, TrackerMsg};
+
+/// A tracker that speaks HTTP(S), the announce is a GET request
+/// with the parameters encoded in the query string, and the response
+/// is a bencoded dictionary.
+#[derive(Debug)]
+pub struct HttpTracker {
+    /// The announce URL, i.e "http://bttracker.debian.org:6969/announce"
+    pub url: String,
+    pub ctx: TrackerCtx,
+    /// A string that the tracker might send on the response,
+    /// it must be sent back on the next announces.
+    pub tracker_id: Option<String>,
+    /// Announces should not be more frequent than this, in seconds.
+    pub min_interval: Option<u32>,
+    pub tx: mpsc::Sender<TrackerMsg>,
+    pub rx: mpsc::Receiver<TrackerMsg>,
+    client: reqwest::Client,
+}
+
+/// The bencoded response of an announce.
+#[derive(Debug, Default, PartialEq)]
+pub struct Response {
+    /// If present, no other keys are present,
+    /// and the announce failed.
+    pub failure_reason: Option<String>,
+    /// The announce succeeded, but the tracker has something to say.
+    pub warning_message: Option<String>,
+    pub interval: u32,
+    pub min_interval: Option<u32>,
+    pub tracker_id: Option<String>,
+    /// Number of seeders.
+    pub complete: u32,
+    /// Number of leechers.
+    pub incomplete: u32,
+    /// Peers of the "peers" and "peers6" keys,
+    /// in both the compact and the dictionary model.
+    pub peers: Vec<SocketAddr>,
+}
+
+impl From<&Response> for announce::Response {
+    fn from(value: &Response) -> Self {
+        Self {
+            action: Action::Announce.into(),
+            transaction_id: 0,
+            interval: value.interval,
+            leechers: value.incomplete,
+            seeders: value.complete,
+        }
+    }
+}
+
+impl HttpTracker {
+    /// How many peers we want to receive on each announce.
+    const NUM_WANT: u32 = 200;
+
+    /// How long an announce can take, a tracker that doesn't answer
+    /// fails the announce, so that the next tracker of its tier is used.
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// How long connecting to the tracker can take.
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn new(url: String, ctx: TrackerCtx) -> Self {
+        let (tx, rx) = mpsc::channel::<TrackerMsg>(300);
+        let client = reqwest::Client::builder()
+            .timeout(Self::TIMEOUT)
+            .connect_timeout(Self::CONNECT_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+
+        Self {
+            ctx: TrackerCtx {
+                tracker_addr: url.clone(),
+                ..ctx
+            },
+            url,
+            tracker_id: None,
+            min_interval: None,
+            tx,
+            rx,
+            client,
+        }
+    }
+
+    /// If the given tracker URL speaks HTTP or HTTPS.
+    pub fn is_http(url: &str) -> bool {
+        url.starts_with("http://") || url.starts_with("https://")
+    }
+
+    /// Build the URL of an announce request, with the query string.
+    pub fn announce_url(
+        &self,
+        event: Event,
+        info_hash: [u8; 20],
+        downloaded: u64,
+        uploaded: u64,
+        left: u64,
+    ) -> String {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+
+        let mut url = format!(
+            "{}{separator}info_hash={}&peer_id={}&port={}&uploaded={uploaded}&downloaded={downloaded}&left={left}&compact=1&numwant={}",
+            self.url,
+            urlencoding::encode_binary(&info_hash),
+            urlencoding::encode_binary(&self.ctx.peer_id),
+            self.ctx.local_peer_addr.port(),
+            Self::NUM_WANT,
+        );
+
+        if let Some(event) = event.as_http_str() {
+            url.push_str("&event=");
+            url.push_str(event);
+        }
+
+        if let Some(tracker_id) = &self.tracker_id {
+            url.push_str("&trackerid=");
+            url.push_str(&urlencoding::encode(tracker_id));
+        }
+
+        url
+    }
+
+    /// Send an announce request and return the response,
+    /// with the list of peers.
+    #[tracing::instrument(skip(self, info_hash))]
+    pub async fn announce(
+        &mut self,
+        event: Event,
+        info_hash: [u8; 20],
+        downloaded: u64,
+        uploaded: u64,
+        left: u64,
+    ) -> Result<Response, Error> {
+        let url = self.announce_url(event, info_hash, downloaded, uploaded, left);
+        info!("announcing to {url}");
+
+        let bytes = self.client.get(url).send().await?.bytes().await?;
+        let res = Response::from_bencode(&bytes).map_err(|_| Error::BencodeError)?;
+
+        if let Some(failure_reason) = res.failure_reason {
+            warn!("tracker {} failed: {failure_reason}", self.url);
+            return Err(Error::TrackerFailure(failure_reason));
+        }
+
+        if let Some(warning_message) = &res.warning_message {
+            warn!("tracker {} warning: {warning_message}", self.url);
+        }
+
+        if res.tracker_id.is_some() {
+            self.tracker_id = res.tracker_id.clone();
+        }
+
+        if res.min_interval.is_some() {
+            self.min_interval = res.min_interval;
+        }
+
+        info!("res from announce {res:#?}");
+
+        Ok(res)
+    }
+
+    /// Send the first announce, with the "started" event. `left` is how many
+    /// bytes of the torrent are left to download, 0 if we don't have the info.
+    pub async fn announce_exchange(
+        &mut self,
+        info_hash: [u8; 20],
+        listen: Option<SocketAddr>,
+        left: u64,
+    ) -> Result<(announce::Response, Vec<SocketAddr>), Error> {
+        if let Some(listen) = listen {
+            self.ctx.local_peer_addr.set_port(listen.port());
+        }
+
+        let res = self.announce(Event::Started, info_hash, 0, 0, left).await?;
+
+        Ok(((&res).into(), res.peers))
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn run(&mut self) -> Result<(), Error> {
+        info!("running http tracker");
+        loop {
+            select! {
+                Some(msg) = self.rx.recv() => {
+                    match msg {
+                        TrackerMsg::Announce {
+                            info_hash,
+                            downloaded,
+                            uploaded,
+                            recipient,
+                            event,
+                            left,
+                        } => {
+                            let r = self
+                                .announce(event.clone(), info_hash, downloaded, uploaded, left)
+                                .await
+                                .map(|r| ((&r).into(), r.peers));
+
+                            if let Some(recipient) = recipient {
+                                let _ = recipient.send(r);
+                            }
+
+                            if event == Event::Stopped {
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+                // the torrent dropped this tracker
+                else => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Peers in the dictionary model, the "peer id" is ignored.
+fn decode_peer_dict(object: Object) -> Result<SocketAddr, decoding::Error> {
+    let mut ip = None;
+    let mut port = None;
+
+    let mut dict_dec = object.try_into_dictionary()?;
+    while let Some(pair) = dict_dec.next_pair()? {
+        match pair {
+            (b"ip", value) => {
+                ip = String::decode_bencode_object(value)
+                    .context("ip")
+                    .map(Some)?;
+            }
+            (b"port", value) => {
+                port = u16::decode_bencode_object(value)
+                    .context("port")
+                    .map(Some)?;
+            }
+            _ => {}
+        }
+    }
+
+    let ip = ip.ok_or_else(|| decoding::Error::missing_field("ip"))?;
+    let port = port.ok_or_else(|| decoding::Error::missing_field("port"))?;
+    let ip: IpAddr = ip
+        .parse()
+        .map_err(|_| decoding::Error::malformed_content(Error::TrackerCompactPeerList))?;
+
+    Ok((ip, port).into())
+}
+
+impl FromBencode for Response {
+    fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error>
+    where
+        Self: Sized,
+    {
+        let mut res = Response::default();
+
+        let mut dict_dec = object.try_into_dictionary()?;
+        while let Some(pair) = dict_dec.next_pair()? {
+            match pair {
+                (b"failure reason", value) => {
+                    res.failure_reason = String::decode_bencode_object(value)
+                        .context("failure reason")
+                        .map(Some)?;
+                }
+                (b"warning message", value) => {
+                    res.warning_message = String::decode_bencode_object(value)
+                        .context("warning message")
+                        .map(Some)?;
+                }
+                (b"interval", value) => {
+                    res.interval = u32::decode_bencode_object(value).context("interval")?;
+                }
+                (b"min interval", value) => {
+                    res.min_interval = u32::decode_bencode_object(value)
+                        .context("min interval")
+                        .map(Some)?;
+                }
+                (b"tracker id", value) => {
+                    res.tracker_id = String::decode_bencode_object(value)
+                        .context("tracker id")
+                        .map(Some)?;
+                }
+                (b"complete", value) => {
+                    res.complete = u32::decode_bencode_object(value).context("complete")?;
+                }
+                (b"incomplete", value) => {
+                    res.incomplete = u32::decode_bencode_object(value).context("incomplete")?;
+                }
+                (b"peers", Object::Bytes(bytes)) => {
+                    let peers = Tracker::parse_compact_peer_list(bytes, false)
+                        .map_err(decoding::Error::malformed_content)?;
+                    res.peers.extend(peers);
+                }
+                (b"peers", Object::List(mut list)) => {
+                    while let Some(peer) = list.next_object()? {
+                        res.peers.push(decode_peer_dict(peer).context("peers")?);
+                    }
+                }
+                (b"peers6", Object::Bytes(bytes)) => {
+                    let peers = Tracker::parse_compact_peer_list(bytes, true)
+                        .map_err(decoding::Error::malformed_content)?;
+                    res.peers.extend(peers);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        spawn,
+        sync::oneshot,
+    };
+
+    use super::*;
+
+    /// A local stand-in of an HTTP tracker, answers each request with
+    /// the next body of `bodies`, and returns the request lines that it received.
+    async fn http_tracker(bodies: Vec<Vec<u8>>) -> (String, oneshot::Receiver<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (tx, rx) = oneshot::channel();
+
+        spawn(async move {
+            let mut requests = Vec::new();
+
+            for body in bodies {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 4096];
+                let len = socket.read(&mut buf).await.unwrap();
+                let req = String::from_utf8_lossy(&buf[..len]).to_string();
+                requests.push(req.lines().next().unwrap().to_owned());
+
+                let mut res = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .into_bytes();
+                res.extend(body);
+
+                socket.write_all(&res).await.unwrap();
+            }
+
+            let _ = tx.send(requests);
+        });
+
+        (url, rx)
+    }
+
+    #[tokio::test]
+    async fn announce_compact() {
+        let mut body =
+            b"d8:completei5e10:incompletei3e8:intervali1800e12:min intervali900e5:peers12:"
+                .to_vec();
+        body.extend([127, 0, 0, 1, 0x1A, 0xE1, 10, 0, 0, 2, 0x1A, 0xE2]);
+        body.extend(b"6:peers618:");
+        body.extend([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1A, 0xE3]);
+        body.extend(b"10:tracker id3:abce");
+
+        let second = b"d8:completei6e10:incompletei3e8:intervali1800e5:peers0:e".to_vec();
+
+        let (url, requests) = http_tracker(vec![body, second]).await;
+
+        let mut tracker = HttpTracker::new(url, TrackerCtx::default());
+        let info_hash = [0xAB; 20];
+
+        let (res, peers) = tracker
+            .announce_exchange(info_hash, Some("0.0.0.0:51413".parse().unwrap()), 40)
+            .await
+            .unwrap();
+
+        assert_eq!(res.interval, 1800);
+        assert_eq!(res.seeders, 5);
+        assert_eq!(res.leechers, 3);
+        assert_eq!(
+            peers,
+            vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:6882".parse().unwrap(),
+                "[::1]:6883".parse().unwrap(),
+            ]
+        );
+        assert_eq!(tracker.min_interval, Some(900));
+        assert_eq!(tracker.tracker_id, Some("abc".to_owned()));
+
+        // the next announce must send the tracker id back
+        let res = tracker
+            .announce(Event::None, info_hash, 10, 20, 30)
+            .await
+            .unwrap();
+
+        assert_eq!(res.complete, 6);
+        assert!(res.peers.is_empty());
+
+        let requests = requests.await.unwrap();
+        let info_hash = "%AB".repeat(20);
+
+        assert!(requests[0].starts_with("GET /announce?info_hash="));
+        assert!(requests[0].contains(&format!("info_hash={info_hash}&")));
+        assert!(requests[0].contains("&port=51413&"));
+        assert!(requests[0].contains("&compact=1"));
+        assert!(requests[0].contains("&event=started"));
+        assert!(requests[0].contains("&left=40&"));
+        assert!(!requests[0].contains("trackerid"));
+
+        assert!(requests[1].contains("&uploaded=20&downloaded=10&left=30&"));
+        assert!(!requests[1].contains("event="));
+        assert!(requests[1].contains("&trackerid=abc"));
+    }
+
+    #[tokio::test]
+    async fn announce_dict_peers() {
+        let body = b"d8:intervali60e5:peersld2:ip9:127.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881eed2:ip3:::14:porti6882eeee".to_vec();
+
+        let (url, _) = http_tracker(vec![body]).await;
+
+        let mut tracker = HttpTracker::new(url, TrackerCtx::default());
+        let (res, peers) = tracker.announce_exchange([0; 20], None, 0).await.unwrap();
+
+        assert_eq!(res.interval, 60);
+        assert_eq!(
+            peers,
+            vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "[::1]:6882".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn announce_failure_and_warning() {
+        let failure = b"d14:failure reason17:torrent not founde".to_vec();
+        let warning =
+            b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe115:warning message7:go awaye"
+                .to_vec();
+
+        let (url, _) = http_tracker(vec![failure, warning]).await;
+
+        let mut tracker = HttpTracker::new(url, TrackerCtx::default());
+        let r = tracker.announce(Event::Started, [0; 20], 0, 0, 0).await;
+
+        match r {
+            Err(Error::TrackerFailure(reason)) => assert_eq!(reason, "torrent not found"),
+            r => panic!("expected a failure, got {r:?}"),
+        }
+
+        // a warning does not make the announce fail
+        let res = tracker
+            .announce(Event::Started, [0; 20], 0, 0, 0)
+            .await
+            .unwrap();
+
+        assert_eq!(res.warning_message, Some("go away".to_owned()));
+        assert_eq!(res.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn is_http() {
+        assert!(HttpTracker::is_http(
+            "http://bttracker.debian.org:6969/announce"
+        ));
+        assert!(HttpTracker::is_http("https://tracker.example.org/announce"));
+        assert!(!HttpTracker::is_http("udp://tracker.opentrackr.org:1337"));
+    }
+}
@@ -2,6 +2,8 @@ pub mod action;
 pub mod announce;
 pub mod connect;
 pub mod event;
+pub mod http;
+pub mod tiers;
 
 use super::tracker::action::Action;
 use std::{
@@ -10,7 +12,7 @@ use std::{
     time::Duration,
 };
 
-use crate::error::Error;
+use crate::{error::Error, magnet_parser::get_tracker_addr};
 use rand::Rng;
 use tokio::{
     net::{ToSocketAddrs, UdpSocket},
@@ -71,6 +73,9 @@ impl Default for TrackerCtx {
     }
 }
 
+/// The response of a tracker to an announce, with the peers it returned.
+pub type AnnounceResult = Result<(announce::Response, Vec<SocketAddr>), Error>;
+
 #[derive(Debug)]
 pub enum TrackerMsg {
     Announce {
@@ -79,10 +84,39 @@ pub enum TrackerMsg {
         downloaded: u64,
         uploaded: u64,
         left: u64,
-        recipient: Option<oneshot::Sender<Result<announce::Response, Error>>>,
+        recipient: Option<oneshot::Sender<AnnounceResult>>,
     },
 }
 
+/// Connect to the tracker of the given announce URL, speaking HTTP or UDP
+/// depending on its scheme, and spawn its event loop. The tracker will use
+/// the peer_id and the local peer address of `ctx`.
+pub async fn spawn_tracker(url: &str, ctx: TrackerCtx) -> Result<mpsc::Sender<TrackerMsg>, Error> {
+    if http::HttpTracker::is_http(url) {
+        let mut tracker = http::HttpTracker::new(url.to_owned(), ctx);
+        let tx = tracker.tx.clone();
+
+        spawn(async move {
+            tracker.run().await?;
+            Ok::<(), Error>(())
+        });
+
+        return Ok(tx);
+    }
+
+    let mut tracker = Tracker::connect(vec![get_tracker_addr(url)]).await?;
+    tracker.ctx.peer_id = ctx.peer_id;
+    tracker.ctx.local_peer_addr = ctx.local_peer_addr;
+    let tx = tracker.tx.clone();
+
+    spawn(async move {
+        tracker.run().await?;
+        Ok::<(), Error>(())
+    });
+
+    Ok(tx)
+}
+
 impl Tracker {
     const ANNOUNCE_RES_BUF_LEN: usize = 8192;
 
@@ -291,7 +325,10 @@ impl Tracker {
     }
 
     #[tracing::instrument(skip(buf, is_ipv6))]
-    fn parse_compact_peer_list(buf: &[u8], is_ipv6: bool) -> Result<Vec<SocketAddr>, Error> {
+    pub(crate) fn parse_compact_peer_list(
+        buf: &[u8],
+        is_ipv6: bool,
+    ) -> Result<Vec<SocketAddr>, Error> {
         let mut peer_list = Vec::<SocketAddr>::new();
 
         // in ipv4 the addresses come in packets of 6 bytes,
@@ -334,7 +371,7 @@ impl Tracker {
         downloaded: u64,
         uploaded: u64,
         left: u64,
-    ) -> Result<announce::Response, Error> {
+    ) -> Result<(announce::Response, Vec<SocketAddr>), Error> {
         info!("announcing {event:#?} to tracker");
         let socket = UdpSocket::bind(self.local_addr).await?;
         socket.connect(self.peer_addr).await?;
@@ -351,7 +388,7 @@ impl Tracker {
             event: event.into(),
             ip_address: 0,
             num_want: u32::MAX,
-            port: self.local_addr.port(),
+            port: self.ctx.local_peer_addr.port(),
         };
 
         let mut len = 0_usize;
@@ -373,11 +410,21 @@ impl Tracker {
             }
         }
 
+        if len == 0 {
+            return Err(Error::TrackerResponse);
+        }
+
         let res = &res[..len];
 
-        let (res, _) = announce::Response::deserialize(res)?;
+        let (res, payload) = announce::Response::deserialize(res)?;
 
-        Ok(res)
+        if res.transaction_id != req.transaction_id || res.action != req.action {
+            return Err(Error::TrackerResponse);
+        }
+
+        let peers = Self::parse_compact_peer_list(payload, socket.peer_addr()?.is_ipv6())?;
+
+        Ok((res, peers))
     }
 
     #[tracing::instrument(skip(self))]
@@ -409,6 +456,8 @@ impl Tracker {
                         }
                     }
                 }
+                // the torrent dropped this tracker
+                else => return Ok(()),
             }
         }
     }
@@ -0,0 +1,391 @@
+//! The trackers of a torrent, grouped in tiers, as described in BEP 12.
+//! http://www.bittorrent.org/beps/bep_0012.html
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+use tokio::sync::mpsc;
+
+use super::TrackerMsg;
+
+/// The state of one of the trackers of a torrent.
+#[derive(Debug, Clone)]
+pub struct TrackerStatus {
+    /// The announce URL of the tracker.
+    pub url: String,
+    /// Sender of the task of the tracker, `None` until we connect to it,
+    /// and after an announce fails, so that we connect again on the next try.
+    pub tx: Option<mpsc::Sender<TrackerMsg>>,
+    /// When we last announced to this tracker with success.
+    pub last_announce: Option<Instant>,
+    /// When we will announce to this tracker again, either at the
+    /// interval that the tracker asked for, or to retry after a failure.
+    pub next_announce: Option<Instant>,
+    /// The error of the last announce, if it failed.
+    pub error: Option<String>,
+    /// How many peers the tracker returned on the last announce.
+    pub peers: usize,
+    /// How many announces in a row have failed.
+    pub fails: u32,
+    /// If an announce to this tracker is in flight.
+    pub announcing: bool,
+}
+
+impl TrackerStatus {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            tx: None,
+            last_announce: None,
+            next_announce: None,
+            error: None,
+            peers: 0,
+            fails: 0,
+            announcing: false,
+        }
+    }
+
+    /// If the last announce to this tracker succeeded.
+    pub fn is_working(&self) -> bool {
+        self.error.is_none() && self.last_announce.is_some()
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        self.next_announce.is_none_or(|next| next <= now)
+    }
+}
+
+/// Each tier starts by announcing to its first tracker. When an announce
+/// fails, the tracker is moved to the back of its tier, and the tier falls
+/// back to its next tracker that was not tried yet. A tracker that responds
+/// is moved to the front of its tier.
+///
+/// Every tracker that is working is announced to again at its interval, and
+/// the trackers that failed are retried after a back off, so that they are
+/// announced to again once they work.
+#[derive(Debug, Clone, Default)]
+pub struct Tiers {
+    pub tiers: Vec<Vec<TrackerStatus>>,
+}
+
+impl Tiers {
+    /// Announce to a tracker at most this often,
+    /// even if it asks for a smaller interval.
+    const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// How long to wait before retrying a tracker that failed,
+    /// doubled at each failure in a row.
+    const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// The retry interval never grows larger than this.
+    const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+    /// Create the tiers from the URLs of the trackers, the trackers
+    /// inside each tier are shuffled, as per BEP 12.
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        let mut rng = rand::thread_rng();
+
+        let tiers = tiers
+            .into_iter()
+            .filter(|tier| !tier.is_empty())
+            .map(|mut tier| {
+                tier.shuffle(&mut rng);
+                tier.into_iter().map(TrackerStatus::new).collect()
+            })
+            .collect();
+
+        Self { tiers }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// Iterate over the trackers of all tiers.
+    pub fn iter(&self) -> impl Iterator<Item = &TrackerStatus> {
+        self.tiers.iter().flatten()
+    }
+
+    pub fn get_mut(&mut self, url: &str) -> Option<&mut TrackerStatus> {
+        self.tiers.iter_mut().flatten().find(|t| t.url == url)
+    }
+
+    /// Get the URLs of the trackers that we should announce to now: the
+    /// trackers that are working and the ones that failed, when they are due,
+    /// and for each tier without a working tracker, its next tracker that
+    /// was not tried yet.
+    pub fn due(&self, now: Instant) -> Vec<String> {
+        let mut urls = Vec::new();
+
+        for tier in &self.tiers {
+            urls.extend(
+                tier.iter()
+                    .filter(|t| !t.announcing && (t.is_working() || t.error.is_some()))
+                    .filter(|t| t.is_due(now))
+                    .map(|t| t.url.clone()),
+            );
+
+            // fall back to the next tracker of the tier
+            if tier.iter().any(|t| t.is_working() || t.announcing) {
+                continue;
+            }
+
+            if let Some(tracker) = tier.iter().find(|t| t.error.is_none()) {
+                urls.push(tracker.url.clone());
+            }
+        }
+
+        urls
+    }
+
+    /// Get the URLs and senders of all trackers that are working.
+    pub fn working(&self) -> Vec<(String, mpsc::Sender<TrackerMsg>)> {
+        self.iter()
+            .filter(|t| t.is_working())
+            .filter_map(|t| Some((t.url.clone(), t.tx.clone()?)))
+            .collect()
+    }
+
+    /// Mark that an announce to the tracker is in flight.
+    pub fn announcing(&mut self, url: &str) {
+        if let Some(tracker) = self.get_mut(url) {
+            tracker.announcing = true;
+        }
+    }
+
+    /// The tracker answered an announce, it will be announced again after
+    /// `interval` seconds, and it is moved to the front of its tier.
+    pub fn success(
+        &mut self,
+        url: &str,
+        tx: Option<mpsc::Sender<TrackerMsg>>,
+        interval: u32,
+        peers: usize,
+        now: Instant,
+    ) {
+        if let Some(tracker) = self.get_mut(url) {
+            let interval = Duration::from_secs(interval.into()).max(Self::MIN_INTERVAL);
+
+            tracker.tx = tx;
+            tracker.announcing = false;
+            tracker.error = None;
+            tracker.fails = 0;
+            tracker.peers = peers;
+            tracker.last_announce = Some(now);
+            tracker.next_announce = Some(now + interval);
+        }
+        self.promote(url);
+    }
+
+    /// The announce to the tracker failed, it is moved to the back of its
+    /// tier, the next tracker of the tier will be used if the tier has no
+    /// working tracker, and this one can only be retried after a back off.
+    pub fn failure(&mut self, url: &str, error: String, now: Instant) {
+        if let Some(tracker) = self.get_mut(url) {
+            let retry = Self::RETRY_INTERVAL
+                .saturating_mul(2_u32.saturating_pow(tracker.fails))
+                .min(Self::MAX_RETRY_INTERVAL);
+
+            tracker.tx = None;
+            tracker.announcing = false;
+            tracker.error = Some(error);
+            tracker.fails += 1;
+            tracker.next_announce = Some(now + retry);
+        }
+        self.demote(url);
+    }
+
+    /// Move the tracker to the front of its tier.
+    fn promote(&mut self, url: &str) {
+        for tier in &mut self.tiers {
+            if let Some(i) = tier.iter().position(|t| t.url == url) {
+                let tracker = tier.remove(i);
+                tier.insert(0, tracker);
+                return;
+            }
+        }
+    }
+
+    /// Move the tracker to the back of its tier.
+    fn demote(&mut self, url: &str) {
+        for tier in &mut self.tiers {
+            if let Some(i) = tier.iter().position(|t| t.url == url) {
+                let tracker = tier.remove(i);
+                tier.push(tracker);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiers() -> Tiers {
+        let mut tiers = Tiers::new(vec![
+            vec!["udp://a".to_owned()],
+            vec![],
+            vec!["http://b".to_owned(), "http://c".to_owned()],
+        ]);
+        // undo the shuffle, to have a predictable order
+        tiers.tiers[1].sort_by(|a, b| a.url.cmp(&b.url));
+        tiers
+    }
+
+    #[test]
+    fn announce_first_tracker_of_each_tier() {
+        let tiers = tiers();
+
+        assert_eq!(tiers.tiers.len(), 2);
+        assert_eq!(
+            tiers.due(Instant::now()),
+            vec!["udp://a".to_owned(), "http://b".to_owned()]
+        );
+    }
+
+    #[test]
+    fn fallback_and_promote() {
+        let mut tiers = tiers();
+        let now = Instant::now();
+
+        tiers.announcing("udp://a");
+        tiers.announcing("http://b");
+
+        // a tier with an announce in flight is not announced again
+        assert!(tiers.due(now).is_empty());
+
+        tiers.success("udp://a", None, 1800, 10, now);
+        tiers.failure("http://b", "timeout".to_owned(), now);
+
+        // fallback to the next tracker of the tier
+        assert_eq!(tiers.due(now), vec!["http://c".to_owned()]);
+
+        tiers.announcing("http://c");
+        tiers.success("http://c", None, 30, 5, now);
+
+        // the tracker that responded goes to the front of the tier
+        let urls: Vec<&str> = tiers.tiers[1].iter().map(|t| t.url.as_str()).collect();
+        assert_eq!(urls, vec!["http://c", "http://b"]);
+
+        assert!(tiers.due(now).is_empty());
+
+        // the interval of "c" is smaller than the minimum,
+        // and "b" is retried after a back off
+        let retry = now + Duration::from_secs(60);
+        assert_eq!(
+            tiers.due(retry),
+            vec!["http://c".to_owned(), "http://b".to_owned()]
+        );
+
+        tiers.announcing("http://c");
+        tiers.announcing("http://b");
+        tiers.success("http://c", None, 1800, 5, retry);
+        tiers.success("http://b", None, 1800, 5, retry);
+
+        // periodic re-announce to all working trackers
+        assert_eq!(
+            tiers.due(retry + Duration::from_secs(1800)),
+            vec![
+                "udp://a".to_owned(),
+                "http://b".to_owned(),
+                "http://c".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn reannounce_to_every_working_tracker() {
+        let mut tiers = Tiers::new(vec![
+            vec!["udp://a".to_owned(), "udp://b".to_owned()],
+            vec!["http://c".to_owned(), "http://d".to_owned()],
+        ]);
+        for tier in &mut tiers.tiers {
+            tier.sort_by(|a, b| a.url.cmp(&b.url));
+        }
+        let now = Instant::now();
+        let later = now + Duration::from_secs(1800);
+
+        assert_eq!(
+            tiers.due(now),
+            vec!["udp://a".to_owned(), "http://c".to_owned()]
+        );
+
+        tiers.announcing("udp://a");
+        tiers.announcing("http://c");
+        tiers.success("udp://a", None, 1800, 10, now);
+        tiers.success("http://c", None, 1800, 10, now);
+        assert!(tiers.due(now).is_empty());
+
+        // both tiers announce again to their working tracker
+        assert_eq!(
+            tiers.due(later),
+            vec!["udp://a".to_owned(), "http://c".to_owned()]
+        );
+
+        // the tracker that failed goes to the back of its tier
+        tiers.announcing("udp://a");
+        tiers.failure("udp://a", "timeout".to_owned(), later);
+
+        let urls: Vec<&str> = tiers.tiers[0].iter().map(|t| t.url.as_str()).collect();
+        assert_eq!(urls, vec!["udp://b", "udp://a"]);
+        assert_eq!(
+            tiers.due(later),
+            vec!["udp://b".to_owned(), "http://c".to_owned()]
+        );
+
+        tiers.announcing("udp://b");
+        tiers.success("udp://b", None, 1800, 10, later);
+        assert_eq!(tiers.due(later), vec!["http://c".to_owned()]);
+
+        // the tracker that failed is retried while "b" works
+        let retry = later + Tiers::RETRY_INTERVAL;
+        assert_eq!(
+            tiers.due(retry),
+            vec!["udp://a".to_owned(), "http://c".to_owned()]
+        );
+
+        tiers.announcing("udp://a");
+        tiers.announcing("http://c");
+        tiers.success("udp://a", None, 1800, 10, retry);
+        tiers.success("http://c", None, 1800, 10, retry);
+
+        // both trackers of the first tier are working
+        assert_eq!(
+            tiers.due(retry + Duration::from_secs(1800)),
+            vec![
+                "udp://a".to_owned(),
+                "udp://b".to_owned(),
+                "http://c".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn retry_after_all_trackers_of_a_tier_failed() {
+        let mut tiers = tiers();
+        let now = Instant::now();
+
+        tiers.failure("http://b", "timeout".to_owned(), now);
+        tiers.failure("http://c", "timeout".to_owned(), now);
+        tiers.success("udp://a", None, 1800, 10, now);
+
+        // the trackers that failed are retried after a back off
+        assert!(tiers.due(now).is_empty());
+        assert_eq!(
+            tiers.due(now + Tiers::RETRY_INTERVAL),
+            vec!["http://b".to_owned(), "http://c".to_owned()]
+        );
+
+        // each failure in a row doubles the time to retry
+        tiers.failure("http://b", "timeout".to_owned(), now);
+        assert_eq!(tiers.get_mut("http://b").unwrap().fails, 2);
+        assert_eq!(
+            tiers.get_mut("http://b").unwrap().next_announce,
+            Some(now + Tiers::RETRY_INTERVAL * 2)
+        );
+        assert_eq!(
+            tiers.due(now + Tiers::RETRY_INTERVAL),
+            vec!["http://c".to_owned()]
+        );
+    }
+}